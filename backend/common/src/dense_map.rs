@@ -71,6 +71,20 @@ where
         }
     }
 
+    /// Add an item with the given Id, as long as that Id was previously
+    /// used and has since been removed. If not, the item is handed back.
+    pub fn add_at(&mut self, id: Id, item: T) -> Result<(), T> {
+        let id: usize = id.into();
+        match self.retired.iter().position(|&retired| retired == id) {
+            Some(idx) => {
+                self.retired.swap_remove(idx);
+                self.items[id] = Some(item);
+                Ok(())
+            }
+            None => Err(item),
+        }
+    }
+
     pub fn get(&self, id: Id) -> Option<&T> {
         let id: usize = id.into();
         self.items.get(id).and_then(|item| item.as_ref())
@@ -162,4 +176,26 @@ mod test {
 
         assert_eq!(map.len(), 0);
     }
+
+    #[test]
+    fn items_can_only_be_added_at_retired_ids() {
+        let mut map = DenseMap::<usize, usize>::new();
+
+        let id1 = map.add(1);
+        let id2 = map.add(2);
+        let id3 = map.add(3);
+        map.remove(id1);
+        map.remove(id2);
+
+        // Occupied and never used IDs are refused:
+        assert_eq!(map.add_at(id3, 4), Err(4));
+        assert_eq!(map.add_at(10, 4), Err(4));
+
+        // A retired ID can be taken, and is no longer handed out by `add`:
+        assert_eq!(map.add_at(id1, 4), Ok(()));
+        assert_eq!(map.get(id1), Some(&4));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.add(5), id2);
+        assert_eq!(map.add(6), 3);
+    }
 }
@@ -64,4 +64,12 @@ pub enum FromTelemetryCore {
 pub enum MuteReason {
     Overquota,
     ChainNotAllowed,
+    NodeBlocked,
+    MessageTooLarge,
+    /// The node didn't report everything that we require of it.
+    Invalid,
+    /// The shard is already sending us more nodes than we're willing to accept from it.
+    TooManyShardNodes,
+    /// We haven't heard from the node for too long.
+    Stale,
 }
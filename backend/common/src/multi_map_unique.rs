@@ -41,6 +41,23 @@ impl<K, V> MultiMapUnique<K, V> {
         self.key_to_values.get(key)
     }
 
+    /// Return the key that a value is associated with, if any.
+    ///
+    /// ```
+    /// let mut m = common::MultiMapUnique::new();
+    ///
+    /// m.insert("a", 1);
+    ///
+    /// assert_eq!(m.get_key(&1), Some(&"a"));
+    /// assert_eq!(m.get_key(&2), None);
+    /// ```
+    pub fn get_key(&self, value: &V) -> Option<&K>
+    where
+        V: Eq + Hash,
+    {
+        self.value_to_key.get(value)
+    }
+
     /// Remove a value from the MultiMap, returning the key it was found
     /// under, if it was found at all.
     ///
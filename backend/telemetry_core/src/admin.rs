@@ -0,0 +1,492 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! An optional HTTP server exposing administrative operations. These operations can
+//! modify the running state of the aggregator, so the admin server listens on a separate
+//! address to the public one and should not be exposed to the outside world.
+
+use crate::aggregator::AggregatorSet;
+use crate::find_location::LocationOverride;
+use crate::shard_recording::ShardReplicas;
+use crate::state::RecommendedVersion;
+use common::http_utils;
+use common::node_types::BlockHash;
+use futures::Future;
+use hyper::{Body, Method, Request, Response};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+/// Start the admin server, handling requests until an error occurs.
+pub async fn start_server(
+    addr: SocketAddr,
+    aggregator: AggregatorSet,
+    shard_replicas: Option<ShardReplicas>,
+) -> anyhow::Result<()> {
+    let operations = Operations::default();
+    http_utils::start_server(addr, move |addr, req| {
+        let aggregator = aggregator.clone();
+        let shard_replicas = shard_replicas.clone();
+        let operations = operations.clone();
+        async move {
+            log::info!(
+                "Admin request from {:?}: {} {}",
+                addr,
+                req.method(),
+                req.uri()
+            );
+            let path = req.uri().path().trim_end_matches('/').to_owned();
+            let res = match (req.method(), path.as_str()) {
+                // List the admin operations that are in progress. Responds with a JSON array
+                // of their IDs, descriptions and how long they've been running for:
+                (&Method::GET, "/operations") => json_response(&operations.list()),
+                // Cancel an admin operation that is in progress, given its ID. The cancelled
+                // request is responded to with a 409 status:
+                (&Method::DELETE, path) if path.starts_with("/operations/") => {
+                    cancel_operation(&operations, &path["/operations/".len()..])
+                }
+                _ => {
+                    let description = format!("{} {}", req.method(), path);
+                    let operation = handle_request(aggregator, shard_replicas, req, &path);
+                    operations.run(description, operation).await
+                }
+            };
+
+            Ok(res.unwrap_or_else(|(status, msg)| {
+                Response::builder().status(status).body(msg.into()).unwrap()
+            }))
+        }
+    })
+    .await
+}
+
+async fn handle_request(
+    aggregator: AggregatorSet,
+    shard_replicas: Option<ShardReplicas>,
+    req: Request<Body>,
+    path: &str,
+) -> AdminResult {
+    match (req.method(), path) {
+        // Atomically replace the denylist. Expects a JSON array of chain names, and
+        // responds with a JSON array of the genesis hashes of chains that were removed:
+        (&Method::POST, "/denylist") => replace_denylist(aggregator, req).await,
+        // Atomically replace the node blocklist. Expects a JSON array of node network
+        // IDs, and responds with the number of connected nodes that were removed:
+        (&Method::POST, "/node-blocklist") => replace_node_blocklist(aggregator, req).await,
+        // Replace the location overrides applied to newly connecting nodes. Expects a
+        // JSON array of "CIDR=LATITUDE,LONGITUDE,CITY" strings, and responds with the
+        // number of overrides now in effect:
+        (&Method::POST, "/location-overrides") => replace_location_overrides(aggregator, req).await,
+        // Replace the versions that nodes on each chain are recommended to run. Expects
+        // a JSON array of "GENESIS_HASH=VERSION" strings, and responds with the number
+        // of chains which now have a recommended version:
+        (&Method::POST, "/recommended-versions") => {
+            replace_recommended_versions(aggregator, req).await
+        }
+        // Inspect what a feed connection is subscribed to. Responds with a JSON array
+        // containing the view of each aggregator that knows about the feed ID:
+        (&Method::GET, path) if path.starts_with("/feeds/") => {
+            feed_subscriptions(aggregator, &path["/feeds/".len()..]).await
+        }
+        // Inspect the nodes on a chain, given its genesis hash. Responds with a JSON
+        // array containing details about each node. With `?raw=true`, the last payload of
+        // each kind that the node sent us is included as well, if we're keeping them:
+        (&Method::GET, path) if path.starts_with("/chains/") && path.ends_with("/nodes") => {
+            let genesis_hash = &path["/chains/".len()..path.len() - "/nodes".len()];
+            let include_raw = req.uri().query() == Some("raw=true");
+            chain_nodes(aggregator, genesis_hash, include_raw).await
+        }
+        // Export where the nodes on a chain are, given its genesis hash. Responds with
+        // a GeoJSON FeatureCollection with a point for each located node:
+        (&Method::GET, path) if path.starts_with("/chains/") && path.ends_with("/geojson") => {
+            let genesis_hash = &path["/chains/".len()..path.len() - "/geojson".len()];
+            chain_geojson(aggregator, genesis_hash).await
+        }
+        // Summarize a chain, given its genesis hash. Responds with a JSON object containing
+        // its label, node counts and the range of finalized block heights of its nodes:
+        (&Method::GET, path) if path.starts_with("/chains/") => {
+            get_chain(aggregator, &path["/chains/".len()..]).await
+        }
+        // List the chains which have been denylisted for appearing and disappearing too
+        // often. Responds with a JSON array of genesis hashes and seconds until expiry:
+        (&Method::GET, "/auto-denylist") => auto_denylisted_chains(aggregator).await,
+        // Take a snapshot of every chain. Responds with a JSON object listing each chain's
+        // genesis hash, label and node counts, and details about each of its nodes if
+        // `?nodes=true` is given:
+        (&Method::GET, "/snapshot") => {
+            let include_nodes = req.uri().query() == Some("nodes=true");
+            snapshot(aggregator, include_nodes).await
+        }
+        // Write a snapshot of every chain to a file on this machine, as newline delimited
+        // JSON with one chain per line, for inspecting during an incident. Expects the
+        // path as a JSON string. Details about each node are included if `?nodes=true`
+        // is given. Responds with the path once the file has been written:
+        (&Method::POST, "/dump-state") => {
+            let include_nodes = req.uri().query() == Some("nodes=true");
+            dump_state(aggregator, req, include_nodes).await
+        }
+        // Stream the messages that shards send to us to a replica, if enabled:
+        (&Method::GET, "/shard-replication") => match shard_replicas {
+            Some(shard_replicas) => Ok(replicate_shard_messages(shard_replicas, req)),
+            None => Err((404, "Shard replication is not enabled".to_owned())),
+        },
+        _ => Err((404, "Not found".to_owned())),
+    }
+}
+
+type AdminResult = Result<Response<Body>, (u16, String)>;
+
+/// Keep track of the admin operations that are in progress, so that they can be
+/// listed and cancelled.
+#[derive(Clone, Default)]
+struct Operations(Arc<Mutex<OperationsInner>>);
+
+#[derive(Default)]
+struct OperationsInner {
+    next_id: u64,
+    running: HashMap<u64, RunningOperation>,
+}
+
+struct RunningOperation {
+    description: String,
+    started_at: Instant,
+    cancel: oneshot::Sender<()>,
+}
+
+/// Details about an admin operation that is in progress.
+#[derive(Debug, serde::Serialize)]
+struct OperationView {
+    id: u64,
+    description: String,
+    running_for_ms: u128,
+}
+
+impl Operations {
+    /// Run an operation until it completes or is cancelled. Cancelling an operation
+    /// stops it at the next point that it waits on something. Any change that it has
+    /// already asked the aggregators to make will still be made, since each of those
+    /// is applied in one go.
+    async fn run(
+        &self,
+        description: String,
+        operation: impl Future<Output = AdminResult>,
+    ) -> AdminResult {
+        let (cancel, cancelled) = oneshot::channel();
+        let id = {
+            let mut inner = self.0.lock().unwrap();
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.running.insert(
+                id,
+                RunningOperation {
+                    description,
+                    started_at: Instant::now(),
+                    cancel,
+                },
+            );
+            id
+        };
+
+        // Forget about the operation however it ends, including if the request goes away:
+        let _guard = ForgetOperation(self, id);
+        tokio::select! {
+            res = operation => res,
+            _ = cancelled => Err((409, format!("Operation {} was cancelled", id))),
+        }
+    }
+
+    /// List the operations that are in progress, oldest first.
+    fn list(&self) -> Vec<OperationView> {
+        let inner = self.0.lock().unwrap();
+        let mut operations: Vec<_> = inner
+            .running
+            .iter()
+            .map(|(&id, op)| OperationView {
+                id,
+                description: op.description.clone(),
+                running_for_ms: op.started_at.elapsed().as_millis(),
+            })
+            .collect();
+        operations.sort_by_key(|op| op.id);
+        operations
+    }
+
+    /// Cancel the operation with the given ID, returning false if there's no such operation.
+    fn cancel(&self, id: u64) -> bool {
+        let operation = self.0.lock().unwrap().running.remove(&id);
+        match operation {
+            Some(operation) => {
+                let _ = operation.cancel.send(());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+struct ForgetOperation<'a>(&'a Operations, u64);
+
+impl Drop for ForgetOperation<'_> {
+    fn drop(&mut self) {
+        (self.0).0.lock().unwrap().running.remove(&self.1);
+    }
+}
+
+fn cancel_operation(operations: &Operations, id: &str) -> AdminResult {
+    let id: u64 = id
+        .parse()
+        .map_err(|e| (400, format!("Invalid operation ID: {}", e)))?;
+    if !operations.cancel(id) {
+        return Err((404, format!("No operation with ID {}", id)));
+    }
+    json_response(&id)
+}
+
+fn replicate_shard_messages(shard_replicas: ShardReplicas, req: Request<Body>) -> Response<Body> {
+    http_utils::upgrade_to_websocket(req, move |mut ws_send, _ws_recv| async move {
+        let rx_events = shard_replicas.subscribe();
+        while let Ok(bytes) = rx_events.recv_async().await {
+            if let Err(e) = ws_send.send_binary(&bytes).await {
+                log::warn!("Closing shard replication connection: {}", e);
+                break;
+            }
+            // Flush whenever we catch up:
+            if rx_events.is_empty() && ws_send.flush().await.is_err() {
+                break;
+            }
+        }
+        let _ = ws_send.close().await;
+    })
+}
+
+async fn replace_denylist(aggregator: AggregatorSet, req: Request<Body>) -> AdminResult {
+    let denylist: Vec<String> = parse_json_body(req).await?;
+    let removed_chains = aggregator
+        .replace_denylist(denylist)
+        .await
+        .map_err(|e| (500, e.to_string()))?;
+    json_response(&removed_chains)
+}
+
+async fn replace_node_blocklist(aggregator: AggregatorSet, req: Request<Body>) -> AdminResult {
+    let node_blocklist: Vec<String> = parse_json_body(req).await?;
+    let removed_count = aggregator
+        .replace_node_blocklist(node_blocklist)
+        .await
+        .map_err(|e| (500, e.to_string()))?;
+    json_response(&removed_count)
+}
+
+async fn replace_location_overrides(aggregator: AggregatorSet, req: Request<Body>) -> AdminResult {
+    let location_overrides: Vec<LocationOverride> = parse_json_body(req).await?;
+    let count = location_overrides.len();
+    aggregator
+        .replace_location_overrides(location_overrides)
+        .await
+        .map_err(|e| (500, e.to_string()))?;
+    json_response(&count)
+}
+
+async fn replace_recommended_versions(
+    aggregator: AggregatorSet,
+    req: Request<Body>,
+) -> AdminResult {
+    let recommended_versions: Vec<RecommendedVersion> = parse_json_body(req).await?;
+    let count = recommended_versions.len();
+    aggregator
+        .replace_recommended_versions(recommended_versions)
+        .await
+        .map_err(|e| (500, e.to_string()))?;
+    json_response(&count)
+}
+
+async fn feed_subscriptions(aggregator: AggregatorSet, feed_id: &str) -> AdminResult {
+    #[derive(serde::Serialize)]
+    struct AggregatorFeedView<T> {
+        aggregator: usize,
+        #[serde(flatten)]
+        view: T,
+    }
+
+    let feed_id: u64 = feed_id
+        .parse()
+        .map_err(|e| (400, format!("Invalid feed ID: {}", e)))?;
+    let views: Vec<_> = aggregator
+        .feed_subscriptions(feed_id)
+        .await
+        .map_err(|e| (500, e.to_string()))?
+        .into_iter()
+        .map(|(aggregator, view)| AggregatorFeedView { aggregator, view })
+        .collect();
+    if views.is_empty() {
+        return Err((404, format!("No feed with ID {}", feed_id)));
+    }
+    json_response(&views)
+}
+
+async fn auto_denylisted_chains(aggregator: AggregatorSet) -> AdminResult {
+    let chains = aggregator
+        .auto_denylisted_chains()
+        .await
+        .map_err(|e| (500, e.to_string()))?;
+    json_response(&chains)
+}
+
+async fn snapshot(aggregator: AggregatorSet, include_nodes: bool) -> AdminResult {
+    let snapshot = aggregator
+        .snapshot(include_nodes)
+        .await
+        .map_err(|e| (500, e.to_string()))?;
+    json_response(&snapshot)
+}
+
+async fn dump_state(
+    aggregator: AggregatorSet,
+    req: Request<Body>,
+    include_nodes: bool,
+) -> AdminResult {
+    let path: PathBuf = parse_json_body(req).await?;
+    aggregator
+        .dump_state(path.clone(), include_nodes)
+        .await
+        .map_err(|e| (500, format!("{:#}", e)))?;
+    json_response(&path)
+}
+
+async fn chain_nodes(
+    aggregator: AggregatorSet,
+    genesis_hash: &str,
+    include_raw: bool,
+) -> AdminResult {
+    let genesis_hash: BlockHash = genesis_hash
+        .parse()
+        .map_err(|e| (400, format!("Invalid genesis hash: {}", e)))?;
+    let nodes = aggregator
+        .chain_nodes(genesis_hash, include_raw)
+        .await
+        .map_err(|e| (500, e.to_string()))?
+        .ok_or_else(|| {
+            (
+                404,
+                format!("No chain with genesis hash {:?}", genesis_hash),
+            )
+        })?;
+    json_response(&nodes)
+}
+
+async fn get_chain(aggregator: AggregatorSet, genesis_hash: &str) -> AdminResult {
+    let genesis_hash: BlockHash = genesis_hash
+        .parse()
+        .map_err(|e| (400, format!("Invalid genesis hash: {}", e)))?;
+    let chain = aggregator
+        .get_chain(genesis_hash)
+        .await
+        .map_err(|e| (500, e.to_string()))?
+        .ok_or_else(|| {
+            (
+                404,
+                format!("No chain with genesis hash {:?}", genesis_hash),
+            )
+        })?;
+    json_response(&chain)
+}
+
+async fn chain_geojson(aggregator: AggregatorSet, genesis_hash: &str) -> AdminResult {
+    let genesis_hash: BlockHash = genesis_hash
+        .parse()
+        .map_err(|e| (400, format!("Invalid genesis hash: {}", e)))?;
+    let geojson = aggregator
+        .chain_geojson(genesis_hash)
+        .await
+        .map_err(|e| (500, e.to_string()))?
+        .ok_or_else(|| {
+            (
+                404,
+                format!("No chain with genesis hash {:?}", genesis_hash),
+            )
+        })?;
+    Ok(Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/geo+json")
+        .body(geojson.into())
+        .unwrap())
+}
+
+async fn parse_json_body<T: serde::de::DeserializeOwned>(
+    req: Request<Body>,
+) -> Result<T, (u16, String)> {
+    let bytes = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| (400, format!("Could not read request body: {}", e)))?;
+    serde_json::from_slice(&bytes).map_err(|e| (400, format!("Invalid JSON body: {}", e)))
+}
+
+fn json_response<T: serde::Serialize>(value: &T) -> AdminResult {
+    let body = serde_json::to_vec(value).map_err(|e| (500, e.to_string()))?;
+    Ok(Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn slow_operations_can_be_listed_and_cancelled() {
+        let operations = Operations::default();
+
+        let slow = tokio::spawn({
+            let operations = operations.clone();
+            async move {
+                let operation = futures::future::pending();
+                operations.run("POST /slow".to_owned(), operation).await
+            }
+        });
+        while operations.list().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let listed = operations.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].description, "POST /slow");
+
+        assert!(operations.cancel(listed[0].id));
+        let res = tokio::time::timeout(Duration::from_secs(5), slow)
+            .await
+            .expect("cancelled operation should finish")
+            .unwrap();
+        assert_eq!(res.unwrap_err().0, 409);
+        assert!(operations.list().is_empty());
+
+        // It can't be cancelled twice:
+        assert!(!operations.cancel(listed[0].id));
+    }
+
+    #[tokio::test]
+    async fn finished_operations_are_no_longer_listed() {
+        let operations = Operations::default();
+        let res = operations
+            .run("GET /quick".to_owned(), async { json_response(&1) })
+            .await;
+        assert!(res.is_ok());
+        assert!(operations.list().is_empty());
+    }
+}
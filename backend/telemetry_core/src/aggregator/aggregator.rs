@@ -14,14 +14,25 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use super::inner_loop;
-use crate::find_location::find_location;
-use crate::state::NodeId;
+use super::chain_flaps::ChainFlapOpts;
+use super::inner_loop::{self, ChainConflictPolicy};
+use super::node_count_thresholds::NodeCountThresholdOpts;
+use crate::find_location::{find_location, LocationCacheOpts, LocationOverride};
+use crate::state::{
+    ChainStaleThreshold, IncompleteNodePolicy, NodeCountSource, NodeId, QuotaCountSource,
+    RecommendedVersion, RequiredNodeField,
+};
 use common::id_type;
-use futures::{future, Sink, SinkExt};
+use common::node_types::BlockHash;
+use futures::{future, stream, Sink, SinkExt, Stream};
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
+use std::path::PathBuf;
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::MissedTickBehavior;
 
 id_type! {
     /// A unique Id is assigned per websocket connection (or more accurately,
@@ -30,6 +41,12 @@ id_type! {
     pub struct ConnId(u64)
 }
 
+/// How many metrics snapshots a subscriber can fall behind by before the oldest are dropped.
+const METRICS_SUBSCRIPTION_CAPACITY: usize = 4;
+
+/// How often to check for nodes which have gone stale, if they're being removed.
+const STALE_NODE_PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Clone)]
 pub struct Aggregator(Arc<AggregatorInternal>);
 
@@ -38,12 +55,124 @@ pub struct Aggregator(Arc<AggregatorInternal>);
 pub struct AggregatorOpts {
     /// Any node from these chains is muted
     pub denylist: Vec<String>,
+    /// If not empty, only nodes from chains whose label or genesis hash is in here are
+    /// accepted. The denylist takes precedence over this.
+    pub allowlist: Vec<String>,
     /// If our incoming message queue exceeds this length, we start
     /// dropping non-essential messages.
     pub max_queue_len: usize,
     /// How many nodes from third party chains are allowed to connect
     /// before we prevent connections from them.
     pub max_third_party_nodes: usize,
+    /// Which nodes count towards `max_third_party_nodes`.
+    pub quota_count_source: QuotaCountSource,
+    /// Third party chains which are exempt from `max_third_party_nodes`.
+    pub quota_exempt_chains: Vec<BlockHash>,
+    /// If provided, no chain (third party or not) can have more than this many nodes.
+    /// Must be greater than 0.
+    pub max_nodes_per_chain: Option<usize>,
+    /// Don't try to locate nodes that report private, loopback
+    /// or link-local addresses.
+    pub skip_private_ip_location: bool,
+    /// What to do when a node reports that it's connected to a different
+    /// chain from the one it was added to.
+    pub chain_conflict_policy: ChainConflictPolicy,
+    /// A feed with more than this many messages waiting to be sent to it,
+    /// which isn't going down, is considered to be lagging.
+    pub feed_lag_threshold: usize,
+    /// If provided, a feed subscribed to a chain which has this many messages waiting to
+    /// be sent to it when there are node updates for it is disconnected, rather than
+    /// letting its queue grow without bound.
+    pub max_feed_queue_len: Option<usize>,
+    /// Which nodes are counted in the node count reported for each chain.
+    pub node_count_source: NodeCountSource,
+    /// The node counts that feeds are told about are rounded to the nearest multiple of
+    /// this, but are never rounded down to 0. Node counts are kept exact everywhere else.
+    /// Must be greater than 0.
+    pub node_count_granularity: usize,
+    /// For this long after startup, or after a shard (re)connects, allow
+    /// up to `max_third_party_nodes_during_warmup` nodes on third party chains.
+    pub quota_warmup: std::time::Duration,
+    /// How many nodes from third party chains are allowed to connect
+    /// during the quota warm-up period.
+    pub max_third_party_nodes_during_warmup: usize,
+    /// If provided, nodes are grouped within their chain according to the part of
+    /// their name that matches this pattern.
+    pub node_group_pattern: Option<regex::Regex>,
+    /// Network IDs of individual nodes that are not allowed to connect.
+    pub node_blocklist: Vec<String>,
+    /// Nodes connecting from IP addresses in these ranges are given these locations.
+    pub location_overrides: Vec<LocationOverride>,
+    /// Labels to use for chains, keyed by genesis hash, in place of the ones their nodes report.
+    pub chain_label_overrides: HashMap<BlockHash, String>,
+    /// The versions that nodes on each chain are recommended to run.
+    pub recommended_versions: Vec<RecommendedVersion>,
+    /// Details that every node must report when it connects.
+    pub required_node_fields: Vec<RequiredNodeField>,
+    /// What to do with nodes that don't report every required detail.
+    pub incomplete_node_policy: IncompleteNodePolicy,
+    /// Nodes which haven't imported a new best block for this long are marked as stale.
+    pub stale_threshold: Duration,
+    /// Chains whose nodes are marked as stale after a different length of time.
+    pub chain_stale_thresholds: Vec<ChainStaleThreshold>,
+    /// Nodes whose best block is more than this many blocks behind the
+    /// best block of their chain are marked as stale.
+    pub stale_block_margin: Option<u64>,
+    /// Feeds aren't told about blocks imported by nodes which are more than
+    /// this many blocks behind the best block of their chain.
+    pub block_import_drop_margin: Option<u64>,
+    /// Tell feeds how long each block took to reach the nodes that imported it after
+    /// the first one. This is a feed message for most block imports, so it's off by default.
+    pub block_propagation_messages: bool,
+    /// Give nodes that reconnect the same ID they had before, if it's still free.
+    pub sticky_node_ids: bool,
+    /// Keep hold of the last payload of each kind that nodes send us, so that they can
+    /// be inspected as they were received.
+    pub keep_raw_node_payloads: bool,
+    /// Serializing node details for newly subscribed feeds is spread across the
+    /// threads in this pool. It can be shared between aggregators.
+    pub serialization_pool: Arc<rayon::ThreadPool>,
+    /// When a feed subscribes to a chain, the details of up to this many nodes are sent
+    /// in each message. Must be greater than 0.
+    pub nodes_per_feed_message: usize,
+    /// When a feed subscribes to a chain, fewer nodes are described in a message if
+    /// describing them all would take more than this many bytes.
+    pub max_feed_message_bytes: usize,
+    /// If provided, errors handling messages from shards are sent here as well as
+    /// being logged. Errors are dropped (and counted) rather than waiting for room
+    /// in the channel, so it should be bounded to a sensible size.
+    pub processing_errors: Option<flume::Sender<inner_loop::ProcessingError>>,
+    /// Messages from shards about a node, which are larger than this many bytes,
+    /// are rejected.
+    pub max_shard_message_size: Option<u64>,
+    /// Nodes added by a shard which already has this many nodes are rejected.
+    pub max_nodes_per_shard: usize,
+    /// If provided, updates from any one node beyond this many per second are dropped.
+    /// Must be greater than 0.
+    pub max_node_messages_per_second: Option<u32>,
+    /// Feeds may ask for messages in the binary encoding (see
+    /// [`crate::feed_message::FeedEncoding`]) rather than JSON.
+    pub binary_feeds: bool,
+    /// If provided, a node which is located again within this many km of where it was
+    /// last located keeps its old location, and feeds aren't told about it.
+    pub min_location_change_km: Option<f64>,
+    /// If provided, the locations of nodes are cached in a file so that
+    /// they're available straight away after a restart.
+    pub location_cache: Option<LocationCacheOpts>,
+    /// If provided, nodes which haven't sent us anything for this long are removed.
+    pub stale_node_timeout: Option<Duration>,
+    /// If provided, chains which appear and disappear too often are denylisted for a while.
+    pub chain_flaps: Option<ChainFlapOpts>,
+    /// If provided, the denylist in effect is saved to this file whenever it changes,
+    /// and loaded from it (in place of `denylist`) on startup.
+    pub denylist_path: Option<PathBuf>,
+    /// Nodes on chains with these genesis hashes are treated as being on the chain with
+    /// the genesis hash that each maps to.
+    pub chain_aliases: HashMap<BlockHash, BlockHash>,
+    /// If provided, chains crossing these node counts are reported via `events`.
+    pub node_count_thresholds: Option<NodeCountThresholdOpts>,
+    /// Notable things that happen are sent here, if provided.
+    pub events: Option<flume::Sender<inner_loop::AggregatorEvent>>,
 }
 
 struct AggregatorInternal {
@@ -62,23 +191,55 @@ struct AggregatorInternal {
 impl Aggregator {
     /// Spawn a new Aggregator. This connects to the telemetry backend
     pub async fn spawn(opts: AggregatorOpts) -> anyhow::Result<Aggregator> {
+        anyhow::ensure!(
+            opts.nodes_per_feed_message > 0,
+            "The number of nodes per feed message must be greater than 0"
+        );
+        anyhow::ensure!(
+            opts.max_nodes_per_chain != Some(0),
+            "The maximum number of nodes per chain must be greater than 0"
+        );
+        anyhow::ensure!(
+            opts.max_node_messages_per_second != Some(0),
+            "The maximum number of messages per second from a node must be greater than 0"
+        );
+        anyhow::ensure!(
+            opts.node_count_granularity > 0,
+            "The node count granularity must be greater than 0"
+        );
+
         let (tx_to_aggregator, rx_from_external) = flume::unbounded();
 
         // Kick off a locator task to locate nodes, which hands back a channel to make location requests
-        let tx_to_locator =
-            find_location(tx_to_aggregator.clone().into_sink().with(|(node_id, msg)| {
+        let tx_to_locator = find_location(
+            tx_to_aggregator.clone().into_sink().with(|(node_id, msg)| {
                 future::ok::<_, flume::SendError<_>>(inner_loop::ToAggregator::FromFindLocation(
                     node_id, msg,
                 ))
-            }));
+            }),
+            opts.location_cache.clone(),
+        );
+
+        // Regularly ask the aggregator loop to remove nodes that have gone stale:
+        if opts.stale_node_timeout.is_some() {
+            let tx_to_aggregator = tx_to_aggregator.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(STALE_NODE_PRUNE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let msg = inner_loop::ToAggregator::PruneStaleNodes;
+                    if tx_to_aggregator.send_async(msg).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
 
         // Handle any incoming messages in our handler loop:
         tokio::spawn(Aggregator::handle_messages(
             rx_from_external,
             tx_to_locator,
-            opts.max_queue_len,
-            opts.denylist,
-            opts.max_third_party_nodes,
+            opts,
         ));
 
         // Return a handle to our aggregator:
@@ -95,18 +256,11 @@ impl Aggregator {
     async fn handle_messages(
         rx_from_external: flume::Receiver<inner_loop::ToAggregator>,
         tx_to_aggregator: flume::Sender<(NodeId, Ipv4Addr)>,
-        max_queue_len: usize,
-        denylist: Vec<String>,
-        max_third_party_nodes: usize,
+        opts: AggregatorOpts,
     ) {
-        inner_loop::InnerLoop::new(
-            tx_to_aggregator,
-            denylist,
-            max_queue_len,
-            max_third_party_nodes,
-        )
-        .handle(rx_from_external)
-        .await;
+        inner_loop::InnerLoop::new(tx_to_aggregator, opts)
+            .handle(rx_from_external)
+            .await;
     }
 
     /// Gather metrics from our aggregator loop
@@ -120,6 +274,217 @@ impl Aggregator {
         Ok(metrics)
     }
 
+    /// Subscribe to a fresh snapshot of metrics from our aggregator loop every `interval`,
+    /// starting immediately. If the subscriber falls behind, the oldest snapshots are dropped
+    /// so that it always catches up with the latest ones. The stream ends if the aggregator
+    /// loop fails.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `interval` is zero.
+    pub fn subscribe_metrics(
+        &self,
+        interval: Duration,
+    ) -> impl Stream<Item = inner_loop::Metrics> + Send + Unpin + 'static {
+        let (tx, rx) = broadcast::channel(METRICS_SUBSCRIPTION_CAPACITY);
+        let mut ticker = tokio::time::interval(interval);
+        // If gathering metrics takes longer than the interval, don't try to catch up:
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                ticker.tick().await;
+                let metrics = match this.gather_metrics().await {
+                    Ok(metrics) => metrics,
+                    Err(e) => {
+                        log::error!("Error obtaining metrics for subscriber (bailing): {}", e);
+                        return;
+                    }
+                };
+                // This only fails once the subscriber has gone away:
+                if tx.send(metrics).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Box::pin(stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(metrics) => return Some((metrics, rx)),
+                    // We fell behind and older snapshots were dropped; carry on from the oldest
+                    // one that's left.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+
+    /// Replace the denylist of our aggregator loop, returning the genesis hashes
+    /// of any chains that were removed as a result.
+    pub async fn replace_denylist(&self, denylist: Vec<String>) -> anyhow::Result<Vec<BlockHash>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::ReplaceDenylist(denylist, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let removed_chains = rx.recv_async().await?;
+        Ok(removed_chains)
+    }
+
+    /// Replace the node blocklist of our aggregator loop, returning the number
+    /// of nodes that were removed as a result.
+    pub async fn replace_node_blocklist(
+        &self,
+        node_blocklist: Vec<String>,
+    ) -> anyhow::Result<usize> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::ReplaceNodeBlocklist(node_blocklist, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let removed_count = rx.recv_async().await?;
+        Ok(removed_count)
+    }
+
+    /// Replace the location overrides of our aggregator loop.
+    pub async fn replace_location_overrides(
+        &self,
+        location_overrides: Vec<LocationOverride>,
+    ) -> anyhow::Result<()> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::ReplaceLocationOverrides(location_overrides, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        rx.recv_async().await?;
+        Ok(())
+    }
+
+    /// Replace the recommended node versions of our aggregator loop.
+    pub async fn replace_recommended_versions(
+        &self,
+        recommended_versions: Vec<RecommendedVersion>,
+    ) -> anyhow::Result<()> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::ReplaceRecommendedVersions(recommended_versions, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        rx.recv_async().await?;
+        Ok(())
+    }
+
+    /// Return details about what the feed with the given connection ID is subscribed to,
+    /// or `None` if no such feed is connected to this aggregator.
+    pub async fn feed_subscriptions(
+        &self,
+        feed_conn_id: u64,
+    ) -> anyhow::Result<Option<inner_loop::FeedSubscriptionView>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GetFeedSubscriptions(feed_conn_id.into(), tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let view = rx.recv_async().await?;
+        Ok(view)
+    }
+
+    /// Return details about each node on the chain with the given genesis hash,
+    /// or `None` if this aggregator doesn't know about such a chain.
+    pub async fn chain_nodes(
+        &self,
+        genesis_hash: BlockHash,
+        include_raw: bool,
+    ) -> anyhow::Result<Option<Vec<inner_loop::NodeView>>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GetChainNodes(genesis_hash, include_raw, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let nodes = rx.recv_async().await?;
+        Ok(nodes)
+    }
+
+    /// Return a summary of the chain with the given genesis hash, or `None` if this
+    /// aggregator doesn't know about such a chain.
+    pub async fn get_chain(
+        &self,
+        genesis_hash: BlockHash,
+    ) -> anyhow::Result<Option<inner_loop::ChainInfo>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GetChain(genesis_hash, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let chain = rx.recv_async().await?;
+        Ok(chain)
+    }
+
+    /// Return a GeoJSON `FeatureCollection` of the located nodes on the chain with the
+    /// given genesis hash, or `None` if our aggregator loop doesn't know about such a chain.
+    pub async fn chain_geojson(&self, genesis_hash: BlockHash) -> anyhow::Result<Option<String>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GetChainGeoJson(genesis_hash, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let geojson = rx.recv_async().await?;
+        Ok(geojson)
+    }
+
+    /// Return a snapshot of every chain that our aggregator loop knows about, with details
+    /// about each of their nodes if `include_nodes` is true.
+    pub async fn snapshot(&self, include_nodes: bool) -> anyhow::Result<inner_loop::StateSnapshot> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GetSnapshot(include_nodes, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let snapshot = rx.recv_async().await?;
+        Ok(snapshot)
+    }
+
+    /// Write a snapshot of every chain that our aggregator loop knows about to the given
+    /// file as newline delimited JSON, with details about each of their nodes if
+    /// `include_nodes` is true. This returns once the file has been written.
+    pub async fn dump_state(&self, path: PathBuf, include_nodes: bool) -> anyhow::Result<()> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::DumpState(path, include_nodes, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        rx.recv_async().await?
+    }
+
+    /// Ask our aggregator loop to save anything worth keeping, tell feeds that every chain
+    /// has gone and stop. This returns once it has done so. The aggregator can't be used
+    /// for anything else afterwards.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::Shutdown(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        rx.recv_async().await?;
+        Ok(())
+    }
+
+    /// Return the chains which have been denylisted for appearing and disappearing too often.
+    pub async fn auto_denylisted_chains(
+        &self,
+    ) -> anyhow::Result<Vec<inner_loop::AutoDenylistedChainView>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GetAutoDenylistedChains(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let chains = rx.recv_async().await?;
+        Ok(chains)
+    }
+
     /// Return a sink that a shard can send messages into to be handled by the aggregator.
     pub fn subscribe_shard(
         &self,
@@ -1,10 +1,21 @@
 use super::aggregator::{Aggregator, AggregatorOpts};
 use super::inner_loop;
+use crate::chain_widget;
+use crate::feed_message::FeedEncoding;
+use crate::find_location::LocationOverride;
+use crate::state::RecommendedVersion;
+use common::node_types::BlockHash;
 use common::EitherSink;
-use futures::{Sink, SinkExt};
-use inner_loop::{FromShardWebsocket, Metrics};
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use inner_loop::{
+    AutoDenylistedChainView, ChainInfo, FeedSubscriptionView, FromFeedWebsocket,
+    FromShardWebsocket, Metrics, NodeView, StateSnapshot, ToFeedWebsocket,
+};
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct AggregatorSet(Arc<AggregatorSetInner>);
@@ -23,9 +34,15 @@ impl AggregatorSet {
     ) -> anyhow::Result<AggregatorSet> {
         assert_ne!(num_aggregators, 0, "You must have 1 or more aggregator");
 
-        let aggregators = futures::future::try_join_all(
-            (0..num_aggregators).map(|_| Aggregator::spawn(opts.clone())),
-        )
+        // Every aggregator sees the same nodes come and go, so only the first
+        // needs to report events:
+        let aggregators = futures::future::try_join_all((0..num_aggregators).map(|idx| {
+            let mut opts = opts.clone();
+            if idx != 0 {
+                opts.events = None;
+            }
+            Aggregator::spawn(opts)
+        }))
         .await?;
 
         let initial_metrics = (0..num_aggregators).map(|_| Metrics::default()).collect();
@@ -42,7 +59,7 @@ impl AggregatorSet {
         Ok(this)
     }
 
-    /// Spawn loops which periodically ask for metrics from each internal aggregator.
+    /// Spawn loops which subscribe to metrics from each internal aggregator every 10 seconds.
     /// Depending on how busy the aggregators are, these metrics won't necessarily be in
     /// sync with each other.
     fn spawn_metrics_loops(&self) {
@@ -50,29 +67,14 @@ impl AggregatorSet {
         for (idx, a) in aggregators.into_iter().enumerate() {
             let inner = Arc::clone(&self.0);
             tokio::spawn(async move {
-                loop {
-                    let now = tokio::time::Instant::now();
-                    let metrics = match a.gather_metrics().await {
-                        Ok(metrics) => metrics,
-                        // Any error here is unlikely and probably means that the aggregator
-                        // loop has failed completely.
-                        Err(e) => {
-                            log::error!("Error obtaining metrics (bailing): {}", e);
-                            return;
-                        }
-                    };
-
+                let mut metrics = a.subscribe_metrics(Duration::from_secs(10));
+                while let Some(metrics) = metrics.next().await {
                     // Lock, update the stored metrics and drop the lock immediately.
-                    // We discard any error; if something went wrong talking to the inner loop,
-                    // it's probably a fatal error
-                    {
-                        inner.metrics.lock().unwrap()[idx] = metrics;
-                    }
-
-                    // Sleep *at least* 10 seconds. If it takes a while to get metrics back, we'll
-                    // end up waiting longer between requests.
-                    tokio::time::sleep_until(now + tokio::time::Duration::from_secs(10)).await;
+                    inner.metrics.lock().unwrap()[idx] = metrics;
                 }
+                // The stream only ends if something went wrong talking to the inner loop,
+                // which is probably a fatal error.
+                log::error!("Stopped obtaining metrics from aggregator {}", idx);
             });
         }
     }
@@ -82,6 +84,148 @@ impl AggregatorSet {
         self.0.metrics.lock().unwrap().clone()
     }
 
+    /// Atomically replace the denylist used by every aggregator, removing any connected
+    /// nodes on chains that are now denied. Returns the genesis hashes of removed chains.
+    pub async fn replace_denylist(&self, denylist: Vec<String>) -> anyhow::Result<Vec<BlockHash>> {
+        let results = futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.replace_denylist(denylist.clone())),
+        )
+        .await?;
+
+        // Each aggregator tracks the same chains, so they should agree on what was removed:
+        let removed_chains: HashSet<BlockHash> = results.into_iter().flatten().collect();
+        Ok(removed_chains.into_iter().collect())
+    }
+
+    /// Atomically replace the node blocklist used by every aggregator, removing any
+    /// connected nodes that are now blocked. Returns the number of nodes removed.
+    pub async fn replace_node_blocklist(
+        &self,
+        node_blocklist: Vec<String>,
+    ) -> anyhow::Result<usize> {
+        let results = futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.replace_node_blocklist(node_blocklist.clone())),
+        )
+        .await?;
+
+        // Each aggregator tracks the same nodes, so they should agree on how many were removed:
+        Ok(results.into_iter().max().unwrap_or(0))
+    }
+
+    /// Replace the location overrides used by every aggregator. These apply to
+    /// nodes that connect from now on.
+    pub async fn replace_location_overrides(
+        &self,
+        location_overrides: Vec<LocationOverride>,
+    ) -> anyhow::Result<()> {
+        futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.replace_location_overrides(location_overrides.clone())),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Replace the versions that nodes on each chain are recommended to run, in every
+    /// aggregator.
+    pub async fn replace_recommended_versions(
+        &self,
+        recommended_versions: Vec<RecommendedVersion>,
+    ) -> anyhow::Result<()> {
+        futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.replace_recommended_versions(recommended_versions.clone())),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Return details about what the feed with the given connection ID is subscribed to.
+    /// Feed connection IDs are assigned by each aggregator, so this hands back the index of
+    /// each aggregator that knows about such a feed, alongside its view of it.
+    pub async fn feed_subscriptions(
+        &self,
+        feed_conn_id: u64,
+    ) -> anyhow::Result<Vec<(usize, FeedSubscriptionView)>> {
+        let results = futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.feed_subscriptions(feed_conn_id)),
+        )
+        .await?;
+
+        Ok(results
+            .into_iter()
+            .enumerate()
+            .filter_map(|(idx, view)| view.map(|view| (idx, view)))
+            .collect())
+    }
+
+    /// Return details about each node on the chain with the given genesis hash, or `None`
+    /// if there's no such chain. Every aggregator knows about every node, so we only need
+    /// to ask one of them. If `include_raw` is true, the payloads that each node last sent
+    /// us are included too (if we're keeping hold of them).
+    pub async fn chain_nodes(
+        &self,
+        genesis_hash: BlockHash,
+        include_raw: bool,
+    ) -> anyhow::Result<Option<Vec<NodeView>>> {
+        self.0.aggregators[0]
+            .chain_nodes(genesis_hash, include_raw)
+            .await
+    }
+
+    /// Return a summary of the chain with the given genesis hash, or `None` if there's no
+    /// such chain. As with [`AggregatorSet::chain_nodes`], we only need to ask one aggregator.
+    pub async fn get_chain(&self, genesis_hash: BlockHash) -> anyhow::Result<Option<ChainInfo>> {
+        self.0.aggregators[0].get_chain(genesis_hash).await
+    }
+
+    /// Return a GeoJSON `FeatureCollection` of the located nodes on the chain with the
+    /// given genesis hash, or `None` if there's no such chain. As with
+    /// [`AggregatorSet::chain_nodes`], we only need to ask one aggregator.
+    pub async fn chain_geojson(&self, genesis_hash: BlockHash) -> anyhow::Result<Option<String>> {
+        self.0.aggregators[0].chain_geojson(genesis_hash).await
+    }
+
+    /// Return a snapshot of every chain, with details about each of their nodes if
+    /// `include_nodes` is true. As with [`AggregatorSet::chain_nodes`], we only need to
+    /// ask one aggregator.
+    pub async fn snapshot(&self, include_nodes: bool) -> anyhow::Result<StateSnapshot> {
+        self.0.aggregators[0].snapshot(include_nodes).await
+    }
+
+    /// Write a snapshot of every chain to the given file as newline delimited JSON. See
+    /// [`Aggregator::dump_state`]. As with [`AggregatorSet::snapshot`], we only need to
+    /// ask one aggregator.
+    pub async fn dump_state(&self, path: PathBuf, include_nodes: bool) -> anyhow::Result<()> {
+        self.0.aggregators[0].dump_state(path, include_nodes).await
+    }
+
+    /// Shut down every aggregator, returning once they've all stopped. See
+    /// [`Aggregator::shutdown`].
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        futures::future::try_join_all(self.0.aggregators.iter().map(|a| a.shutdown())).await?;
+        Ok(())
+    }
+
+    /// Return the chains which have been denylisted for appearing and disappearing too
+    /// often. Every aggregator sees the same chains come and go, so we only need to ask one.
+    pub async fn auto_denylisted_chains(&self) -> anyhow::Result<Vec<AutoDenylistedChainView>> {
+        self.0.aggregators[0].auto_denylisted_chains().await
+    }
+
     /// Return a sink that a shard can send messages into to be handled by all aggregators.
     pub fn subscribe_shard(
         &self,
@@ -132,4 +276,39 @@ impl AggregatorSet {
 
         self.0.aggregators[this_idx].subscribe_feed()
     }
+
+    /// Subscribe to a cut down feed about a single chain, for small embedded widgets (see
+    /// [`crate::chain_widget`]). This hands back the feed ID, a sink which should be sent
+    /// [`FromFeedWebsocket::Disconnected`] when the widget goes away, and the feed messages.
+    pub async fn subscribe_chain_widget(
+        &self,
+        genesis_hash: BlockHash,
+    ) -> anyhow::Result<(
+        u64,
+        impl Sink<FromFeedWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static,
+        impl Stream<Item = ToFeedWebsocket> + Send + Unpin + 'static,
+    )> {
+        let (feed_id, mut tx_to_aggregator) = self.subscribe_feed();
+        let (channel, rx_from_aggregator) = flume::unbounded();
+        tx_to_aggregator
+            .send(FromFeedWebsocket::Initialize {
+                channel,
+                encoding: FeedEncoding::Json,
+            })
+            .await?;
+        tx_to_aggregator
+            .send(FromFeedWebsocket::Subscribe {
+                chain: genesis_hash,
+            })
+            .await?;
+
+        let widget_msgs =
+            rx_from_aggregator
+                .into_stream()
+                .filter_map(move |ToFeedWebsocket::Bytes(bytes)| {
+                    let bytes = chain_widget::filter_feed_messages(&genesis_hash, &bytes);
+                    future::ready(bytes.map(ToFeedWebsocket::Bytes))
+                });
+        Ok((feed_id, tx_to_aggregator, widget_msgs))
+    }
 }
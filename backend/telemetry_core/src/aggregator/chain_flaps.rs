@@ -0,0 +1,137 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Keep track of chains which keep appearing and disappearing (because nodes connect to
+//! them and then immediately drop off), and denylist them for a while if they do so too often.
+
+use common::node_types::BlockHash;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// When to denylist a chain for flapping, and for how long.
+#[derive(Debug, Clone, Copy)]
+pub struct ChainFlapOpts {
+    /// A chain which disappears more than this many times within `window` is denylisted.
+    pub max_flaps: usize,
+    pub window: Duration,
+    /// How long a chain which flaps too often is denylisted for.
+    pub denylist_for: Duration,
+}
+
+pub struct ChainFlaps {
+    opts: ChainFlapOpts,
+    /// When each chain has recently disappeared.
+    flaps: HashMap<BlockHash, VecDeque<Instant>>,
+    /// When each denylisted chain is allowed to return.
+    denylisted_until: HashMap<BlockHash, Instant>,
+}
+
+impl ChainFlaps {
+    pub fn new(opts: ChainFlapOpts) -> Self {
+        ChainFlaps {
+            opts,
+            flaps: HashMap::new(),
+            denylisted_until: HashMap::new(),
+        }
+    }
+
+    /// Note that a chain has disappeared because its last node was removed. Returns
+    /// true if this means that the chain is now denylisted.
+    pub fn chain_removed(&mut self, genesis_hash: BlockHash, now: Instant) -> bool {
+        let window = self.opts.window;
+        let flaps = self.flaps.entry(genesis_hash).or_default();
+        flaps.push_back(now);
+        while matches!(flaps.front(), Some(&at) if now.duration_since(at) > window) {
+            flaps.pop_front();
+        }
+
+        if flaps.len() <= self.opts.max_flaps {
+            return false;
+        }
+        self.flaps.remove(&genesis_hash);
+        self.denylisted_until
+            .insert(genesis_hash, now + self.opts.denylist_for);
+        true
+    }
+
+    /// Denylist a chain until the given time, for instance because it was denylisted
+    /// before we restarted.
+    pub fn restore(&mut self, genesis_hash: BlockHash, until: Instant) {
+        self.denylisted_until.insert(genesis_hash, until);
+    }
+
+    /// Is the given chain currently denylisted?
+    pub fn is_denylisted(&self, genesis_hash: &BlockHash, now: Instant) -> bool {
+        matches!(self.denylisted_until.get(genesis_hash), Some(&until) if until > now)
+    }
+
+    /// The chains that are currently denylisted, and how long until each is allowed again.
+    pub fn denylisted(&self, now: Instant) -> Vec<(BlockHash, Duration)> {
+        self.denylisted_until
+            .iter()
+            .filter(|(_, &until)| until > now)
+            .map(|(&genesis_hash, &until)| (genesis_hash, until - now))
+            .collect()
+    }
+
+    /// Forget about denylistings which have expired and flaps which are no longer
+    /// recent enough to matter, so that we don't hold on to them forever.
+    pub fn prune(&mut self, now: Instant) {
+        let window = self.opts.window;
+        self.denylisted_until.retain(|_, &mut until| until > now);
+        self.flaps.retain(
+            |_, flaps| matches!(flaps.back(), Some(&at) if now.duration_since(at) <= window),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chains_which_flap_too_often_are_denylisted_for_a_while() {
+        let mut flaps = ChainFlaps::new(ChainFlapOpts {
+            max_flaps: 2,
+            window: Duration::from_secs(10),
+            denylist_for: Duration::from_secs(60),
+        });
+        let chain = BlockHash::from_low_u64_be(1);
+        let start = Instant::now();
+        let secs = |n| start + Duration::from_secs(n);
+
+        // Flaps that are far enough apart are fine:
+        assert!(!flaps.chain_removed(chain, secs(0)));
+        assert!(!flaps.chain_removed(chain, secs(8)));
+        assert!(!flaps.chain_removed(chain, secs(16)));
+        assert!(!flaps.is_denylisted(&chain, secs(16)));
+
+        // Too many within the window, and the chain is denylisted:
+        assert!(flaps.chain_removed(chain, secs(17)));
+        assert!(flaps.is_denylisted(&chain, secs(17)));
+        assert_eq!(
+            flaps.denylisted(secs(27)),
+            vec![(chain, Duration::from_secs(50))]
+        );
+
+        // Until it expires:
+        assert!(!flaps.is_denylisted(&chain, secs(77)));
+        assert_eq!(flaps.denylisted(secs(77)), vec![]);
+        flaps.prune(secs(77));
+        assert!(flaps.denylisted_until.is_empty());
+        assert!(flaps.flaps.is_empty());
+    }
+}
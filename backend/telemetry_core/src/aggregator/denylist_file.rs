@@ -0,0 +1,62 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Save the denylist that's in effect to a file, so that changes made to it while
+//! we're running aren't lost when we restart.
+
+use anyhow::Context;
+use common::node_types::{BlockHash, Timestamp};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PersistedDenylist {
+    /// Chain labels which were denylisted by hand, on startup or via the admin server.
+    pub manual: Vec<String>,
+    /// Chains which were denylisted automatically, and when (in unix ms) they're allowed back.
+    pub automatic: Vec<(BlockHash, Timestamp)>,
+}
+
+impl PersistedDenylist {
+    /// Load the denylist saved at the given path, or `None` if nothing has been saved there.
+    pub fn load(path: &Path) -> anyhow::Result<Option<PersistedDenylist>> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Could not read denylist {:?}", path)),
+        };
+        let denylist = serde_json::from_slice(&bytes)
+            .with_context(|| format!("Could not decode denylist {:?}", path))?;
+        Ok(Some(denylist))
+    }
+
+    /// Save the denylist to the given path, replacing whatever was there.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(self)?;
+
+        // Each aggregator keeps its own denylist, so they may be saving at the same time:
+        static NEXT_SAVE_ID: AtomicUsize = AtomicUsize::new(0);
+        let save_id = NEXT_SAVE_ID.fetch_add(1, Ordering::Relaxed);
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(format!(".{}-{}.tmp", std::process::id(), save_id));
+        std::fs::write(&tmp_path, bytes)
+            .with_context(|| format!("Could not write {:?}", tmp_path))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Could not replace denylist {:?}", path))?;
+        Ok(())
+    }
+}
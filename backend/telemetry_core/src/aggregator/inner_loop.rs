@@ -14,25 +14,38 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use super::aggregator::ConnId;
-use crate::feed_message::{self, FeedMessageSerializer};
-use crate::find_location;
-use crate::state::{self, NodeId, State};
+use super::aggregator::{AggregatorOpts, ConnId};
+use super::chain_flaps::ChainFlaps;
+use super::denylist_file::PersistedDenylist;
+use super::node_count_thresholds::{NodeCountThresholds, ThresholdDirection};
+use super::node_rate_limits::NodeRateLimits;
+use crate::feed_message::{
+    self, FeedEncoding, FeedMessageCounts, FeedMessageSerializer, FinalizedFeedMessages,
+};
+use crate::find_location::{self, LocationOverride, LocationOverrides};
+use crate::geojson;
+use crate::state::{self, NodeCountSource, NodeId, RecommendedVersion, State};
+use anyhow::Context;
 use bimap::BiMap;
+use bincode::Options;
 use common::{
     internal_messages::{self, MuteReason, ShardNodeId},
     node_message,
-    node_types::BlockHash,
+    node_types::{BlockHash, BlockNumber, Timestamp},
     time, MultiMapUnique,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Arc,
 };
 use std::{
+    fmt,
+    io::Write,
     net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
     str::FromStr,
+    time::{Duration, Instant},
 };
 
 /// Incoming messages come via subscriptions, and end up looking like this.
@@ -44,6 +57,43 @@ pub enum ToAggregator {
     /// Hand back some metrics. The provided sender is expected not to block when
     /// a message is sent into it.
     GatherMetrics(flume::Sender<Metrics>),
+    /// Replace the denylist, removing any connected nodes on chains that are now denied.
+    /// The genesis hashes of chains that were removed as a result are handed back.
+    ReplaceDenylist(Vec<String>, flume::Sender<Vec<BlockHash>>),
+    /// Replace the node blocklist, removing any connected nodes that are now blocked.
+    /// The number of nodes that were removed as a result is handed back.
+    ReplaceNodeBlocklist(Vec<String>, flume::Sender<usize>),
+    /// Replace the location overrides used for nodes that connect from now on.
+    ReplaceLocationOverrides(Vec<LocationOverride>, flume::Sender<()>),
+    /// Replace the versions that nodes on each chain are recommended to run.
+    ReplaceRecommendedVersions(Vec<RecommendedVersion>, flume::Sender<()>),
+    /// Hand back details about what a feed connection is subscribed to, or `None`
+    /// if no such feed is connected to this aggregator.
+    GetFeedSubscriptions(ConnId, flume::Sender<Option<FeedSubscriptionView>>),
+    /// Hand back details about each node on the chain with the given genesis hash, or
+    /// `None` if we don't know about such a chain.
+    /// If the flag is set, the payloads that each node last sent us are included too.
+    GetChainNodes(BlockHash, bool, flume::Sender<Option<Vec<NodeView>>>),
+    /// Hand back a summary of the chain with the given genesis hash, or `None` if we
+    /// don't know about such a chain.
+    GetChain(BlockHash, flume::Sender<Option<ChainInfo>>),
+    /// Hand back a GeoJSON description of where the nodes on the chain with the given
+    /// genesis hash are, or `None` if we don't know about such a chain.
+    GetChainGeoJson(BlockHash, flume::Sender<Option<String>>),
+    /// Hand back the chains which are denylisted for appearing and disappearing too often.
+    GetAutoDenylistedChains(flume::Sender<Vec<AutoDenylistedChainView>>),
+    /// Hand back a snapshot of every chain we know about, including details about each
+    /// of their nodes if the flag is set.
+    GetSnapshot(bool, flume::Sender<StateSnapshot>),
+    /// Write a snapshot of every chain we know about to the given file as newline
+    /// delimited JSON, including details about each of their nodes if the flag is set.
+    /// The provided sender is told once the file has been written.
+    DumpState(PathBuf, bool, flume::Sender<anyhow::Result<()>>),
+    /// Remove any nodes which haven't imported a new block for too long, if enabled.
+    PruneStaleNodes,
+    /// Save anything worth keeping, tell feeds that every chain has gone, and stop.
+    /// The provided sender is told once we're done.
+    Shutdown(flume::Sender<()>),
 }
 
 /// An incoming shard connection can send these messages to the aggregator.
@@ -53,6 +103,9 @@ pub enum FromShardWebsocket {
     /// so that we have a way to communicate back to it.
     Initialize {
         channel: flume::Sender<ToShardWebsocket>,
+        /// If provided, nodes from this shard that are
+        /// not on one of these chains are muted.
+        allowed_chains: Option<HashSet<BlockHash>>,
     },
     /// Tell the aggregator about a new node.
     Add {
@@ -72,6 +125,30 @@ pub enum FromShardWebsocket {
     Disconnected,
 }
 
+impl From<internal_messages::FromShardAggregator> for FromShardWebsocket {
+    fn from(msg: internal_messages::FromShardAggregator) -> Self {
+        match msg {
+            internal_messages::FromShardAggregator::AddNode {
+                ip,
+                node,
+                local_id,
+                genesis_hash,
+            } => FromShardWebsocket::Add {
+                ip,
+                node,
+                genesis_hash,
+                local_id,
+            },
+            internal_messages::FromShardAggregator::UpdateNode { payload, local_id } => {
+                FromShardWebsocket::Update { local_id, payload }
+            }
+            internal_messages::FromShardAggregator::RemoveNode { local_id } => {
+                FromShardWebsocket::Remove { local_id }
+            }
+        }
+    }
+}
+
 /// The aggregator can these messages back to a shard connection.
 #[derive(Debug)]
 pub enum ToShardWebsocket {
@@ -91,10 +168,22 @@ pub enum FromFeedWebsocket {
     /// progress.
     Initialize {
         channel: flume::Sender<ToFeedWebsocket>,
+        /// How the feed would like messages to be encoded. This is ignored
+        /// (and JSON is used) unless binary feeds are allowed.
+        encoding: FeedEncoding,
     },
     /// The feed can subscribe to a chain to receive
     /// messages relating to it.
     Subscribe { chain: BlockHash },
+    /// The feed can unsubscribe from the chain it's subscribed
+    /// to, to stop receiving messages relating to it.
+    Unsubscribe { chain: BlockHash },
+    /// The feed can ask to only be told about these chains, rather than every chain.
+    /// If this is empty, the feed is told about every chain again.
+    FilterChains { chains: HashSet<BlockHash> },
+    /// The feed can ask to only be told about the chain it's subscribed to as a whole,
+    /// and not about each of its nodes. This can be turned off again.
+    SummaryOnly { enabled: bool },
     /// An explicit ping message.
     Ping { value: Box<str> },
     /// The feed is disconnected.
@@ -119,12 +208,225 @@ pub struct Metrics {
     pub total_messages_to_aggregator: u64,
     /// How many (non-critical) messages have been dropped by the aggregator because it was overwhelmed.
     pub dropped_messages_to_aggregator: u64,
+    /// How many processing errors have been dropped because the error channel was full.
+    pub dropped_processing_errors: u64,
+    /// How many events have been dropped because the event channel was full.
+    pub dropped_events: u64,
+    /// How many feeds have been disconnected for having too many messages queued up.
+    pub disconnected_slow_feeds: u64,
+    /// How many messages from shards have been rejected for being too large.
+    pub oversized_shard_messages: u64,
+    /// How many nodes have been rejected for being over the per-shard node limit.
+    pub rejected_shard_nodes: u64,
+    /// How many updates from nodes have been dropped for arriving too quickly.
+    pub rate_limited_node_messages: u64,
+    /// Chains which are denylisted for appearing and disappearing too often.
+    pub auto_denylisted_chains: Vec<BlockHash>,
     /// How many nodes are currently known to this aggregator.
     pub connected_nodes: usize,
     /// How many feeds are currently connected to this aggregator.
     pub connected_feeds: usize,
     /// How many shards are currently connected to this aggregator.
     pub connected_shards: usize,
+    /// How many connected feeds are consuming messages fast enough.
+    pub keeping_up_feeds: usize,
+    /// How many connected feeds have a growing backlog of messages waiting to be sent.
+    pub lagging_feeds: usize,
+    /// Metrics for each of the chains known to this aggregator.
+    pub chains: HashMap<BlockHash, ChainMetrics>,
+    /// The current label of each of the chains known to this aggregator.
+    pub chain_labels: HashMap<BlockHash, Box<str>>,
+    /// How many nodes are connected to each of the chains known to this aggregator.
+    pub chain_node_counts: HashMap<BlockHash, usize>,
+    /// How many of each type of message have been serialized to send to feeds. A message
+    /// that is sent to several feeds is only counted once.
+    pub feed_messages: FeedMessageCounts,
+}
+
+/// Metrics relating to a single chain.
+#[derive(Clone, Debug, Default)]
+pub struct ChainMetrics {
+    /// On average, how long in ms does it take for a new best block to be finalized?
+    pub average_time_to_finality: Option<u64>,
+    /// How many nodes were added to this chain since metrics were last gathered.
+    pub nodes_added: usize,
+    /// How many nodes were removed from this chain since metrics were last gathered.
+    pub nodes_removed: usize,
+    /// How many nodes are in each group on this chain, if nodes are being grouped.
+    pub node_groups: HashMap<String, usize>,
+    /// The highest best block reported by any node currently on this chain.
+    pub best_block: BlockNumber,
+    /// The highest finalized block reported by any node currently on this chain.
+    pub finalized_block: BlockNumber,
+    /// How many blocks `finalized_block` is behind `best_block`.
+    pub finality_gap: BlockNumber,
+    /// How many nodes on this chain are synced.
+    pub synced_nodes: usize,
+    /// How many nodes on this chain are still syncing.
+    pub syncing_nodes: usize,
+}
+
+/// Count how many nodes are added to and removed from a chain.
+#[derive(Clone, Copy, Debug, Default)]
+struct NodeChurn {
+    added: usize,
+    removed: usize,
+}
+
+/// A read-only view of what a feed connection is subscribed to.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct FeedSubscriptionView {
+    /// The chain that the feed is subscribed to, if any.
+    pub subscribed_chain: Option<BlockHash>,
+    /// How many messages are waiting to be sent to the feed.
+    pub queued_messages: usize,
+}
+
+/// Something went wrong handling a message from a shard.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessingError {
+    pub kind: ProcessingErrorKind,
+    /// The shard connection that the message came from.
+    pub shard_conn_id: ConnId,
+    /// The shard-local ID of the node that the message was about.
+    pub local_id: ShardNodeId,
+}
+
+/// What went wrong handling a message from a shard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessingErrorKind {
+    /// The message was about a node that we don't know about.
+    UnknownNode,
+    /// The message was about a node whose chain we don't know about.
+    UnknownChain,
+}
+
+impl fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            ProcessingErrorKind::UnknownNode => "ID",
+            ProcessingErrorKind::UnknownChain => "chain",
+        };
+        write!(
+            f,
+            "Cannot find {} for node with shard/connectionId of {:?}/{:?}",
+            what, self.shard_conn_id, self.local_id
+        )
+    }
+}
+
+/// Something notable that happened, which might be worth alerting somebody about.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AggregatorEvent {
+    /// The number of nodes on a chain crossed one of the configured thresholds.
+    ChainThresholdCrossed {
+        genesis_hash: BlockHash,
+        threshold: usize,
+        direction: ThresholdDirection,
+    },
+}
+
+impl fmt::Display for AggregatorEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AggregatorEvent::ChainThresholdCrossed {
+                genesis_hash,
+                threshold,
+                direction,
+            } => {
+                let which_way = match direction {
+                    ThresholdDirection::Up => "reached",
+                    ThresholdDirection::Down => "dropped below",
+                };
+                write!(
+                    f,
+                    "Chain {:?} {} {} nodes",
+                    genesis_hash, which_way, threshold
+                )
+            }
+        }
+    }
+}
+
+/// A chain which is denylisted for appearing and disappearing too often.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct AutoDenylistedChainView {
+    pub genesis_hash: BlockHash,
+    /// How many seconds until nodes on the chain are allowed to connect again.
+    pub expires_in_secs: u64,
+}
+
+/// A read-only summary of a single chain.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct ChainInfo {
+    pub genesis_hash: BlockHash,
+    pub label: Box<str>,
+    pub node_count: usize,
+    /// The most nodes that the chain has had at once.
+    pub highest_node_count: usize,
+    /// The lowest and highest finalized block heights reported by the chain's nodes,
+    /// or `None` if it has no nodes.
+    pub finalized_heights: Option<(BlockNumber, BlockNumber)>,
+}
+
+/// A read-only snapshot of every chain that an aggregator knows about.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct StateSnapshot {
+    pub chains: Vec<ChainSnapshot>,
+}
+
+impl StateSnapshot {
+    /// Write this snapshot to a file as newline delimited JSON, with one line per chain,
+    /// so that it's easy to pick through with line based tools.
+    pub fn write_ndjson(&self, path: &Path) -> anyhow::Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Could not create {:?}", path))?;
+        let mut writer = std::io::BufWriter::new(file);
+        for chain in &self.chains {
+            serde_json::to_writer(&mut writer, chain)?;
+            writer.write_all(b"\n")?;
+        }
+        writer
+            .flush()
+            .with_context(|| format!("Could not write {:?}", path))?;
+        Ok(())
+    }
+}
+
+/// A read-only snapshot of a single chain.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct ChainSnapshot {
+    pub genesis_hash: BlockHash,
+    pub label: Box<str>,
+    pub node_count: usize,
+    /// The most nodes that the chain has had at once.
+    pub highest_node_count: usize,
+    /// Details about each node on the chain, if they were asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<NodeView>>,
+}
+
+/// A read-only view of a single node.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct NodeView {
+    /// The ID of the node within its chain, as used in feed messages.
+    pub id: usize,
+    /// The name that the node reported.
+    pub name: Box<str>,
+    /// The height of the node's best block.
+    pub best_block: BlockNumber,
+    /// The height of the node's finalized block.
+    pub finalized_block: BlockNumber,
+    /// How long, in ms, the node takes to import each block, based on recent blocks.
+    pub estimated_block_time: Option<u64>,
+    /// The ID of the shard connection that the node's telemetry arrives through.
+    pub shard_conn_id: Option<u64>,
+    /// Whether the node has been located, or won't be because it reported a private address.
+    pub location_status: find_location::LocationStatus,
+    /// The last payload of each kind that the node sent us, if they were asked for
+    /// and we're keeping hold of them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<serde_json::Value>,
 }
 
 // The frontend sends text based commands; parse them into these messages:
@@ -142,11 +444,48 @@ impl FromStr for FromFeedWebsocket {
             "subscribe" => Ok(FromFeedWebsocket::Subscribe {
                 chain: value.parse()?,
             }),
+            "unsubscribe" => Ok(FromFeedWebsocket::Unsubscribe {
+                chain: value.parse()?,
+            }),
+            "summary" => Ok(FromFeedWebsocket::SummaryOnly {
+                enabled: value.parse()?,
+            }),
+            "filter" => Ok(FromFeedWebsocket::FilterChains {
+                chains: value
+                    .split(',')
+                    .filter(|hash| !hash.is_empty())
+                    .map(|hash| hash.parse())
+                    .collect::<Result<_, _>>()?,
+            }),
             _ => return Err(anyhow::anyhow!("Command {} not recognised", cmd)),
         }
     }
 }
 
+/// What should we do when a node that's already been added reports that it's
+/// now connected to a different chain?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChainConflictPolicy {
+    /// Ignore the conflicting report; the node stays on the chain it was added to.
+    Ignore,
+    /// Remove the node from the chain it was added to and add it to the new chain.
+    Reregister,
+}
+
+impl FromStr for ChainConflictPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(ChainConflictPolicy::Ignore),
+            "reregister" => Ok(ChainConflictPolicy::Reregister),
+            _ => Err(anyhow::anyhow!(
+                "Expecting one of 'ignore' or 'reregister', but got '{}'",
+                s
+            )),
+        }
+    }
+}
+
 /// The aggregator can these messages back to a feed connection.
 #[derive(Clone, Debug)]
 pub enum ToFeedWebsocket {
@@ -161,14 +500,44 @@ pub struct InnerLoop {
     /// We maintain a mapping between NodeId and ConnId+LocalId, so that we know
     /// which messages are about which nodes.
     node_ids: BiMap<NodeId, (ConnId, ShardNodeId)>,
+    /// Nodes which we've removed and asked their shard to mute. Messages about these that
+    /// were already on their way to us are expected, and so are quietly ignored.
+    muted_shard_nodes: HashSet<(ConnId, ShardNodeId)>,
+    /// How many nodes each shard has added, so that no one shard can add too many.
+    shard_node_counts: HashMap<ConnId, usize>,
+    /// The most nodes that we'll accept from a single shard.
+    max_nodes_per_shard: usize,
+    /// How many nodes have been rejected for being over `max_nodes_per_shard`.
+    rejected_shard_nodes: u64,
+    /// How quickly we'll handle updates from each node, if limited.
+    node_rate_limits: Option<NodeRateLimits>,
+    /// How many updates from nodes have been dropped for arriving too quickly.
+    rate_limited_node_messages: u64,
 
     /// Keep track of how to send messages out to feeds.
     feed_channels: HashMap<ConnId, flume::Sender<ToFeedWebsocket>>,
+    /// How many messages were queued up for each feed the last time we gathered metrics.
+    /// Comparing against this tells us whether a feed is falling behind.
+    feed_queue_lens: HashMap<ConnId, usize>,
+    /// Can feeds ask for messages in the binary encoding?
+    binary_feeds: bool,
+    /// Which feeds want messages in the binary encoding. Everybody else gets JSON.
+    binary_feed_conn_ids: HashSet<ConnId>,
+    /// Which feeds don't want messages about individual nodes.
+    summary_feed_conn_ids: HashSet<ConnId>,
     /// Keep track of how to send messages out to shards.
     shard_channels: HashMap<ConnId, flume::Sender<ToShardWebsocket>>,
+    /// Some shards are only allowed to send us nodes on specific chains.
+    shard_allowed_chains: HashMap<ConnId, HashSet<BlockHash>>,
+
+    /// How many nodes have been added to and removed from each chain
+    /// since we last gathered metrics.
+    node_churn: HashMap<BlockHash, NodeChurn>,
 
     /// Which feeds are subscribed to a given chain?
     chain_to_feed_conn_ids: MultiMapUnique<BlockHash, ConnId>,
+    /// Feeds in here are only told about the chains in their filter.
+    feed_chain_filters: HashMap<ConnId, HashSet<BlockHash>>,
 
     /// Send messages here to make geographical location requests.
     tx_to_locator: flume::Sender<(NodeId, Ipv4Addr)>,
@@ -176,25 +545,192 @@ pub struct InnerLoop {
     /// How big can the queue of messages coming in to the aggregator get before messages
     /// are prioritised and dropped to try and get back on track.
     max_queue_len: usize,
+
+    /// Should we avoid trying to locate nodes that report private addresses?
+    skip_private_ip_location: bool,
+
+    /// What to do when a node reports that it's connected to a different chain.
+    chain_conflict_policy: ChainConflictPolicy,
+
+    /// A feed with more than this many messages queued up, which isn't going down,
+    /// is considered to be lagging.
+    feed_lag_threshold: usize,
+    /// Feeds subscribed to a chain with this many messages queued up are disconnected
+    /// rather than being sent more node updates, if provided.
+    max_feed_queue_len: Option<usize>,
+    /// How many feeds have been disconnected for having too many messages queued up.
+    disconnected_slow_feeds: u64,
+
+    /// Which nodes count towards the node count that we report for each chain.
+    node_count_source: NodeCountSource,
+    /// Node counts that we tell feeds about are rounded to the nearest multiple of this.
+    node_count_granularity: usize,
+    /// The label, node count and highest node count that every feed was last told
+    /// about for each chain.
+    announced_chains: HashMap<BlockHash, (Box<str>, usize, usize)>,
+
+    /// For this long after startup or a shard connecting, we allow
+    /// `max_third_party_nodes_during_warmup` nodes on third party chains.
+    quota_warmup: Duration,
+    max_third_party_nodes_during_warmup: usize,
+
+    /// Are nodes being put into groups based on their names?
+    group_nodes: bool,
+
+    /// Nodes connecting from IP addresses in these ranges are given
+    /// these locations rather than being looked up.
+    location_overrides: LocationOverrides,
+
+    /// Serialization work which is worth parallelising happens on this pool.
+    serialization_pool: Arc<rayon::ThreadPool>,
+    /// The most nodes to describe in each message sent to newly subscribed feeds.
+    nodes_per_feed_message: usize,
+    /// Messages sent to newly subscribed feeds are kept to this many bytes where possible.
+    max_feed_message_bytes: usize,
+
+    /// Errors handling shard messages are sent here, if provided.
+    processing_errors: Option<flume::Sender<ProcessingError>>,
+    /// How many errors didn't fit into the `processing_errors` channel.
+    dropped_processing_errors: u64,
+
+    /// Which node count thresholds chains are above, if we're watching for them.
+    node_count_thresholds: Option<NodeCountThresholds>,
+    /// Events are sent here, if provided.
+    events: Option<flume::Sender<AggregatorEvent>>,
+    /// How many events didn't fit into the `events` channel.
+    dropped_events: u64,
+
+    /// Messages from shards about nodes which are larger than this are rejected.
+    max_shard_message_size: Option<u64>,
+    /// Ignore new locations for nodes which are less than this many km from their last one.
+    min_location_change_km: Option<f64>,
+    /// Nodes which haven't sent us anything for this long are removed.
+    stale_node_timeout: Option<Duration>,
+    /// How many messages from shards have been rejected for being too large.
+    oversized_shard_messages: u64,
+
+    /// Chains which keep appearing and disappearing are denylisted for a while, if enabled.
+    chain_flaps: Option<ChainFlaps>,
+    /// The denylist in effect is saved here whenever it changes, if provided.
+    denylist_path: Option<PathBuf>,
+    /// Nodes on chains with these genesis hashes are added to the chain each maps to instead.
+    chain_aliases: HashMap<BlockHash, BlockHash>,
+
+    /// How many of each type of message we've serialized to send to feeds.
+    feed_message_counts: FeedMessageCounts,
 }
 
 impl InnerLoop {
     /// Create a new inner loop handler with the various state it needs.
-    pub fn new(
-        tx_to_locator: flume::Sender<(NodeId, Ipv4Addr)>,
-        denylist: Vec<String>,
-        max_queue_len: usize,
-        max_third_party_nodes: usize,
-    ) -> Self {
-        InnerLoop {
-            node_state: State::new(denylist, max_third_party_nodes),
+    pub fn new(tx_to_locator: flume::Sender<(NodeId, Ipv4Addr)>, opts: AggregatorOpts) -> Self {
+        let mut inner_loop = InnerLoop {
+            node_state: State::new(opts.denylist, opts.max_third_party_nodes),
             node_ids: BiMap::new(),
+            muted_shard_nodes: HashSet::new(),
+            shard_node_counts: HashMap::new(),
+            max_nodes_per_shard: opts.max_nodes_per_shard,
+            rejected_shard_nodes: 0,
+            node_rate_limits: opts.max_node_messages_per_second.map(NodeRateLimits::new),
+            rate_limited_node_messages: 0,
             feed_channels: HashMap::new(),
+            feed_queue_lens: HashMap::new(),
+            binary_feeds: opts.binary_feeds,
+            binary_feed_conn_ids: HashSet::new(),
+            summary_feed_conn_ids: HashSet::new(),
             shard_channels: HashMap::new(),
+            shard_allowed_chains: HashMap::new(),
+            node_churn: HashMap::new(),
             chain_to_feed_conn_ids: MultiMapUnique::new(),
+            feed_chain_filters: HashMap::new(),
             tx_to_locator,
-            max_queue_len,
+            max_queue_len: opts.max_queue_len,
+            skip_private_ip_location: opts.skip_private_ip_location,
+            chain_conflict_policy: opts.chain_conflict_policy,
+            feed_lag_threshold: opts.feed_lag_threshold,
+            max_feed_queue_len: opts.max_feed_queue_len,
+            disconnected_slow_feeds: 0,
+            node_count_source: opts.node_count_source,
+            node_count_granularity: opts.node_count_granularity,
+            announced_chains: HashMap::new(),
+            quota_warmup: opts.quota_warmup,
+            max_third_party_nodes_during_warmup: opts.max_third_party_nodes_during_warmup,
+            group_nodes: opts.node_group_pattern.is_some(),
+            location_overrides: LocationOverrides::new(opts.location_overrides),
+            serialization_pool: opts.serialization_pool,
+            nodes_per_feed_message: opts.nodes_per_feed_message,
+            max_feed_message_bytes: opts.max_feed_message_bytes,
+            processing_errors: opts.processing_errors,
+            dropped_processing_errors: 0,
+            node_count_thresholds: opts.node_count_thresholds.map(NodeCountThresholds::new),
+            events: opts.events,
+            dropped_events: 0,
+            max_shard_message_size: opts.max_shard_message_size,
+            min_location_change_km: opts.min_location_change_km,
+            stale_node_timeout: opts.stale_node_timeout,
+            oversized_shard_messages: 0,
+            chain_flaps: opts.chain_flaps.map(ChainFlaps::new),
+            denylist_path: opts.denylist_path,
+            chain_aliases: opts.chain_aliases,
+            feed_message_counts: FeedMessageCounts::default(),
+        };
+        inner_loop
+            .node_state
+            .set_node_group_pattern(opts.node_group_pattern);
+        inner_loop.node_state.set_allowlist(opts.allowlist);
+        inner_loop
+            .node_state
+            .set_quota_exempt_chains(opts.quota_exempt_chains);
+        inner_loop
+            .node_state
+            .set_required_node_fields(opts.required_node_fields, opts.incomplete_node_policy);
+        inner_loop
+            .node_state
+            .set_recommended_versions(opts.recommended_versions);
+        inner_loop
+            .node_state
+            .set_chain_label_overrides(opts.chain_label_overrides);
+        inner_loop
+            .node_state
+            .set_node_blocklist(opts.node_blocklist);
+        inner_loop
+            .node_state
+            .set_stale_thresholds(opts.stale_threshold, opts.chain_stale_thresholds);
+        inner_loop
+            .node_state
+            .set_stale_block_margin(opts.stale_block_margin);
+        inner_loop
+            .node_state
+            .set_block_import_drop_margin(opts.block_import_drop_margin);
+        inner_loop
+            .node_state
+            .set_block_propagation_messages(opts.block_propagation_messages);
+        inner_loop
+            .node_state
+            .set_quota_count_source(opts.quota_count_source);
+        inner_loop
+            .node_state
+            .set_sticky_node_ids(opts.sticky_node_ids);
+        inner_loop
+            .node_state
+            .set_max_nodes_per_chain(opts.max_nodes_per_chain);
+        inner_loop
+            .node_state
+            .set_keep_raw_node_payloads(opts.keep_raw_node_payloads);
+        inner_loop.load_denylist();
+        inner_loop.start_quota_warmup();
+        inner_loop
+    }
+
+    /// Relax the limit on third party nodes for a while, so that we don't mute
+    /// nodes that are simply all reconnecting at the same time.
+    fn start_quota_warmup(&mut self) {
+        if self.quota_warmup.is_zero() {
+            return;
         }
+        self.node_state.set_quota_warmup(
+            time::now() + self.quota_warmup.as_millis() as u64,
+            self.max_third_party_nodes_during_warmup,
+        );
     }
 
     /// Start handling and responding to incoming messages.
@@ -229,12 +765,64 @@ impl InnerLoop {
                         dropped_messages2.load(Ordering::Relaxed),
                         total_messages2.load(Ordering::Relaxed),
                     ),
+                    ToAggregator::ReplaceDenylist(denylist, tx) => {
+                        self.handle_replace_denylist(denylist, tx)
+                    }
+                    ToAggregator::ReplaceNodeBlocklist(node_blocklist, tx) => {
+                        self.handle_replace_node_blocklist(node_blocklist, tx)
+                    }
+                    ToAggregator::ReplaceLocationOverrides(location_overrides, tx) => {
+                        self.location_overrides = LocationOverrides::new(location_overrides);
+                        let _ = tx.send(());
+                    }
+                    ToAggregator::ReplaceRecommendedVersions(recommended_versions, tx) => {
+                        self.node_state
+                            .set_recommended_versions(recommended_versions);
+                        let _ = tx.send(());
+                    }
+                    ToAggregator::GetFeedSubscriptions(feed_conn_id, tx) => {
+                        self.handle_get_feed_subscriptions(feed_conn_id, tx)
+                    }
+                    ToAggregator::GetChainNodes(genesis_hash, include_raw, tx) => {
+                        self.handle_get_chain_nodes(genesis_hash, include_raw, tx)
+                    }
+                    ToAggregator::GetChain(genesis_hash, tx) => {
+                        let _ = tx.send(self.chain_info(genesis_hash));
+                    }
+                    ToAggregator::GetChainGeoJson(genesis_hash, tx) => {
+                        let genesis_hash = self.canonical_genesis_hash(genesis_hash);
+                        let geojson = self
+                            .node_state
+                            .get_chain_by_genesis_hash(&genesis_hash)
+                            .map(|chain| geojson::chain_geojson(&chain));
+                        let _ = tx.send(geojson);
+                    }
+                    ToAggregator::GetAutoDenylistedChains(tx) => {
+                        self.handle_get_auto_denylisted_chains(tx)
+                    }
+                    ToAggregator::GetSnapshot(include_nodes, tx) => {
+                        let _ = tx.send(self.snapshot(include_nodes));
+                    }
+                    ToAggregator::DumpState(path, include_nodes, tx) => {
+                        // Writing the file could take a while, so we don't wait for it:
+                        let snapshot = self.snapshot(include_nodes);
+                        tokio::task::spawn_blocking(move || {
+                            let _ = tx.send(snapshot.write_ndjson(&path));
+                        });
+                    }
+                    ToAggregator::PruneStaleNodes => self.prune_stale_nodes(time::now()),
+                    ToAggregator::Shutdown(tx) => {
+                        self.shutdown();
+                        let _ = tx.send(());
+                        break;
+                    }
                 }
             }
         });
 
         while let Ok(msg) = rx_from_external.recv_async().await {
             total_messages.fetch_add(1, Ordering::Relaxed);
+            let is_shutdown = matches!(msg, ToAggregator::Shutdown(_));
 
             // ignore node updates if we have too many messages to handle, in an attempt
             // to reduce the queue length back to something reasonable, lest it get out of
@@ -255,7 +843,28 @@ impl InnerLoop {
                 log::error!("Cannot send message into aggregator: {}", e);
                 break;
             }
+            if is_shutdown {
+                break;
+            }
+        }
+    }
+
+    /// Get ready to stop: save the denylist if we're keeping hold of it, and tell feeds
+    /// that every chain has gone. Feeds and shards are disconnected when we're dropped.
+    fn shutdown(&mut self) {
+        self.save_denylist();
+
+        let genesis_hashes: Vec<BlockHash> = self
+            .node_state
+            .iter_chains()
+            .map(|chain| chain.genesis_hash())
+            .collect();
+        for genesis_hash in genesis_hashes {
+            let mut feed_messages_for_all = self.broadcast_serializer();
+            feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
+            self.finalize_and_broadcast_to_all_feeds(&genesis_hash, feed_messages_for_all);
         }
+        self.announced_chains.clear();
     }
 
     /// Gather and return some metrics.
@@ -273,6 +882,63 @@ impl InnerLoop {
         let connected_shards = self.shard_channels.len();
         let connected_feeds = self.feed_channels.len();
         let total_messages_to_feeds: usize = self.feed_channels.values().map(|c| c.len()).sum();
+        let lagging_feeds = self.classify_lagging_feeds();
+        let keeping_up_feeds = connected_feeds - lagging_feeds;
+        let mut chains: HashMap<BlockHash, ChainMetrics> = self
+            .node_state
+            .iter_chains()
+            .map(|chain| {
+                let node_groups = match self.group_nodes {
+                    true => chain
+                        .subgroups()
+                        .into_iter()
+                        .map(|(group, node_ids)| (group, node_ids.len()))
+                        .collect(),
+                    false => HashMap::new(),
+                };
+                let (best_block, finalized_block) =
+                    chain
+                        .iter_nodes()
+                        .fold((0, 0), |(best, finalized), (_, node)| {
+                            (
+                                best.max(node.best().height),
+                                finalized.max(node.finalized().height),
+                            )
+                        });
+                let (synced_nodes, syncing_nodes) = chain.sync_breakdown();
+                let metrics = ChainMetrics {
+                    average_time_to_finality: chain.average_time_to_finality(),
+                    node_groups,
+                    best_block,
+                    finalized_block,
+                    finality_gap: best_block.saturating_sub(finalized_block),
+                    synced_nodes,
+                    syncing_nodes,
+                    ..Default::default()
+                };
+                (chain.genesis_hash(), metrics)
+            })
+            .collect();
+
+        let auto_denylisted_chains = match &mut self.chain_flaps {
+            Some(chain_flaps) => {
+                let now = Instant::now();
+                chain_flaps.prune(now);
+                chain_flaps
+                    .denylisted(now)
+                    .into_iter()
+                    .map(|(genesis_hash, _)| genesis_hash)
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        // Chains may have gone away since we last looked, but we still report their churn:
+        for (genesis_hash, churn) in std::mem::take(&mut self.node_churn) {
+            let metrics = chains.entry(genesis_hash).or_default();
+            metrics.nodes_added = churn.added;
+            metrics.nodes_removed = churn.removed;
+        }
 
         // Ignore error sending; assume the receiver stopped caring and dropped the channel:
         let _ = rx.send(Metrics {
@@ -283,19 +949,306 @@ impl InnerLoop {
             current_messages_to_aggregator,
             total_messages_to_aggregator,
             dropped_messages_to_aggregator,
+            dropped_processing_errors: self.dropped_processing_errors,
+            dropped_events: self.dropped_events,
+            disconnected_slow_feeds: self.disconnected_slow_feeds,
+            oversized_shard_messages: self.oversized_shard_messages,
+            rejected_shard_nodes: self.rejected_shard_nodes,
+            rate_limited_node_messages: self.rate_limited_node_messages,
+            auto_denylisted_chains,
             connected_nodes,
             connected_feeds,
             connected_shards,
+            keeping_up_feeds,
+            lagging_feeds,
+            chains,
+            chain_labels: self.node_state.chain_labels(),
+            chain_node_counts: self.node_state.chain_node_counts(),
+            feed_messages: self.feed_message_counts,
         });
     }
 
+    /// Compare the current queue length of each feed against what it was the last time
+    /// we checked, returning the number of feeds which are lagging behind.
+    fn classify_lagging_feeds(&mut self) -> usize {
+        let mut lagging_feeds = 0;
+        let mut feed_queue_lens = HashMap::with_capacity(self.feed_channels.len());
+        for (&feed_conn_id, channel) in &self.feed_channels {
+            let queue_len = channel.len();
+            let last_queue_len = self.feed_queue_lens.get(&feed_conn_id).copied();
+            let is_lagging = queue_len > self.feed_lag_threshold
+                && matches!(last_queue_len, Some(last) if queue_len >= last);
+            if is_lagging {
+                lagging_feeds += 1;
+            }
+            feed_queue_lens.insert(feed_conn_id, queue_len);
+        }
+        self.feed_queue_lens = feed_queue_lens;
+        lagging_feeds
+    }
+
+    /// Replace the denylist, and then mute and remove any nodes on chains that are now
+    /// denied. Nodes on chains that are now allowed will be added again as they reconnect.
+    fn handle_replace_denylist(
+        &mut self,
+        denylist: Vec<String>,
+        tx: flume::Sender<Vec<BlockHash>>,
+    ) {
+        self.node_state.set_denylist(denylist);
+        self.save_denylist();
+
+        let node_ids = self.node_state.denied_node_ids();
+        let affected_chains: HashSet<BlockHash> = node_ids
+            .iter()
+            .filter_map(|&node_id| self.node_state.get_chain_by_node_id(node_id))
+            .map(|chain| chain.genesis_hash())
+            .collect();
+
+        self.mute_and_remove_nodes(node_ids, MuteReason::ChainNotAllowed);
+
+        let removed_chains = affected_chains
+            .into_iter()
+            .filter(|hash| self.node_state.get_chain_by_genesis_hash(hash).is_none())
+            .collect();
+
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(removed_chains);
+    }
+
+    /// Carry on with the denylist that was saved last time we ran, if there is one.
+    fn load_denylist(&mut self) {
+        let path = match &self.denylist_path {
+            Some(path) => path,
+            None => return,
+        };
+        let denylist = match PersistedDenylist::load(path) {
+            Ok(Some(denylist)) => denylist,
+            Ok(None) => return,
+            Err(e) => {
+                log::warn!("Starting with the configured denylist: {:#}", e);
+                return;
+            }
+        };
+
+        self.node_state.set_denylist(denylist.manual);
+        if let Some(chain_flaps) = &mut self.chain_flaps {
+            let (now, now_ms) = (Instant::now(), time::now());
+            for (genesis_hash, expires_at) in denylist.automatic {
+                if expires_at > now_ms {
+                    let until = now + Duration::from_millis(expires_at - now_ms);
+                    chain_flaps.restore(genesis_hash, until);
+                }
+            }
+        }
+    }
+
+    /// Save the denylist in effect, including chains which were denylisted automatically,
+    /// so that it survives a restart. Does nothing if we haven't been given somewhere to save it.
+    fn save_denylist(&self) {
+        let path = match &self.denylist_path {
+            Some(path) => path,
+            None => return,
+        };
+        let automatic = match &self.chain_flaps {
+            Some(chain_flaps) => {
+                let now_ms = time::now();
+                chain_flaps
+                    .denylisted(Instant::now())
+                    .into_iter()
+                    .map(|(genesis_hash, expires_in)| {
+                        (genesis_hash, now_ms + expires_in.as_millis() as u64)
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let denylist = PersistedDenylist {
+            manual: self.node_state.denylist(),
+            automatic,
+        };
+        if let Err(e) = denylist.save(path) {
+            log::error!("Could not save the denylist: {:#}", e);
+        }
+    }
+
+    /// Replace the node blocklist, and then mute and remove any nodes that are now blocked.
+    fn handle_replace_node_blocklist(
+        &mut self,
+        node_blocklist: Vec<String>,
+        tx: flume::Sender<usize>,
+    ) {
+        self.node_state.set_node_blocklist(node_blocklist);
+
+        let node_ids = self.node_state.blocked_node_ids();
+        let removed_count = node_ids.len();
+        self.mute_and_remove_nodes(node_ids, MuteReason::NodeBlocked);
+
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(removed_count);
+    }
+
+    /// Mute and remove any nodes which haven't sent us anything for longer than the
+    /// stale node timeout, telling feeds as if they'd disconnected.
+    fn prune_stale_nodes(&mut self, now: Timestamp) {
+        let stale_node_timeout = match self.stale_node_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        let threshold = now.saturating_sub(stale_node_timeout.as_millis() as u64);
+        let node_ids = self.node_state.stale_node_ids(threshold);
+        if !node_ids.is_empty() {
+            log::info!("Removing {} stale nodes", node_ids.len());
+            self.mute_and_remove_nodes(node_ids, MuteReason::Stale);
+        }
+    }
+
+    /// Tell the relevant shards to stop sending us messages about these nodes,
+    /// and then remove them.
+    fn mute_and_remove_nodes(&mut self, node_ids: Vec<NodeId>, reason: MuteReason) {
+        for node_id in &node_ids {
+            if let Some(&(shard_conn_id, local_id)) = self.node_ids.get_by_left(node_id) {
+                self.muted_shard_nodes.insert((shard_conn_id, local_id));
+                if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                    let _ = shard_conn.send(ToShardWebsocket::Mute {
+                        local_id,
+                        reason: reason.clone(),
+                    });
+                }
+            }
+        }
+
+        self.remove_nodes_and_broadcast_result(node_ids);
+    }
+
+    /// Hand back details about the subscriptions of a single feed.
+    fn handle_get_feed_subscriptions(
+        &self,
+        feed_conn_id: ConnId,
+        tx: flume::Sender<Option<FeedSubscriptionView>>,
+    ) {
+        let view = self
+            .feed_channels
+            .get(&feed_conn_id)
+            .map(|channel| FeedSubscriptionView {
+                subscribed_chain: self.chain_to_feed_conn_ids.get_key(&feed_conn_id).copied(),
+                queued_messages: channel.len(),
+            });
+        let _ = tx.send(view);
+    }
+
+    /// Hand back the chains which are denylisted for flapping.
+    fn handle_get_auto_denylisted_chains(&self, tx: flume::Sender<Vec<AutoDenylistedChainView>>) {
+        let chains = match &self.chain_flaps {
+            Some(chain_flaps) => chain_flaps
+                .denylisted(Instant::now())
+                .into_iter()
+                .map(|(genesis_hash, expires_in)| AutoDenylistedChainView {
+                    genesis_hash,
+                    expires_in_secs: expires_in.as_secs(),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let _ = tx.send(chains);
+    }
+
+    /// Hand back details about each of the nodes on a chain.
+    fn handle_get_chain_nodes(
+        &self,
+        genesis_hash: BlockHash,
+        include_raw: bool,
+        tx: flume::Sender<Option<Vec<NodeView>>>,
+    ) {
+        let genesis_hash = self.canonical_genesis_hash(genesis_hash);
+        let nodes = self
+            .node_state
+            .get_chain_by_genesis_hash(&genesis_hash)
+            .map(|chain| self.node_views(&chain, include_raw));
+        let _ = tx.send(nodes);
+    }
+
+    /// Describe each node on a chain, optionally with the payloads each last sent us.
+    fn node_views(&self, chain: &state::StateChain, include_raw: bool) -> Vec<NodeView> {
+        chain
+            .iter_nodes()
+            .map(|(node_id, node)| NodeView {
+                id: node_id.get_chain_node_id().into(),
+                name: node.details().name.clone(),
+                best_block: node.best().height,
+                finalized_block: node.finalized().height,
+                estimated_block_time: node.estimated_block_time(),
+                shard_conn_id: self
+                    .node_ids
+                    .get_by_left(&node_id)
+                    .map(|(conn_id, _)| (*conn_id).into()),
+                location_status: node.location_status(),
+                raw: node
+                    .raw_payloads()
+                    .filter(|_| include_raw)
+                    .and_then(|raw| serde_json::to_value(raw).ok()),
+            })
+            .collect()
+    }
+
+    /// Summarize the chain with the given genesis hash, if we know about it.
+    fn chain_info(&self, genesis_hash: BlockHash) -> Option<ChainInfo> {
+        let genesis_hash = self.canonical_genesis_hash(genesis_hash);
+        let chain = self.node_state.get_chain_by_genesis_hash(&genesis_hash)?;
+        let finalized_heights = chain
+            .iter_nodes()
+            .map(|(_, node)| node.finalized().height)
+            .fold(None, |range, height| match range {
+                None => Some((height, height)),
+                Some((lowest, highest)) => Some((height.min(lowest), height.max(highest))),
+            });
+        Some(ChainInfo {
+            genesis_hash: chain.genesis_hash(),
+            label: chain.label().into(),
+            node_count: chain.node_count(),
+            highest_node_count: chain.highest_node_count(),
+            finalized_heights,
+        })
+    }
+
+    /// Take a snapshot of every chain we know about, optionally describing their nodes too.
+    fn snapshot(&self, include_nodes: bool) -> StateSnapshot {
+        let chains = self
+            .node_state
+            .iter_chains()
+            .map(|chain| ChainSnapshot {
+                genesis_hash: chain.genesis_hash(),
+                label: chain.label().into(),
+                node_count: chain.node_count(),
+                highest_node_count: chain.highest_node_count(),
+                nodes: include_nodes.then(|| self.node_views(&chain, false)),
+            })
+            .collect();
+        StateSnapshot { chains }
+    }
+
     /// Handle messages that come from the node geographical locator.
     fn handle_from_find_location(&mut self, node_id: NodeId, location: find_location::Location) {
+        // Lookups can give slightly different answers for the same node, so ignore
+        // small movements rather than jiggling the node around on the map:
+        if let (Some(min_change_km), Some(new_location)) = (self.min_location_change_km, &location)
+        {
+            let old_location = self
+                .node_state
+                .get_node(node_id)
+                .and_then(|node| node.location());
+            if let Some(old_location) = old_location {
+                if find_location::distance_km(old_location, new_location) < min_change_km {
+                    return;
+                }
+            }
+        }
+
         self.node_state
             .update_node_location(node_id, location.clone());
 
         if let Some(loc) = location {
-            let mut feed_message_serializer = FeedMessageSerializer::new();
+            let mut feed_message_serializer = self.broadcast_serializer();
             feed_message_serializer.push(feed_message::LocatedNode(
                 node_id.get_chain_node_id().into(),
                 loc.latitude,
@@ -314,14 +1267,40 @@ impl InnerLoop {
                     feed_message_serializer,
                 );
             }
+
+            // If we only count located nodes, the chain's node count has gone up:
+            if self.node_count_source == NodeCountSource::Located {
+                if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
+                    let label = chain.label().to_owned();
+                    let genesis_hash = chain.genesis_hash();
+                    let node_count = chain.node_count_from(self.node_count_source);
+                    let mut feed_messages_for_all = self.broadcast_serializer();
+                    self.announce_chain(
+                        &mut feed_messages_for_all,
+                        &label,
+                        genesis_hash,
+                        node_count,
+                    );
+                    self.finalize_and_broadcast_to_all_feeds(&genesis_hash, feed_messages_for_all);
+                }
+            }
         }
     }
 
     /// Handle messages coming from shards.
     fn handle_from_shard(&mut self, shard_conn_id: ConnId, msg: FromShardWebsocket) {
         match msg {
-            FromShardWebsocket::Initialize { channel } => {
+            FromShardWebsocket::Initialize {
+                channel,
+                allowed_chains,
+            } => {
                 self.shard_channels.insert(shard_conn_id, channel);
+                // A shard connecting may well mean a load of nodes reconnecting:
+                self.start_quota_warmup();
+                if let Some(allowed_chains) = allowed_chains {
+                    self.shard_allowed_chains
+                        .insert(shard_conn_id, allowed_chains);
+                }
             }
             FromShardWebsocket::Add {
                 local_id,
@@ -329,72 +1308,35 @@ impl InnerLoop {
                 node,
                 genesis_hash,
             } => {
-                match self.node_state.add_node(genesis_hash, node) {
-                    state::AddNodeResult::ChainOnDenyList => {
-                        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
-                            let _ = shard_conn.send(ToShardWebsocket::Mute {
-                                local_id,
-                                reason: MuteReason::ChainNotAllowed,
-                            });
-                        }
-                    }
-                    state::AddNodeResult::ChainOverQuota => {
-                        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
-                            let _ = shard_conn.send(ToShardWebsocket::Mute {
-                                local_id,
-                                reason: MuteReason::Overquota,
-                            });
-                        }
-                    }
-                    state::AddNodeResult::NodeAddedToChain(details) => {
-                        let node_id = details.id;
-
-                        // Record ID <-> (shardId,localId) for future messages:
-                        self.node_ids.insert(node_id, (shard_conn_id, local_id));
-
-                        // Don't hold onto details too long because we want &mut self later:
-                        let new_chain_label = details.new_chain_label.to_owned();
-                        let chain_node_count = details.chain_node_count;
-                        let has_chain_label_changed = details.has_chain_label_changed;
-
-                        // Tell chain subscribers about the node we've just added:
-                        let mut feed_messages_for_chain = FeedMessageSerializer::new();
-                        feed_messages_for_chain.push(feed_message::AddedNode(
-                            node_id.get_chain_node_id().into(),
-                            &details.node,
-                        ));
-                        self.finalize_and_broadcast_to_chain_feeds(
-                            &genesis_hash,
-                            feed_messages_for_chain,
-                        );
-                        // Tell everybody about the new node count and potential rename:
-                        let mut feed_messages_for_all = FeedMessageSerializer::new();
-                        if has_chain_label_changed {
-                            feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
-                        }
-                        feed_messages_for_all.push(feed_message::AddedChain(
-                            &new_chain_label,
-                            genesis_hash,
-                            chain_node_count,
-                        ));
-                        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
+                let node_id = match self.add_node(shard_conn_id, local_id, genesis_hash, node) {
+                    Some(node_id) => node_id,
+                    None => return,
+                };
 
-                        // Ask for the grographical location of the node.
-                        // Currently we only geographically locate IPV4 addresses so ignore IPV6.
-                        if let IpAddr::V4(ip_v4) = ip {
-                            let _ = self.tx_to_locator.send((node_id, ip_v4));
-                        }
-                    }
+                // Ask for the grographical location of the node, unless we've been told where
+                // it is or it's reported a private address that can't be meaningfully located.
+                // Currently we only geographically locate IPV4 addresses so ignore IPV6.
+                if let Some(location) = self.location_overrides.find(ip) {
+                    self.handle_from_find_location(node_id, Some(location));
+                } else if self.skip_private_ip_location && find_location::is_private_ip(ip) {
+                    self.node_state.set_node_location_private(node_id);
+                } else if let IpAddr::V4(ip_v4) = ip {
+                    let _ = self.tx_to_locator.send((node_id, ip_v4));
                 }
             }
             FromShardWebsocket::Remove { local_id } => {
                 let node_id = match self.node_ids.remove_by_right(&(shard_conn_id, local_id)) {
-                    Some((node_id, _)) => node_id,
+                    Some((node_id, _)) => {
+                        self.forget_shard_node(shard_conn_id, local_id);
+                        node_id
+                    }
+                    // The shard still tells us when nodes that we've muted disconnect:
+                    None if self.muted_shard_nodes.remove(&(shard_conn_id, local_id)) => return,
                     None => {
-                        log::error!(
-                            "Cannot find ID for node with shard/connectionId of {:?}/{:?}",
+                        self.report_processing_error(
+                            ProcessingErrorKind::UnknownNode,
                             shard_conn_id,
-                            local_id
+                            local_id,
                         );
                         return;
                     }
@@ -402,19 +1344,40 @@ impl InnerLoop {
                 self.remove_nodes_and_broadcast_result(Some(node_id));
             }
             FromShardWebsocket::Update { local_id, payload } => {
+                if self.is_oversized_shard_message(&payload) {
+                    log::warn!(
+                        "Ignoring update from node with shard/connectionId of {:?}/{:?}: message is too large",
+                        shard_conn_id,
+                        local_id
+                    );
+                    return;
+                }
+
                 let node_id = match self.node_ids.get_by_right(&(shard_conn_id, local_id)) {
                     Some(id) => *id,
+                    None if self.muted_shard_nodes.contains(&(shard_conn_id, local_id)) => return,
                     None => {
-                        log::error!(
-                            "Cannot find ID for node with shard/connectionId of {:?}/{:?}",
+                        self.report_processing_error(
+                            ProcessingErrorKind::UnknownNode,
                             shard_conn_id,
-                            local_id
+                            local_id,
                         );
                         return;
                     }
                 };
 
-                let mut feed_message_serializer = FeedMessageSerializer::new();
+                // The node is telling us (again) which chain it's connected to:
+                if let node_message::Payload::SystemConnected(info) = payload {
+                    self.handle_system_connected_update(node_id, shard_conn_id, local_id, info);
+                    return;
+                }
+
+                // Anything else from a node sending us too many updates is dropped:
+                if self.is_rate_limited(shard_conn_id, local_id) {
+                    return;
+                }
+
+                let mut feed_message_serializer = self.broadcast_serializer();
                 self.node_state
                     .update_node(node_id, payload, &mut feed_message_serializer);
 
@@ -428,6 +1391,9 @@ impl InnerLoop {
             }
             FromShardWebsocket::Disconnected => {
                 self.shard_channels.remove(&shard_conn_id);
+                self.shard_allowed_chains.remove(&shard_conn_id);
+                self.muted_shard_nodes
+                    .retain(|&(this_shard_conn_id, _)| shard_conn_id != this_shard_conn_id);
 
                 // Find all nodes associated with this shard connection ID:
                 let node_ids_to_remove: Vec<NodeId> = self
@@ -443,46 +1409,400 @@ impl InnerLoop {
         }
     }
 
-    /// Handle messages coming from feeds.
-    fn handle_from_feed(&mut self, feed_conn_id: ConnId, msg: FromFeedWebsocket) {
-        match msg {
-            FromFeedWebsocket::Initialize { channel } => {
-                self.feed_channels.insert(feed_conn_id, channel.clone());
+    /// Add a node to the chain with the given genesis hash (or the chain it's an alias of),
+    /// muting it on the shard if that's not allowed, and telling feeds about it if it was added.
+    /// Every node that a shard adds, or moves to another chain, is checked here.
+    fn add_node(
+        &mut self,
+        shard_conn_id: ConnId,
+        local_id: ShardNodeId,
+        genesis_hash: BlockHash,
+        node: common::node_types::NodeDetails,
+    ) -> Option<NodeId> {
+        // Don't hold on to nodes whose details are unreasonably large:
+        if self.is_oversized_shard_message(&node) {
+            log::warn!(
+                "Muting node with shard/connectionId of {:?}/{:?}: details are too large",
+                shard_conn_id,
+                local_id
+            );
+            if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                let _ = shard_conn.send(ToShardWebsocket::Mute {
+                    local_id,
+                    reason: MuteReason::MessageTooLarge,
+                });
+            }
+            return None;
+        }
 
-                // Tell the new feed subscription some basic things to get it going:
-                let mut feed_serializer = FeedMessageSerializer::new();
-                feed_serializer.push(feed_message::Version(32));
-                for chain in self.node_state.iter_chains() {
-                    feed_serializer.push(feed_message::AddedChain(
-                        chain.label(),
-                        chain.genesis_hash(),
-                        chain.node_count(),
-                    ));
-                }
+        // Is this shard allowed to send us nodes on this chain?
+        let is_chain_allowed = match self.shard_allowed_chains.get(&shard_conn_id) {
+            Some(allowed_chains) => allowed_chains.contains(&genesis_hash),
+            None => true,
+        };
+        if !is_chain_allowed {
+            if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                let _ = shard_conn.send(ToShardWebsocket::Mute {
+                    local_id,
+                    reason: MuteReason::ChainNotAllowed,
+                });
+            }
+            return None;
+        }
 
-                // Send this to the channel that subscribed:
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = channel.send(ToFeedWebsocket::Bytes(bytes));
+        // One shard reporting an implausible number of nodes is probably broken or
+        // malicious, so don't let it take over:
+        let shard_node_count = self
+            .shard_node_counts
+            .get(&shard_conn_id)
+            .copied()
+            .unwrap_or(0);
+        if shard_node_count >= self.max_nodes_per_shard {
+            log::warn!(
+                "Muting node with shard/connectionId of {:?}/{:?}: shard already has {} nodes",
+                shard_conn_id,
+                local_id,
+                shard_node_count
+            );
+            self.rejected_shard_nodes += 1;
+            if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                let _ = shard_conn.send(ToShardWebsocket::Mute {
+                    local_id,
+                    reason: MuteReason::TooManyShardNodes,
+                });
+            }
+            return None;
+        }
+
+        let genesis_hash = self.canonical_genesis_hash(genesis_hash);
+
+        // Chains which keep appearing and disappearing are denylisted for a while:
+        if let Some(chain_flaps) = &self.chain_flaps {
+            if chain_flaps.is_denylisted(&genesis_hash, Instant::now()) {
+                if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                    let _ = shard_conn.send(ToShardWebsocket::Mute {
+                        local_id,
+                        reason: MuteReason::ChainNotAllowed,
+                    });
                 }
+                return None;
             }
-            FromFeedWebsocket::Ping { value } => {
-                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
-                    Some(chan) => chan,
-                    None => return,
-                };
+        }
 
-                // Pong!
-                let mut feed_serializer = FeedMessageSerializer::new();
-                feed_serializer.push(feed_message::Pong(&value));
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+        // Made up front, since the details of an added node borrow our state:
+        let mut feed_messages_for_chain = self.broadcast_serializer();
+        match self.node_state.add_node(genesis_hash, node) {
+            state::AddNodeResult::ChainOnDenyList | state::AddNodeResult::ChainNotOnAllowList => {
+                if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                    let _ = shard_conn.send(ToShardWebsocket::Mute {
+                        local_id,
+                        reason: MuteReason::ChainNotAllowed,
+                    });
                 }
+                None
             }
-            FromFeedWebsocket::Subscribe { chain } => {
-                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
-                    Some(chan) => chan,
-                    None => return,
-                };
+            state::AddNodeResult::NodeBlocked => {
+                if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                    let _ = shard_conn.send(ToShardWebsocket::Mute {
+                        local_id,
+                        reason: MuteReason::NodeBlocked,
+                    });
+                }
+                None
+            }
+            state::AddNodeResult::NodeIncomplete => {
+                if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                    let _ = shard_conn.send(ToShardWebsocket::Mute {
+                        local_id,
+                        reason: MuteReason::Invalid,
+                    });
+                }
+                None
+            }
+            state::AddNodeResult::ChainOverQuota => {
+                if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                    let _ = shard_conn.send(ToShardWebsocket::Mute {
+                        local_id,
+                        reason: MuteReason::Overquota,
+                    });
+                }
+                None
+            }
+            state::AddNodeResult::NodeAddedToChain(details) => {
+                let node_id = details.id;
+
+                // Record ID <-> (shardId,localId) for future messages:
+                self.node_ids.insert(node_id, (shard_conn_id, local_id));
+                *self.shard_node_counts.entry(shard_conn_id).or_default() += 1;
+                self.node_churn.entry(genesis_hash).or_default().added += 1;
+
+                // Don't hold onto details too long because we want &mut self later:
+                let new_chain_label = details.new_chain_label.to_owned();
+                let chain_node_count = details.chain_node_count;
+                let has_chain_label_changed = details.has_chain_label_changed;
+
+                // Tell chain subscribers about the node we've just added:
+                feed_messages_for_chain.push(feed_message::AddedNode(
+                    node_id.get_chain_node_id().into(),
+                    &details.node,
+                ));
+                if let Some(group) = details.node.group() {
+                    feed_messages_for_chain.push(feed_message::NodeGroup(
+                        node_id.get_chain_node_id().into(),
+                        group,
+                    ));
+                }
+                self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_messages_for_chain);
+                self.update_node_count_thresholds(genesis_hash, chain_node_count);
+
+                // Tell everybody about the new node count and potential rename:
+                let chain_node_count = match self.node_count_source {
+                    NodeCountSource::All => chain_node_count,
+                    _ => self.reported_node_count(&genesis_hash),
+                };
+                let mut feed_messages_for_all = self.broadcast_serializer();
+                if has_chain_label_changed {
+                    feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
+                    self.announced_chains.remove(&genesis_hash);
+                }
+                self.announce_chain(
+                    &mut feed_messages_for_all,
+                    &new_chain_label,
+                    genesis_hash,
+                    chain_node_count,
+                );
+                self.finalize_and_broadcast_to_all_feeds(&genesis_hash, feed_messages_for_all);
+
+                Some(node_id)
+            }
+        }
+    }
+
+    /// Report any node count thresholds that a chain has crossed now that it has this
+    /// many nodes. If events aren't keeping up, they're dropped.
+    fn update_node_count_thresholds(&mut self, genesis_hash: BlockHash, node_count: usize) {
+        let node_count_thresholds = match &mut self.node_count_thresholds {
+            Some(node_count_thresholds) => node_count_thresholds,
+            None => return,
+        };
+        for (threshold, direction) in node_count_thresholds.update(genesis_hash, node_count) {
+            let event = AggregatorEvent::ChainThresholdCrossed {
+                genesis_hash,
+                threshold,
+                direction,
+            };
+            if let Some(tx) = &self.events {
+                if let Err(flume::TrySendError::Full(_)) = tx.try_send(event) {
+                    self.dropped_events += 1;
+                }
+            }
+        }
+    }
+
+    /// The genesis hash of the chain that nodes reporting the given genesis hash are shown on.
+    fn canonical_genesis_hash(&self, genesis_hash: BlockHash) -> BlockHash {
+        self.chain_aliases
+            .get(&genesis_hash)
+            .copied()
+            .unwrap_or(genesis_hash)
+    }
+
+    /// A node added by the given shard has gone away.
+    fn forget_shard_node(&mut self, shard_conn_id: ConnId, local_id: ShardNodeId) {
+        if let Some(count) = self.shard_node_counts.get_mut(&shard_conn_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.shard_node_counts.remove(&shard_conn_id);
+            }
+        }
+        if let Some(node_rate_limits) = &mut self.node_rate_limits {
+            node_rate_limits.forget((shard_conn_id, local_id));
+        }
+    }
+
+    /// Is the content of a message from a shard larger than we allow? Shards send us
+    /// bincode encoded messages, so that's how we measure them. Oversized messages are counted.
+    fn is_oversized_shard_message<T: serde::Serialize>(&mut self, content: &T) -> bool {
+        let max_size = match self.max_shard_message_size {
+            Some(max_size) => max_size,
+            None => return false,
+        };
+        let size = bincode::options()
+            .serialized_size(content)
+            .unwrap_or(u64::MAX);
+        if size <= max_size {
+            return false;
+        }
+        self.oversized_shard_messages += 1;
+        true
+    }
+
+    /// Should we drop an update from this node because it's sending them too quickly?
+    /// Dropped updates are counted.
+    fn is_rate_limited(&mut self, shard_conn_id: ConnId, local_id: ShardNodeId) -> bool {
+        let node_rate_limits = match &mut self.node_rate_limits {
+            Some(node_rate_limits) => node_rate_limits,
+            None => return false,
+        };
+        if node_rate_limits.allow((shard_conn_id, local_id), Instant::now()) {
+            return false;
+        }
+        self.rate_limited_node_messages += 1;
+        true
+    }
+
+    /// Log an error handling a message from a shard, and send it on to anybody
+    /// listening for them. If they aren't keeping up, the error is dropped.
+    fn report_processing_error(
+        &mut self,
+        kind: ProcessingErrorKind,
+        shard_conn_id: ConnId,
+        local_id: ShardNodeId,
+    ) {
+        let error = ProcessingError {
+            kind,
+            shard_conn_id,
+            local_id,
+        };
+        log::error!("{}", error);
+
+        if let Some(tx) = &self.processing_errors {
+            if let Err(flume::TrySendError::Full(_)) = tx.try_send(error) {
+                self.dropped_processing_errors += 1;
+            }
+        }
+    }
+
+    /// A node that we've already added has reported that it's connected to a chain. If this
+    /// differs from the chain that it was added to, follow our [`ChainConflictPolicy`].
+    fn handle_system_connected_update(
+        &mut self,
+        node_id: NodeId,
+        shard_conn_id: ConnId,
+        local_id: ShardNodeId,
+        info: node_message::SystemConnected,
+    ) {
+        let (current_genesis_hash, location) = match self.node_state.get_chain_by_node_id(node_id) {
+            Some(chain) => {
+                let location = chain
+                    .nodes_slice()
+                    .get(usize::from(node_id.get_chain_node_id()))
+                    .and_then(|node| node.as_ref())
+                    .and_then(|node| node.location().cloned());
+                (chain.genesis_hash(), location)
+            }
+            None => {
+                self.report_processing_error(
+                    ProcessingErrorKind::UnknownChain,
+                    shard_conn_id,
+                    local_id,
+                );
+                return;
+            }
+        };
+
+        // Nothing to do if the node is still on the same chain:
+        if current_genesis_hash == self.canonical_genesis_hash(info.genesis_hash) {
+            return;
+        }
+
+        match self.chain_conflict_policy {
+            ChainConflictPolicy::Ignore => {
+                log::warn!(
+                    "Ignoring node {:?} reporting chain {:?}; it was added to chain {:?}",
+                    node_id,
+                    info.genesis_hash,
+                    current_genesis_hash
+                );
+            }
+            ChainConflictPolicy::Reregister => {
+                self.remove_nodes_and_broadcast_result(Some(node_id));
+                let new_node_id =
+                    match self.add_node(shard_conn_id, local_id, info.genesis_hash, info.node) {
+                        Some(node_id) => node_id,
+                        None => {
+                            // The node has been muted, so the shard will still tell us when it
+                            // disconnects:
+                            self.muted_shard_nodes.insert((shard_conn_id, local_id));
+                            return;
+                        }
+                    };
+
+                // The node hasn't moved, so we don't need to locate it again:
+                if let Some(location) = location {
+                    self.handle_from_find_location(new_node_id, Some(Arc::new(location)));
+                }
+            }
+        }
+    }
+
+    /// Handle messages coming from feeds.
+    fn handle_from_feed(&mut self, feed_conn_id: ConnId, msg: FromFeedWebsocket) {
+        match msg {
+            FromFeedWebsocket::Initialize { channel, encoding } => {
+                self.feed_channels.insert(feed_conn_id, channel.clone());
+                if encoding == FeedEncoding::Binary && self.binary_feeds {
+                    self.binary_feed_conn_ids.insert(feed_conn_id);
+                }
+                let encoding = self.feed_encoding(feed_conn_id);
+
+                // Tell the new feed subscription some basic things to get it going:
+                let mut feed_serializer = FeedMessageSerializer::for_encoding(encoding);
+                feed_serializer.push(feed_message::Version(32));
+                for chain in self.node_state.iter_chains() {
+                    let node_count =
+                        self.public_node_count(chain.node_count_from(self.node_count_source));
+                    feed_serializer.push(feed_message::AddedChain(
+                        chain.label(),
+                        chain.genesis_hash(),
+                        node_count,
+                    ));
+                    // The highest node count is only worth mentioning if it's different:
+                    let highest_node_count = self
+                        .announced_chains
+                        .get(&chain.genesis_hash())
+                        .map_or(node_count, |&(_, _, highest)| highest.max(node_count));
+                    if highest_node_count != node_count {
+                        feed_serializer.push(feed_message::ChainNodeCounts(
+                            chain.genesis_hash(),
+                            node_count,
+                            highest_node_count,
+                        ));
+                    }
+                }
+
+                // Send this to the channel that subscribed:
+                self.feed_message_counts.add(feed_serializer.counts());
+                if let Some(messages) = feed_serializer.into_finalized_encodings() {
+                    let _ = channel.send(ToFeedWebsocket::Bytes(messages.encoded_as(encoding)));
+                }
+            }
+            FromFeedWebsocket::Ping { value } => {
+                let encoding = self.feed_encoding(feed_conn_id);
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                // Pong!
+                let mut feed_serializer = FeedMessageSerializer::for_encoding(encoding);
+                feed_serializer.push(feed_message::Pong(&value));
+                self.feed_message_counts.add(feed_serializer.counts());
+                if let Some(messages) = feed_serializer.into_finalized_encodings() {
+                    let _ =
+                        feed_channel.send(ToFeedWebsocket::Bytes(messages.encoded_as(encoding)));
+                }
+            }
+            FromFeedWebsocket::Subscribe { chain } => {
+                let chain = self.canonical_genesis_hash(chain);
+                if !self.feed_wants_chain(feed_conn_id, &chain) {
+                    return;
+                }
+                let encoding = self.feed_encoding(feed_conn_id);
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
 
                 // Unsubscribe from previous chain if subscribed to one:
                 let old_genesis_hash = self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
@@ -499,7 +1819,7 @@ impl InnerLoop {
                 };
 
                 // Send messages to the feed about this subscription:
-                let mut feed_serializer = FeedMessageSerializer::new();
+                let mut feed_serializer = FeedMessageSerializer::for_encoding(encoding);
                 if let Some(old_chain) = old_chain {
                     feed_serializer.push(feed_message::UnsubscribedFrom(old_chain.genesis_hash()));
                 }
@@ -509,49 +1829,116 @@ impl InnerLoop {
                     new_chain.best_block().height,
                     new_chain.timestamp(),
                     new_chain.average_block_time(),
+                    new_chain.best_block().hash,
                 ));
                 feed_serializer.push(feed_message::BestFinalized(
                     new_chain.finalized_block().height,
                     new_chain.finalized_block().hash,
                 ));
                 feed_serializer.push(feed_message::ChainStatsUpdate(new_chain.stats()));
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                feed_serializer.push(feed_message::AverageTimeToFinality(
+                    new_chain.average_time_to_finality(),
+                ));
+                feed_serializer.push(feed_message::ChainDecentralization(
+                    new_chain.decentralization_score(),
+                    new_chain.located_fraction(),
+                ));
+                if let Some((up_to_date, outdated)) = new_chain.version_compliance() {
+                    feed_serializer.push(feed_message::VersionCompliance(
+                        new_chain.genesis_hash(),
+                        up_to_date,
+                        outdated,
+                    ));
+                }
+                if let Some(set_id) = new_chain.authority_set_id() {
+                    feed_serializer.push(feed_message::AuthoritySet(set_id));
+                }
+                let (synced, syncing) = new_chain.sync_breakdown();
+                feed_serializer.push(feed_message::SyncBreakdown(
+                    new_chain.genesis_hash(),
+                    synced,
+                    syncing,
+                ));
+                self.feed_message_counts.add(feed_serializer.counts());
+                if let Some(messages) = feed_serializer.into_finalized_encodings() {
+                    let _ =
+                        feed_channel.send(ToFeedWebsocket::Bytes(messages.encoded_as(encoding)));
                 }
 
                 // If many (eg 10k) nodes are connected, serializing all of their info takes time.
-                // So, parallelise this with Rayon, but we still send out messages for each node in order
-                // (which is helpful for the UI as it tries to maintain a sorted list of nodes). The chunk
-                // size is the max number of node info we fit into 1 message; smaller messages allow the UI
-                // to react a little faster and not have to wait for a larger update to come in. A chunk size
-                // of 64 means each message is ~32k.
+                // So, parallelise this with Rayon, on our own pool so that we don't use more threads
+                // than we've been given. The chunk size is the max number of node info we fit
+                // into 1 message; smaller messages allow the UI to react a little faster and not have to
+                // wait for a larger update to come in. Since the size of node info varies a lot (node
+                // names can be long), a chunk is split into several messages if it would otherwise be
+                // more than `max_feed_message_bytes`. A node which doesn't fit on its own gets its own
+                // message.
+                //
+                // The UI tries to maintain a sorted list of nodes, and relies on the following ordering,
+                // which must survive any changes to how this is parallelised:
+                // - Nodes are sent in order of their ID, both within and across messages.
+                // - A node's AddedNode message comes before any other message about that node.
                 use rayon::prelude::*;
-                let all_feed_messages: Vec<_> = new_chain
-                    .nodes_slice()
-                    .par_iter()
-                    .enumerate()
-                    .chunks(64)
-                    .filter_map(|nodes| {
-                        let mut feed_serializer = FeedMessageSerializer::new();
-                        for (node_id, node) in nodes
-                            .iter()
-                            .filter_map(|&(idx, n)| n.as_ref().map(|n| (idx, n)))
-                        {
-                            feed_serializer.push(feed_message::AddedNode(node_id, node));
-                            feed_serializer.push(feed_message::FinalizedBlock(
-                                node_id,
-                                node.finalized().height,
-                                node.finalized().hash,
-                            ));
-                            if node.stale() {
-                                feed_serializer.push(feed_message::StaleNode(node_id));
+                // Feeds which only want a summary of the chain aren't told about its nodes:
+                let nodes_slice = if self.summary_feed_conn_ids.contains(&feed_conn_id) {
+                    &[]
+                } else {
+                    new_chain.nodes_slice()
+                };
+                let nodes_per_feed_message = self.nodes_per_feed_message;
+                let max_feed_message_bytes = self.max_feed_message_bytes;
+                let all_feed_messages: Vec<_> = self.serialization_pool.install(|| {
+                    nodes_slice
+                        .par_iter()
+                        .enumerate()
+                        .chunks(nodes_per_feed_message)
+                        .flat_map_iter(|nodes| {
+                            let mut feed_serializers = Vec::new();
+                            let mut feed_serializer = FeedMessageSerializer::for_encoding(encoding);
+                            for (node_id, node) in nodes
+                                .iter()
+                                .filter_map(|&(idx, n)| n.as_ref().map(|n| (idx, n)))
+                            {
+                                let mut node_serializer =
+                                    FeedMessageSerializer::for_encoding(encoding);
+                                node_serializer.push(feed_message::AddedNode(node_id, node));
+                                if let Some(group) = node.group() {
+                                    node_serializer.push(feed_message::NodeGroup(node_id, group));
+                                }
+                                node_serializer.push(feed_message::FinalizedBlock(
+                                    node_id,
+                                    node.finalized().height,
+                                    node.finalized().hash,
+                                ));
+                                if node.stale() {
+                                    node_serializer.push(feed_message::StaleNode(node_id));
+                                }
+
+                                // Finalizing a message adds a byte to it:
+                                let finalized_len = feed_serializer.current_byte_len()
+                                    + node_serializer.current_byte_len()
+                                    + 1;
+                                if feed_serializer.current_byte_len() > 0
+                                    && finalized_len > max_feed_message_bytes
+                                {
+                                    feed_serializers.push(std::mem::replace(
+                                        &mut feed_serializer,
+                                        FeedMessageSerializer::for_encoding(encoding),
+                                    ));
+                                }
+                                feed_serializer.extend(node_serializer);
                             }
-                        }
-                        feed_serializer.into_finalized()
-                    })
-                    .collect();
-                for bytes in all_feed_messages {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                            feed_serializers.push(feed_serializer);
+                            feed_serializers
+                        })
+                        .collect()
+                });
+                for feed_serializer in all_feed_messages {
+                    self.feed_message_counts.add(feed_serializer.counts());
+                    if let Some(messages) = feed_serializer.into_finalized_encodings() {
+                        let _ = feed_channel
+                            .send(ToFeedWebsocket::Bytes(messages.encoded_as(encoding)));
+                    }
                 }
 
                 // Actually make a note of the new chain subsciption:
@@ -559,14 +1946,126 @@ impl InnerLoop {
                 self.chain_to_feed_conn_ids
                     .insert(new_genesis_hash, feed_conn_id);
             }
+            FromFeedWebsocket::Unsubscribe { chain } => {
+                let chain = self.canonical_genesis_hash(chain);
+                let encoding = self.feed_encoding(feed_conn_id);
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                // Nothing to do if the feed isn't subscribed to this chain:
+                if self.chain_to_feed_conn_ids.get_key(&feed_conn_id) != Some(&chain) {
+                    return;
+                }
+                self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+
+                let mut feed_serializer = FeedMessageSerializer::for_encoding(encoding);
+                feed_serializer.push(feed_message::UnsubscribedFrom(chain));
+                self.feed_message_counts.add(feed_serializer.counts());
+                if let Some(messages) = feed_serializer.into_finalized_encodings() {
+                    let _ =
+                        feed_channel.send(ToFeedWebsocket::Bytes(messages.encoded_as(encoding)));
+                }
+            }
+            FromFeedWebsocket::FilterChains { chains } => {
+                let chains: HashSet<BlockHash> = chains
+                    .into_iter()
+                    .map(|chain| self.canonical_genesis_hash(chain))
+                    .collect();
+                let encoding = self.feed_encoding(feed_conn_id);
+                if !self.feed_channels.contains_key(&feed_conn_id) {
+                    return;
+                }
+
+                let old_filter = if chains.is_empty() {
+                    self.feed_chain_filters.remove(&feed_conn_id)
+                } else {
+                    self.feed_chain_filters.insert(feed_conn_id, chains)
+                };
+                let new_filter = self.feed_chain_filters.get(&feed_conn_id);
+                let is_wanted = |filter: Option<&HashSet<BlockHash>>, chain: &BlockHash| {
+                    filter.is_none_or(|filter| filter.contains(chain))
+                };
+
+                // Tell the feed about chains it can now see, and forget about the rest:
+                let mut feed_serializer = FeedMessageSerializer::for_encoding(encoding);
+                if let Some(&chain) = self.chain_to_feed_conn_ids.get_key(&feed_conn_id) {
+                    if !is_wanted(new_filter, &chain) {
+                        self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+                        feed_serializer.push(feed_message::UnsubscribedFrom(chain));
+                    }
+                }
+                for chain in self.node_state.iter_chains() {
+                    let genesis_hash = chain.genesis_hash();
+                    match (
+                        is_wanted(old_filter.as_ref(), &genesis_hash),
+                        is_wanted(new_filter, &genesis_hash),
+                    ) {
+                        (true, false) => {
+                            feed_serializer.push(feed_message::RemovedChain(genesis_hash))
+                        }
+                        (false, true) => feed_serializer.push(feed_message::AddedChain(
+                            chain.label(),
+                            genesis_hash,
+                            self.public_node_count(chain.node_count_from(self.node_count_source)),
+                        )),
+                        _ => {}
+                    }
+                }
+
+                self.feed_message_counts.add(feed_serializer.counts());
+                if let (Some(messages), Some(feed_channel)) = (
+                    feed_serializer.into_finalized_encodings(),
+                    self.feed_channels.get(&feed_conn_id),
+                ) {
+                    let _ =
+                        feed_channel.send(ToFeedWebsocket::Bytes(messages.encoded_as(encoding)));
+                }
+            }
+            FromFeedWebsocket::SummaryOnly { enabled } => {
+                if !self.feed_channels.contains_key(&feed_conn_id) {
+                    return;
+                }
+                let changed = if enabled {
+                    self.summary_feed_conn_ids.insert(feed_conn_id)
+                } else {
+                    self.summary_feed_conn_ids.remove(&feed_conn_id)
+                };
+
+                // Subscribe the feed to its chain again, so that it's either told about
+                // every node or stops hearing about them from a clean slate:
+                if let Some(&chain) = self.chain_to_feed_conn_ids.get_key(&feed_conn_id) {
+                    if changed {
+                        self.handle_from_feed(feed_conn_id, FromFeedWebsocket::Subscribe { chain });
+                    }
+                }
+            }
             FromFeedWebsocket::Disconnected => {
                 // The feed has disconnected; clean up references to it:
-                self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
-                self.feed_channels.remove(&feed_conn_id);
+                self.forget_feed(feed_conn_id);
             }
         }
     }
 
+    /// Does the feed with the given connection ID want to hear about the given chain?
+    fn feed_wants_chain(&self, feed_conn_id: ConnId, genesis_hash: &BlockHash) -> bool {
+        self.feed_chain_filters
+            .get(&feed_conn_id)
+            .is_none_or(|filter| filter.contains(genesis_hash))
+    }
+
+    /// Clean up all references to a feed. Once its channel is dropped here, the
+    /// websocket connection for the feed will close after sending what's queued.
+    fn forget_feed(&mut self, feed_conn_id: ConnId) {
+        self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+        self.feed_channels.remove(&feed_conn_id);
+        self.feed_queue_lens.remove(&feed_conn_id);
+        self.binary_feed_conn_ids.remove(&feed_conn_id);
+        self.summary_feed_conn_ids.remove(&feed_conn_id);
+        self.feed_chain_filters.remove(&feed_conn_id);
+    }
+
     /// Remove all of the node IDs provided and broadcast messages to feeds as needed.
     fn remove_nodes_and_broadcast_result(&mut self, node_ids: impl IntoIterator<Item = NodeId>) {
         // Group by chain to simplify the handling of feed messages:
@@ -581,9 +2080,28 @@ impl InnerLoop {
         }
 
         // Remove the nodes for each chain
-        let mut feed_messages_for_all = FeedMessageSerializer::new();
-        for (chain_label, node_ids) in node_ids_per_chain {
-            let mut feed_messages_for_chain = FeedMessageSerializer::new();
+        for (genesis_hash, node_ids) in node_ids_per_chain {
+            let mut feed_messages_for_all = self.broadcast_serializer();
+            let removes_whole_chain = self
+                .node_state
+                .get_chain_by_genesis_hash(&genesis_hash)
+                .is_some_and(|chain| chain.node_count() == node_ids.len());
+
+            // Feeds forget everything about a chain once it's removed, so if every node is
+            // going, just tell them that once rather than announcing each node removal:
+            if removes_whole_chain {
+                let mut discarded_for_chain = FeedMessageSerializer::new();
+                let mut discarded_for_all = FeedMessageSerializer::new();
+                for node_id in node_ids {
+                    self.remove_node(node_id, &mut discarded_for_chain, &mut discarded_for_all);
+                }
+                feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
+                self.announced_chains.remove(&genesis_hash);
+                self.finalize_and_broadcast_to_all_feeds(&genesis_hash, feed_messages_for_all);
+                continue;
+            }
+
+            let mut feed_messages_for_chain = self.broadcast_serializer();
             for node_id in node_ids {
                 self.remove_node(
                     node_id,
@@ -591,9 +2109,9 @@ impl InnerLoop {
                     &mut feed_messages_for_all,
                 );
             }
-            self.finalize_and_broadcast_to_chain_feeds(&chain_label, feed_messages_for_chain);
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_messages_for_chain);
+            self.finalize_and_broadcast_to_all_feeds(&genesis_hash, feed_messages_for_all);
         }
-        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
     }
 
     /// Remove a single node by its ID, pushing any messages we'd want to send
@@ -606,7 +2124,9 @@ impl InnerLoop {
         feed_for_all: &mut FeedMessageSerializer,
     ) {
         // Remove our top level association (this may already have been done).
-        self.node_ids.remove_by_left(&node_id);
+        if let Some((_, (shard_conn_id, local_id))) = self.node_ids.remove_by_left(&node_id) {
+            self.forget_shard_node(shard_conn_id, local_id);
+        }
 
         let removed_details = match self.node_state.remove_node(node_id) {
             Some(remove_details) => remove_details,
@@ -615,21 +2135,46 @@ impl InnerLoop {
                 return;
             }
         };
+        self.node_churn
+            .entry(removed_details.chain_genesis_hash)
+            .or_default()
+            .removed += 1;
+        self.update_node_count_thresholds(
+            removed_details.chain_genesis_hash,
+            removed_details.chain_node_count,
+        );
+
+        if removed_details.chain_node_count == 0 {
+            let genesis_hash = removed_details.chain_genesis_hash;
+            if let Some(chain_flaps) = &mut self.chain_flaps {
+                if chain_flaps.chain_removed(genesis_hash, Instant::now()) {
+                    log::warn!(
+                        "Denylisting chain {:?} for a while: it keeps appearing and disappearing",
+                        genesis_hash
+                    );
+                    self.save_denylist();
+                }
+            }
+        }
 
         // The chain has been removed (no nodes left in it, or it was renamed):
         if removed_details.chain_node_count == 0 || removed_details.has_chain_label_changed {
             feed_for_all.push(feed_message::RemovedChain(
                 removed_details.chain_genesis_hash,
             ));
+            self.announced_chains
+                .remove(&removed_details.chain_genesis_hash);
         }
 
         // If the chain still exists, tell everybody about the new label or updated node count:
         if removed_details.chain_node_count != 0 {
-            feed_for_all.push(feed_message::AddedChain(
+            let node_count = self.reported_node_count(&removed_details.chain_genesis_hash);
+            self.announce_chain(
+                feed_for_all,
                 &removed_details.new_chain_label,
                 removed_details.chain_genesis_hash,
-                removed_details.chain_node_count,
-            ));
+                node_count,
+            );
         }
 
         // Assuming the chain hasn't gone away, tell chain subscribers about the node removal
@@ -640,39 +2185,3017 @@ impl InnerLoop {
         }
     }
 
+    /// The node count that we tell feeds about for a chain, which depends on
+    /// our configured [`NodeCountSource`].
+    fn reported_node_count(&self, genesis_hash: &BlockHash) -> usize {
+        self.node_state
+            .get_chain_by_genesis_hash(genesis_hash)
+            .map(|chain| chain.node_count_from(self.node_count_source))
+            .unwrap_or(0)
+    }
+
+    /// Tell every feed about the label and node counts of a chain, unless that's what they
+    /// were last told. Feeds that already know the label are only sent the node counts.
+    /// Feeds that connect later are told about every chain anyway.
+    fn announce_chain(
+        &mut self,
+        feed_for_all: &mut FeedMessageSerializer,
+        label: &str,
+        genesis_hash: BlockHash,
+        node_count: usize,
+    ) {
+        let node_count = self.public_node_count(node_count);
+        // The highest node count we've told feeds about, which follows the same rules:
+        let highest_node_count = self
+            .announced_chains
+            .get(&genesis_hash)
+            .map_or(node_count, |&(_, _, highest)| highest.max(node_count));
+        let announced = (Box::from(label), node_count, highest_node_count);
+        match self.announced_chains.get(&genesis_hash) {
+            Some(last_announced) if *last_announced == announced => return,
+            Some((last_label, _, _)) if **last_label == *label => {
+                feed_for_all.push(feed_message::ChainNodeCounts(
+                    genesis_hash,
+                    node_count,
+                    highest_node_count,
+                ));
+            }
+            _ => {
+                feed_for_all.push(feed_message::AddedChain(label, genesis_hash, node_count));
+            }
+        }
+        self.announced_chains.insert(genesis_hash, announced);
+    }
+
+    /// The node count to tell feeds about, given the actual node count of a chain. Chains
+    /// with any nodes at all are never shown as having none.
+    fn public_node_count(&self, node_count: usize) -> usize {
+        let granularity = self.node_count_granularity;
+        if node_count == 0 {
+            return 0;
+        }
+        ((node_count + granularity / 2) / granularity * granularity).max(granularity)
+    }
+
+    /// How the feed with the given connection ID wants messages to be encoded.
+    fn feed_encoding(&self, feed_conn_id: ConnId) -> FeedEncoding {
+        if self.binary_feed_conn_ids.contains(&feed_conn_id) {
+            FeedEncoding::Binary
+        } else {
+            FeedEncoding::Json
+        }
+    }
+
+    /// A [`FeedMessageSerializer`] for messages which will be broadcast to feeds. This
+    /// only bothers with the binary encoding if some feed wants it.
+    fn broadcast_serializer(&self) -> FeedMessageSerializer {
+        if self.binary_feed_conn_ids.is_empty() {
+            FeedMessageSerializer::new()
+        } else {
+            FeedMessageSerializer::with_binary()
+        }
+    }
+
     /// Finalize a [`FeedMessageSerializer`] and broadcast the result to feeds for the chain.
     fn finalize_and_broadcast_to_chain_feeds(
         &mut self,
         genesis_hash: &BlockHash,
         serializer: FeedMessageSerializer,
     ) {
-        if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_chain_feeds(genesis_hash, ToFeedWebsocket::Bytes(bytes));
+        self.feed_message_counts.add(serializer.counts());
+        if let Some(messages) = serializer.into_finalized_encodings() {
+            self.broadcast_to_chain_feeds(genesis_hash, &messages);
         }
     }
 
-    /// Send a message to all chain feeds.
-    fn broadcast_to_chain_feeds(&mut self, genesis_hash: &BlockHash, message: ToFeedWebsocket) {
+    /// Send messages to all chain feeds. Feeds which already have `max_feed_queue_len`
+    /// messages queued up are disconnected instead; we'd rather lose a slow feed than let
+    /// its queue grow forever or give it a partial view of the chain's nodes.
+    fn broadcast_to_chain_feeds(
+        &mut self,
+        genesis_hash: &BlockHash,
+        messages: &FinalizedFeedMessages,
+    ) {
+        let mut slow_feeds = vec![];
         if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
             for &feed_id in feeds {
-                if let Some(chan) = self.feed_channels.get_mut(&feed_id) {
-                    let _ = chan.send(message.clone());
+                if let Some(chan) = self.feed_channels.get(&feed_id) {
+                    if self.max_feed_queue_len.is_some_and(|max| chan.len() >= max) {
+                        slow_feeds.push((feed_id, chan.len()));
+                        continue;
+                    }
+                    let messages = if self.summary_feed_conn_ids.contains(&feed_id) {
+                        match messages.summary() {
+                            Some(summary) => summary,
+                            None => continue,
+                        }
+                    } else {
+                        messages
+                    };
+                    let bytes = messages.encoded_as(self.feed_encoding(feed_id));
+                    let _ = chan.send(ToFeedWebsocket::Bytes(bytes));
                 }
             }
         }
+        for (feed_id, queue_len) in slow_feeds {
+            log::warn!(
+                "Disconnecting feed {:?} subscribed to chain {}: it has {} messages waiting to be sent",
+                feed_id,
+                genesis_hash,
+                queue_len
+            );
+            self.forget_feed(feed_id);
+            self.disconnected_slow_feeds += 1;
+        }
+    }
+
+    /// Finalize a [`FeedMessageSerializer`] holding messages about the given chain, and
+    /// broadcast the result to all feeds that want to hear about it.
+    fn finalize_and_broadcast_to_all_feeds(
+        &mut self,
+        genesis_hash: &BlockHash,
+        serializer: FeedMessageSerializer,
+    ) {
+        self.feed_message_counts.add(serializer.counts());
+        if let Some(messages) = serializer.into_finalized_encodings() {
+            self.broadcast_to_all_feeds(genesis_hash, &messages);
+        }
+    }
+
+    /// Send messages about a chain to every feed that wants to hear about it. These tell feeds
+    /// which chains exist, so unlike node updates they're sent regardless of how many messages
+    /// a feed has queued up.
+    fn broadcast_to_all_feeds(&self, genesis_hash: &BlockHash, messages: &FinalizedFeedMessages) {
+        for (&feed_id, chan) in &self.feed_channels {
+            if !self.feed_wants_chain(feed_id, genesis_hash) {
+                continue;
+            }
+            let bytes = messages.encoded_as(self.feed_encoding(feed_id));
+            let _ = chan.send(ToFeedWebsocket::Bytes(bytes));
+        }
     }
+}
 
-    /// Finalize a [`FeedMessageSerializer`] and broadcast the result to all feeds
-    fn finalize_and_broadcast_to_all_feeds(&mut self, serializer: FeedMessageSerializer) {
-        if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_all_feeds(ToFeedWebsocket::Bytes(bytes));
+#[cfg(test)]
+mod test {
+    use super::super::aggregator::Aggregator;
+    use super::*;
+    use crate::aggregator::{ChainFlapOpts, NodeCountThresholdOpts};
+    use crate::state::{IncompleteNodePolicy, QuotaCountSource, RequiredNodeField};
+    use common::node_types::{Block, NetworkId, NodeDetails, NodeLocation};
+    use futures::{SinkExt, StreamExt};
+
+    fn opts() -> AggregatorOpts {
+        AggregatorOpts {
+            denylist: vec![],
+            allowlist: vec![],
+            max_queue_len: 10_000,
+            max_third_party_nodes: 1000,
+            quota_count_source: QuotaCountSource::All,
+            quota_exempt_chains: vec![],
+            max_nodes_per_chain: None,
+            skip_private_ip_location: false,
+            chain_conflict_policy: ChainConflictPolicy::Reregister,
+            feed_lag_threshold: 1000,
+            max_feed_queue_len: None,
+            node_count_source: NodeCountSource::All,
+            node_count_granularity: 1,
+            quota_warmup: Duration::ZERO,
+            max_third_party_nodes_during_warmup: 1000,
+            node_group_pattern: None,
+            node_blocklist: vec![],
+            location_overrides: vec![],
+            location_cache: None,
+            stale_node_timeout: None,
+            chain_label_overrides: HashMap::new(),
+            recommended_versions: vec![],
+            required_node_fields: vec![],
+            incomplete_node_policy: IncompleteNodePolicy::Reject,
+            stale_threshold: Duration::from_secs(120),
+            chain_stale_thresholds: vec![],
+            stale_block_margin: None,
+            block_import_drop_margin: None,
+            block_propagation_messages: false,
+            sticky_node_ids: false,
+            keep_raw_node_payloads: false,
+            serialization_pool: serialization_pool(2),
+            nodes_per_feed_message: 64,
+            max_feed_message_bytes: 32 * 1024,
+            processing_errors: None,
+            max_shard_message_size: None,
+            max_nodes_per_shard: 10_000,
+            max_node_messages_per_second: None,
+            binary_feeds: true,
+            min_location_change_km: None,
+            chain_flaps: None,
+            denylist_path: None,
+            chain_aliases: HashMap::new(),
+            node_count_thresholds: None,
+            events: None,
         }
     }
 
-    /// Send a message to everybody.
-    fn broadcast_to_all_feeds(&mut self, message: ToFeedWebsocket) {
-        for chan in self.feed_channels.values_mut() {
-            let _ = chan.send(message.clone());
+    fn serialization_pool(num_threads: usize) -> Arc<rayon::ThreadPool> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
+        Arc::new(pool)
+    }
+
+    fn node(name: &str, chain: &str) -> NodeDetails {
+        NodeDetails {
+            chain: chain.into(),
+            name: name.into(),
+            implementation: "Bar".into(),
+            target_arch: Some("x86_64".into()),
+            target_os: Some("linux".into()),
+            target_env: Some("env".into()),
+            version: "0.1".into(),
+            validator: None,
+            network_id: NetworkId::new(),
+            startup_time: None,
+            sysinfo: None,
         }
     }
+
+    fn add_node(
+        inner: &mut InnerLoop,
+        shard_conn_id: u64,
+        local_id: usize,
+        ip: &str,
+        genesis: u64,
+    ) {
+        add_node_on_chain(inner, shard_conn_id, local_id, ip, genesis, "Chain One");
+    }
+
+    fn add_node_on_chain(
+        inner: &mut InnerLoop,
+        shard_conn_id: u64,
+        local_id: usize,
+        ip: &str,
+        genesis: u64,
+        chain: &str,
+    ) {
+        inner.handle_from_shard(
+            shard_conn_id.into(),
+            FromShardWebsocket::Add {
+                local_id: local_id.into(),
+                ip: ip.parse().unwrap(),
+                node: node("A", chain),
+                genesis_hash: BlockHash::from_low_u64_be(genesis),
+            },
+        );
+    }
+
+    /// The chain and node count in an AddedChain or ChainNodeCounts message.
+    fn announced_node_count(msg: &[serde_json::Value]) -> Option<(BlockHash, u64)> {
+        let (hash, count) = match msg[0].as_u64()? {
+            11 => (&msg[1][1], &msg[1][2]),
+            30 => (&msg[1][0], &msg[1][1]),
+            _ => return None,
+        };
+        Some((serde_json::from_value(hash.clone()).ok()?, count.as_u64()?))
+    }
+
+    fn node_id(inner: &InnerLoop, shard_conn_id: u64, local_id: usize) -> NodeId {
+        *inner
+            .node_ids
+            .get_by_right(&(shard_conn_id.into(), local_id.into()))
+            .expect("node should exist")
+    }
+
+    #[test]
+    fn private_ips_are_not_sent_to_locator() {
+        let (tx_to_locator, rx_from_inner) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                skip_private_ip_location: true,
+                ..opts()
+            },
+        );
+
+        add_node(&mut inner, 1, 1, "10.0.0.1", 1);
+        add_node(&mut inner, 1, 2, "fd00::1", 1);
+        add_node(&mut inner, 1, 3, "8.8.8.8", 1);
+
+        // Only the public address should be handed to the locator:
+        let located: Vec<_> = rx_from_inner.drain().map(|(_, ip)| ip).collect();
+        assert_eq!(located, vec![Ipv4Addr::new(8, 8, 8, 8)]);
+
+        // The nodes that weren't located say why when inspected:
+        let (tx, rx) = flume::unbounded();
+        inner.handle_get_chain_nodes(BlockHash::from_low_u64_be(1), false, tx);
+        let statuses: Vec<_> = rx
+            .recv()
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|node| node.location_status)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                find_location::LocationStatus::Private,
+                find_location::LocationStatus::Private,
+                find_location::LocationStatus::Unknown
+            ]
+        );
+    }
+
+    #[test]
+    fn replacing_denylist_removes_exactly_newly_denied_chains() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+
+        let (tx_to_shard, rx_from_inner) = flume::unbounded();
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            },
+        );
+
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 2, "Chain Two");
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 2, "Chain Two");
+        add_node_on_chain(&mut inner, 1, 4, "8.8.8.8", 3, "Chain Three");
+
+        let (tx, rx) = flume::unbounded();
+        inner.handle_replace_denylist(vec!["Chain Two".into(), "Unknown".into()], tx);
+
+        // Only "Chain Two" was removed:
+        assert_eq!(rx.recv().unwrap(), vec![BlockHash::from_low_u64_be(2)]);
+        let mut chains: Vec<_> = inner
+            .node_state
+            .iter_chains()
+            .map(|c| c.label().to_owned())
+            .collect();
+        chains.sort();
+        assert_eq!(chains, vec!["Chain One", "Chain Three"]);
+        assert_eq!(inner.node_ids.len(), 2);
+
+        // Both nodes on it were muted:
+        let muted: Vec<usize> = rx_from_inner
+            .drain()
+            .map(|msg| match msg {
+                ToShardWebsocket::Mute { local_id, .. } => local_id.into(),
+            })
+            .collect();
+        assert_eq!(muted.len(), 2);
+        assert!(muted.contains(&2) && muted.contains(&3));
+    }
+
+    fn switch_chain(inner: &mut InnerLoop, shard_conn_id: u64, local_id: usize, genesis: u64) {
+        inner.handle_from_shard(
+            shard_conn_id.into(),
+            FromShardWebsocket::Update {
+                local_id: local_id.into(),
+                payload: node_message::Payload::SystemConnected(node_message::SystemConnected {
+                    genesis_hash: BlockHash::from_low_u64_be(genesis),
+                    node: node("A", "Chain Two"),
+                }),
+            },
+        );
+    }
+
+    fn node_counts(inner: &InnerLoop) -> Vec<(String, usize)> {
+        let mut counts: Vec<_> = inner
+            .node_state
+            .iter_chains()
+            .map(|c| (c.label().to_owned(), c.node_count()))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    #[test]
+    fn node_switching_chains_is_ignored_if_configured() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                chain_conflict_policy: ChainConflictPolicy::Ignore,
+                ..opts()
+            },
+        );
+
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+        add_node(&mut inner, 1, 2, "8.8.8.8", 1);
+        let old_node_id = node_id(&inner, 1, 1);
+
+        switch_chain(&mut inner, 1, 1, 2);
+
+        assert_eq!(node_counts(&inner), vec![("Chain One".to_owned(), 2)]);
+        assert_eq!(node_id(&inner, 1, 1), old_node_id);
+    }
+
+    #[test]
+    fn node_switching_chains_is_reregistered_if_configured() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+        add_node(&mut inner, 1, 2, "8.8.8.8", 1);
+
+        switch_chain(&mut inner, 1, 1, 2);
+
+        assert_eq!(
+            node_counts(&inner),
+            vec![("Chain One".to_owned(), 1), ("Chain Two".to_owned(), 1)]
+        );
+        assert_eq!(inner.node_ids.len(), 2);
+        let chain = inner
+            .node_state
+            .get_chain_by_node_id(node_id(&inner, 1, 1))
+            .unwrap();
+        assert_eq!(chain.genesis_hash(), BlockHash::from_low_u64_be(2));
+
+        // Reporting the same chain again changes nothing:
+        switch_chain(&mut inner, 1, 1, 2);
+        assert_eq!(
+            node_counts(&inner),
+            vec![("Chain One".to_owned(), 1), ("Chain Two".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn slow_feeds_are_classified_as_lagging() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                feed_lag_threshold: 3,
+                ..opts()
+            },
+        );
+
+        // One feed which never reads its messages, and one which keeps up:
+        let (tx_to_slow_feed, rx_slow_feed) = flume::unbounded();
+        let (tx_to_fast_feed, rx_fast_feed) = flume::unbounded();
+        for (feed_conn_id, channel) in [(1, tx_to_slow_feed), (2, tx_to_fast_feed)] {
+            inner.handle_from_feed(
+                feed_conn_id.into(),
+                FromFeedWebsocket::Initialize {
+                    channel,
+                    encoding: FeedEncoding::Json,
+                },
+            );
+        }
+
+        let gather_metrics = |inner: &mut InnerLoop| {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_gather_metrics(tx, 0, 0, 0);
+            rx.recv().unwrap()
+        };
+
+        let mut lagging = vec![];
+        for n in 0..4 {
+            add_node(&mut inner, 1, n, "8.8.8.8", 1);
+            rx_fast_feed.drain().for_each(drop);
+            let metrics = gather_metrics(&mut inner);
+            lagging.push((metrics.keeping_up_feeds, metrics.lagging_feeds));
+        }
+
+        // The slow feed is only lagging once its queue is over the threshold
+        // and we've seen it grow:
+        assert_eq!(lagging, vec![(2, 0), (2, 0), (1, 1), (1, 1)]);
+
+        // Once the slow feed catches up, it's no longer lagging:
+        rx_slow_feed.drain().for_each(drop);
+        let metrics = gather_metrics(&mut inner);
+        assert_eq!((metrics.keeping_up_feeds, metrics.lagging_feeds), (2, 0));
+    }
+
+    #[test]
+    fn stalled_feeds_are_disconnected() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                max_feed_queue_len: Some(10),
+                ..opts()
+            },
+        );
+        add_node(&mut inner, 1, 0, "8.8.8.8", 1);
+
+        // One feed which never reads its messages, and one which keeps up:
+        let (tx_to_slow_feed, rx_slow_feed) = flume::unbounded();
+        let (tx_to_fast_feed, rx_fast_feed) = flume::unbounded();
+        for (feed_conn_id, channel) in [(1, tx_to_slow_feed), (2, tx_to_fast_feed)] {
+            inner.handle_from_feed(
+                feed_conn_id.into(),
+                FromFeedWebsocket::Initialize {
+                    channel,
+                    encoding: FeedEncoding::Json,
+                },
+            );
+            inner.handle_from_feed(
+                feed_conn_id.into(),
+                FromFeedWebsocket::Subscribe {
+                    chain: BlockHash::from_low_u64_be(1),
+                },
+            );
+        }
+
+        // Fill up the slow feed's queue with node updates:
+        let mut local_id = 1;
+        while rx_slow_feed.len() < 10 {
+            add_node(&mut inner, 1, local_id, "8.8.8.8", 1);
+            rx_fast_feed.drain().for_each(drop);
+            local_id += 1;
+        }
+        assert!(inner.feed_channels.contains_key(&1.into()));
+        let full_queue_len = rx_slow_feed.len();
+
+        // Feeds with a full queue are still told about new chains:
+        add_node(&mut inner, 2, 0, "8.8.8.8", 2);
+        rx_fast_feed.drain().for_each(drop);
+        assert_eq!(rx_slow_feed.len(), full_queue_len + 1);
+        assert!(inner.feed_channels.contains_key(&1.into()));
+
+        // But rather than queueing more node updates, the slow feed is disconnected:
+        add_node(&mut inner, 1, local_id, "8.8.8.8", 1);
+        assert!(!inner.feed_channels.contains_key(&1.into()));
+        assert_eq!(inner.chain_to_feed_conn_ids.get_key(&1.into()), None);
+        assert_eq!(rx_slow_feed.drain().count(), full_queue_len + 1);
+        assert!(rx_slow_feed.is_disconnected());
+
+        // The feed which kept up is unaffected:
+        assert!(rx_fast_feed.drain().count() > 0);
+        assert!(!rx_fast_feed.is_disconnected());
+
+        let (tx, rx) = flume::unbounded();
+        inner.handle_gather_metrics(tx, 0, 0, 0);
+        assert_eq!(rx.recv().unwrap().disconnected_slow_feeds, 1);
+    }
+
+    #[test]
+    fn reported_node_count_follows_configured_source() {
+        let counts: Vec<u64> = [
+            NodeCountSource::All,
+            NodeCountSource::Validators,
+            NodeCountSource::Located,
+        ]
+        .into_iter()
+        .map(|node_count_source| {
+            let (tx_to_locator, _rx) = flume::unbounded();
+            let mut inner = InnerLoop::new(
+                tx_to_locator,
+                AggregatorOpts {
+                    node_count_source,
+                    ..opts()
+                },
+            );
+
+            // Two validators and three located nodes, out of five:
+            let nodes = [
+                (true, true),
+                (true, false),
+                (false, false),
+                (false, true),
+                (false, true),
+            ];
+            for (local_id, &(is_validator, is_located)) in nodes.iter().enumerate() {
+                let mut details = node("A", "Chain One");
+                if is_validator {
+                    details.validator = Some("validator".into());
+                }
+                inner.handle_from_shard(
+                    1.into(),
+                    FromShardWebsocket::Add {
+                        local_id: local_id.into(),
+                        ip: "8.8.8.8".parse().unwrap(),
+                        node: details,
+                        genesis_hash: BlockHash::from_low_u64_be(1),
+                    },
+                );
+                if is_located {
+                    let location = common::node_types::NodeLocation {
+                        latitude: 1.0,
+                        longitude: 2.0,
+                        city: "City".into(),
+                    };
+                    let node_id = node_id(&inner, 1, local_id);
+                    inner.handle_from_find_location(node_id, Some(Arc::new(location)));
+                }
+            }
+
+            // The quota still counts every node:
+            let chain = inner
+                .node_state
+                .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(1))
+                .unwrap();
+            assert_eq!(chain.node_count(), 5);
+
+            // A new feed is told about the configured count:
+            let (tx_to_feed, rx_from_inner) = flume::unbounded();
+            inner.handle_from_feed(
+                1.into(),
+                FromFeedWebsocket::Initialize {
+                    channel: tx_to_feed,
+                    encoding: FeedEncoding::Json,
+                },
+            );
+            let ToFeedWebsocket::Bytes(bytes) = rx_from_inner.recv().unwrap();
+            let msgs: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(msgs[2], 11, "expected an AddedChain message");
+            msgs[3][2].as_u64().unwrap()
+        })
+        .collect();
+
+        assert_eq!(counts, vec![5, 2, 3]);
+    }
+
+    #[test]
+    fn node_counts_told_to_feeds_can_be_rounded() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                node_count_granularity: 10,
+                max_third_party_nodes: 14,
+                max_third_party_nodes_during_warmup: 14,
+                ..opts()
+            },
+        );
+        let (tx_to_shard, rx_from_inner_to_shard) = flume::unbounded();
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            },
+        );
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+
+        let announced_node_counts = |rx: &flume::Receiver<ToFeedWebsocket>| -> Vec<u64> {
+            rx.drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                    serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+                })
+                .collect::<Vec<_>>()
+                .chunks(2)
+                .filter_map(announced_node_count)
+                .map(|(_, node_count)| node_count)
+                .collect()
+        };
+
+        // A chain with fewer nodes than the granularity isn't shown as having none:
+        add_node(&mut inner, 1, 0, "8.8.8.8", 1);
+        assert_eq!(announced_node_counts(&rx_from_inner), vec![10]);
+
+        // Feeds are only told when the rounded node count changes:
+        for local_id in 1..16 {
+            add_node(&mut inner, 1, local_id, "8.8.8.8", 1);
+        }
+        assert!(announced_node_counts(&rx_from_inner).is_empty());
+
+        // But the quota sees the exact node count, muting the last two nodes:
+        let muted = rx_from_inner_to_shard
+            .drain()
+            .filter(|msg| {
+                matches!(
+                    msg,
+                    ToShardWebsocket::Mute {
+                        reason: MuteReason::Overquota,
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(muted, 2);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        assert_eq!(inner.reported_node_count(&genesis_hash), 14);
+        let chain = inner.chain_info(genesis_hash).unwrap();
+        assert_eq!((chain.node_count, chain.highest_node_count), (14, 14));
+
+        // New feeds are told about the rounded node count too:
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            2.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        assert_eq!(announced_node_counts(&rx_from_inner), vec![10]);
+    }
+
+    #[test]
+    fn chains_crossing_node_count_thresholds_are_reported() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let (tx_events, rx_events) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                node_count_thresholds: Some(NodeCountThresholdOpts {
+                    thresholds: vec![2],
+                    chain_thresholds: HashMap::new(),
+                    hysteresis: 0,
+                }),
+                events: Some(tx_events),
+                ..opts()
+            },
+        );
+        let crossed = |direction| AggregatorEvent::ChainThresholdCrossed {
+            genesis_hash: BlockHash::from_low_u64_be(1),
+            threshold: 2,
+            direction,
+        };
+
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+        assert!(rx_events.is_empty());
+        add_node(&mut inner, 1, 2, "8.8.8.8", 1);
+        assert_eq!(
+            rx_events.drain().collect::<Vec<_>>(),
+            vec![crossed(ThresholdDirection::Up)]
+        );
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Remove { local_id: 1.into() });
+        assert_eq!(
+            rx_events.drain().collect::<Vec<_>>(),
+            vec![crossed(ThresholdDirection::Down)]
+        );
+    }
+
+    #[test]
+    fn aliased_chains_are_shown_as_one() {
+        let canonical = BlockHash::from_low_u64_be(1);
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                chain_aliases: [(BlockHash::from_low_u64_be(2), canonical)]
+                    .into_iter()
+                    .collect(),
+                ..opts()
+            },
+        );
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        rx_from_inner.drain().for_each(drop);
+
+        // Collect the chains and node counts announced to the feed:
+        let added_chains = || -> Vec<(BlockHash, u64)> {
+            rx_from_inner
+                .drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                    let msgs: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+                    msgs.chunks(2)
+                        .filter_map(announced_node_count)
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        };
+
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+        add_node(&mut inner, 1, 2, "8.8.8.8", 2);
+        assert_eq!(added_chains(), vec![(canonical, 1), (canonical, 2)]);
+        assert_eq!(inner.node_state.iter_chains().count(), 1);
+        assert_eq!(
+            inner
+                .node_state
+                .get_chain_by_genesis_hash(&canonical)
+                .unwrap()
+                .node_count(),
+            2
+        );
+
+        // Nodes reporting the alias don't count as having switched chains:
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Update {
+                local_id: 1.into(),
+                payload: node_message::Payload::SystemConnected(node_message::SystemConnected {
+                    genesis_hash: BlockHash::from_low_u64_be(2),
+                    node: node("A", "Chain One"),
+                }),
+            },
+        );
+        assert_eq!(added_chains(), vec![]);
+
+        // Removing the node on the aliased chain is counted against the canonical one:
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Remove { local_id: 2.into() });
+        assert_eq!(added_chains(), vec![(canonical, 1)]);
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Disconnected);
+        assert!(inner
+            .node_state
+            .get_chain_by_genesis_hash(&canonical)
+            .is_none());
+    }
+
+    #[test]
+    fn aliased_chains_can_be_looked_up_and_unsubscribed_from() {
+        let canonical = BlockHash::from_low_u64_be(1);
+        let alias = BlockHash::from_low_u64_be(2);
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                chain_aliases: [(alias, canonical)].into_iter().collect(),
+                ..opts()
+            },
+        );
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+
+        // Admin queries by the alias find the canonical chain:
+        assert_eq!(inner.chain_info(alias).unwrap().genesis_hash, canonical);
+        let (tx, rx) = flume::unbounded();
+        inner.handle_get_chain_nodes(alias, false, tx);
+        assert_eq!(rx.recv().unwrap().unwrap().len(), 1);
+
+        // Feeds can subscribe and unsubscribe using the alias:
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        inner.handle_from_feed(1.into(), FromFeedWebsocket::Subscribe { chain: alias });
+        assert!(inner.chain_to_feed_conn_ids.get_key(&1.into()).is_some());
+        rx_from_inner.drain().for_each(drop);
+
+        inner.handle_from_feed(1.into(), FromFeedWebsocket::Unsubscribe { chain: alias });
+        assert!(inner.chain_to_feed_conn_ids.get_key(&1.into()).is_none());
+        let msgs: Vec<serde_json::Value> = rx_from_inner
+            .drain()
+            .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+            })
+            .collect();
+        assert!(msgs.chunks(2).any(|msg| msg[0] == 14));
+    }
+
+    #[test]
+    fn chain_labels_can_be_overridden() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                chain_label_overrides: [(genesis_hash, "Bar".to_owned())].into_iter().collect(),
+                ..opts()
+            },
+        );
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        rx_from_inner.drain().for_each(drop);
+
+        let feed_messages = || -> Vec<serde_json::Value> {
+            rx_from_inner
+                .drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                    serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+                })
+                .collect()
+        };
+
+        // The chain is announced with the overridden label:
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "foo");
+        let msgs = feed_messages();
+        assert_eq!(msgs[0], 11, "expected an AddedChain message");
+        assert_eq!(msgs[1][0], "Bar");
+
+        // Most nodes reporting a different label doesn't rename the chain:
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 1, "baz");
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 1, "baz");
+        let msgs = feed_messages();
+        assert!(!msgs.chunks(2).any(|msg| msg[0] == 12), "no RemovedChain");
+        assert!(!msgs.chunks(2).any(|msg| msg[0] == 11), "no AddedChain");
+        let node_counts: Vec<_> = msgs.chunks(2).filter_map(announced_node_count).collect();
+        assert_eq!(node_counts, vec![(genesis_hash, 2), (genesis_hash, 3)]);
+        assert_eq!(
+            inner
+                .node_state
+                .get_chain_by_genesis_hash(&genesis_hash)
+                .unwrap()
+                .label(),
+            "Bar"
+        );
+    }
+
+    #[test]
+    fn growing_chains_are_announced_with_lighter_messages() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        rx_from_inner.drain().for_each(drop);
+
+        let feed_messages = |rx: &flume::Receiver<ToFeedWebsocket>| -> Vec<serde_json::Value> {
+            rx.drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                    serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+                })
+                .collect()
+        };
+
+        // A new chain is announced in full:
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        let msgs = feed_messages(&rx_from_inner);
+        assert_eq!(msgs[msgs.len() - 2], 11, "expected an AddedChain message");
+
+        // As it grows and shrinks, feeds are only told about the node counts:
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 1, "Chain One");
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Remove { local_id: 3.into() });
+        let msgs = feed_messages(&rx_from_inner);
+        assert_eq!(
+            msgs,
+            vec![
+                serde_json::json!(30),
+                serde_json::json!([genesis_hash, 2, 2]),
+                serde_json::json!(30),
+                serde_json::json!([genesis_hash, 3, 3]),
+                serde_json::json!(30),
+                serde_json::json!([genesis_hash, 2, 3]),
+            ]
+        );
+
+        // New feeds are told about the highest node count too:
+        let (tx_to_feed, rx_new_feed) = flume::unbounded();
+        inner.handle_from_feed(
+            2.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        let msgs = feed_messages(&rx_new_feed);
+        assert_eq!(
+            &msgs[2..],
+            &[
+                serde_json::json!(11),
+                serde_json::json!(["Chain One", genesis_hash, 2]),
+                serde_json::json!(30),
+                serde_json::json!([genesis_hash, 2, 3]),
+            ]
+        );
+
+        // But the chain is announced in full again once most nodes rename it:
+        for local_id in 4..7 {
+            add_node_on_chain(&mut inner, 1, local_id, "8.8.8.8", 1, "Chain Two");
+        }
+        let actions: Vec<_> = feed_messages(&rx_from_inner)
+            .into_iter()
+            .step_by(2)
+            .collect();
+        assert_eq!(actions, vec![30, 30, 12, 11]);
+    }
+
+    #[test]
+    fn unchanged_chains_are_not_announced_again() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                node_count_source: NodeCountSource::Validators,
+                ..opts()
+            },
+        );
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        rx_from_inner.drain().for_each(drop);
+
+        // Collect the node counts announced to the feed:
+        let added_chains = || -> Vec<u64> {
+            rx_from_inner
+                .drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                    let msgs: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+                    msgs.chunks(2)
+                        .filter_map(announced_node_count)
+                        .map(|(_, node_count)| node_count)
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        };
+
+        // The first node on a chain is always announced:
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        assert_eq!(added_chains(), vec![0]);
+
+        // Another non-validator doesn't change what the feed knows about the chain:
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 1, "Chain One");
+        assert_eq!(added_chains(), Vec::<u64>::new());
+
+        // But a validator does:
+        let mut details = node("A", "Chain One");
+        details.validator = Some("validator".into());
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Add {
+                local_id: 3.into(),
+                ip: "8.8.8.8".parse().unwrap(),
+                node: details,
+                genesis_hash: BlockHash::from_low_u64_be(1),
+            },
+        );
+        assert_eq!(added_chains(), vec![1]);
+
+        // As does removing it again, while removing a non-validator doesn't:
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Remove { local_id: 3.into() });
+        assert_eq!(added_chains(), vec![0]);
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Remove { local_id: 2.into() });
+        assert_eq!(added_chains(), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn unsubscribing_stops_chain_updates() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 2, "Chain Two");
+
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        let subscribe: FromFeedWebsocket = format!("subscribe:{:?}", BlockHash::from_low_u64_be(1))
+            .parse()
+            .unwrap();
+        inner.handle_from_feed(1.into(), subscribe);
+
+        // Unsubscribing from a chain we aren't subscribed to does nothing:
+        let unsubscribe = |genesis: u64| -> FromFeedWebsocket {
+            format!("unsubscribe:{:?}", BlockHash::from_low_u64_be(genesis))
+                .parse()
+                .unwrap()
+        };
+        inner.handle_from_feed(1.into(), unsubscribe(2));
+        rx_from_inner.drain().for_each(drop);
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 1, "Chain One");
+        let is_subscribed = |inner: &InnerLoop| {
+            inner
+                .chain_to_feed_conn_ids
+                .get_values(&BlockHash::from_low_u64_be(1))
+                .is_some_and(|feeds| feeds.contains(&1.into()))
+        };
+        assert!(is_subscribed(&inner));
+        // An AddedNode for the chain, and an AddedChain for everybody:
+        assert_eq!(rx_from_inner.drain().count(), 2);
+
+        // Unsubscribing from the chain we're subscribed to tells the feed:
+        inner.handle_from_feed(1.into(), unsubscribe(1));
+        let ToFeedWebsocket::Bytes(bytes) = rx_from_inner.recv().unwrap();
+        let msgs: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(msgs[0], 14, "expected an UnsubscribedFrom message");
+        assert!(!is_subscribed(&inner));
+
+        // Now only the AddedChain message is received:
+        add_node_on_chain(&mut inner, 1, 4, "8.8.8.8", 1, "Chain One");
+        assert_eq!(rx_from_inner.drain().count(), 1);
+    }
+
+    #[test]
+    fn node_churn_is_reported_per_chain() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        let gather_metrics = |inner: &mut InnerLoop| {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_gather_metrics(tx, 0, 0, 0);
+            rx.recv().unwrap()
+        };
+        let churn = |metrics: &Metrics, genesis: u64| {
+            let chain = &metrics.chains[&BlockHash::from_low_u64_be(genesis)];
+            (chain.nodes_added, chain.nodes_removed)
+        };
+        let remove_node = |inner: &mut InnerLoop, local_id: usize| {
+            inner.handle_from_shard(
+                1.into(),
+                FromShardWebsocket::Remove {
+                    local_id: local_id.into(),
+                },
+            );
+        };
+
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 4, "8.8.8.8", 2, "Chain Two");
+        remove_node(&mut inner, 1);
+        remove_node(&mut inner, 4);
+
+        let metrics = gather_metrics(&mut inner);
+        assert_eq!(churn(&metrics, 1), (3, 1));
+        // Chain Two has gone, but we still hear about it:
+        assert_eq!(churn(&metrics, 2), (1, 1));
+
+        // Counts are reset each time metrics are gathered:
+        remove_node(&mut inner, 2);
+        let metrics = gather_metrics(&mut inner);
+        assert_eq!(churn(&metrics, 1), (0, 1));
+        assert!(!metrics.chains.contains_key(&BlockHash::from_low_u64_be(2)));
+    }
+
+    #[test]
+    fn small_location_changes_are_ignored() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                min_location_change_km: Some(10.0),
+                ..opts()
+            },
+        );
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        let subscribe: FromFeedWebsocket = format!("subscribe:{:?}", BlockHash::from_low_u64_be(1))
+            .parse()
+            .unwrap();
+        inner.handle_from_feed(1.into(), subscribe);
+        rx_from_inner.drain().for_each(drop);
+
+        let mut locate = |latitude, longitude| {
+            inner.handle_from_find_location(
+                node_id(&inner, 1, 1),
+                Some(Arc::new(NodeLocation {
+                    latitude,
+                    longitude,
+                    city: "Berlin".into(),
+                })),
+            );
+            // Count the LocatedNode messages sent to the feed:
+            rx_from_inner
+                .drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                    serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+                })
+                .step_by(2)
+                .filter(|action| action == 5)
+                .count()
+        };
+
+        assert_eq!(locate(52.52, 13.40), 1);
+        // A few hundred metres away:
+        assert_eq!(locate(52.521, 13.405), 0);
+        // London:
+        assert_eq!(locate(51.51, -0.13), 1);
+    }
+
+    #[test]
+    fn chain_metrics_include_the_highest_blocks_of_nodes() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        let send_update = |inner: &mut InnerLoop, local_id: usize, payload| {
+            inner.handle_from_shard(
+                1.into(),
+                FromShardWebsocket::Update {
+                    local_id: local_id.into(),
+                    payload,
+                },
+            )
+        };
+        let import = |height| {
+            node_message::Payload::BlockImport(Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            })
+        };
+        let finalize = |height: u64| {
+            node_message::Payload::NotifyFinalized(node_message::Finalized {
+                hash: BlockHash::from_low_u64_be(height),
+                height: height.to_string().into(),
+            })
+        };
+
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 2, "Chain Two");
+        send_update(&mut inner, 1, import(10));
+        send_update(&mut inner, 1, finalize(4));
+        send_update(&mut inner, 2, import(8));
+        send_update(&mut inner, 2, finalize(7));
+
+        let (tx, rx) = flume::unbounded();
+        inner.handle_gather_metrics(tx, 0, 0, 0);
+        let metrics = rx.recv().unwrap();
+
+        // The highest best and finalized blocks can come from different nodes:
+        let chain_one = &metrics.chains[&BlockHash::from_low_u64_be(1)];
+        assert_eq!(
+            (
+                chain_one.best_block,
+                chain_one.finalized_block,
+                chain_one.finality_gap
+            ),
+            (10, 7, 3)
+        );
+
+        // Nodes which haven't reported any blocks yet:
+        let chain_two = &metrics.chains[&BlockHash::from_low_u64_be(2)];
+        assert_eq!(
+            (
+                chain_two.best_block,
+                chain_two.finalized_block,
+                chain_two.finality_gap
+            ),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn node_counts_are_reported_per_chain() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 2, 1, "8.8.8.8", 2, "Chain Two");
+        inner.remove_nodes_and_broadcast_result(vec![node_id(&inner, 1, 2)]);
+
+        let (tx, rx) = flume::unbounded();
+        inner.handle_gather_metrics(tx, 0, 0, 0);
+        let metrics = rx.recv().unwrap();
+        assert_eq!(
+            metrics.chain_node_counts,
+            HashMap::from([
+                (BlockHash::from_low_u64_be(1), 2),
+                (BlockHash::from_low_u64_be(2), 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn shards_can_only_add_nodes_on_their_allowed_chains() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+
+        // Shard 1 can only send nodes on chain 1; shard 2 can send anything:
+        let (tx_to_shard, rx_from_inner) = flume::unbounded();
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: Some([BlockHash::from_low_u64_be(1)].into_iter().collect()),
+            },
+        );
+        let (tx_to_shard2, rx_from_inner2) = flume::unbounded();
+        inner.handle_from_shard(
+            2.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard2,
+                allowed_chains: None,
+            },
+        );
+
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 2, "Chain Two");
+        add_node_on_chain(&mut inner, 2, 1, "8.8.8.8", 2, "Chain Two");
+
+        // Only the node on the non-allowed chain was muted:
+        let muted: Vec<_> = rx_from_inner
+            .drain()
+            .map(|msg| match msg {
+                ToShardWebsocket::Mute { local_id, reason } => (usize::from(local_id), reason),
+            })
+            .collect();
+        assert!(matches!(muted[..], [(2, MuteReason::ChainNotAllowed)]));
+        assert_eq!(rx_from_inner2.drain().count(), 0);
+
+        let mut chains: Vec<_> = inner
+            .node_state
+            .iter_chains()
+            .map(|c| (c.label().to_owned(), c.node_count()))
+            .collect();
+        chains.sort();
+        assert_eq!(
+            chains,
+            vec![("Chain One".to_owned(), 1), ("Chain Two".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn shards_cannot_move_nodes_onto_chains_they_are_not_allowed() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        let (tx_to_shard, rx_from_inner) = flume::unbounded();
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: Some([BlockHash::from_low_u64_be(1)].into_iter().collect()),
+            },
+        );
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+
+        // The node then says that it's connected to a chain the shard isn't allowed:
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Update {
+                local_id: 1.into(),
+                payload: node_message::Payload::SystemConnected(node_message::SystemConnected {
+                    genesis_hash: BlockHash::from_low_u64_be(2),
+                    node: node("A", "Chain Two"),
+                }),
+            },
+        );
+
+        let muted: Vec<_> = rx_from_inner
+            .drain()
+            .map(|msg| match msg {
+                ToShardWebsocket::Mute { local_id, reason } => (usize::from(local_id), reason),
+            })
+            .collect();
+        assert!(matches!(muted[..], [(1, MuteReason::ChainNotAllowed)]));
+        assert!(inner.node_ids.is_empty());
+        assert_eq!(inner.node_state.iter_chains().count(), 0);
+    }
+
+    #[test]
+    fn nodes_which_cannot_be_reregistered_are_muted() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let (tx_errors, rx_errors) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                denylist: vec!["Denied".into()],
+                processing_errors: Some(tx_errors),
+                ..opts()
+            },
+        );
+        let (tx_to_shard, rx_from_inner) = flume::unbounded();
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            },
+        );
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+
+        // The node moves to a chain that it can't be added to:
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Update {
+                local_id: 1.into(),
+                payload: node_message::Payload::SystemConnected(node_message::SystemConnected {
+                    genesis_hash: BlockHash::from_low_u64_be(2),
+                    node: node("A", "Denied"),
+                }),
+            },
+        );
+        assert!(matches!(
+            rx_from_inner.try_recv(),
+            Ok(ToShardWebsocket::Mute {
+                reason: MuteReason::ChainNotAllowed,
+                ..
+            })
+        ));
+        assert!(inner.node_ids.is_empty());
+
+        // So it disconnecting later isn't an error:
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Remove { local_id: 1.into() });
+        assert!(rx_errors.is_empty());
+        assert!(inner.muted_shard_nodes.is_empty());
+    }
+
+    #[test]
+    fn feed_subscriptions_can_be_inspected() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+
+        let get_view = |inner: &InnerLoop, feed_conn_id: u64| {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_get_feed_subscriptions(feed_conn_id.into(), tx);
+            rx.recv().unwrap()
+        };
+
+        // Unknown feeds have no view:
+        assert_eq!(get_view(&inner, 1), None);
+
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        assert_eq!(
+            get_view(&inner, 1),
+            Some(FeedSubscriptionView {
+                subscribed_chain: None,
+                queued_messages: 1,
+            })
+        );
+
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+            },
+        );
+        rx_from_inner.drain().for_each(drop);
+        assert_eq!(
+            get_view(&inner, 1),
+            Some(FeedSubscriptionView {
+                subscribed_chain: Some(BlockHash::from_low_u64_be(1)),
+                queued_messages: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn feeds_can_filter_the_chains_they_hear_about() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 2, "Chain Two");
+
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(2),
+            },
+        );
+        rx_from_inner.drain().for_each(drop);
+
+        let received_actions = |rx: &flume::Receiver<ToFeedWebsocket>| -> Vec<u64> {
+            rx.drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                    serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+                })
+                .step_by(2)
+                .map(|action| action.as_u64().unwrap())
+                .collect()
+        };
+
+        // Filtering out the chain the feed is subscribed to unsubscribes it, and the
+        // feed is told to forget about that chain:
+        let filter: FromFeedWebsocket = format!("filter:{:?}", BlockHash::from_low_u64_be(1))
+            .parse()
+            .unwrap();
+        inner.handle_from_feed(1.into(), filter);
+        assert_eq!(received_actions(&rx_from_inner), vec![14, 12]);
+
+        // Nothing about the second chain reaches the feed, even if it asks:
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(2),
+            },
+        );
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 2, "Chain Two");
+        assert_eq!(received_actions(&rx_from_inner), Vec::<u64>::new());
+        assert_eq!(inner.chain_to_feed_conn_ids.get_key(&1.into()), None);
+
+        // But the feed still hears about the first chain:
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+            },
+        );
+        rx_from_inner.drain().for_each(drop);
+        add_node_on_chain(&mut inner, 1, 4, "8.8.8.8", 1, "Chain One");
+        let actions = received_actions(&rx_from_inner);
+        assert!(actions.contains(&3), "expected an AddedNode message");
+        assert!(actions.contains(&30), "expected a ChainNodeCounts message");
+
+        // Clearing the filter tells the feed about the second chain again:
+        let filter: FromFeedWebsocket = "filter:".parse().unwrap();
+        inner.handle_from_feed(1.into(), filter);
+        assert_eq!(received_actions(&rx_from_inner), vec![11]);
+        assert!(inner.feed_chain_filters.is_empty());
+    }
+
+    #[test]
+    fn summary_feeds_are_not_told_about_nodes() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+
+        // A summary feed and a normal feed, both subscribed to the chain:
+        let (tx_to_summary_feed, rx_summary_feed) = flume::unbounded();
+        let (tx_to_full_feed, rx_full_feed) = flume::unbounded();
+        for (feed_conn_id, channel) in [(1, tx_to_summary_feed), (2, tx_to_full_feed)] {
+            inner.handle_from_feed(
+                feed_conn_id.into(),
+                FromFeedWebsocket::Initialize {
+                    channel,
+                    encoding: FeedEncoding::Json,
+                },
+            );
+        }
+        let summary_only: FromFeedWebsocket = "summary:true".parse().unwrap();
+        inner.handle_from_feed(1.into(), summary_only);
+        for feed_conn_id in [1, 2] {
+            inner.handle_from_feed(
+                feed_conn_id.into(),
+                FromFeedWebsocket::Subscribe {
+                    chain: BlockHash::from_low_u64_be(1),
+                },
+            );
+        }
+
+        // Nodes come and go, and one finalizes a block:
+        add_node(&mut inner, 1, 2, "8.8.8.8", 1);
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Update {
+                local_id: 2.into(),
+                payload: node_message::Payload::BlockImport(Block {
+                    hash: BlockHash::from_low_u64_be(2),
+                    height: 10,
+                }),
+            },
+        );
+        add_node(&mut inner, 1, 3, "8.8.8.8", 1);
+        inner.remove_nodes_and_broadcast_result(vec![node_id(&inner, 1, 3)]);
+
+        let received_actions = |rx: &flume::Receiver<ToFeedWebsocket>| -> Vec<u64> {
+            rx.drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                    serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+                })
+                .step_by(2)
+                .map(|action| action.as_u64().unwrap())
+                .collect()
+        };
+        let node_actions = [3, 4, 5, 6, 7, 8, 9, 20, 21, 24, 28];
+
+        // The summary feed hears about the chain, but nothing about its nodes:
+        let summary_actions = received_actions(&rx_summary_feed);
+        assert!(
+            summary_actions.contains(&11),
+            "expected an AddedChain message"
+        );
+        assert!(summary_actions.contains(&1), "expected a BestBlock message");
+        assert!(!summary_actions.iter().any(|a| node_actions.contains(a)));
+
+        // Whereas the normal feed is told about everything:
+        let full_actions = received_actions(&rx_full_feed);
+        for action in [3, 4, 6, 11] {
+            assert!(full_actions.contains(&action), "expected action {action}");
+        }
+
+        // Turning summaries off again subscribes the feed afresh, telling it about every node:
+        inner.handle_from_feed(1.into(), FromFeedWebsocket::SummaryOnly { enabled: false });
+        let actions = received_actions(&rx_summary_feed);
+        assert_eq!(actions.iter().filter(|&&a| a == 3).count(), 2);
+    }
+
+    #[test]
+    fn chain_nodes_can_be_inspected() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 2, 1, "8.8.8.8", 1, "Chain One");
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Update {
+                local_id: 1.into(),
+                payload: node_message::Payload::BlockImport(Block {
+                    hash: BlockHash::from_low_u64_be(10),
+                    height: 10,
+                }),
+            },
+        );
+
+        let get_nodes = |inner: &InnerLoop, genesis_hash: u64| {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_get_chain_nodes(BlockHash::from_low_u64_be(genesis_hash), false, tx);
+            rx.recv().unwrap()
+        };
+
+        // Unknown chains have no nodes to inspect:
+        assert_eq!(get_nodes(&inner, 2), None);
+
+        // A single block isn't enough to estimate the node's block time from. Each node
+        // shows which shard its telemetry arrives through:
+        assert_eq!(
+            get_nodes(&inner, 1),
+            Some(vec![
+                NodeView {
+                    id: 0,
+                    name: "A".into(),
+                    best_block: 10,
+                    finalized_block: 0,
+                    estimated_block_time: None,
+                    shard_conn_id: Some(1),
+                    location_status: find_location::LocationStatus::Unknown,
+                    raw: None,
+                },
+                NodeView {
+                    id: 1,
+                    name: "A".into(),
+                    best_block: 0,
+                    finalized_block: 0,
+                    estimated_block_time: None,
+                    shard_conn_id: Some(2),
+                    location_status: find_location::LocationStatus::Unknown,
+                    raw: None,
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn raw_node_payloads_can_be_inspected() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                keep_raw_node_payloads: true,
+                ..opts()
+            },
+        );
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+
+        let block = Block {
+            hash: BlockHash::from_low_u64_be(10),
+            height: 10,
+        };
+        let interval = node_message::SystemInterval {
+            peers: Some(5),
+            txcount: Some(2),
+            bandwidth_upload: Some(1.5),
+            bandwidth_download: None,
+            finalized_height: Some(8),
+            finalized_hash: Some(BlockHash::from_low_u64_be(8)),
+            block: None,
+            used_state_cache_size: Some(1234.0),
+        };
+        for payload in [
+            node_message::Payload::BlockImport(block),
+            node_message::Payload::SystemInterval(interval.clone()),
+        ] {
+            inner.handle_from_shard(
+                1.into(),
+                FromShardWebsocket::Update {
+                    local_id: 1.into(),
+                    payload,
+                },
+            );
+        }
+
+        let get_raw = |include_raw| {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_get_chain_nodes(BlockHash::from_low_u64_be(1), include_raw, tx);
+            rx.recv().unwrap().unwrap().remove(0).raw
+        };
+
+        // Raw payloads are only handed back when asked for:
+        assert_eq!(get_raw(false), None);
+        let raw = get_raw(true).expect("raw payloads should be kept");
+        assert_eq!(raw["block_import"], serde_json::to_value(block).unwrap());
+        assert_eq!(
+            raw["system_interval"],
+            serde_json::to_value(&interval).unwrap()
+        );
+        assert_eq!(
+            raw["system_connected"],
+            serde_json::to_value(node_message::SystemConnected {
+                genesis_hash: BlockHash::from_low_u64_be(1),
+                node: node("A", "Chain One"),
+            })
+            .unwrap()
+        );
+        assert_eq!(raw["notify_finalized"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn snapshots_describe_every_chain() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 2, "Chain Two");
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Remove { local_id: 2.into() });
+
+        let mut snapshot = inner.snapshot(false);
+        snapshot.chains.sort_by_key(|chain| chain.genesis_hash);
+        assert_eq!(
+            snapshot.chains,
+            vec![
+                ChainSnapshot {
+                    genesis_hash: BlockHash::from_low_u64_be(1),
+                    label: "Chain One".into(),
+                    node_count: 1,
+                    highest_node_count: 2,
+                    nodes: None,
+                },
+                ChainSnapshot {
+                    genesis_hash: BlockHash::from_low_u64_be(2),
+                    label: "Chain Two".into(),
+                    node_count: 1,
+                    highest_node_count: 1,
+                    nodes: None,
+                }
+            ]
+        );
+        assert!(!serde_json::to_string(&snapshot)
+            .unwrap()
+            .contains("nodes\""));
+
+        // Nodes are described the same way as when inspecting a single chain:
+        let snapshot = inner.snapshot(true);
+        for chain in snapshot.chains {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_get_chain_nodes(chain.genesis_hash, false, tx);
+            assert_eq!(chain.nodes, rx.recv().unwrap());
+        }
+    }
+
+    #[test]
+    fn updates_for_unknown_nodes_are_reported() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let (tx_errors, rx_errors) = flume::bounded(1);
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                processing_errors: Some(tx_errors),
+                ..opts()
+            },
+        );
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+
+        let update_unknown_node = |inner: &mut InnerLoop| {
+            inner.handle_from_shard(
+                1.into(),
+                FromShardWebsocket::Update {
+                    local_id: 5.into(),
+                    payload: node_message::Payload::BlockImport(Block {
+                        hash: BlockHash::from_low_u64_be(10),
+                        height: 10,
+                    }),
+                },
+            );
+        };
+
+        update_unknown_node(&mut inner);
+        assert_eq!(
+            rx_errors.try_recv().unwrap(),
+            ProcessingError {
+                kind: ProcessingErrorKind::UnknownNode,
+                shard_conn_id: 1.into(),
+                local_id: 5.into(),
+            }
+        );
+        assert_eq!(inner.dropped_processing_errors, 0);
+
+        // Errors that don't fit into the channel are dropped and counted:
+        update_unknown_node(&mut inner);
+        update_unknown_node(&mut inner);
+        assert_eq!(rx_errors.drain().count(), 1);
+        assert_eq!(inner.dropped_processing_errors, 1);
+    }
+
+    #[test]
+    fn oversized_shard_messages_are_rejected_and_counted() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                max_shard_message_size: Some(1000),
+                ..opts()
+            },
+        );
+        let (tx_to_shard, rx_from_inner) = flume::unbounded();
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            },
+        );
+        let huge_node = node(&"A".repeat(2000), "Chain One");
+
+        // A node with huge details is muted rather than added:
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Add {
+                local_id: 1.into(),
+                ip: "8.8.8.8".parse().unwrap(),
+                node: huge_node.clone(),
+                genesis_hash: BlockHash::from_low_u64_be(1),
+            },
+        );
+        assert!(matches!(
+            rx_from_inner.try_recv(),
+            Ok(ToShardWebsocket::Mute {
+                reason: MuteReason::MessageTooLarge,
+                ..
+            })
+        ));
+        assert_eq!(inner.node_ids.len(), 0);
+        assert_eq!(inner.oversized_shard_messages, 1);
+
+        // Normal sized nodes are fine, but huge updates from them are ignored:
+        add_node(&mut inner, 1, 2, "8.8.8.8", 1);
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Update {
+                local_id: 2.into(),
+                payload: node_message::Payload::SystemConnected(node_message::SystemConnected {
+                    genesis_hash: BlockHash::from_low_u64_be(2),
+                    node: huge_node,
+                }),
+            },
+        );
+        assert_eq!(inner.oversized_shard_messages, 2);
+        let chain = inner
+            .node_state
+            .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(1))
+            .unwrap();
+        assert_eq!(chain.node_count(), 1);
+        assert!(rx_from_inner.is_empty());
+    }
+
+    #[test]
+    fn shards_with_too_many_nodes_are_muted() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                max_nodes_per_shard: 2,
+                ..opts()
+            },
+        );
+        let (tx_to_shard_1, rx_from_inner_1) = flume::unbounded();
+        let (tx_to_shard_2, rx_from_inner_2) = flume::unbounded();
+        for (shard_conn_id, channel) in [(1, tx_to_shard_1), (2, tx_to_shard_2)] {
+            inner.handle_from_shard(
+                shard_conn_id.into(),
+                FromShardWebsocket::Initialize {
+                    channel,
+                    allowed_chains: None,
+                },
+            );
+        }
+
+        // The first shard can only add two nodes:
+        for local_id in 1..=3 {
+            add_node(&mut inner, 1, local_id, "8.8.8.8", 1);
+        }
+        assert!(matches!(
+            rx_from_inner_1.try_recv(),
+            Ok(ToShardWebsocket::Mute {
+                local_id,
+                reason: MuteReason::TooManyShardNodes,
+            }) if local_id == 3.into()
+        ));
+        assert_eq!(inner.rejected_shard_nodes, 1);
+
+        // The other shard is unaffected:
+        add_node(&mut inner, 2, 1, "8.8.8.8", 1);
+        add_node(&mut inner, 2, 2, "8.8.8.8", 1);
+        assert!(rx_from_inner_2.is_empty());
+        assert_eq!(inner.node_ids.len(), 4);
+
+        // Once one of its nodes goes away, the first shard can add another:
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Remove { local_id: 1.into() });
+        add_node(&mut inner, 1, 4, "8.8.8.8", 1);
+        assert!(rx_from_inner_1.is_empty());
+        assert_eq!(inner.node_ids.len(), 4);
+        assert_eq!(inner.rejected_shard_nodes, 1);
+    }
+
+    #[test]
+    fn nodes_sending_too_many_updates_are_rate_limited() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                max_node_messages_per_second: Some(3),
+                ..opts()
+            },
+        );
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+        add_node(&mut inner, 1, 2, "8.8.8.8", 1);
+        let import_block = |inner: &mut InnerLoop, local_id: usize, height| {
+            inner.handle_from_shard(
+                1.into(),
+                FromShardWebsocket::Update {
+                    local_id: local_id.into(),
+                    payload: node_message::Payload::BlockImport(Block {
+                        hash: BlockHash::from_low_u64_be(height),
+                        height,
+                    }),
+                },
+            );
+        };
+        let best_height = |inner: &InnerLoop, local_id| {
+            inner
+                .node_state
+                .get_node(node_id(inner, 1, local_id))
+                .unwrap()
+                .best()
+                .height
+        };
+
+        // Only the first few updates in a burst get through:
+        for height in 1..=10 {
+            import_block(&mut inner, 1, height);
+        }
+        assert_eq!(best_height(&inner, 1), 3);
+        assert_eq!(inner.rate_limited_node_messages, 7);
+
+        // Other nodes are unaffected:
+        import_block(&mut inner, 2, 10);
+        assert_eq!(best_height(&inner, 2), 10);
+        assert_eq!(inner.rate_limited_node_messages, 7);
+    }
+
+    #[test]
+    fn blocked_nodes_are_muted_on_any_chain() {
+        let (tx_to_locator, _rx_from_inner) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                node_blocklist: vec!["bad-node".into()],
+                ..opts()
+            },
+        );
+        let (tx_to_shard, rx_from_inner) = flume::unbounded();
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            },
+        );
+
+        let node_with_id = |chain: &str, network_id: &str| NodeDetails {
+            network_id: network_id.parse().unwrap(),
+            ..node("A", chain)
+        };
+        let add = |inner: &mut InnerLoop, local_id: usize, genesis: u64, node| {
+            inner.handle_from_shard(
+                1.into(),
+                FromShardWebsocket::Add {
+                    local_id: local_id.into(),
+                    ip: "8.8.8.8".parse().unwrap(),
+                    node,
+                    genesis_hash: BlockHash::from_low_u64_be(genesis),
+                },
+            )
+        };
+
+        // The blocked node is muted whichever chain it tries to join:
+        add(&mut inner, 1, 1, node_with_id("Chain One", "bad-node"));
+        add(&mut inner, 2, 2, node_with_id("Chain Two", "bad-node"));
+        add(&mut inner, 3, 1, node_with_id("Chain One", "good-node"));
+
+        let muted: Vec<_> = rx_from_inner
+            .drain()
+            .map(|msg| match msg {
+                ToShardWebsocket::Mute { local_id, reason } => (usize::from(local_id), reason),
+            })
+            .collect();
+        assert!(matches!(
+            muted[..],
+            [(1, MuteReason::NodeBlocked), (2, MuteReason::NodeBlocked)]
+        ));
+        assert_eq!(inner.node_ids.len(), 1);
+
+        // Blocking a connected node removes it:
+        let (tx, rx) = flume::unbounded();
+        inner.handle_replace_node_blocklist(vec!["good-node".into()], tx);
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert!(matches!(
+            rx_from_inner.try_recv(),
+            Ok(ToShardWebsocket::Mute {
+                reason: MuteReason::NodeBlocked,
+                ..
+            })
+        ));
+        assert!(inner.node_ids.is_empty());
+    }
+
+    /// An aggregator which requires every node to report a network ID, and a channel
+    /// receiving anything it tells the shard.
+    fn inner_requiring_network_ids(
+        policy: IncompleteNodePolicy,
+    ) -> (InnerLoop, flume::Receiver<ToShardWebsocket>) {
+        let (tx_to_locator, _rx_from_inner) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                required_node_fields: vec![RequiredNodeField::NetworkId],
+                incomplete_node_policy: policy,
+                ..opts()
+            },
+        );
+        let (tx_to_shard, rx_from_inner) = flume::unbounded();
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            },
+        );
+        (inner, rx_from_inner)
+    }
+
+    #[test]
+    fn incomplete_nodes_can_be_rejected() {
+        let (mut inner, rx_from_inner) = inner_requiring_network_ids(IncompleteNodePolicy::Reject);
+
+        // The test nodes don't report a network ID:
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+        assert!(matches!(
+            rx_from_inner.try_recv(),
+            Ok(ToShardWebsocket::Mute {
+                reason: MuteReason::Invalid,
+                ..
+            })
+        ));
+        assert!(inner.node_ids.is_empty());
+    }
+
+    #[test]
+    fn incomplete_nodes_can_be_given_placeholders() {
+        let (mut inner, rx_from_inner) =
+            inner_requiring_network_ids(IncompleteNodePolicy::Placeholder);
+
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+        add_node(&mut inner, 1, 2, "8.8.8.8", 1);
+        assert!(rx_from_inner.is_empty());
+
+        // Each node is given its own placeholder network ID:
+        let network_id = |local_id| {
+            let node_id = node_id(&inner, 1, local_id);
+            let chain = inner.node_state.get_chain_by_node_id(node_id).unwrap();
+            let (_, node) = chain.iter_nodes().find(|(id, _)| *id == node_id).unwrap();
+            node.details().network_id
+        };
+        assert_eq!(&*network_id(1), "unknown-1");
+        assert_eq!(&*network_id(2), "unknown-2");
+    }
+
+    #[test]
+    fn removing_a_whole_chain_sends_one_message() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 2, 1, "8.8.8.8", 2, "Chain Two");
+        add_node_on_chain(&mut inner, 2, 2, "8.8.8.8", 2, "Chain Two");
+
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        let subscribe: FromFeedWebsocket = format!("subscribe:{:?}", BlockHash::from_low_u64_be(1))
+            .parse()
+            .unwrap();
+        inner.handle_from_feed(1.into(), subscribe);
+        rx_from_inner.drain().for_each(drop);
+
+        let feed_messages = |rx: &flume::Receiver<ToFeedWebsocket>| -> Vec<serde_json::Value> {
+            rx.drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                    serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+                })
+                .collect()
+        };
+
+        // The shard carrying all of chain one disconnects; we're told once that it's gone:
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Disconnected);
+        let msgs = feed_messages(&rx_from_inner);
+        assert_eq!(msgs.len(), 2, "expected a single message, got {:?}", msgs);
+        assert_eq!(msgs[0], 12, "expected a RemovedChain message");
+
+        // Removing only some of a chain's nodes still announces each removal:
+        add_node_on_chain(&mut inner, 2, 3, "8.8.8.8", 2, "Chain Two");
+        rx_from_inner.drain().for_each(drop);
+        inner.remove_nodes_and_broadcast_result(vec![node_id(&inner, 2, 1), node_id(&inner, 2, 2)]);
+        let msgs = feed_messages(&rx_from_inner);
+        assert_eq!(
+            msgs.len(),
+            4,
+            "expected two AddedChain messages, got {:?}",
+            msgs
+        );
+        assert!(inner
+            .node_state
+            .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(2))
+            .is_some());
+    }
+
+    #[test]
+    fn subscribing_sends_nodes_in_order() {
+        check_subscribing_sends_nodes_in_order(opts());
+    }
+
+    #[test]
+    fn subscribing_sends_nodes_in_order_with_one_serialization_thread() {
+        check_subscribing_sends_nodes_in_order(AggregatorOpts {
+            serialization_pool: serialization_pool(1),
+            ..opts()
+        });
+    }
+
+    #[test]
+    fn subscribing_sends_nodes_in_order_with_small_messages() {
+        check_subscribing_sends_nodes_in_order(AggregatorOpts {
+            nodes_per_feed_message: 1,
+            ..opts()
+        });
+    }
+
+    #[test]
+    fn subscribing_sends_nodes_in_order_with_a_small_byte_budget() {
+        check_subscribing_sends_nodes_in_order(AggregatorOpts {
+            max_feed_message_bytes: 1000,
+            ..opts()
+        });
+    }
+
+    #[test]
+    fn subscribing_sends_nodes_within_the_byte_budget() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                max_feed_message_bytes: 4 * 1024,
+                ..opts()
+            },
+        );
+
+        // Nodes with long names, so that far fewer than 64 of them fit in a message:
+        for local_id in 0..100 {
+            let name = format!("{}-{}", "long-node-name".repeat(50), local_id);
+            inner.handle_from_shard(
+                1.into(),
+                FromShardWebsocket::Add {
+                    local_id: local_id.into(),
+                    ip: "8.8.8.8".parse().unwrap(),
+                    node: node(&name, "Chain One"),
+                    genesis_hash: BlockHash::from_low_u64_be(1),
+                },
+            );
+        }
+
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        rx_from_inner.drain().for_each(drop);
+        let subscribe: FromFeedWebsocket = format!("subscribe:{:?}", BlockHash::from_low_u64_be(1))
+            .parse()
+            .unwrap();
+        inner.handle_from_feed(1.into(), subscribe);
+
+        // The first message describes the chain, and the rest describe its nodes:
+        let node_msgs: Vec<_> = rx_from_inner
+            .drain()
+            .skip(1)
+            .map(|ToFeedWebsocket::Bytes(bytes)| bytes)
+            .collect();
+        for bytes in &node_msgs {
+            assert!(bytes.len() <= 4 * 1024, "message is {} bytes", bytes.len());
+        }
+        let added_nodes: usize = node_msgs
+            .iter()
+            .map(|bytes| {
+                let msgs: Vec<serde_json::Value> = serde_json::from_slice(bytes).unwrap();
+                msgs.chunks(2).filter(|msg| msg[0] == 3).count()
+            })
+            .sum();
+        assert_eq!(added_nodes, 100);
+        assert!(
+            node_msgs.len() > 2,
+            "expected nodes to be sent in several messages"
+        );
+    }
+
+    #[tokio::test]
+    async fn aggregator_cannot_send_zero_nodes_per_feed_message() {
+        let opts = AggregatorOpts {
+            nodes_per_feed_message: 0,
+            ..opts()
+        };
+        assert!(Aggregator::spawn(opts).await.is_err());
+    }
+
+    #[test]
+    fn stale_nodes_are_pruned_after_the_timeout() {
+        let opts = AggregatorOpts {
+            stale_node_timeout: Some(Duration::from_secs(60)),
+            ..opts()
+        };
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts);
+        let added_at = time::now();
+
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 2, "Chain Two");
+
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        rx_from_inner.drain().for_each(drop);
+
+        // Nothing has timed out yet:
+        inner.prune_stale_nodes(added_at + 30_000);
+        assert_eq!(inner.node_ids.len(), 3);
+        assert!(rx_from_inner.is_empty());
+
+        // Later, every node has timed out, taking their chains with them:
+        inner.prune_stale_nodes(added_at + 61_000);
+        assert!(inner.node_ids.is_empty());
+        assert_eq!(inner.node_state.iter_chains().count(), 0);
+        let msgs: Vec<serde_json::Value> = rx_from_inner
+            .drain()
+            .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+            })
+            .collect();
+        let removed_chains: Vec<_> = msgs
+            .chunks(2)
+            .filter(|msg| msg[0] == 12)
+            .map(|msg| msg[1].clone())
+            .collect();
+        assert_eq!(removed_chains.len(), 2, "got {:?}", msgs);
+    }
+
+    #[test]
+    fn pruned_stale_nodes_are_muted_and_their_updates_ignored() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let (tx_errors, rx_errors) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                stale_node_timeout: Some(Duration::from_secs(60)),
+                processing_errors: Some(tx_errors),
+                ..opts()
+            },
+        );
+        let (tx_to_shard, rx_from_inner) = flume::unbounded();
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            },
+        );
+        let added_at = time::now();
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+
+        inner.prune_stale_nodes(added_at + 61_000);
+        assert!(inner.node_ids.is_empty());
+        assert!(matches!(
+            rx_from_inner.try_recv(),
+            Ok(ToShardWebsocket::Mute {
+                reason: MuteReason::Stale,
+                ..
+            })
+        ));
+
+        // Messages the shard sent before it muted the node aren't errors:
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Update {
+                local_id: 1.into(),
+                payload: node_message::Payload::BlockImport(Block {
+                    hash: BlockHash::from_low_u64_be(10),
+                    height: 10,
+                }),
+            },
+        );
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Remove { local_id: 1.into() });
+        assert!(rx_errors.is_empty());
+        assert!(inner.muted_shard_nodes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn updates_are_dropped_when_the_queue_is_too_long() {
+        let opts = AggregatorOpts {
+            max_queue_len: 0,
+            ..opts()
+        };
+        let aggregator = Aggregator::spawn(opts).await.unwrap();
+        let mut tx_to_aggregator = aggregator.subscribe_shard();
+
+        // Nothing gets a chance to handle these until we wait for metrics below,
+        // so the queue backs up:
+        for _ in 0..1000 {
+            tx_to_aggregator
+                .send(FromShardWebsocket::Update {
+                    local_id: 1.into(),
+                    payload: node_message::Payload::BlockImport(Block {
+                        hash: BlockHash::from_low_u64_be(2),
+                        height: 1,
+                    }),
+                })
+                .await
+                .unwrap();
+        }
+
+        let metrics = aggregator.gather_metrics().await.unwrap();
+        assert_eq!(metrics.total_messages_to_aggregator, 1001);
+        assert!(metrics.dropped_messages_to_aggregator > 0);
+        assert!(metrics.dropped_messages_to_aggregator < 1000);
+    }
+
+    #[tokio::test]
+    async fn shutting_down_says_goodbye_to_feeds() {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry_core_shutdown_denylist_{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let aggregator = Aggregator::spawn(AggregatorOpts {
+            denylist: vec!["Denied".into()],
+            denylist_path: Some(path.clone()),
+            ..opts()
+        })
+        .await
+        .unwrap();
+
+        let mut tx_to_aggregator = aggregator.subscribe_shard();
+        let (tx_to_shard, rx_from_aggregator) = flume::unbounded();
+        tx_to_aggregator
+            .send(FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            })
+            .await
+            .unwrap();
+        for (local_id, genesis) in [(1, 1), (2, 1), (3, 2)] {
+            tx_to_aggregator
+                .send(FromShardWebsocket::Add {
+                    local_id: local_id.into(),
+                    ip: "8.8.8.8".parse().unwrap(),
+                    node: node("A", "Chain One"),
+                    genesis_hash: BlockHash::from_low_u64_be(genesis),
+                })
+                .await
+                .unwrap();
+        }
+
+        let (_, mut tx_from_feed) = aggregator.subscribe_feed();
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        tx_from_feed
+            .send(FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            })
+            .await
+            .unwrap();
+
+        aggregator.shutdown().await.unwrap();
+
+        // The feed is told that every chain has gone, and then disconnected:
+        let removed_chains: HashSet<BlockHash> = rx_from_inner
+            .drain()
+            .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+            })
+            .collect::<Vec<_>>()
+            .chunks(2)
+            .filter(|msg| msg[0] == 12)
+            .map(|msg| serde_json::from_value(msg[1].clone()).unwrap())
+            .collect();
+        assert_eq!(
+            removed_chains,
+            [1, 2].into_iter().map(BlockHash::from_low_u64_be).collect()
+        );
+        assert!(rx_from_inner.is_disconnected());
+        assert!(rx_from_aggregator.is_disconnected());
+
+        // The denylist in effect was saved on the way out:
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("Denied"));
+        std::fs::remove_file(&path).unwrap();
+
+        // And the aggregator is no longer running:
+        assert!(aggregator
+            .get_chain(BlockHash::from_low_u64_be(1))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn state_can_be_dumped_as_ndjson() {
+        let aggregator = Aggregator::spawn(opts()).await.unwrap();
+        let mut tx_to_aggregator = aggregator.subscribe_shard();
+        let (tx_to_shard, _rx_from_aggregator) = flume::unbounded();
+        tx_to_aggregator
+            .send(FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            })
+            .await
+            .unwrap();
+        for (local_id, genesis) in [(1, 1), (2, 1), (3, 2)] {
+            tx_to_aggregator
+                .send(FromShardWebsocket::Add {
+                    local_id: local_id.into(),
+                    ip: "8.8.8.8".parse().unwrap(),
+                    node: node("A", "Chain One"),
+                    genesis_hash: BlockHash::from_low_u64_be(genesis),
+                })
+                .await
+                .unwrap();
+        }
+
+        let path = std::env::temp_dir().join(format!(
+            "telemetry_core_state_dump_{}.ndjson",
+            std::process::id()
+        ));
+        aggregator.dump_state(path.clone(), true).await.unwrap();
+        let dumped = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Each line describes one chain and its nodes:
+        let mut chains: Vec<serde_json::Value> = dumped
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        chains.sort_by_key(|chain| chain["node_count"].as_u64());
+        assert_eq!(chains.len(), 2);
+        for (chain, (genesis, node_count)) in chains.iter().zip([(2, 1), (1, 2)]) {
+            assert_eq!(
+                chain["genesis_hash"],
+                serde_json::to_value(BlockHash::from_low_u64_be(genesis)).unwrap()
+            );
+            assert_eq!(chain["node_count"], node_count);
+            assert_eq!(chain["nodes"].as_array().unwrap().len(), node_count);
+        }
+    }
+
+    #[tokio::test]
+    async fn single_chains_can_be_looked_up() {
+        let aggregator = Aggregator::spawn(opts()).await.unwrap();
+        let mut tx_to_aggregator = aggregator.subscribe_shard();
+        let (tx_to_shard, _rx_from_aggregator) = flume::unbounded();
+        tx_to_aggregator
+            .send(FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            })
+            .await
+            .unwrap();
+        for (local_id, finalized_height) in [(1, 8), (2, 5)] {
+            tx_to_aggregator
+                .send(FromShardWebsocket::Add {
+                    local_id: local_id.into(),
+                    ip: "8.8.8.8".parse().unwrap(),
+                    node: node("A", "Chain One"),
+                    genesis_hash: BlockHash::from_low_u64_be(1),
+                })
+                .await
+                .unwrap();
+            tx_to_aggregator
+                .send(FromShardWebsocket::Update {
+                    local_id: local_id.into(),
+                    payload: node_message::Payload::NotifyFinalized(node_message::Finalized {
+                        hash: BlockHash::from_low_u64_be(finalized_height),
+                        height: finalized_height.to_string().into(),
+                    }),
+                })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            aggregator
+                .get_chain(BlockHash::from_low_u64_be(1))
+                .await
+                .unwrap(),
+            Some(ChainInfo {
+                genesis_hash: BlockHash::from_low_u64_be(1),
+                label: "Chain One".into(),
+                node_count: 2,
+                highest_node_count: 2,
+                finalized_heights: Some((5, 8)),
+            })
+        );
+        assert_eq!(
+            aggregator
+                .get_chain(BlockHash::from_low_u64_be(2))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_can_be_streamed_at_an_interval() {
+        let aggregator = Aggregator::spawn(opts()).await.unwrap();
+        let interval = Duration::from_millis(100);
+        let mut metrics = aggregator.subscribe_metrics(interval);
+
+        // The first snapshot arrives straight away:
+        let first = metrics.next().await.unwrap();
+        assert_eq!(first.connected_feeds, 0);
+
+        // Later snapshots reflect what's happened since:
+        let (_feed_id, mut tx_to_aggregator) = aggregator.subscribe_feed();
+        let (channel, _rx_from_aggregator) = flume::unbounded();
+        tx_to_aggregator
+            .send(FromFeedWebsocket::Initialize {
+                channel,
+                encoding: FeedEncoding::Json,
+            })
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        let second = metrics.next().await.unwrap();
+        let third = metrics.next().await.unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(second.connected_feeds, 1);
+        assert_eq!(third.connected_feeds, 1);
+        assert!(third.timestamp_unix_ms > second.timestamp_unix_ms);
+
+        // Two snapshots are at least one interval apart, and shouldn't be much slower:
+        assert!(elapsed >= interval, "elapsed {:?}", elapsed);
+        assert!(elapsed < interval * 10, "elapsed {:?}", elapsed);
+    }
+
+    fn check_subscribing_sends_nodes_in_order(opts: AggregatorOpts) {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts);
+
+        // Enough nodes that they're serialized in several chunks:
+        for local_id in 0..200 {
+            add_node_on_chain(&mut inner, 1, local_id, "8.8.8.8", 1, "Chain One");
+        }
+        // Leave a gap, so that IDs don't simply line up with positions:
+        inner.remove_nodes_and_broadcast_result(vec![node_id(&inner, 1, 100)]);
+
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        rx_from_inner.drain().for_each(drop);
+        let subscribe: FromFeedWebsocket = format!("subscribe:{:?}", BlockHash::from_low_u64_be(1))
+            .parse()
+            .unwrap();
+        inner.handle_from_feed(1.into(), subscribe);
+
+        // The first message describes the chain, and the rest describe its nodes:
+        let node_msgs: Vec<Vec<serde_json::Value>> = rx_from_inner
+            .drain()
+            .skip(1)
+            .map(|ToFeedWebsocket::Bytes(bytes)| serde_json::from_slice(&bytes).unwrap())
+            .collect();
+        assert!(node_msgs.len() > 1, "expected nodes to be sent in chunks");
+
+        // Pair up actions and payloads across all messages, noting each node ID mentioned:
+        let mut added = Vec::new();
+        for msgs in &node_msgs {
+            for pair in msgs.chunks(2) {
+                let action = pair[0].as_u64().unwrap();
+                // Payloads either start with the node ID or (for StaleNode) are the node ID:
+                let node_id = pair[1].get(0).unwrap_or(&pair[1]).as_u64().unwrap();
+                if action == 3 {
+                    added.push(node_id);
+                } else {
+                    assert_eq!(
+                        added.last(),
+                        Some(&node_id),
+                        "node mentioned before being added"
+                    );
+                }
+            }
+        }
+
+        let expected: Vec<u64> = (0..200).filter(|&id| id != 100).collect();
+        assert_eq!(added, expected);
+    }
+
+    #[test]
+    fn best_and_finalized_block_messages_carry_hashes() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        let subscribe: FromFeedWebsocket = format!("subscribe:{:?}", BlockHash::from_low_u64_be(1))
+            .parse()
+            .unwrap();
+        inner.handle_from_feed(1.into(), subscribe);
+        rx_from_inner.drain().for_each(drop);
+
+        let best_hash = BlockHash::from_low_u64_be(0xbe57);
+        let finalized_hash = BlockHash::from_low_u64_be(0xf1a1);
+        let send_update = |inner: &mut InnerLoop, payload| {
+            inner.handle_from_shard(
+                1.into(),
+                FromShardWebsocket::Update {
+                    local_id: 1.into(),
+                    payload,
+                },
+            )
+        };
+        send_update(
+            &mut inner,
+            node_message::Payload::BlockImport(Block {
+                hash: best_hash,
+                height: 5,
+            }),
+        );
+        send_update(
+            &mut inner,
+            node_message::Payload::NotifyFinalized(node_message::Finalized {
+                hash: finalized_hash,
+                height: "3".into(),
+            }),
+        );
+
+        // Find the payload of each message by action:
+        let msgs: HashMap<u64, serde_json::Value> = rx_from_inner
+            .drain()
+            .flat_map(|ToFeedWebsocket::Bytes(bytes)| {
+                serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).unwrap()
+            })
+            .collect::<Vec<_>>()
+            .chunks(2)
+            .map(|pair| (pair[0].as_u64().unwrap(), pair[1].clone()))
+            .collect();
+        let hash = |value: &serde_json::Value| -> BlockHash {
+            serde_json::from_value(value.clone()).unwrap()
+        };
+
+        // BestBlock and ImportedBlock:
+        assert_eq!(msgs[&1][0], 5);
+        assert_eq!(hash(&msgs[&1][3]), best_hash);
+        assert_eq!(msgs[&6][1][0], 5);
+        assert_eq!(hash(&msgs[&6][1][1]), best_hash);
+        // BestFinalized and FinalizedBlock:
+        assert_eq!(msgs[&2][0], 3);
+        assert_eq!(hash(&msgs[&2][1]), finalized_hash);
+        assert_eq!(msgs[&7][1], 3);
+        assert_eq!(hash(&msgs[&7][2]), finalized_hash);
+    }
+
+    #[test]
+    fn overridden_locations_are_not_looked_up() {
+        let (tx_to_locator, rx_from_inner) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                skip_private_ip_location: true,
+                location_overrides: vec!["10.0.0.0/8=1.0,2.0,Datacenter".parse().unwrap()],
+                ..opts()
+            },
+        );
+
+        add_node(&mut inner, 1, 1, "10.0.0.1", 1);
+        add_node(&mut inner, 1, 2, "8.8.8.8", 1);
+
+        // Only the address without an override is looked up:
+        let located: Vec<_> = rx_from_inner.drain().map(|(_, ip)| ip).collect();
+        assert_eq!(located, vec![Ipv4Addr::new(8, 8, 8, 8)]);
+
+        let chain = inner
+            .node_state
+            .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(1))
+            .unwrap();
+        let id: usize = node_id(&inner, 1, 1).get_chain_node_id().into();
+        let node = chain.nodes_slice()[id].as_ref().unwrap();
+        assert_eq!(
+            node.location_status(),
+            find_location::LocationStatus::Located
+        );
+        assert_eq!(&*node.location().unwrap().city, "Datacenter");
+    }
+
+    #[test]
+    fn feed_messages_are_counted_by_type() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+
+        let gather_counts = |inner: &mut InnerLoop| -> HashMap<&str, u64> {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_gather_metrics(tx, 0, 0, 0);
+            rx.recv().unwrap().feed_messages.iter().collect()
+        };
+
+        // Subscribe a feed to the only chain, so that it receives every message:
+        add_node_on_chain(&mut inner, 1, 1, "8.8.8.8", 1, "Chain One");
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Json,
+            },
+        );
+        let subscribe: FromFeedWebsocket = format!("subscribe:{:?}", BlockHash::from_low_u64_be(1))
+            .parse()
+            .unwrap();
+        inner.handle_from_feed(1.into(), subscribe);
+        rx_from_inner.drain().for_each(drop);
+        let counts_before = gather_counts(&mut inner);
+
+        add_node_on_chain(&mut inner, 1, 2, "8.8.8.8", 1, "Chain One");
+        add_node_on_chain(&mut inner, 1, 3, "8.8.8.8", 1, "Chain One");
+        inner.handle_from_find_location(
+            node_id(&inner, 1, 2),
+            Some(Arc::new(NodeLocation {
+                latitude: 1.0,
+                longitude: 2.0,
+                city: "Somewhere".into(),
+            })),
+        );
+        inner.remove_nodes_and_broadcast_result(vec![node_id(&inner, 1, 1)]);
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Disconnected);
+
+        // Count the messages that the feed actually received:
+        let mut expected: HashMap<&str, u64> = HashMap::new();
+        for ToFeedWebsocket::Bytes(bytes) in rx_from_inner.drain() {
+            let msgs: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+            for pair in msgs.chunks(2) {
+                let name = match pair[0].as_u64().unwrap() {
+                    3 => "AddedNode",
+                    4 => "RemovedNode",
+                    5 => "LocatedNode",
+                    7 => "FinalizedBlock",
+                    11 => "AddedChain",
+                    12 => "RemovedChain",
+                    _ => continue,
+                };
+                *expected.entry(name).or_default() += 1;
+            }
+        }
+        assert_eq!(expected["AddedNode"], 2);
+        assert_eq!(expected["LocatedNode"], 1);
+        assert_eq!(expected["RemovedChain"], 1);
+
+        let counts = gather_counts(&mut inner);
+        for (name, count) in expected {
+            assert_eq!(
+                counts[name] - counts_before[name],
+                count,
+                "unexpected count of {} messages",
+                name
+            );
+        }
+        assert_eq!(counts["StaleNode"], 0);
+    }
+
+    #[test]
+    fn denylist_changes_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry_core_denylist_{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let new_inner = || {
+            let (tx_to_locator, _rx) = flume::unbounded();
+            InnerLoop::new(
+                tx_to_locator,
+                AggregatorOpts {
+                    denylist: vec!["Configured".into()],
+                    denylist_path: Some(path.clone()),
+                    chain_flaps: Some(ChainFlapOpts {
+                        max_flaps: 0,
+                        window: Duration::from_secs(60),
+                        denylist_for: Duration::from_secs(60),
+                    }),
+                    ..opts()
+                },
+            )
+        };
+        let auto_denylisted_chains = |inner: &InnerLoop| {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_get_auto_denylisted_chains(tx);
+            rx.recv()
+                .unwrap()
+                .into_iter()
+                .map(|chain| chain.genesis_hash)
+                .collect::<Vec<_>>()
+        };
+
+        // Change the denylist at runtime, and have a chain denylisted for flapping:
+        let mut inner = new_inner();
+        let (tx, _rx) = flume::unbounded();
+        inner.handle_replace_denylist(vec!["Runtime".into()], tx);
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+        inner.handle_from_shard(1.into(), FromShardWebsocket::Remove { local_id: 1.into() });
+        assert_eq!(
+            auto_denylisted_chains(&inner),
+            vec![BlockHash::from_low_u64_be(1)]
+        );
+
+        // Both survive a restart, in place of the configured denylist:
+        let inner = new_inner();
+        assert_eq!(inner.node_state.denylist(), vec!["Runtime".to_owned()]);
+        assert_eq!(
+            auto_denylisted_chains(&inner),
+            vec![BlockHash::from_low_u64_be(1)]
+        );
+
+        // And are saved separately:
+        let persisted = PersistedDenylist::load(&path).unwrap().unwrap();
+        assert_eq!(persisted.manual, vec!["Runtime".to_owned()]);
+        assert_eq!(persisted.automatic.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flapping_chains_are_denylisted_for_a_while() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                chain_flaps: Some(ChainFlapOpts {
+                    max_flaps: 1,
+                    window: Duration::from_secs(60),
+                    denylist_for: Duration::from_millis(200),
+                }),
+                ..opts()
+            },
+        );
+        let (tx_to_shard, rx_from_inner) = flume::unbounded();
+        inner.handle_from_shard(
+            1.into(),
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                allowed_chains: None,
+            },
+        );
+        let auto_denylisted_chains = |inner: &InnerLoop| {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_get_auto_denylisted_chains(tx);
+            rx.recv().unwrap()
+        };
+
+        // The chain appears and disappears twice, which is once too often:
+        for local_id in 1..=2 {
+            add_node(&mut inner, 1, local_id, "8.8.8.8", 1);
+            inner.handle_from_shard(
+                1.into(),
+                FromShardWebsocket::Remove {
+                    local_id: local_id.into(),
+                },
+            );
+        }
+        assert_eq!(
+            auto_denylisted_chains(&inner),
+            vec![AutoDenylistedChainView {
+                genesis_hash: BlockHash::from_low_u64_be(1),
+                expires_in_secs: 0,
+            }]
+        );
+
+        // So nodes on it are muted:
+        add_node(&mut inner, 1, 3, "8.8.8.8", 1);
+        assert!(matches!(
+            rx_from_inner.try_recv(),
+            Ok(ToShardWebsocket::Mute {
+                reason: MuteReason::ChainNotAllowed,
+                ..
+            })
+        ));
+        assert_eq!(inner.node_ids.len(), 0);
+
+        // Until the denylisting expires:
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(auto_denylisted_chains(&inner), vec![]);
+        add_node(&mut inner, 1, 4, "8.8.8.8", 1);
+        assert!(rx_from_inner.try_recv().is_err());
+        assert_eq!(inner.node_ids.len(), 1);
+    }
+
+    #[test]
+    fn feeds_can_ask_for_binary_messages() {
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(tx_to_locator, opts());
+        let mut subscribe = |feed_conn_id: u64, encoding| {
+            let (tx_to_feed, rx_from_inner) = flume::unbounded();
+            inner.handle_from_feed(
+                feed_conn_id.into(),
+                FromFeedWebsocket::Initialize {
+                    channel: tx_to_feed,
+                    encoding,
+                },
+            );
+            rx_from_inner
+        };
+        let json_feed = subscribe(1, FeedEncoding::Json);
+        let binary_feed = subscribe(2, FeedEncoding::Binary);
+        add_node(&mut inner, 1, 1, "8.8.8.8", 1);
+
+        // JSON feeds are unaffected by a binary feed being around:
+        for ToFeedWebsocket::Bytes(bytes) in json_feed.drain() {
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap();
+        }
+
+        // Binary feeds get the same messages:
+        let mut actions = Vec::new();
+        for ToFeedWebsocket::Bytes(bytes) in binary_feed.drain() {
+            let mut bytes = &bytes[..];
+            while !bytes.is_empty() {
+                actions.push(bytes[0]);
+                let len = u32::from_le_bytes(bytes[1..5].try_into().unwrap()) as usize;
+                bytes = &bytes[5 + len..];
+            }
+        }
+        // Version, then the new chain being renamed from its placeholder label:
+        assert_eq!(actions, vec![0, 12, 11]);
+
+        // Feeds asking for binary messages get JSON if they aren't allowed:
+        let (tx_to_locator, _rx) = flume::unbounded();
+        let mut inner = InnerLoop::new(
+            tx_to_locator,
+            AggregatorOpts {
+                binary_feeds: false,
+                ..opts()
+            },
+        );
+        let (tx_to_feed, rx_from_inner) = flume::unbounded();
+        inner.handle_from_feed(
+            1.into(),
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+                encoding: FeedEncoding::Binary,
+            },
+        );
+        let ToFeedWebsocket::Bytes(bytes) = rx_from_inner.recv().unwrap();
+        serde_json::from_slice::<serde_json::Value>(&bytes).unwrap();
+    }
 }
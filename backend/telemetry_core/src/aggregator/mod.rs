@@ -16,10 +16,19 @@
 
 mod aggregator;
 mod aggregator_set;
+mod chain_flaps;
+mod denylist_file;
 mod inner_loop;
+mod node_count_thresholds;
+mod node_rate_limits;
+mod prometheus;
 
 // Expose the various message types that can be worked with externally:
 pub use aggregator::AggregatorOpts;
-pub use inner_loop::{FromFeedWebsocket, FromShardWebsocket, ToFeedWebsocket, ToShardWebsocket};
+pub use chain_flaps::ChainFlapOpts;
+pub use inner_loop::{
+    ChainConflictPolicy, FromFeedWebsocket, FromShardWebsocket, ToFeedWebsocket, ToShardWebsocket,
+};
+pub use node_count_thresholds::{ChainNodeCountThreshold, NodeCountThresholdOpts};
 
 pub use aggregator_set::*;
@@ -0,0 +1,189 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Keep track of which node count thresholds each chain is above, so that we can say
+//! when a chain crosses one (for instance, to alert when a chain drops below 10 nodes).
+
+use common::node_types::BlockHash;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+/// Which node counts we want to know about chains crossing.
+#[derive(Debug, Clone, Default)]
+pub struct NodeCountThresholdOpts {
+    /// Thresholds which apply to every chain without thresholds of its own.
+    pub thresholds: Vec<usize>,
+    /// Thresholds for particular chains, which replace `thresholds` for them.
+    pub chain_thresholds: HashMap<BlockHash, Vec<usize>>,
+    /// A chain which has dropped below a threshold needs to reach this many nodes above it
+    /// before it's said to have crossed it again, so that a node count hovering around a
+    /// threshold doesn't keep crossing it.
+    pub hysteresis: usize,
+}
+
+/// A node count threshold for a particular chain. Parsed from strings of the form
+/// `GENESIS_HASH=NODE_COUNT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainNodeCountThreshold {
+    pub genesis_hash: BlockHash,
+    pub threshold: usize,
+}
+
+impl FromStr for ChainNodeCountThreshold {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (genesis_hash, threshold) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected GENESIS_HASH=NODE_COUNT"))?;
+        let genesis_hash = genesis_hash
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid genesis hash '{}'", genesis_hash))?;
+        let threshold = threshold
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid node count '{}'", threshold))?;
+        Ok(ChainNodeCountThreshold {
+            genesis_hash,
+            threshold,
+        })
+    }
+}
+
+/// Which way a chain's node count crossed a threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum ThresholdDirection {
+    Up,
+    Down,
+}
+
+pub struct NodeCountThresholds {
+    opts: NodeCountThresholdOpts,
+    /// The thresholds that each chain is currently above.
+    above: HashMap<BlockHash, HashSet<usize>>,
+}
+
+impl NodeCountThresholds {
+    pub fn new(opts: NodeCountThresholdOpts) -> Self {
+        NodeCountThresholds {
+            opts,
+            above: HashMap::new(),
+        }
+    }
+
+    /// Note the current node count of a chain, returning any thresholds that it's crossed
+    /// since the last time. A chain drops below a threshold as soon as it has fewer nodes
+    /// than it, but only rises above it again once it reaches the threshold plus the
+    /// hysteresis. A chain with no nodes is forgotten about.
+    pub fn update(
+        &mut self,
+        genesis_hash: BlockHash,
+        node_count: usize,
+    ) -> Vec<(usize, ThresholdDirection)> {
+        let thresholds = self
+            .opts
+            .chain_thresholds
+            .get(&genesis_hash)
+            .unwrap_or(&self.opts.thresholds);
+        let above = self.above.entry(genesis_hash).or_default();
+
+        let mut crossed = Vec::new();
+        for &threshold in thresholds {
+            let is_above = above.contains(&threshold);
+            if is_above && node_count < threshold {
+                above.remove(&threshold);
+                crossed.push((threshold, ThresholdDirection::Down));
+            } else if !is_above && node_count >= threshold + self.opts.hysteresis {
+                above.insert(threshold);
+                crossed.push((threshold, ThresholdDirection::Up));
+            }
+        }
+
+        if node_count == 0 {
+            self.above.remove(&genesis_hash);
+        }
+        crossed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn thresholds_are_crossed_once_per_genuine_crossing() {
+        let chain = BlockHash::from_low_u64_be(1);
+        let mut thresholds = NodeCountThresholds::new(NodeCountThresholdOpts {
+            thresholds: vec![10],
+            chain_thresholds: HashMap::new(),
+            hysteresis: 2,
+        });
+        let mut crossings = |counts: &[usize]| -> Vec<_> {
+            counts
+                .iter()
+                .flat_map(|&count| thresholds.update(chain, count))
+                .collect()
+        };
+
+        // Reaching the threshold isn't enough; the chain needs to clear the hysteresis:
+        assert_eq!(crossings(&[1, 9, 10, 11]), vec![]);
+        assert_eq!(crossings(&[12]), vec![(10, ThresholdDirection::Up)]);
+
+        // Dropping below the threshold is noticed straight away, but hovering around it
+        // doesn't count as crossing it again:
+        assert_eq!(
+            crossings(&[11, 10, 9, 10, 11, 9, 8]),
+            vec![(10, ThresholdDirection::Down)]
+        );
+
+        // Recovering properly does:
+        assert_eq!(
+            crossings(&[12, 13, 9]),
+            vec![(10, ThresholdDirection::Up), (10, ThresholdDirection::Down)]
+        );
+    }
+
+    #[test]
+    fn chains_can_have_their_own_thresholds() {
+        let chain = BlockHash::from_low_u64_be(1);
+        let other_chain = BlockHash::from_low_u64_be(2);
+        let mut thresholds = NodeCountThresholds::new(NodeCountThresholdOpts {
+            thresholds: vec![10],
+            chain_thresholds: [(chain, vec![2, 5])].into_iter().collect(),
+            hysteresis: 0,
+        });
+
+        assert_eq!(
+            thresholds.update(chain, 10),
+            vec![(2, ThresholdDirection::Up), (5, ThresholdDirection::Up)]
+        );
+        assert_eq!(
+            thresholds.update(other_chain, 10),
+            vec![(10, ThresholdDirection::Up)]
+        );
+
+        // Chains which disappear drop below everything:
+        assert_eq!(
+            thresholds.update(chain, 0),
+            vec![(2, ThresholdDirection::Down), (5, ThresholdDirection::Down)]
+        );
+        assert!(!thresholds.above.contains_key(&chain));
+
+        let parsed: ChainNodeCountThreshold = format!("{:?}=5", chain).parse().unwrap();
+        assert_eq!(parsed.threshold, 5);
+        assert!("0x01=5".parse::<ChainNodeCountThreshold>().is_err());
+    }
+}
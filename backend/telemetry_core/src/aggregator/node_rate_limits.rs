@@ -0,0 +1,93 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Limit how many messages each node can have handled per second, so that a single chatty
+//! node can't take up all of the aggregator's time. Each node has a bucket of tokens which
+//! refills at the allowed rate, and holds at most a second's worth of them.
+
+use super::aggregator::ConnId;
+use common::internal_messages::ShardNodeId;
+use std::collections::HashMap;
+use std::time::Instant;
+
+pub struct NodeRateLimits {
+    messages_per_second: f64,
+    /// How many messages each node can currently send, and when we last topped that up.
+    buckets: HashMap<(ConnId, ShardNodeId), (f64, Instant)>,
+}
+
+impl NodeRateLimits {
+    pub fn new(messages_per_second: u32) -> Self {
+        NodeRateLimits {
+            messages_per_second: messages_per_second as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token for a message from the given node. Returns false if the node has none
+    /// left, in which case the message should be dropped.
+    pub fn allow(&mut self, node: (ConnId, ShardNodeId), now: Instant) -> bool {
+        let rate = self.messages_per_second;
+        let (tokens, last_refill) = self.buckets.entry(node).or_insert((rate, now));
+
+        let elapsed = now.saturating_duration_since(*last_refill).as_secs_f64();
+        *tokens = (*tokens + elapsed * rate).min(rate);
+        *last_refill = now;
+
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+
+    /// Forget about a node which has gone.
+    pub fn forget(&mut self, node: (ConnId, ShardNodeId)) {
+        self.buckets.remove(&node);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn nodes_are_limited_to_their_rate() {
+        let mut limits = NodeRateLimits::new(2);
+        let chatty = (ConnId::from(1), ShardNodeId::from(1));
+        let quiet = (ConnId::from(1), ShardNodeId::from(2));
+        let start = Instant::now();
+        let millis = |n| start + Duration::from_millis(n);
+
+        // A second's worth of messages can arrive at once, but no more:
+        assert!(limits.allow(chatty, millis(0)));
+        assert!(limits.allow(chatty, millis(0)));
+        assert!(!limits.allow(chatty, millis(0)));
+
+        // Other nodes are unaffected:
+        assert!(limits.allow(quiet, millis(0)));
+
+        // Tokens come back at the allowed rate:
+        assert!(!limits.allow(chatty, millis(250)));
+        assert!(limits.allow(chatty, millis(500)));
+        assert!(!limits.allow(chatty, millis(500)));
+
+        // Nodes that we've forgotten about start afresh:
+        limits.forget(chatty);
+        assert!(limits.allow(chatty, millis(500)));
+    }
+}
@@ -0,0 +1,251 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Render [`Metrics`] in the Prometheus text exposition format (version 0.0.4).
+
+use super::inner_loop::Metrics;
+use std::fmt::Write;
+
+impl Metrics {
+    /// Render these metrics in the Prometheus text format, labelling each one with the
+    /// index of the aggregator that they came from.
+    pub fn to_prometheus(&self, aggregator: usize) -> String {
+        // Instead of using the rust prometheus library (which is optimised around global variables updated across a codebase),
+        // we just split out the text format that prometheus expects ourselves. See:
+        //
+        // https://github.com/prometheus/docs/blob/master/content/docs/instrumenting/exposition_formats.md#text-format-details
+        //
+        // For an example and explanation of this text based format. The minimal output we produce here seems to
+        // be handled correctly when pointing a current version of prometheus at it.
+        //
+        // Note: '{{' and '}}' are just escaped versions of '{' and '}' in Rust fmt strings.
+        let mut s = String::new();
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_connected_feeds{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.connected_feeds, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_keeping_up_feeds{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.keeping_up_feeds, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_lagging_feeds{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.lagging_feeds, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_connected_nodes{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.connected_nodes, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_chains{{aggregator=\"{}\"}} {} {}",
+            aggregator,
+            self.chain_node_counts.len(),
+            self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_connected_shards{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.connected_shards, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_chains_subscribed_to{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.chains_subscribed_to, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_subscribed_feeds{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.subscribed_feeds, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_total_messages_to_feeds{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.total_messages_to_feeds, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_current_messages_to_aggregator{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.current_messages_to_aggregator, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_total_messages_to_aggregator{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.total_messages_to_aggregator, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_dropped_messages_to_aggregator{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.dropped_messages_to_aggregator, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_dropped_processing_errors{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.dropped_processing_errors, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_dropped_events{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.dropped_events, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_disconnected_slow_feeds{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.disconnected_slow_feeds, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_oversized_shard_messages{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.oversized_shard_messages, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_rejected_shard_nodes{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.rejected_shard_nodes, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_rate_limited_node_messages{{aggregator=\"{}\"}} {} {}",
+            aggregator, self.rate_limited_node_messages, self.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_auto_denylisted_chains{{aggregator=\"{}\"}} {} {}",
+            aggregator,
+            self.auto_denylisted_chains.len(),
+            self.timestamp_unix_ms
+        );
+        for (message, count) in self.feed_messages.iter() {
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_feed_messages{{aggregator=\"{}\",message=\"{}\"}} {} {}",
+                aggregator, message, count, self.timestamp_unix_ms
+            );
+        }
+        for (genesis_hash, label) in &self.chain_labels {
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_label{{aggregator=\"{}\",genesis_hash=\"{:?}\",label={:?}}} 1 {}",
+                aggregator, genesis_hash, label, self.timestamp_unix_ms
+            );
+        }
+        for (genesis_hash, node_count) in &self.chain_node_counts {
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_nodes{{aggregator=\"{}\",genesis_hash=\"{:?}\"}} {} {}",
+                aggregator, genesis_hash, node_count, self.timestamp_unix_ms
+            );
+        }
+        for (genesis_hash, chain) in &self.chains {
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_nodes_added{{aggregator=\"{}\",genesis_hash=\"{:?}\"}} {} {}",
+                aggregator, genesis_hash, chain.nodes_added, self.timestamp_unix_ms
+            );
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_nodes_removed{{aggregator=\"{}\",genesis_hash=\"{:?}\"}} {} {}",
+                aggregator, genesis_hash, chain.nodes_removed, self.timestamp_unix_ms
+            );
+            for (group, node_count) in &chain.node_groups {
+                let _ = writeln!(
+                    &mut s,
+                    "telemetry_core_chain_node_group_nodes{{aggregator=\"{}\",genesis_hash=\"{:?}\",group={:?}}} {} {}",
+                    aggregator, genesis_hash, group, node_count, self.timestamp_unix_ms
+                );
+            }
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_best_block{{aggregator=\"{}\",genesis_hash=\"{:?}\"}} {} {}",
+                aggregator, genesis_hash, chain.best_block, self.timestamp_unix_ms
+            );
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_finalized_block{{aggregator=\"{}\",genesis_hash=\"{:?}\"}} {} {}",
+                aggregator, genesis_hash, chain.finalized_block, self.timestamp_unix_ms
+            );
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_finality_gap{{aggregator=\"{}\",genesis_hash=\"{:?}\"}} {} {}",
+                aggregator, genesis_hash, chain.finality_gap, self.timestamp_unix_ms
+            );
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_synced_nodes{{aggregator=\"{}\",genesis_hash=\"{:?}\"}} {} {}",
+                aggregator, genesis_hash, chain.synced_nodes, self.timestamp_unix_ms
+            );
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_syncing_nodes{{aggregator=\"{}\",genesis_hash=\"{:?}\"}} {} {}",
+                aggregator, genesis_hash, chain.syncing_nodes, self.timestamp_unix_ms
+            );
+            if let Some(time_to_finality) = chain.average_time_to_finality {
+                let _ = writeln!(
+                    &mut s,
+                    "telemetry_core_chain_average_time_to_finality_ms{{aggregator=\"{}\",genesis_hash=\"{:?}\"}} {} {}",
+                    aggregator, genesis_hash, time_to_finality, self.timestamp_unix_ms
+                );
+            }
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_types::BlockHash;
+
+    #[test]
+    fn metrics_are_rendered_in_the_prometheus_text_format() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let metrics = Metrics {
+            timestamp_unix_ms: 1234,
+            connected_nodes: 3,
+            current_messages_to_aggregator: 5,
+            dropped_messages_to_aggregator: 2,
+            chain_node_counts: [(genesis_hash, 3)].into_iter().collect(),
+            ..Default::default()
+        };
+
+        let rendered = metrics.to_prometheus(1);
+        let lines: Vec<_> = rendered.lines().filter(|l| !l.is_empty()).collect();
+        for expected in [
+            "telemetry_core_connected_nodes{aggregator=\"1\"} 3 1234".to_owned(),
+            "telemetry_core_chains{aggregator=\"1\"} 1 1234".to_owned(),
+            "telemetry_core_current_messages_to_aggregator{aggregator=\"1\"} 5 1234".to_owned(),
+            "telemetry_core_dropped_messages_to_aggregator{aggregator=\"1\"} 2 1234".to_owned(),
+            format!(
+                "telemetry_core_chain_nodes{{aggregator=\"1\",genesis_hash=\"{:?}\"}} 3 1234",
+                genesis_hash
+            ),
+        ] {
+            assert!(lines.contains(&&*expected), "missing line: {}", expected);
+        }
+
+        // Every line is a metric name, labels, a value and a timestamp:
+        for line in lines {
+            assert_eq!(
+                line.split('}').nth(1).unwrap().split_whitespace().count(),
+                2
+            );
+        }
+    }
+}
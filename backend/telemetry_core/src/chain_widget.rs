@@ -0,0 +1,167 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A cut down feed for small widgets embedded on other pages, which show a summary of
+//! a single chain and where its nodes are. Widgets are sent the usual feed messages for
+//! the chain they're interested in, minus everything that they don't need.
+
+use crate::feed_message::{self, FeedMessage};
+use common::node_types::BlockHash;
+use serde_json::Value;
+
+const BEST_BLOCK: u8 = feed_message::BestBlock::ACTION;
+const BEST_FINALIZED: u8 = feed_message::BestFinalized::ACTION;
+const ADDED_NODE: u8 = feed_message::AddedNode::<'static>::ACTION;
+const REMOVED_NODE: u8 = feed_message::RemovedNode::ACTION;
+const LOCATED_NODE: u8 = feed_message::LocatedNode::<'static>::ACTION;
+const ADDED_CHAIN: u8 = feed_message::AddedChain::<'static>::ACTION;
+const REMOVED_CHAIN: u8 = feed_message::RemovedChain::ACTION;
+const CHAIN_NODE_COUNTS: u8 = feed_message::ChainNodeCounts::ACTION;
+
+/// Keep only the feed messages that a widget showing the given chain needs: the chain's
+/// label and node count, its best and finalized blocks, and where its nodes are. Newly
+/// added nodes are sent as just their location, if it's known. Hands back `None` if there's
+/// nothing left to send.
+pub fn filter_feed_messages(genesis_hash: &BlockHash, bytes: &[u8]) -> Option<bytes::Bytes> {
+    let messages: Vec<Value> = serde_json::from_slice(bytes).ok()?;
+    let genesis_hash = Value::String(format!("{:?}", genesis_hash));
+
+    let mut filtered = Vec::new();
+    for pair in messages.chunks_exact(2) {
+        let (action, payload) = match pair[0].as_u64() {
+            Some(action) => (action as u8, &pair[1]),
+            None => continue,
+        };
+        let keep = match action {
+            // Every feed is told about every chain, but we only care about one:
+            ADDED_CHAIN => payload.get(1) == Some(&genesis_hash),
+            REMOVED_CHAIN => *payload == genesis_hash,
+            CHAIN_NODE_COUNTS => payload.get(0) == Some(&genesis_hash),
+            BEST_BLOCK | BEST_FINALIZED | LOCATED_NODE | REMOVED_NODE => true,
+            ADDED_NODE => {
+                if let (Some(id), Some(Value::Array(location))) = (payload.get(0), payload.get(6)) {
+                    let mut located = vec![id.clone()];
+                    located.extend(location.iter().cloned());
+                    filtered.push(LOCATED_NODE.into());
+                    filtered.push(Value::Array(located));
+                }
+                false
+            }
+            _ => false,
+        };
+        if keep {
+            filtered.push(action.into());
+            filtered.push(payload.clone());
+        }
+    }
+
+    if filtered.is_empty() {
+        return None;
+    }
+    serde_json::to_vec(&filtered).ok().map(Into::into)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed_message::{FeedEncoding, FeedMessageSerializer};
+    use crate::state::Node;
+    use common::node_types::{NetworkId, NodeDetails, NodeLocation};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn node(name: &str) -> Node {
+        Node::new(NodeDetails {
+            chain: "Chain One".into(),
+            name: name.into(),
+            implementation: "Bar".into(),
+            version: "0.1".into(),
+            validator: None,
+            network_id: NetworkId::new(),
+            startup_time: None,
+            target_os: None,
+            target_arch: None,
+            target_env: None,
+            sysinfo: None,
+        })
+    }
+
+    #[test]
+    fn widgets_only_get_chain_summaries_and_node_locations() {
+        let chain_one = BlockHash::from_low_u64_be(1);
+        let chain_two = BlockHash::from_low_u64_be(2);
+
+        let mut located = node("A");
+        located.update_location(Some(Arc::new(NodeLocation {
+            latitude: 52.5,
+            longitude: 13.25,
+            city: "Berlin".into(),
+        })));
+        let unlocated = node("B");
+
+        let mut feed = FeedMessageSerializer::new();
+        feed.push(feed_message::TimeSync(1234));
+        feed.push(feed_message::AddedChain("Chain One", chain_one, 2));
+        feed.push(feed_message::AddedChain("Chain Two", chain_two, 5));
+        feed.push(feed_message::ChainNodeCounts(chain_one, 3, 3));
+        feed.push(feed_message::ChainNodeCounts(chain_two, 6, 6));
+        feed.push(feed_message::BestBlock(10, 1234, None, BlockHash::zero()));
+        feed.push(feed_message::BestFinalized(8, BlockHash::zero()));
+        feed.push(feed_message::AddedNode(0, &located));
+        feed.push(feed_message::AddedNode(1, &unlocated));
+        feed.push(feed_message::NodeStatsUpdate(0, located.stats()));
+        feed.push(feed_message::LocatedNode(1, 1.5, 2.5, "Elsewhere"));
+        feed.push(feed_message::RemovedNode(0));
+        feed.push(feed_message::RemovedChain(chain_two));
+        let bytes = feed
+            .into_finalized_encodings()
+            .unwrap()
+            .encoded_as(FeedEncoding::Json);
+
+        let filtered = filter_feed_messages(&chain_one, &bytes).unwrap();
+        let filtered: Value = serde_json::from_slice(&filtered).unwrap();
+        let zero_hash = format!("{:?}", BlockHash::zero());
+        assert_eq!(
+            filtered,
+            json!([
+                ADDED_CHAIN,
+                ["Chain One", format!("{:?}", chain_one), 2],
+                CHAIN_NODE_COUNTS,
+                [format!("{:?}", chain_one), 3, 3],
+                BEST_BLOCK,
+                [10, 1234, null, zero_hash],
+                BEST_FINALIZED,
+                [8, zero_hash],
+                LOCATED_NODE,
+                [0, 52.5, 13.25, "Berlin"],
+                LOCATED_NODE,
+                [1, 1.5, 2.5, "Elsewhere"],
+                REMOVED_NODE,
+                0,
+            ])
+        );
+
+        // Nothing is sent if there's nothing of interest:
+        let mut feed = FeedMessageSerializer::new();
+        feed.push(feed_message::TimeSync(1234));
+        feed.push(feed_message::RemovedChain(chain_two));
+        let bytes = feed
+            .into_finalized_encodings()
+            .unwrap()
+            .encoded_as(FeedEncoding::Json);
+        assert_eq!(filter_feed_messages(&chain_one, &bytes), None);
+    }
+}
@@ -18,6 +18,8 @@
 //! send to subscribed feeds (browsers).
 
 use serde::Serialize;
+use std::ops::Range;
+use std::sync::OnceLock;
 
 use crate::state::Node;
 use common::node_types::{
@@ -44,9 +46,28 @@ where
     }
 }
 
+/// How messages are encoded for a feed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FeedEncoding {
+    /// A JSON array of alternating actions and payloads. This is what the UI expects.
+    #[default]
+    Json,
+    /// Each message is its action, followed by the length of its payload as a
+    /// little-endian `u32`, followed by the bincode encoded payload. Payloads are
+    /// the same tuples that are sent as JSON, and actions match the JSON ones.
+    Binary,
+}
+
 pub struct FeedMessageSerializer {
     /// Current buffer,
     buffer: Vec<u8>,
+    /// Messages in the binary encoding, if we've been asked to produce them too.
+    binary_buffer: Option<Vec<u8>>,
+    /// Where each message about an individual node is in the JSON buffer, including
+    /// the '[' or ',' before it, so that they can be left out of summaries.
+    node_message_spans: Vec<Range<usize>>,
+    /// How many of each message we've serialized.
+    counts: FeedMessageCounts,
 }
 
 const BUFCAP: usize = 128;
@@ -55,6 +76,26 @@ impl FeedMessageSerializer {
     pub fn new() -> Self {
         Self {
             buffer: Vec::with_capacity(BUFCAP),
+            binary_buffer: None,
+            node_message_spans: Vec::new(),
+            counts: FeedMessageCounts::default(),
+        }
+    }
+
+    /// Serialize messages to both JSON and the binary encoding, so that the result
+    /// can be sent to feeds using either of them.
+    pub fn with_binary() -> Self {
+        Self {
+            binary_buffer: Some(Vec::with_capacity(BUFCAP)),
+            ..Self::new()
+        }
+    }
+
+    /// A serializer which can produce messages for feeds using the given encoding.
+    pub fn for_encoding(encoding: FeedEncoding) -> Self {
+        match encoding {
+            FeedEncoding::Json => Self::new(),
+            FeedEncoding::Binary => Self::with_binary(),
         }
     }
 
@@ -67,10 +108,26 @@ impl FeedMessageSerializer {
             _ => b',',
         };
 
+        let start = self.buffer.len();
         self.buffer.push(glue);
-        self.write(&Message::ACTION);
+        self.counts.0[Message::ACTION as usize] += 1;
+        let _ = to_writer(&mut self.buffer, &Message::ACTION);
         self.buffer.push(b',');
+
+        // Leave space for the payload length, and fill it in once we know it:
+        let length_offset = self.binary_buffer.as_mut().map(|binary_buffer| {
+            binary_buffer.push(Message::ACTION);
+            binary_buffer.extend_from_slice(&[0; 4]);
+            binary_buffer.len()
+        });
         msg.write_to_feed(self);
+        if let (Some(binary_buffer), Some(offset)) = (&mut self.binary_buffer, length_offset) {
+            let length = (binary_buffer.len() - offset) as u32;
+            binary_buffer[offset - 4..offset].copy_from_slice(&length.to_le_bytes());
+        }
+        if is_node_message(Message::ACTION) {
+            self.node_message_spans.push(start..self.buffer.len());
+        }
     }
 
     fn write<S>(&mut self, value: &S)
@@ -78,26 +135,210 @@ impl FeedMessageSerializer {
         S: Serialize,
     {
         let _ = to_writer(&mut self.buffer, value);
+        if let Some(binary_buffer) = &mut self.binary_buffer {
+            let _ = bincode::serialize_into(binary_buffer, value);
+        }
+    }
+
+    /// Append the messages pushed to another serializer to the ones pushed to this one.
+    /// Both serializers should have been asked for the same encodings.
+    pub fn extend(&mut self, other: FeedMessageSerializer) {
+        if other.buffer.is_empty() {
+            return;
+        }
+        // The other messages end up this far into our buffer:
+        let offset = self.buffer.len();
+        self.node_message_spans.extend(
+            other
+                .node_message_spans
+                .into_iter()
+                .map(|span| span.start + offset..span.end + offset),
+        );
+        if self.buffer.is_empty() {
+            self.buffer = other.buffer;
+        } else {
+            // Swap the opening '[' of the other messages for a ',' to join them up:
+            self.buffer.push(b',');
+            self.buffer.extend_from_slice(&other.buffer[1..]);
+        }
+        if let (Some(binary_buffer), Some(other_binary_buffer)) =
+            (&mut self.binary_buffer, other.binary_buffer)
+        {
+            binary_buffer.extend(other_binary_buffer);
+        }
+        self.counts.add(&other.counts);
+    }
+
+    /// How many bytes the JSON messages serialized so far take up. Finalizing them adds
+    /// one more byte. Joining two serializers with [`FeedMessageSerializer::extend`] gives
+    /// one whose length is the sum of theirs.
+    pub fn current_byte_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// How many of each message have been pushed to this serializer.
+    pub fn counts(&self) -> &FeedMessageCounts {
+        &self.counts
     }
 
-    /// Return the bytes that we've serialized so far, consuming the serializer.
-    pub fn into_finalized(mut self) -> Option<bytes::Bytes> {
+    /// Return the bytes that we've serialized so far in each encoding we were
+    /// asked for, consuming the serializer.
+    pub fn into_finalized_encodings(mut self) -> Option<FinalizedFeedMessages> {
         if self.buffer.is_empty() {
             return None;
         }
 
         self.buffer.push(b']');
-        Some(self.buffer.into())
+        Some(FinalizedFeedMessages {
+            json: self.buffer.into(),
+            binary: self.binary_buffer.map(Into::into),
+            node_message_spans: self.node_message_spans,
+            summary: OnceLock::new(),
+        })
     }
 }
 
+/// Messages about individual nodes, which feeds that only want a summary of
+/// each chain aren't sent.
+const NODE_MESSAGE_ACTIONS: &[u8] = &[
+    AddedNode::ACTION,
+    RemovedNode::ACTION,
+    LocatedNode::ACTION,
+    ImportedBlock::ACTION,
+    FinalizedBlock::ACTION,
+    NodeStatsUpdate::ACTION,
+    Hardware::ACTION,
+    StaleNode::ACTION,
+    NodeIOUpdate::ACTION,
+    NodeGroup::ACTION,
+    BlockPropagation::ACTION,
+];
+
+/// Is the message with this action about an individual node?
+fn is_node_message(action: u8) -> bool {
+    NODE_MESSAGE_ACTIONS.contains(&action)
+}
+
+/// Finalized feed messages, ready to be sent to feeds.
+#[derive(Debug, Clone)]
+pub struct FinalizedFeedMessages {
+    json: bytes::Bytes,
+    binary: Option<bytes::Bytes>,
+    /// Where each message about an individual node is in the JSON.
+    node_message_spans: Vec<Range<usize>>,
+    /// The same messages minus those about individual nodes, worked out
+    /// the first time that somebody asks for them.
+    summary: OnceLock<Option<Box<FinalizedFeedMessages>>>,
+}
+
+impl FinalizedFeedMessages {
+    /// The messages in the given encoding. If they weren't serialized to the binary
+    /// encoding, we fall back to JSON.
+    pub fn encoded_as(&self, encoding: FeedEncoding) -> bytes::Bytes {
+        match (encoding, &self.binary) {
+            (FeedEncoding::Binary, Some(binary)) => binary.clone(),
+            _ => self.json.clone(),
+        }
+    }
+
+    /// These messages without any of the ones about individual nodes, or `None` if
+    /// every message was about an individual node.
+    pub fn summary(&self) -> Option<&FinalizedFeedMessages> {
+        if self.node_message_spans.is_empty() {
+            return Some(self);
+        }
+        self.summary
+            .get_or_init(|| {
+                // Copy the JSON between the node messages. Each span includes the glue
+                // before it, so the first message kept may start with a ',' instead of '[':
+                let mut json = Vec::with_capacity(self.json.len());
+                let mut last_end = 0;
+                for span in &self.node_message_spans {
+                    json.extend_from_slice(&self.json[last_end..span.start]);
+                    last_end = span.end;
+                }
+                json.extend_from_slice(&self.json[last_end..]);
+                if json.len() <= 1 {
+                    return None;
+                }
+                json[0] = b'[';
+
+                // Binary messages are self-delimiting, so we can just skip over the ones
+                // about individual nodes:
+                let binary = self.binary.as_ref().map(|binary| {
+                    let mut summary = Vec::with_capacity(binary.len());
+                    let mut rest = &binary[..];
+                    while rest.len() >= 5 {
+                        let len = u32::from_le_bytes([rest[1], rest[2], rest[3], rest[4]]);
+                        let (message, remaining) = rest.split_at(5 + len as usize);
+                        if !is_node_message(message[0]) {
+                            summary.extend_from_slice(message);
+                        }
+                        rest = remaining;
+                    }
+                    summary.into()
+                });
+
+                Some(Box::new(FinalizedFeedMessages {
+                    json: json.into(),
+                    binary,
+                    node_message_spans: Vec::new(),
+                    summary: OnceLock::new(),
+                }))
+            })
+            .as_deref()
+    }
+}
+
+/// A count of feed messages, by type.
+#[derive(Debug, Clone, Copy)]
+pub struct FeedMessageCounts([u64; ACTION_SLOTS]);
+
+impl Default for FeedMessageCounts {
+    fn default() -> Self {
+        FeedMessageCounts([0; ACTION_SLOTS])
+    }
+}
+
+impl FeedMessageCounts {
+    /// Add another set of counts to these ones.
+    pub fn add(&mut self, other: &FeedMessageCounts) {
+        for (count, other_count) in self.0.iter_mut().zip(other.0.iter()) {
+            *count += other_count;
+        }
+    }
+
+    /// Iterate over the name of each type of message and how many of them there are.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        ACTION_NAMES
+            .iter()
+            .map(move |&(action, name)| (name, self.0[action as usize]))
+    }
+}
+
+/// Enough space to index counts by any action.
+const ACTION_SLOTS: usize = {
+    let mut max = 0;
+    let mut idx = 0;
+    while idx < ACTION_NAMES.len() {
+        if ACTION_NAMES[idx].0 > max {
+            max = ACTION_NAMES[idx].0;
+        }
+        idx += 1;
+    }
+    max as usize + 1
+};
+
 macro_rules! actions {
-    ($($action:literal: $t:ty,)*) => {
+    ($($action:literal: $name:ident $(<$lt:lifetime>)?,)*) => {
         $(
-            impl FeedMessage for $t {
+            impl FeedMessage for $name $(<$lt>)? {
                 const ACTION: u8 = $action;
             }
         )*
+
+        /// The action and name of every type of message.
+        const ACTION_NAMES: &[(u8, &str)] = &[$(($action, stringify!($name)),)*];
     }
 }
 
@@ -123,13 +364,26 @@ actions! {
     20: StaleNode,
     21: NodeIOUpdate<'_>,
     22: ChainStatsUpdate<'_>,
+    23: AverageTimeToFinality,
+    24: NodeGroup<'_>,
+    25: ChainDecentralization,
+    26: VersionCompliance,
+    27: SyncBreakdown,
+    28: BlockPropagation,
+    29: AuthoritySet,
+    30: ChainNodeCounts,
 }
 
 #[derive(Serialize)]
 pub struct Version(pub usize);
 
 #[derive(Serialize)]
-pub struct BestBlock(pub BlockNumber, pub Timestamp, pub Option<u64>);
+pub struct BestBlock(
+    pub BlockNumber,
+    pub Timestamp,
+    pub Option<u64>,
+    pub BlockHash,
+);
 
 #[derive(Serialize)]
 pub struct BestFinalized(pub BlockNumber, pub BlockHash);
@@ -148,6 +402,11 @@ pub struct ImportedBlock<'a>(pub FeedNodeId, pub &'a BlockDetails);
 #[derive(Serialize)]
 pub struct FinalizedBlock(pub FeedNodeId, pub BlockNumber, pub BlockHash);
 
+/// A node imported a block that another node on the chain imported first, followed
+/// by how many milliseconds it had been since that first import.
+#[derive(Serialize)]
+pub struct BlockPropagation(pub FeedNodeId, pub BlockNumber, pub u64);
+
 #[derive(Serialize)]
 pub struct NodeStatsUpdate<'a>(pub FeedNodeId, pub &'a NodeStats);
 
@@ -166,6 +425,11 @@ pub struct AddedChain<'a>(pub &'a str, pub BlockHash, pub usize);
 #[derive(Serialize)]
 pub struct RemovedChain(pub BlockHash);
 
+/// The node count of a chain that feeds already know the label of, and the most
+/// nodes it has had at once.
+#[derive(Serialize)]
+pub struct ChainNodeCounts(pub BlockHash, pub usize, pub usize);
+
 #[derive(Serialize)]
 pub struct SubscribedTo(pub BlockHash);
 
@@ -178,6 +442,30 @@ pub struct Pong<'a>(pub &'a str);
 #[derive(Serialize)]
 pub struct StaleNode(pub FeedNodeId);
 
+#[derive(Serialize)]
+pub struct AverageTimeToFinality(pub Option<u64>);
+
+#[derive(Serialize)]
+pub struct NodeGroup<'a>(pub FeedNodeId, pub &'a str);
+
+/// How geographically decentralized a chain is, from 0 to 1, followed by
+/// the fraction of its nodes that were located in order to work that out.
+#[derive(Serialize)]
+pub struct ChainDecentralization(pub f64, pub f64);
+
+/// How many nodes on a chain run at least its recommended version, followed by
+/// how many run an older one. Nodes reporting versions we can't parse aren't counted.
+#[derive(Serialize)]
+pub struct VersionCompliance(pub BlockHash, pub usize, pub usize);
+
+/// The ID of the newest GRANDPA authority set of a chain.
+#[derive(Serialize)]
+pub struct AuthoritySet(pub u64);
+
+/// How many nodes on a chain are synced, followed by how many are still syncing.
+#[derive(Serialize)]
+pub struct SyncBreakdown(pub BlockHash, pub usize, pub usize);
+
 impl FeedMessageWrite for AddedNode<'_> {
     fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
         let AddedNode(nid, node) = self;
@@ -230,3 +518,198 @@ pub struct ChainStats {
     pub disk_sequential_write_score: Ranking<(u32, Option<u32>)>,
     pub disk_random_write_score: Ranking<(u32, Option<u32>)>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_types::{NetworkId, NodeDetails};
+
+    /// Split binary encoded messages into each action and its payload.
+    fn binary_messages(mut bytes: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut messages = Vec::new();
+        while !bytes.is_empty() {
+            let action = bytes[0];
+            let length = u32::from_le_bytes(bytes[1..5].try_into().unwrap()) as usize;
+            messages.push((action, &bytes[5..5 + length]));
+            bytes = &bytes[5 + length..];
+        }
+        messages
+    }
+
+    #[test]
+    fn messages_round_trip_through_the_binary_encoding() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let node = Node::new(NodeDetails {
+            chain: "Chain One".into(),
+            name: "Alice".into(),
+            implementation: "Bar".into(),
+            version: "0.1".into(),
+            validator: Some("5Validator".into()),
+            network_id: NetworkId::from("12D3Koo").unwrap(),
+            startup_time: None,
+            target_os: None,
+            target_arch: None,
+            target_env: None,
+            sysinfo: None,
+        });
+
+        let mut feed = FeedMessageSerializer::with_binary();
+        feed.push(AddedChain("Chain One", genesis_hash, 2));
+        feed.push(AddedNode(3, &node));
+        feed.push(RemovedNode(4));
+        feed.push(LocatedNode(5, 52.5, 13.25, "Berlin"));
+        feed.push(FinalizedBlock(6, 100, genesis_hash));
+        feed.push(StaleNode(7));
+        let messages = feed.into_finalized_encodings().unwrap();
+        let binary = messages.encoded_as(FeedEncoding::Binary);
+        let messages = binary_messages(&binary);
+
+        let actions: Vec<u8> = messages.iter().map(|&(action, _)| action).collect();
+        assert_eq!(
+            actions,
+            vec![
+                AddedChain::ACTION,
+                AddedNode::ACTION,
+                RemovedNode::ACTION,
+                LocatedNode::ACTION,
+                FinalizedBlock::ACTION,
+                StaleNode::ACTION
+            ]
+        );
+
+        let added_chain: (String, BlockHash, usize) = bincode::deserialize(messages[0].1).unwrap();
+        assert_eq!(added_chain, ("Chain One".to_owned(), genesis_hash, 2));
+
+        // Only the start of an added node is decoded; the rest can't be deserialized again:
+        type NodeDetailsTuple = (String, String, String, Option<String>, String);
+        let added_node: (usize, NodeDetailsTuple, NodeStats) =
+            bincode::deserialize(messages[1].1).unwrap();
+        assert_eq!(added_node.0, 3);
+        assert_eq!(
+            added_node.1,
+            (
+                "Alice".to_owned(),
+                "Bar".to_owned(),
+                "0.1".to_owned(),
+                Some("5Validator".to_owned()),
+                "12D3Koo".to_owned()
+            )
+        );
+        assert_eq!(added_node.2, *node.stats());
+
+        let removed_node: usize = bincode::deserialize(messages[2].1).unwrap();
+        assert_eq!(removed_node, 4);
+
+        let located_node: (usize, f32, f32, String) = bincode::deserialize(messages[3].1).unwrap();
+        assert_eq!(located_node, (5, 52.5, 13.25, "Berlin".to_owned()));
+
+        let finalized_block: (usize, BlockNumber, BlockHash) =
+            bincode::deserialize(messages[4].1).unwrap();
+        assert_eq!(finalized_block, (6, 100, genesis_hash));
+
+        let stale_node: usize = bincode::deserialize(messages[5].1).unwrap();
+        assert_eq!(stale_node, 7);
+    }
+
+    #[test]
+    fn json_is_unchanged_when_also_encoding_to_binary() {
+        let mut json_only = FeedMessageSerializer::new();
+        let mut both = FeedMessageSerializer::with_binary();
+        for feed in [&mut json_only, &mut both] {
+            feed.push(AddedChain("Chain One", BlockHash::zero(), 2));
+            feed.push(StaleNode(7));
+        }
+        let both = both.into_finalized_encodings().unwrap();
+        assert_eq!(
+            both.encoded_as(FeedEncoding::Json),
+            json_only
+                .into_finalized_encodings()
+                .unwrap()
+                .encoded_as(FeedEncoding::Json)
+        );
+
+        // Without the binary encoding, feeds asking for it get JSON:
+        let mut json_only = FeedMessageSerializer::new();
+        json_only.push(StaleNode(7));
+        let json_only = json_only.into_finalized_encodings().unwrap();
+        assert_eq!(
+            json_only.encoded_as(FeedEncoding::Binary),
+            json_only.encoded_as(FeedEncoding::Json)
+        );
+    }
+
+    #[test]
+    fn serializers_can_be_joined_up() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut all = FeedMessageSerializer::with_binary();
+        let mut first = FeedMessageSerializer::with_binary();
+        let mut second = FeedMessageSerializer::with_binary();
+        for (feed, len) in [(&mut all, 0), (&mut first, 0)] {
+            assert_eq!(feed.current_byte_len(), len);
+            feed.push(AddedChain("Chain One", genesis_hash, 2));
+        }
+        for feed in [&mut all, &mut second] {
+            feed.push(FinalizedBlock(6, 100, genesis_hash));
+            feed.push(StaleNode(7));
+        }
+        let expected_len = first.current_byte_len() + second.current_byte_len();
+
+        first.extend(second);
+        assert_eq!(first.current_byte_len(), expected_len);
+        assert_eq!(first.counts().0, all.counts().0);
+        let joined = first.into_finalized_encodings().unwrap();
+        let all = all.into_finalized_encodings().unwrap();
+        let json = joined.encoded_as(FeedEncoding::Json);
+        assert_eq!(json.len(), expected_len + 1);
+        assert_eq!(json, all.encoded_as(FeedEncoding::Json));
+        assert_eq!(
+            joined.encoded_as(FeedEncoding::Binary),
+            all.encoded_as(FeedEncoding::Binary)
+        );
+    }
+
+    #[test]
+    fn summaries_leave_out_messages_about_nodes() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+
+        // Node messages at the start, middle and end, split across joined serializers:
+        let mut feed = FeedMessageSerializer::with_binary();
+        feed.push(StaleNode(7));
+        feed.push(AddedChain("Chain One", genesis_hash, 2));
+        let mut rest = FeedMessageSerializer::with_binary();
+        rest.push(FinalizedBlock(6, 100, genesis_hash));
+        rest.push(RemovedChain(genesis_hash));
+        rest.push(RemovedNode(4));
+        feed.extend(rest);
+        let messages = feed.into_finalized_encodings().unwrap();
+
+        let summary = messages.summary().unwrap();
+        let json: serde_json::Value =
+            serde_json::from_slice(&summary.encoded_as(FeedEncoding::Json)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([11, ["Chain One", genesis_hash, 2], 12, genesis_hash])
+        );
+        let binary = summary.encoded_as(FeedEncoding::Binary);
+        let actions: Vec<u8> = binary_messages(&binary)
+            .iter()
+            .map(|&(action, _)| action)
+            .collect();
+        assert_eq!(actions, vec![AddedChain::ACTION, RemovedChain::ACTION]);
+
+        // Nothing is left if every message is about a node:
+        let mut feed = FeedMessageSerializer::new();
+        feed.push(StaleNode(7));
+        feed.push(RemovedNode(4));
+        assert!(feed.into_finalized_encodings().unwrap().summary().is_none());
+
+        // And nothing is taken away if no message is:
+        let mut feed = FeedMessageSerializer::new();
+        feed.push(RemovedChain(genesis_hash));
+        let messages = feed.into_finalized_encodings().unwrap();
+        assert_eq!(
+            messages.summary().unwrap().encoded_as(FeedEncoding::Json),
+            messages.encoded_as(FeedEncoding::Json)
+        );
+    }
+}
@@ -14,37 +14,228 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::{Sink, SinkExt};
 use parking_lot::RwLock;
 use rustc_hash::FxHashMap;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use anyhow::Context;
-use common::node_types::NodeLocation;
+use common::node_types::{NodeLocation, Timestamp};
+use common::time;
+use ipnet::IpNet;
 use tokio::sync::Semaphore;
 
 /// The returned location is optional; it may be None if not found.
 pub type Location = Option<Arc<NodeLocation>>;
 
+/// What do we know about the location of a node?
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum LocationStatus {
+    /// We don't know where the node is (yet).
+    Unknown,
+    /// The node has been located.
+    Located,
+    /// The node reported a private address, so we didn't try to locate it.
+    Private,
+}
+
+/// Is the IP address one that can't meaningfully be geographically located?
+/// This covers private (RFC1918/ULA), loopback, link-local and unspecified addresses.
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => {
+            let first_segment = ip.segments()[0];
+            let is_unique_local = (first_segment & 0xfe00) == 0xfc00;
+            let is_unicast_link_local = (first_segment & 0xffc0) == 0xfe80;
+            ip.is_loopback() || ip.is_unspecified() || is_unique_local || is_unicast_link_local
+        }
+    }
+}
+
+/// Every IP address in the given range is known to be at the given location.
+/// Parsed from strings of the form `CIDR=LATITUDE,LONGITUDE,CITY`,
+/// for example `10.0.0.0/8=52.52,13.40,Berlin`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct LocationOverride {
+    range: IpNet,
+    location: Arc<NodeLocation>,
+}
+
+impl FromStr for LocationOverride {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, location) = s
+            .split_once('=')
+            .with_context(|| "Expected CIDR=LATITUDE,LONGITUDE,CITY")?;
+        let range = range
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid CIDR range '{}'", range))?;
+
+        // The city is last, so that it can contain commas:
+        let mut parts = location.splitn(3, ',');
+        let mut next_part = |name| {
+            parts
+                .next()
+                .map(str::trim)
+                .with_context(|| format!("Missing {} in location '{}'", name, location))
+        };
+        let latitude = next_part("latitude")?
+            .parse()
+            .with_context(|| "Invalid latitude")?;
+        let longitude = next_part("longitude")?
+            .parse()
+            .with_context(|| "Invalid longitude")?;
+        let city = next_part("city")?.into();
+
+        Ok(LocationOverride {
+            range,
+            location: Arc::new(NodeLocation {
+                latitude,
+                longitude,
+                city,
+            }),
+        })
+    }
+}
+
+impl TryFrom<String> for LocationOverride {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// A set of [`LocationOverride`]s. If the ranges overlap, the most specific
+/// range containing an IP address decides its location.
+#[derive(Debug, Clone, Default)]
+pub struct LocationOverrides(Vec<LocationOverride>);
+
+impl LocationOverrides {
+    pub fn new(mut overrides: Vec<LocationOverride>) -> Self {
+        // Longest prefixes first, so that the first match is the most specific:
+        overrides.sort_by_key(|o| std::cmp::Reverse(o.range.prefix_len()));
+        LocationOverrides(overrides)
+    }
+
+    /// Find the overridden location of this IP address, if there is one.
+    pub fn find(&self, ip: IpAddr) -> Option<Arc<NodeLocation>> {
+        self.0
+            .iter()
+            .find(|o| o.range.contains(&ip))
+            .map(|o| o.location.clone())
+    }
+}
+
+/// The distance in km between two locations, along the surface of the earth.
+pub fn distance_km(a: &NodeLocation, b: &NodeLocation) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let (lat_a, lat_b) = (
+        (a.latitude as f64).to_radians(),
+        (b.latitude as f64).to_radians(),
+    );
+    let delta_lat = lat_b - lat_a;
+    let delta_lon = (b.longitude as f64 - a.longitude as f64).to_radians();
+
+    // The haversine formula:
+    let h = (delta_lat / 2.0).sin().powi(2)
+        + lat_a.cos() * lat_b.cos() * (delta_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+/// How often newly found locations are saved to the location cache file.
+const LOCATION_CACHE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keep the locations that we've found in a file, so that nodes can be located
+/// straight away after a restart.
+#[derive(Debug, Clone)]
+pub struct LocationCacheOpts {
+    /// The JSON file that locations are loaded from on startup, and saved to.
+    pub path: PathBuf,
+    /// Locations found longer ago than this are looked up again.
+    pub ttl: Duration,
+}
+
+/// A location that we've found, and when we found it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CachedLocation {
+    location: Arc<NodeLocation>,
+    located_at: Timestamp,
+}
+
+impl CachedLocation {
+    /// Locations that are built in rather than looked up never expire.
+    fn builtin(location: NodeLocation) -> Self {
+        CachedLocation {
+            location: Arc::new(location),
+            located_at: Timestamp::MAX,
+        }
+    }
+
+    fn is_expired(&self, now: Timestamp, ttl: Duration) -> bool {
+        now.saturating_sub(self.located_at) > ttl.as_millis() as u64
+    }
+}
+
+/// Load the locations saved in a cache file, leaving out any that have expired.
+/// If the file doesn't exist yet, there's nothing to load.
+fn load_location_cache(
+    path: &Path,
+    ttl: Duration,
+) -> anyhow::Result<FxHashMap<Ipv4Addr, CachedLocation>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(FxHashMap::default()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Could not read location cache {:?}", path))
+        }
+    };
+    let mut cache: FxHashMap<Ipv4Addr, CachedLocation> = serde_json::from_slice(&bytes)
+        .with_context(|| format!("Could not decode location cache {:?}", path))?;
+
+    let now = time::now();
+    cache.retain(|_, cached| !cached.is_expired(now, ttl));
+    Ok(cache)
+}
+
 /// This is responsible for taking an IP address and attempting
 /// to find a geographical location from this
-pub fn find_location<Id, R>(response_chan: R) -> flume::Sender<(Id, Ipv4Addr)>
+pub fn find_location<Id, R>(
+    response_chan: R,
+    cache_opts: Option<LocationCacheOpts>,
+) -> flume::Sender<(Id, Ipv4Addr)>
 where
     R: Sink<(Id, Option<Arc<NodeLocation>>)> + Unpin + Send + Clone + 'static,
     Id: Clone + Send + 'static,
 {
     let (tx, rx) = flume::unbounded();
 
-    // cache entries
-    let mut cache: FxHashMap<Ipv4Addr, Arc<NodeLocation>> = FxHashMap::default();
+    // cache entries, starting with any that were saved last time
+    let mut cache = match &cache_opts {
+        Some(opts) => load_location_cache(&opts.path, opts.ttl).unwrap_or_else(|e| {
+            log::warn!("Starting with an empty location cache: {:#}", e);
+            FxHashMap::default()
+        }),
+        None => FxHashMap::default(),
+    };
 
     // Default entry for localhost
     cache.insert(
         Ipv4Addr::new(127, 0, 0, 1),
-        Arc::new(NodeLocation {
+        CachedLocation::builtin(NodeLocation {
             latitude: 52.516_6667,
             longitude: 13.4,
             city: "Berlin".into(),
@@ -52,7 +243,27 @@ where
     );
 
     // Create a locator with our cache. This is used to obtain locations.
-    let locator = Locator::new(cache);
+    let locator = Locator::new(cache, cache_opts.as_ref().map(|opts| opts.ttl));
+
+    // Periodically save any new locations that we find:
+    if let Some(opts) = cache_opts {
+        let locator = locator.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(LOCATION_CACHE_SAVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if !locator.is_dirty.swap(false, Ordering::Relaxed) {
+                    continue;
+                }
+                let locator = locator.clone();
+                let path = opts.path.clone();
+                let saved = tokio::task::spawn_blocking(move || locator.save(&path)).await;
+                if let Ok(Err(e)) = saved {
+                    log::warn!("Couldn't save location cache: {:#}", e);
+                }
+            }
+        });
+    }
 
     // Spawn a loop to handle location requests
     tokio::spawn(async move {
@@ -60,22 +271,21 @@ where
         // number of concurrent location requests is more than this.
         let semaphore = Arc::new(Semaphore::new(4));
 
-        loop {
-            while let Ok((id, ip_address)) = rx.recv_async().await {
-                let permit = semaphore.clone().acquire_owned().await.unwrap();
-                let mut response_chan = response_chan.clone();
-                let locator = locator.clone();
-
-                // Once we have acquired our permit, spawn a task to avoid
-                // blocking this loop so that we can handle concurrent requests.
-                tokio::spawn(async move {
-                    let location = locator.locate(ip_address).await;
-                    let _ = response_chan.send((id, location)).await;
-
-                    // ensure permit is moved into task by dropping it explicitly:
-                    drop(permit);
-                });
-            }
+        // Keep going until nobody is left to make requests.
+        while let Ok((id, ip_address)) = rx.recv_async().await {
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let mut response_chan = response_chan.clone();
+            let locator = locator.clone();
+
+            // Once we have acquired our permit, spawn a task to avoid
+            // blocking this loop so that we can handle concurrent requests.
+            tokio::spawn(async move {
+                let location = locator.locate(ip_address).await;
+                let _ = response_chan.send((id, location)).await;
+
+                // ensure permit is moved into task by dropping it explicitly:
+                drop(permit);
+            });
         }
     });
 
@@ -87,24 +297,61 @@ where
 #[derive(Clone)]
 struct Locator {
     client: reqwest::Client,
-    cache: Arc<RwLock<FxHashMap<Ipv4Addr, Arc<NodeLocation>>>>,
+    cache: Arc<RwLock<FxHashMap<Ipv4Addr, CachedLocation>>>,
+    /// If provided, cached locations older than this are looked up again.
+    ttl: Option<Duration>,
+    /// Have any locations been added to the cache since it was last saved?
+    is_dirty: Arc<AtomicBool>,
 }
 
 impl Locator {
-    pub fn new(cache: FxHashMap<Ipv4Addr, Arc<NodeLocation>>) -> Self {
+    pub fn new(cache: FxHashMap<Ipv4Addr, CachedLocation>, ttl: Option<Duration>) -> Self {
         let client = reqwest::Client::new();
 
         Locator {
             client,
             cache: Arc::new(RwLock::new(cache)),
+            ttl,
+            is_dirty: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// Save the locations we've looked up to a file. Built in locations aren't saved.
+    /// The file is replaced in one go, so that it's never left half written.
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let cache: FxHashMap<Ipv4Addr, CachedLocation> = self
+            .cache
+            .read()
+            .iter()
+            .filter(|(_, cached)| cached.located_at != Timestamp::MAX)
+            .map(|(ip, cached)| (*ip, cached.clone()))
+            .collect();
+        let bytes = serde_json::to_vec(&cache)?;
+
+        // Each aggregator has its own locator, so they may be saving at the same time:
+        static NEXT_SAVE_ID: AtomicUsize = AtomicUsize::new(0);
+        let save_id = NEXT_SAVE_ID.fetch_add(1, Ordering::Relaxed);
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(format!(".{}-{}.tmp", std::process::id(), save_id));
+        std::fs::write(&tmp_path, bytes)
+            .with_context(|| format!("Could not write {:?}", tmp_path))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Could not replace location cache {:?}", path))?;
+        Ok(())
+    }
+
     pub async fn locate(&self, ip: Ipv4Addr) -> Option<Arc<NodeLocation>> {
-        // Return location quickly if it's cached:
+        // Return location quickly if it's cached (and hasn't expired):
         let cached_loc = {
             let cache_reader = self.cache.read();
-            cache_reader.get(&ip).cloned()
+            let now = time::now();
+            cache_reader
+                .get(&ip)
+                .filter(|cached| match self.ttl {
+                    Some(ttl) => !cached.is_expired(now, ttl),
+                    None => true,
+                })
+                .map(|cached| cached.location.clone())
         };
         if cached_loc.is_some() {
             return cached_loc;
@@ -134,7 +381,12 @@ impl Locator {
 
         // If we successfully obtained a location, cache it
         if let Ok(location) = &location {
-            self.cache.write().insert(ip, location.clone());
+            let cached = CachedLocation {
+                location: location.clone(),
+                located_at: time::now(),
+            };
+            self.cache.write().insert(ip, cached);
+            self.is_dirty.store(true, Ordering::Relaxed);
         }
 
         // Discard the error; we've logged information above.
@@ -234,4 +486,123 @@ mod tests {
 
         assert!(location.is_none());
     }
+
+    #[test]
+    fn private_ips_are_detected() {
+        let private_ips = [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.10.10",
+            "0.0.0.0",
+            "::1",
+            "fd12:3456:789a::1",
+            "fe80::1",
+        ];
+        for ip in private_ips {
+            assert!(
+                is_private_ip(ip.parse().unwrap()),
+                "{} should be private",
+                ip
+            );
+        }
+
+        let public_ips = ["8.8.8.8", "172.32.0.1", "2001:4860:4860::8888"];
+        for ip in public_ips {
+            assert!(
+                !is_private_ip(ip.parse().unwrap()),
+                "{} should be public",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn most_specific_location_override_wins() {
+        let overrides = LocationOverrides::new(
+            [
+                "10.0.0.0/8=1.0,2.0,Wide",
+                "10.1.2.0/24=5.0,6.0,Narrow, With Commas",
+                "10.1.0.0/16=3.0,4.0,Middle",
+            ]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect(),
+        );
+        let city = |ip: &str| overrides.find(ip.parse().unwrap()).map(|l| l.city.clone());
+
+        assert_eq!(city("10.1.2.3").as_deref(), Some("Narrow, With Commas"));
+        assert_eq!(city("10.1.3.3").as_deref(), Some("Middle"));
+        assert_eq!(city("10.2.3.4").as_deref(), Some("Wide"));
+        assert_eq!(city("11.0.0.1"), None);
+        assert_eq!(city("::1"), None);
+
+        let location = overrides.find("10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!((location.latitude, location.longitude), (5.0, 6.0));
+    }
+
+    #[test]
+    fn invalid_location_overrides_are_rejected() {
+        for s in [
+            "10.0.0.0/8",
+            "10.0.0.0/33=1,2,X",
+            "10.0.0.0/8=1,2",
+            "10.0.0.0/8=a,2,X",
+        ] {
+            assert!(
+                s.parse::<LocationOverride>().is_err(),
+                "{} should be invalid",
+                s
+            );
+        }
+    }
+
+    fn located_at(located_at: Timestamp) -> CachedLocation {
+        CachedLocation {
+            location: Arc::new(NodeLocation {
+                latitude: 52.5,
+                longitude: 13.25,
+                city: "Berlin".into(),
+            }),
+            located_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_locations_are_saved_and_loaded_again() {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry_core_location_cache_{}.json",
+            std::process::id()
+        ));
+        let ttl = Duration::from_secs(60 * 60);
+        let now = time::now();
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        let expired_ip = Ipv4Addr::new(5, 6, 7, 8);
+        let builtin_ip = Ipv4Addr::new(127, 0, 0, 1);
+
+        let mut cache = FxHashMap::default();
+        cache.insert(ip, located_at(now));
+        cache.insert(expired_ip, located_at(now - 2 * 60 * 60 * 1000));
+        cache.insert(builtin_ip, located_at(Timestamp::MAX));
+        Locator::new(cache, Some(ttl)).save(&path).unwrap();
+
+        // Expired and built in locations aren't loaded:
+        let loaded = load_location_cache(&path, ttl).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.get(&ip), Some(&located_at(now)));
+
+        // The cached location is used without looking it up again:
+        let locator = Locator::new(loaded, Some(ttl));
+        let location = locator.locate(ip).await.unwrap();
+        assert_eq!(location.city.as_ref(), "Berlin");
+    }
+
+    #[test]
+    fn a_missing_location_cache_is_empty() {
+        let path = std::env::temp_dir().join("telemetry_core_no_such_location_cache.json");
+        let loaded = load_location_cache(&path, Duration::from_secs(60)).unwrap();
+        assert!(loaded.is_empty());
+    }
 }
@@ -0,0 +1,114 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Export where the nodes on a chain are as GeoJSON (RFC 7946), so that it can be
+//! handed straight to mapping tools.
+
+use crate::state::StateChain;
+use serde_json::json;
+
+/// Build a GeoJSON `FeatureCollection` with a `Point` feature for each located node on
+/// the chain. Each feature carries the node's ID, name and best block height. Nodes
+/// that haven't been located are left out.
+pub fn chain_geojson(chain: &StateChain<'_>) -> String {
+    let features: Vec<_> = chain
+        .iter_nodes()
+        .filter_map(|(node_id, node)| {
+            let location = node.location()?;
+            Some(json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    // GeoJSON positions are longitude first:
+                    "coordinates": [location.longitude, location.latitude],
+                },
+                "properties": {
+                    "id": usize::from(node_id.get_chain_node_id()),
+                    "name": node.details().name,
+                    "best_block": node.best().height,
+                },
+            }))
+        })
+        .collect();
+
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::State;
+    use common::node_types::{BlockHash, NetworkId, NodeDetails, NodeLocation};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    fn node(name: &str) -> NodeDetails {
+        NodeDetails {
+            chain: "Chain One".into(),
+            name: name.into(),
+            implementation: "Bar".into(),
+            version: "0.1".into(),
+            validator: None,
+            network_id: NetworkId::new(),
+            startup_time: None,
+            target_os: None,
+            target_arch: None,
+            target_env: None,
+            sysinfo: None,
+        }
+    }
+
+    #[test]
+    fn located_nodes_are_exported_as_points() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut state = State::new(None, 1000);
+        let located = state.add_node(genesis_hash, node("A")).unwrap_id();
+        state.add_node(genesis_hash, node("B")).unwrap_id();
+        state.update_node_location(
+            located,
+            Some(Arc::new(NodeLocation {
+                latitude: 52.5,
+                longitude: 13.25,
+                city: "Berlin".into(),
+            })),
+        );
+
+        let chain = state.get_chain_by_genesis_hash(&genesis_hash).unwrap();
+        let geojson: Value = serde_json::from_str(&chain_geojson(&chain)).unwrap();
+        assert_eq!(geojson["type"], "FeatureCollection");
+
+        // The unlocated node is left out:
+        let features = geojson["features"].as_array().unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0]["type"], "Feature");
+        assert_eq!(
+            features[0]["geometry"],
+            json!({ "type": "Point", "coordinates": [13.25, 52.5] })
+        );
+        assert_eq!(
+            features[0]["properties"],
+            json!({
+                "id": usize::from(located.get_chain_node_id()),
+                "name": "A",
+                "best_block": 0,
+            })
+        );
+    }
+}
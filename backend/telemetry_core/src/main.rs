@@ -14,24 +14,41 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+mod admin;
 mod aggregator;
+mod chain_widget;
 mod feed_message;
 mod find_location;
+mod geojson;
+mod shard_recording;
 mod state;
+mod synthetic;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::Arc;
 use tokio::time::{Duration, Instant};
 
 use aggregator::{
-    AggregatorOpts, AggregatorSet, FromFeedWebsocket, FromShardWebsocket, ToFeedWebsocket,
+    AggregatorOpts, AggregatorSet, ChainConflictPolicy, ChainFlapOpts, ChainNodeCountThreshold,
+    FromFeedWebsocket, FromShardWebsocket, NodeCountThresholdOpts, ToFeedWebsocket,
     ToShardWebsocket,
 };
 use bincode::Options;
 use common::http_utils;
 use common::internal_messages;
+use common::node_types::BlockHash;
 use common::ready_chunks_all::ReadyChunksAll;
+use feed_message::FeedEncoding;
+use find_location::{LocationCacheOpts, LocationOverride};
 use futures::{SinkExt, StreamExt};
 use hyper::{Method, Response};
+use shard_recording::{ShardConnRecorder, ShardRecorder};
 use simple_logger::SimpleLogger;
+use state::{
+    ChainLabelOverride, ChainStaleThreshold, IncompleteNodePolicy, NodeCountSource,
+    QuotaCountSource, RecommendedVersion, RequiredNodeField,
+};
 use structopt::StructOpt;
 
 #[cfg(not(target_env = "msvc"))]
@@ -63,6 +80,136 @@ struct Opts {
     /// telemetry. Case sensitive.
     #[structopt(long, required = false)]
     denylist: Vec<String>,
+    /// Space delimited list of the names or genesis hashes of the only chains that are
+    /// allowed to connect to telemetry. If not given, any chain not on the --denylist can
+    /// connect. Chain names are case sensitive.
+    #[structopt(long, required = false)]
+    allowlist: Vec<String>,
+    /// Space delimited list of the network IDs of individual nodes that are not allowed to
+    /// connect to telemetry, whichever chain they report. Can be replaced at runtime via
+    /// the admin server.
+    #[structopt(long, required = false)]
+    node_blocklist: Vec<String>,
+    /// Give every node connecting from an IP address in a CIDR range a fixed location
+    /// rather than looking it up, in the form CIDR=LATITUDE,LONGITUDE,CITY. Can be given
+    /// multiple times; if ranges overlap, the most specific one wins. Can be replaced at
+    /// runtime via the admin server.
+    #[structopt(long = "location-override")]
+    location_overrides: Vec<LocationOverride>,
+    /// The latest version that nodes on a chain are recommended to run, in the form
+    /// GENESIS_HASH=VERSION. Feeds are told how many nodes on the chain are up to date
+    /// with it. Can be given multiple times, and replaced at runtime via the admin server.
+    #[structopt(long = "recommended-version")]
+    recommended_versions: Vec<RecommendedVersion>,
+    /// A label to show for a chain in place of the ones its nodes report, in the form
+    /// GENESIS_HASH=LABEL. Can be given multiple times.
+    #[structopt(long = "chain-label")]
+    chain_label_overrides: Vec<ChainLabelOverride>,
+    /// A detail that every node must report when it connects: 'name', 'version' or
+    /// 'network-id'. Can be given multiple times. See --incomplete-node-policy for what
+    /// happens to nodes that don't.
+    #[structopt(long = "required-node-field")]
+    required_node_fields: Vec<RequiredNodeField>,
+    /// What to do with nodes that don't report every --required-node-field. 'reject' mutes
+    /// them, and 'placeholder' lets them connect with placeholders filling in the gaps.
+    #[structopt(long, default_value = "reject")]
+    incomplete_node_policy: IncompleteNodePolicy,
+    /// Nodes which haven't imported a new best block for this many seconds are marked as
+    /// stale. Chains with unusually long or short block times may want to adjust this.
+    #[structopt(long, default_value = "120")]
+    stale_threshold_secs: u64,
+    /// How many seconds nodes on a particular chain can go without importing a new best
+    /// block before they're stale, in the form GENESIS_HASH=SECONDS. This overrides
+    /// --stale-threshold-secs for that chain. Can be given multiple times.
+    #[structopt(long = "chain-stale-threshold")]
+    chain_stale_thresholds: Vec<ChainStaleThreshold>,
+    /// If provided, nodes whose best block is more than this many blocks behind the best
+    /// block of their chain are marked as stale, even if they're still sending updates.
+    #[structopt(long)]
+    stale_block_margin: Option<u64>,
+    /// If provided, feeds aren't told about the blocks imported by nodes which are more than
+    /// this many blocks behind the best block of their chain, to cut down on the noise from
+    /// nodes that are catching up. Their finalized blocks and other updates still pass.
+    #[structopt(long)]
+    block_import_drop_margin: Option<u64>,
+    /// Send feeds a message saying how long each block took to reach the nodes that import
+    /// it after the first one. This adds a feed message for most block imports.
+    #[structopt(long)]
+    block_propagation_messages: bool,
+    /// Give nodes that reconnect the same ID that they had before (as long as nothing else
+    /// has taken it), so that they keep their place in the UI. Nodes are recognised by
+    /// their network ID.
+    #[structopt(long)]
+    sticky_node_ids: bool,
+    /// Keep hold of the last payload of each kind that every node sends us, so that they
+    /// can be inspected via the admin server as they were received. This is meant for
+    /// debugging, and uses a fair bit more memory per node.
+    #[structopt(long)]
+    keep_raw_node_payloads: bool,
+    /// If provided, record every message that shards send to us into this file, so
+    /// that they can be replayed later with --replay-shard-messages.
+    #[structopt(long)]
+    record_shard_messages: Option<std::path::PathBuf>,
+    /// If provided, replay the shard messages recorded in this file on startup,
+    /// as if they were being sent by shards. Useful for load testing.
+    #[structopt(long)]
+    replay_shard_messages: Option<std::path::PathBuf>,
+    /// How fast to replay recorded shard messages; 2 replays them twice as fast
+    /// as they were recorded.
+    #[structopt(long, default_value = "1")]
+    replay_speed: f64,
+    /// Allow replicas to follow the messages that shards send to us, via the
+    /// /shard-replication endpoint on the admin server.
+    #[structopt(long)]
+    shard_replication: bool,
+    /// If provided, follow the shard messages sent to the telemetry core at this
+    /// admin server URL (eg ws://primary:9000/shard-replication), building up the
+    /// same state so that this can take over its shard connections if it fails.
+    #[structopt(long)]
+    replicate_shard_messages_from: Option<hyper::Uri>,
+    /// If provided, generate this many made up chains, with nodes whose blocks, finality
+    /// and locations evolve over time. Useful for developing the UI without a real network;
+    /// never use this in production.
+    #[structopt(long)]
+    synthetic_chains: Option<usize>,
+    /// How many nodes each synthetic chain has.
+    #[structopt(long, default_value = "10")]
+    synthetic_nodes_per_chain: usize,
+    /// How many nodes on each synthetic chain are replaced by new ones every minute.
+    #[structopt(long, default_value = "1")]
+    synthetic_churn_per_minute: f64,
+    /// If provided, messages from shards about a node which are larger than this many bytes
+    /// are rejected. Nodes whose details are too large are muted.
+    #[structopt(long)]
+    max_shard_message_size: Option<u64>,
+    /// New nodes from a shard which already has this many nodes connected are muted. A
+    /// shard reporting this many nodes is likely to be broken or misbehaving.
+    #[structopt(long, default_value = "100000")]
+    max_nodes_per_shard: usize,
+    /// If provided, updates from any one node beyond this many per second are dropped, so
+    /// that a single chatty node can't hold up everything else. Must be greater than 0.
+    #[structopt(long)]
+    max_node_messages_per_second: Option<u32>,
+    /// Allow feeds to ask for a compact binary encoding of feed messages rather than JSON,
+    /// by connecting to `/feed?encoding=binary`. Feeds get JSON unless they ask.
+    #[structopt(long)]
+    binary_feeds: bool,
+    /// If provided, a node which is located again less than this many km from where it
+    /// was last located keeps its old location, so that it doesn't jitter around the map.
+    #[structopt(long)]
+    min_location_change_km: Option<f64>,
+    /// If provided, the locations found for nodes are saved to this JSON file, and loaded
+    /// from it on startup, so that nodes can be located straight away after a restart.
+    #[structopt(long)]
+    location_cache_path: Option<std::path::PathBuf>,
+    /// How long locations in the --location-cache-path file are used for before they're
+    /// looked up again.
+    #[structopt(long, default_value = "604800")]
+    location_cache_ttl_secs: u64,
+    /// If provided, nodes which haven't sent us anything for this many seconds are
+    /// removed, so that they stop taking up room on their chain.
+    #[structopt(long)]
+    stale_node_timeout_secs: Option<u64>,
     /// If it takes longer than this number of seconds to send the current batch of messages
     /// to a feed, the feed connection will be closed.
     #[structopt(long, default_value = "10")]
@@ -71,6 +218,11 @@ struct Opts {
     /// on the machine. If no value is given, use an internal default that we have deemed sane.
     #[structopt(long)]
     worker_threads: Option<usize>,
+    /// Number of threads used to serialize node details for newly subscribed feeds, shared
+    /// between all aggregators. If "0" is given, or no value is given, use the number of CPUs
+    /// available on the machine.
+    #[structopt(long)]
+    serialization_threads: Option<usize>,
     /// Each aggregator keeps track of the entire node state. Feed subscriptions are split across
     /// aggregators.
     #[structopt(long)]
@@ -82,6 +234,161 @@ struct Opts {
     /// How many nodes from third party chains are allowed to connect before we prevent connections from them.
     #[structopt(long, default_value = "1000")]
     max_third_party_nodes: usize,
+    /// Which nodes count towards --max-third-party-nodes. Either 'all', 'distinct-network-ids'
+    /// (a node connecting more than once counts once) or 'validators' (observers don't count).
+    #[structopt(long, default_value = "all")]
+    quota_count_source: QuotaCountSource,
+    /// The genesis hash of a third party chain which, like first party chains, allows any
+    /// number of nodes to connect regardless of --max-third-party-nodes. Can be given
+    /// multiple times.
+    #[structopt(long = "quota-exempt-chain")]
+    quota_exempt_chains: Vec<BlockHash>,
+    /// If provided, no more than this many nodes can connect to any one chain. Unlike
+    /// --max-third-party-nodes, this counts every node and applies to every chain,
+    /// including first party and quota exempt ones. Must be greater than 0.
+    #[structopt(long)]
+    max_nodes_per_chain: Option<usize>,
+    /// Don't attempt to geographically locate nodes which report private, loopback or
+    /// link-local IP addresses (for instance, nodes behind NAT).
+    #[structopt(long)]
+    skip_private_ip_location: bool,
+    /// If provided, start an admin server listening on this socket address. The admin
+    /// server exposes operations that modify the running state of telemetry, and so this
+    /// address should not be publically accessible.
+    #[structopt(long)]
+    admin_listen: Option<std::net::SocketAddr>,
+    /// What to do when a node reports that it's connected to a different chain from the one
+    /// it was added to. Either 'reregister' (move the node to the new chain) or 'ignore'
+    /// (leave the node where it is).
+    #[structopt(long, default_value = "reregister")]
+    chain_conflict_policy: ChainConflictPolicy,
+    /// A feed with more than this many messages waiting to be sent to it, where that
+    /// number isn't going down, is reported as lagging in the metrics.
+    #[structopt(long, default_value = "1000")]
+    feed_lag_threshold: usize,
+    /// Disconnect a feed subscribed to a chain if it has this many messages waiting to be
+    /// sent to it when there are node updates for it. Messages about which chains exist are
+    /// still sent to it until then. If not provided, feeds can fall behind without limit.
+    #[structopt(long)]
+    max_feed_queue_len: Option<usize>,
+    /// When a feed subscribes to a chain, describe up to this many nodes in each message
+    /// sent to it. Larger messages have less overhead, and smaller ones let the UI show
+    /// something sooner. Must be greater than 0.
+    #[structopt(long, default_value = "64")]
+    nodes_per_feed_message: usize,
+    /// When a feed subscribes to a chain, keep each message sent to it to about this many
+    /// bytes, describing fewer nodes in a message if need be. A node whose details are
+    /// larger than this is described in a message of its own.
+    #[structopt(long, default_value = "32768")]
+    max_feed_message_bytes: usize,
+    /// Which nodes to count in the node count reported for each chain. Either 'all',
+    /// 'validators' or 'located'. Third party chain quotas always count every node.
+    #[structopt(long, default_value = "all")]
+    node_count_source: NodeCountSource,
+    /// Round the node counts that feeds are told about to the nearest multiple of this,
+    /// for chains which would rather not reveal exactly how many nodes they have. Quotas
+    /// and metrics still use exact node counts. Chains with any nodes are shown as having
+    /// at least this many. Must be greater than 0.
+    #[structopt(long, default_value = "1")]
+    node_count_granularity: usize,
+    /// Restrict the shard connecting from an IP address to only submitting nodes on the given
+    /// chains, in the form 'IP=GENESIS_HASH[,GENESIS_HASH...]'. Nodes on other chains from that
+    /// shard are muted. Can be provided multiple times.
+    #[structopt(long)]
+    shard_allowlist: Vec<ShardAllowlist>,
+    /// For this many seconds after startup, or after a shard connects, allow more nodes from
+    /// third party chains to connect (see --max-third-party-nodes-during-warmup), so that a
+    /// surge of reconnecting nodes isn't muted. "0" disables this.
+    #[structopt(long, default_value = "0")]
+    quota_warmup_secs: u64,
+    /// How many nodes from third party chains are allowed to connect during the quota warm-up
+    /// period. Defaults to twice --max-third-party-nodes.
+    #[structopt(long)]
+    max_third_party_nodes_during_warmup: Option<usize>,
+    /// A regular expression used to put the nodes on each chain into groups based on their
+    /// names. The group is taken from the first capture group (or the whole match if there
+    /// isn't one), and nodes whose names don't match are put into a "default" group. For
+    /// example, '^([a-z]+)-' groups "eu-node-1" and "eu-node-2" into "eu".
+    #[structopt(long)]
+    node_group_pattern: Option<regex::Regex>,
+    /// If provided, a chain which disappears (because its last node went away) more than this
+    /// many times within --chain-flap-window-secs is denylisted for --chain-flap-denylist-secs.
+    #[structopt(long)]
+    max_chain_flaps: Option<usize>,
+    /// The window of time in which chains disappearing count towards --max-chain-flaps.
+    #[structopt(long, default_value = "60")]
+    chain_flap_window_secs: u64,
+    /// How long chains which flap too often are denylisted for.
+    #[structopt(long, default_value = "600")]
+    chain_flap_denylist_secs: u64,
+    /// If provided, the denylist in effect (including changes made via the admin server and
+    /// chains denylisted for flapping) is saved to this file, and on startup is loaded from
+    /// it in place of --denylist, so that changes survive a restart.
+    #[structopt(long)]
+    denylist_path: Option<std::path::PathBuf>,
+    /// Show nodes on one chain as if they were on another, in the form
+    /// ALIAS_GENESIS_HASH=CANONICAL_GENESIS_HASH. Useful for testnets which have been
+    /// restarted with a new genesis hash. Can be given multiple times.
+    #[structopt(long = "chain-alias")]
+    chain_aliases: Vec<ChainAlias>,
+    /// Log whenever the number of nodes on a chain rises to or drops below this. Can be
+    /// given multiple times.
+    #[structopt(long = "node-count-threshold")]
+    node_count_thresholds: Vec<usize>,
+    /// A node count threshold for a particular chain, in the form GENESIS_HASH=NODE_COUNT.
+    /// Chains given thresholds of their own don't use --node-count-threshold. Can be given
+    /// multiple times.
+    #[structopt(long = "chain-node-count-threshold")]
+    chain_node_count_thresholds: Vec<ChainNodeCountThreshold>,
+    /// A chain which drops below a node count threshold has to rise this many nodes above
+    /// it before it's said to have reached it again, so that a chain hovering around a
+    /// threshold doesn't keep crossing it.
+    #[structopt(long, default_value = "2")]
+    node_count_threshold_hysteresis: usize,
+}
+
+/// The chains that a shard connecting from some IP address is allowed to submit nodes for.
+#[derive(Debug)]
+struct ShardAllowlist {
+    ip: IpAddr,
+    chains: HashSet<BlockHash>,
+}
+
+impl FromStr for ShardAllowlist {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, chains) = s.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("Expecting format `IP=GENESIS_HASH[,GENESIS_HASH...]`")
+        })?;
+        let chains = chains
+            .split(',')
+            .map(|hash| hash.trim().parse())
+            .collect::<Result<_, _>>()?;
+        Ok(ShardAllowlist {
+            ip: ip.trim().parse()?,
+            chains,
+        })
+    }
+}
+
+/// A genesis hash whose nodes are shown on the chain with some other genesis hash.
+#[derive(Debug)]
+struct ChainAlias {
+    alias: BlockHash,
+    canonical: BlockHash,
+}
+
+impl FromStr for ChainAlias {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (alias, canonical) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expecting format `GENESIS_HASH=GENESIS_HASH`"))?;
+        Ok(ChainAlias {
+            alias: alias.trim().parse()?,
+            canonical: canonical.trim().parse()?,
+        })
+    }
 }
 
 fn main() {
@@ -126,20 +433,189 @@ fn main() {
 /// Declare our routes and start the server.
 async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()> {
     let aggregator_queue_len = opts.aggregator_queue_len.unwrap_or(10_000);
+    let serialization_threads = match opts.serialization_threads {
+        Some(0) | None => num_cpus::get(),
+        Some(n) => n,
+    };
+    let serialization_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(serialization_threads)
+        .thread_name(|idx| format!("telemetry_core_serializer_{}", idx))
+        .build()?;
+    let node_count_thresholds =
+        if opts.node_count_thresholds.is_empty() && opts.chain_node_count_thresholds.is_empty() {
+            None
+        } else {
+            let mut chain_thresholds: HashMap<BlockHash, Vec<usize>> = HashMap::new();
+            for t in opts.chain_node_count_thresholds {
+                chain_thresholds
+                    .entry(t.genesis_hash)
+                    .or_default()
+                    .push(t.threshold);
+            }
+            Some(NodeCountThresholdOpts {
+                thresholds: opts.node_count_thresholds,
+                chain_thresholds,
+                hysteresis: opts.node_count_threshold_hysteresis,
+            })
+        };
+    let events = node_count_thresholds.as_ref().map(|_| {
+        let (tx, rx) = flume::bounded(1000);
+        tokio::spawn(async move {
+            while let Ok(event) = rx.recv_async().await {
+                log::warn!("{}", event);
+            }
+        });
+        tx
+    });
+    let mut location_overrides = opts.location_overrides;
+    if opts.synthetic_chains.is_some() {
+        location_overrides.extend(synthetic::location_overrides());
+    }
     let aggregator = AggregatorSet::spawn(
         num_aggregators,
         AggregatorOpts {
             max_queue_len: aggregator_queue_len,
             denylist: opts.denylist,
+            allowlist: opts.allowlist,
             max_third_party_nodes: opts.max_third_party_nodes,
+            quota_count_source: opts.quota_count_source,
+            quota_exempt_chains: opts.quota_exempt_chains,
+            max_nodes_per_chain: opts.max_nodes_per_chain,
+            skip_private_ip_location: opts.skip_private_ip_location,
+            chain_conflict_policy: opts.chain_conflict_policy,
+            feed_lag_threshold: opts.feed_lag_threshold,
+            max_feed_queue_len: opts.max_feed_queue_len,
+            node_count_source: opts.node_count_source,
+            node_count_granularity: opts.node_count_granularity,
+            quota_warmup: Duration::from_secs(opts.quota_warmup_secs),
+            max_third_party_nodes_during_warmup: opts
+                .max_third_party_nodes_during_warmup
+                .unwrap_or(opts.max_third_party_nodes.saturating_mul(2)),
+            node_group_pattern: opts.node_group_pattern,
+            node_blocklist: opts.node_blocklist,
+            location_overrides,
+            chain_label_overrides: opts
+                .chain_label_overrides
+                .into_iter()
+                .map(|o| (o.genesis_hash, o.label))
+                .collect(),
+            recommended_versions: opts.recommended_versions,
+            required_node_fields: opts.required_node_fields,
+            incomplete_node_policy: opts.incomplete_node_policy,
+            stale_threshold: Duration::from_secs(opts.stale_threshold_secs),
+            chain_stale_thresholds: opts.chain_stale_thresholds,
+            stale_block_margin: opts.stale_block_margin,
+            block_import_drop_margin: opts.block_import_drop_margin,
+            block_propagation_messages: opts.block_propagation_messages,
+            sticky_node_ids: opts.sticky_node_ids,
+            keep_raw_node_payloads: opts.keep_raw_node_payloads,
+            serialization_pool: Arc::new(serialization_pool),
+            nodes_per_feed_message: opts.nodes_per_feed_message,
+            max_feed_message_bytes: opts.max_feed_message_bytes,
+            processing_errors: None,
+            max_shard_message_size: opts.max_shard_message_size,
+            max_nodes_per_shard: opts.max_nodes_per_shard,
+            max_node_messages_per_second: opts.max_node_messages_per_second,
+            binary_feeds: opts.binary_feeds,
+            min_location_change_km: opts.min_location_change_km,
+            location_cache: opts.location_cache_path.map(|path| LocationCacheOpts {
+                path,
+                ttl: Duration::from_secs(opts.location_cache_ttl_secs),
+            }),
+            stale_node_timeout: opts.stale_node_timeout_secs.map(Duration::from_secs),
+            chain_flaps: opts.max_chain_flaps.map(|max_flaps| ChainFlapOpts {
+                max_flaps,
+                window: Duration::from_secs(opts.chain_flap_window_secs),
+                denylist_for: Duration::from_secs(opts.chain_flap_denylist_secs),
+            }),
+            denylist_path: opts.denylist_path,
+            chain_aliases: opts
+                .chain_aliases
+                .into_iter()
+                .map(|a| (a.alias, a.canonical))
+                .collect(),
+            node_count_thresholds,
+            events,
         },
     )
     .await?;
     let socket_addr = opts.socket;
+    let mut shard_allowlists: HashMap<IpAddr, HashSet<BlockHash>> = HashMap::new();
+    for allowlist in opts.shard_allowlist {
+        shard_allowlists
+            .entry(allowlist.ip)
+            .or_default()
+            .extend(allowlist.chains);
+    }
+    let shard_allowlists = Arc::new(shard_allowlists);
     let feed_timeout = opts.feed_timeout;
 
+    let mut shard_recorder = ShardRecorder::new();
+    if let Some(path) = &opts.record_shard_messages {
+        shard_recorder.record_to_file(path)?;
+    }
+    let shard_replicas = if opts.shard_replication {
+        Some(shard_recorder.replicate())
+    } else {
+        None
+    };
+    let shard_recorder = if shard_recorder.is_recording() {
+        Some(shard_recorder)
+    } else {
+        None
+    };
+    if let Some(path) = opts.replay_shard_messages {
+        let aggregator = aggregator.clone();
+        let speed = opts.replay_speed;
+        tokio::spawn(async move {
+            match shard_recording::replay(&path, aggregator, speed).await {
+                Ok(count) => log::info!("Replayed {} shard events from {:?}", count, path),
+                Err(e) => log::error!("Error replaying shard messages: {}", e),
+            }
+        });
+    }
+
+    if let Some(uri) = opts.replicate_shard_messages_from {
+        let aggregator = aggregator.clone();
+        tokio::spawn(async move {
+            if let Err(e) = shard_recording::follow(uri, aggregator).await {
+                log::error!("Error replicating shard messages: {}", e);
+            }
+        });
+    }
+
+    if let Some(chains) = opts.synthetic_chains {
+        let aggregator = aggregator.clone();
+        let synthetic_opts = synthetic::SyntheticOpts {
+            chains,
+            nodes_per_chain: opts.synthetic_nodes_per_chain,
+            churn_per_minute: opts.synthetic_churn_per_minute,
+        };
+        log::warn!(
+            "Generating synthetic chains and nodes: {:?}",
+            synthetic_opts
+        );
+        tokio::spawn(async move {
+            if let Err(e) = synthetic::run(aggregator, synthetic_opts).await {
+                log::error!("Error generating synthetic nodes: {}", e);
+            }
+        });
+    }
+
+    if let Some(admin_addr) = opts.admin_listen {
+        let aggregator = aggregator.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::start_server(admin_addr, aggregator, shard_replicas).await {
+                log::error!("Error running admin server: {}", e);
+            }
+        });
+    }
+
+    let shutdown_aggregator = aggregator.clone();
     let server = http_utils::start_server(socket_addr, move |addr, req| {
         let aggregator = aggregator.clone();
+        let shard_allowlists = Arc::clone(&shard_allowlists);
+        let shard_recorder = shard_recorder.clone();
         async move {
             match (req.method(), req.uri().path().trim_end_matches('/')) {
                 // Check that the server is up and running:
@@ -147,10 +623,12 @@ async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()>
                 // Subscribe to feed messages:
                 (&Method::GET, "/feed") => {
                     log::info!("Opening /feed connection from {:?}", addr);
+                    let encoding = requested_feed_encoding(req.uri().query());
                     Ok(http_utils::upgrade_to_websocket(
                         req,
                         move |ws_send, ws_recv| async move {
                             let (feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
+                            log::debug!("Feed connection from {:?} has ID {}", addr, feed_id);
                             let (mut tx_to_aggregator, mut ws_send) =
                                 handle_feed_websocket_connection(
                                     ws_send,
@@ -158,6 +636,7 @@ async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()>
                                     tx_to_aggregator,
                                     feed_timeout,
                                     feed_id,
+                                    encoding,
                                 )
                                 .await;
                             log::info!("Closing /feed connection from {:?}", addr);
@@ -167,6 +646,48 @@ async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()>
                         },
                     ))
                 }
+                // Subscribe to a cut down feed about a single chain, for embedded widgets:
+                (&Method::GET, path) if path.starts_with("/feed/widget/") => {
+                    let genesis_hash: BlockHash = match path["/feed/widget/".len()..].parse() {
+                        Ok(hash) => hash,
+                        Err(_) => {
+                            return Ok(Response::builder()
+                                .status(400)
+                                .body("Invalid genesis hash".into())
+                                .unwrap())
+                        }
+                    };
+                    log::info!("Opening chain widget connection from {:?}", addr);
+                    Ok(http_utils::upgrade_to_websocket(
+                        req,
+                        move |ws_send, ws_recv| async move {
+                            let (feed_id, mut tx_to_aggregator, widget_msgs) =
+                                match aggregator.subscribe_chain_widget(genesis_hash).await {
+                                    Ok(widget) => widget,
+                                    Err(e) => {
+                                        log::error!("Error subscribing chain widget: {}", e);
+                                        return;
+                                    }
+                                };
+                            log::debug!(
+                                "Chain widget connection from {:?} has ID {}",
+                                addr,
+                                feed_id
+                            );
+                            let mut ws_send = handle_chain_widget_websocket_connection(
+                                ws_send,
+                                ws_recv,
+                                widget_msgs,
+                                feed_timeout,
+                            )
+                            .await;
+                            log::info!("Closing chain widget connection from {:?}", addr);
+                            // Tell the aggregator that this connection has closed, so it can tidy up.
+                            let _ = tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
+                            let _ = ws_send.close().await;
+                        },
+                    ))
+                }
                 // Subscribe to shard messages:
                 (&Method::GET, "/shard_submit") => {
                     Ok(http_utils::upgrade_to_websocket(
@@ -174,14 +695,23 @@ async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()>
                         move |ws_send, ws_recv| async move {
                             log::info!("Opening /shard_submit connection from {:?}", addr);
                             let tx_to_aggregator = aggregator.subscribe_shard();
+                            let allowed_chains = shard_allowlists.get(&addr.ip()).cloned();
+                            let recorder = shard_recorder
+                                .as_ref()
+                                .map(|r| r.connected(allowed_chains.clone()));
                             let (mut tx_to_aggregator, mut ws_send) =
                                 handle_shard_websocket_connection(
                                     ws_send,
                                     ws_recv,
                                     tx_to_aggregator,
+                                    allowed_chains,
+                                    recorder.clone(),
                                 )
                                 .await;
                             log::info!("Closing /shard_submit connection from {:?}", addr);
+                            if let Some(recorder) = recorder {
+                                recorder.disconnected();
+                            }
                             // Tell the aggregator that this connection has closed, so it can tidy up.
                             let _ = tx_to_aggregator
                                 .send(FromShardWebsocket::Disconnected)
@@ -201,15 +731,56 @@ async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()>
         }
     });
 
-    server.await?;
+    // Run until the server fails or we're asked to stop, in which case we give the
+    // aggregators the chance to save things and say goodbye to feeds first:
+    tokio::select! {
+        res = server => res?,
+        _ = shutdown_signal() => {
+            log::info!("Shutting down");
+            shutdown_aggregator.shutdown().await?;
+        }
+    }
     Ok(())
 }
 
+/// Wait until we're asked to stop, with Ctrl+C or (on unix) SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::warn!("Cannot listen for Ctrl+C: {}", e);
+            futures::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                log::warn!("Cannot listen for SIGTERM: {}", e);
+                futures::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
 /// This handles messages coming to/from a shard connection
 async fn handle_shard_websocket_connection<S>(
     mut ws_send: http_utils::WsSender,
     mut ws_recv: http_utils::WsReceiver,
     mut tx_to_aggregator: S,
+    allowed_chains: Option<HashSet<BlockHash>>,
+    recorder: Option<ShardConnRecorder>,
 ) -> (S, http_utils::WsSender)
 where
     S: futures::Sink<FromShardWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
@@ -219,6 +790,7 @@ where
     // Tell the aggregator about this new connection, and give it a way to send messages to us:
     let init_msg = FromShardWebsocket::Initialize {
         channel: tx_to_shard_conn,
+        allowed_chains,
     };
     if let Err(e) = tx_to_aggregator.send(init_msg).await {
         log::error!("Error sending message to aggregator: {}", e);
@@ -266,28 +838,12 @@ where
                     }
                 };
 
-            // Convert and send to the aggregator:
-            let aggregator_msg = match msg {
-                internal_messages::FromShardAggregator::AddNode {
-                    ip,
-                    node,
-                    local_id,
-                    genesis_hash,
-                } => FromShardWebsocket::Add {
-                    ip,
-                    node,
-                    genesis_hash,
-                    local_id,
-                },
-                internal_messages::FromShardAggregator::UpdateNode { payload, local_id } => {
-                    FromShardWebsocket::Update { local_id, payload }
-                }
-                internal_messages::FromShardAggregator::RemoveNode { local_id } => {
-                    FromShardWebsocket::Remove { local_id }
-                }
-            };
+            if let Some(recorder) = &recorder {
+                recorder.message(&msg);
+            }
 
-            if let Err(e) = tx_to_aggregator.send(aggregator_msg).await {
+            // Convert and send to the aggregator:
+            if let Err(e) = tx_to_aggregator.send(msg.into()).await {
                 log::error!("Failed to send message to aggregator; closing shard: {}", e);
                 break;
             }
@@ -345,12 +901,27 @@ where
 }
 
 /// This handles messages coming from a feed connection
+/// Feeds can ask for messages in the binary encoding by connecting with `?encoding=binary`.
+/// Anything else gets JSON.
+fn requested_feed_encoding(query: Option<&str>) -> FeedEncoding {
+    let asked_for_binary = query
+        .unwrap_or("")
+        .split('&')
+        .any(|param| param == "encoding=binary");
+    if asked_for_binary {
+        FeedEncoding::Binary
+    } else {
+        FeedEncoding::Json
+    }
+}
+
 async fn handle_feed_websocket_connection<S>(
     mut ws_send: http_utils::WsSender,
     mut ws_recv: http_utils::WsReceiver,
     mut tx_to_aggregator: S,
     feed_timeout: u64,
     _feed_id: u64, // <- can be useful for debugging purposes.
+    encoding: FeedEncoding,
 ) -> (S, http_utils::WsSender)
 where
     S: futures::Sink<FromFeedWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
@@ -368,6 +939,7 @@ where
     // Tell the aggregator about this new connection, and give it a way to send messages to us:
     let init_msg = FromFeedWebsocket::Initialize {
         channel: tx_to_feed_conn,
+        encoding,
     };
     if let Err(e) = tx_to_aggregator.send(init_msg).await {
         log::error!("Error sending message to aggregator: {}", e);
@@ -498,67 +1070,76 @@ where
     (tx_to_aggregator, ws_send)
 }
 
+/// This handles messages to a chain widget connection. Widgets don't send us any commands;
+/// they are subscribed to one chain up front and just receive messages about it.
+async fn handle_chain_widget_websocket_connection<M>(
+    mut ws_send: http_utils::WsSender,
+    mut ws_recv: http_utils::WsReceiver,
+    mut widget_msgs: M,
+    feed_timeout: u64,
+) -> http_utils::WsSender
+where
+    M: futures::Stream<Item = ToFeedWebsocket> + Unpin + Send + 'static,
+{
+    // Channel to notify the send loop if the widget goes away:
+    let (send_closer_tx, mut send_closer_rx) = tokio::sync::oneshot::channel::<()>();
+
+    // Anything the widget sends is ignored; we just wait for it to close:
+    let recv_handle = tokio::spawn(async move {
+        loop {
+            let mut bytes = Vec::new();
+            if ws_recv.receive_data(&mut bytes).await.is_err() {
+                break;
+            }
+        }
+        drop(send_closer_tx);
+    });
+
+    loop {
+        let msg = tokio::select! {
+            msg = widget_msgs.next() => msg,
+            _ = &mut send_closer_rx => { break }
+        };
+        let bytes = match msg {
+            Some(ToFeedWebsocket::Bytes(bytes)) => bytes,
+            None => break,
+        };
+
+        // If the widget is too slow to receive messages, we'll drop it.
+        let message_send_deadline = Instant::now() + Duration::from_secs(feed_timeout);
+        let sent = tokio::time::timeout_at(message_send_deadline, async {
+            ws_send.send_binary(&bytes).await?;
+            ws_send.flush().await
+        })
+        .await;
+        match sent {
+            Err(_) => {
+                log::warn!("Closing chain widget websocket that was too slow to keep up");
+                break;
+            }
+            Ok(Err(e)) => {
+                log::warn!(
+                    "Closing chain widget websocket due to error sending data: {}",
+                    e
+                );
+                break;
+            }
+            Ok(_) => {}
+        }
+    }
+
+    recv_handle.abort();
+    ws_send
+}
+
 async fn return_prometheus_metrics(aggregator: AggregatorSet) -> Response<hyper::Body> {
+    // We use the latest metrics that we've captured so far from the aggregators, rather
+    // than waiting for fresh ones:
     let metrics = aggregator.latest_metrics();
 
-    // Instead of using the rust prometheus library (which is optimised around global variables updated across a codebase),
-    // we just split out the text format that prometheus expects ourselves, and use the latest metrics that we've
-    // captured so far from the aggregators. See:
-    //
-    // https://github.com/prometheus/docs/blob/master/content/docs/instrumenting/exposition_formats.md#text-format-details
-    //
-    // For an example and explanation of this text based format. The minimal output we produce here seems to
-    // be handled correctly when pointing a current version of prometheus at it.
-    //
-    // Note: '{{' and '}}' are just escaped versions of '{' and '}' in Rust fmt strings.
-    use std::fmt::Write;
     let mut s = String::new();
     for (idx, m) in metrics.iter().enumerate() {
-        let _ = write!(
-            &mut s,
-            "telemetry_core_connected_feeds{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.connected_feeds, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_connected_nodes{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.connected_nodes, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_connected_shards{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.connected_shards, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_chains_subscribed_to{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.chains_subscribed_to, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_subscribed_feeds{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.subscribed_feeds, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_total_messages_to_feeds{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.total_messages_to_feeds, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_current_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
-            idx, m.current_messages_to_aggregator, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_total_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
-            idx, m.total_messages_to_aggregator, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_dropped_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
-            idx, m.dropped_messages_to_aggregator, m.timestamp_unix_ms
-        );
+        s.push_str(&m.to_prometheus(idx));
     }
 
     Response::builder()
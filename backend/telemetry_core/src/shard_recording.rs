@@ -0,0 +1,548 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Record the messages that shards send to us, and replay them later. This is useful
+//! for load testing the aggregator with realistic traffic. Recorded messages can also be
+//! streamed to replicas as they arrive, which build up the same state as we have, so that
+//! they can take over shard connections if we go away.
+
+use crate::aggregator::{AggregatorSet, FromShardWebsocket};
+use anyhow::Context;
+use bincode::Options;
+use bytes::Bytes;
+use common::internal_messages::{FromShardAggregator, ShardNodeId};
+use common::node_types::BlockHash;
+use common::ws_client;
+use futures::{Sink, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long to wait before reconnecting to the telemetry core that we're replicating.
+const REPLICATION_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A single recorded event on some shard connection.
+#[derive(Serialize, Deserialize, Debug)]
+struct RecordedShardEvent {
+    /// Milliseconds since recording started.
+    elapsed_ms: u64,
+    /// Identifies the shard connection that this event happened on.
+    shard_conn_id: u64,
+    event: ShardEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum ShardEvent {
+    Connected {
+        allowed_chains: Option<HashSet<BlockHash>>,
+    },
+    Message(Box<FromShardAggregator>),
+    Disconnected,
+}
+
+/// Records messages from every shard connection to a file and/or to replicas. Recording
+/// never blocks; events are handed to a separate thread which writes them out, and
+/// replicas are each given an unbounded queue of events.
+#[derive(Clone)]
+pub struct ShardRecorder {
+    started: Instant,
+    next_shard_conn_id: Arc<AtomicU64>,
+    file: Option<flume::Sender<RecordedShardEvent>>,
+    replicas: Option<ShardReplicas>,
+}
+
+impl Default for ShardRecorder {
+    fn default() -> Self {
+        ShardRecorder::new()
+    }
+}
+
+impl ShardRecorder {
+    /// A recorder that doesn't record anything until it's told where to.
+    pub fn new() -> ShardRecorder {
+        ShardRecorder {
+            started: Instant::now(),
+            next_shard_conn_id: Arc::new(AtomicU64::new(1)),
+            file: None,
+            replicas: None,
+        }
+    }
+
+    /// Is this recording anywhere?
+    pub fn is_recording(&self) -> bool {
+        self.file.is_some() || self.replicas.is_some()
+    }
+
+    /// Record to the file at the given path, replacing it if it exists.
+    pub fn record_to_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Could not create recording file {:?}", path))?;
+        let (tx, rx) = flume::unbounded::<RecordedShardEvent>();
+
+        std::thread::spawn(move || {
+            let mut writer = std::io::BufWriter::new(file);
+            while let Ok(event) = rx.recv() {
+                if let Err(e) = bincode::options().serialize_into(&mut writer, &event) {
+                    log::error!("Failed to record shard message; stopping recording: {}", e);
+                    return;
+                }
+                // Write out what we have whenever we catch up, so that the recording
+                // is usable even if we're not shut down cleanly:
+                if rx.is_empty() {
+                    if let Err(e) = writer.flush() {
+                        log::error!("Failed to write shard recording; stopping recording: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+
+        self.file = Some(tx);
+        Ok(())
+    }
+
+    /// Record to any replicas that subscribe to the [`ShardReplicas`] handed back.
+    pub fn replicate(&mut self) -> ShardReplicas {
+        let replicas = ShardReplicas::default();
+        self.replicas = Some(replicas.clone());
+        replicas
+    }
+
+    /// Record that a new shard has connected, handing back something
+    /// which can record what happens on that connection.
+    pub fn connected(&self, allowed_chains: Option<HashSet<BlockHash>>) -> ShardConnRecorder {
+        let recorder = ShardConnRecorder {
+            recorder: self.clone(),
+            shard_conn_id: self.next_shard_conn_id.fetch_add(1, Ordering::Relaxed),
+        };
+        recorder.record(ShardEvent::Connected { allowed_chains });
+        recorder
+    }
+}
+
+/// Records messages from a single shard connection.
+#[derive(Clone)]
+pub struct ShardConnRecorder {
+    recorder: ShardRecorder,
+    shard_conn_id: u64,
+}
+
+impl ShardConnRecorder {
+    /// Record a message that the shard sent.
+    pub fn message(&self, msg: &FromShardAggregator) {
+        self.record(ShardEvent::Message(Box::new(msg.clone())));
+    }
+
+    /// Record that the shard disconnected.
+    pub fn disconnected(&self) {
+        self.record(ShardEvent::Disconnected);
+    }
+
+    fn record(&self, event: ShardEvent) {
+        let event = RecordedShardEvent {
+            elapsed_ms: self.recorder.started.elapsed().as_millis() as u64,
+            shard_conn_id: self.shard_conn_id,
+            event,
+        };
+        if let Some(replicas) = &self.recorder.replicas {
+            replicas.send(&event);
+        }
+        if let Some(file) = &self.recorder.file {
+            // Ignore errors; the writer will have logged why it stopped.
+            let _ = file.send(event);
+        }
+    }
+}
+
+/// Hands out the shard events being recorded to any replicas that subscribe. Replicas
+/// are first sent the events needed to know about every currently connected shard and
+/// node, and then every event as it happens. Anything sent about a node before the replica
+/// subscribed is otherwise lost, so replicas converge on our state as nodes send updates.
+#[derive(Clone, Default)]
+pub struct ShardReplicas(Arc<Mutex<ReplicationState>>);
+
+#[derive(Default)]
+struct ReplicationState {
+    /// What a new replica needs to be sent about each connected shard.
+    shards: HashMap<u64, ShardBacklog>,
+    /// Encoded events are sent to each of these.
+    replicas: Vec<flume::Sender<Bytes>>,
+}
+
+struct ShardBacklog {
+    /// The event saying that the shard connected.
+    connected: Bytes,
+    /// The event adding each node that's still connected via the shard.
+    nodes: HashMap<ShardNodeId, Bytes>,
+}
+
+impl ShardReplicas {
+    /// Subscribe a new replica, handing back the encoded events to send to it.
+    pub fn subscribe(&self) -> flume::Receiver<Bytes> {
+        let (tx, rx) = flume::unbounded();
+        let mut state = self.0.lock().unwrap();
+        for shard in state.shards.values() {
+            let _ = tx.send(shard.connected.clone());
+            for node in shard.nodes.values() {
+                let _ = tx.send(node.clone());
+            }
+        }
+        state.replicas.push(tx);
+        rx
+    }
+
+    fn send(&self, recorded: &RecordedShardEvent) {
+        let bytes: Bytes = match bincode::options().serialize(recorded) {
+            Ok(bytes) => bytes.into(),
+            Err(e) => {
+                log::error!("Failed to encode shard message for replicas: {}", e);
+                return;
+            }
+        };
+
+        let mut state = self.0.lock().unwrap();
+        let shard_conn_id = recorded.shard_conn_id;
+        match &recorded.event {
+            ShardEvent::Connected { .. } => {
+                let backlog = ShardBacklog {
+                    connected: bytes.clone(),
+                    nodes: HashMap::new(),
+                };
+                state.shards.insert(shard_conn_id, backlog);
+            }
+            ShardEvent::Message(msg) => {
+                if let Some(backlog) = state.shards.get_mut(&shard_conn_id) {
+                    match &**msg {
+                        FromShardAggregator::AddNode { local_id, .. } => {
+                            backlog.nodes.insert(*local_id, bytes.clone());
+                        }
+                        FromShardAggregator::RemoveNode { local_id } => {
+                            backlog.nodes.remove(local_id);
+                        }
+                        FromShardAggregator::UpdateNode { .. } => {}
+                    }
+                }
+            }
+            ShardEvent::Disconnected => {
+                state.shards.remove(&shard_conn_id);
+            }
+        }
+
+        // Forget about any replicas that have gone away:
+        state
+            .replicas
+            .retain(|replica| replica.send(bytes.clone()).is_ok());
+    }
+}
+
+/// Sends recorded shard events into an aggregator, as if they came from shards.
+struct ShardEventPlayer {
+    aggregator: AggregatorSet,
+    shards: HashMap<u64, Box<dyn Sink<FromShardWebsocket, Error = anyhow::Error> + Send + Unpin>>,
+}
+
+impl ShardEventPlayer {
+    fn new(aggregator: AggregatorSet) -> Self {
+        ShardEventPlayer {
+            aggregator,
+            shards: HashMap::new(),
+        }
+    }
+
+    async fn play(&mut self, recorded: &RecordedShardEvent) -> anyhow::Result<()> {
+        let msg = match &recorded.event {
+            ShardEvent::Connected { allowed_chains } => {
+                // Nobody is listening for mute messages, so the aggregator's attempts
+                // to send them will be ignored:
+                let (channel, _) = flume::unbounded();
+                self.shards.insert(
+                    recorded.shard_conn_id,
+                    Box::new(self.aggregator.subscribe_shard()),
+                );
+                FromShardWebsocket::Initialize {
+                    channel,
+                    allowed_chains: allowed_chains.clone(),
+                }
+            }
+            ShardEvent::Message(msg) => (**msg).clone().into(),
+            ShardEvent::Disconnected => FromShardWebsocket::Disconnected,
+        };
+
+        let tx_to_aggregator = match self.shards.get_mut(&recorded.shard_conn_id) {
+            Some(tx) => tx,
+            None => anyhow::bail!("Recording has messages for an unknown shard connection"),
+        };
+        tx_to_aggregator.send(msg).await?;
+
+        if let ShardEvent::Disconnected = recorded.event {
+            self.shards.remove(&recorded.shard_conn_id);
+        }
+        Ok(())
+    }
+
+    /// Disconnect every shard that's still connected, as if they had all gone away.
+    async fn disconnect_all(&mut self) -> anyhow::Result<()> {
+        for (_, mut tx_to_aggregator) in self.shards.drain() {
+            tx_to_aggregator
+                .send(FromShardWebsocket::Disconnected)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Replay a recording made by [`ShardRecorder`], sending the messages into the aggregator
+/// as if they came from shards. A `speed` of 2.0 replays messages twice as fast as they
+/// were recorded. Returns the number of events that were replayed.
+pub async fn replay(path: &Path, aggregator: AggregatorSet, speed: f64) -> anyhow::Result<usize> {
+    anyhow::ensure!(speed > 0.0, "Replay speed must be greater than 0");
+
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Could not read recording file {:?}", path))?;
+    let mut reader = &bytes[..];
+    let mut events = Vec::new();
+    while !reader.is_empty() {
+        let event: RecordedShardEvent = bincode::options()
+            .deserialize_from(&mut reader)
+            .with_context(|| format!("Invalid recording file {:?}", path))?;
+        events.push(event);
+    }
+
+    let started = tokio::time::Instant::now();
+    let mut player = ShardEventPlayer::new(aggregator);
+    for recorded in &events {
+        let replay_at = Duration::from_millis(recorded.elapsed_ms).div_f64(speed);
+        tokio::time::sleep_until(started + replay_at).await;
+        player.play(recorded).await?;
+    }
+
+    Ok(events.len())
+}
+
+/// Replicate the shard messages that another telemetry core records (see [`ShardReplicas`]),
+/// sending them into our aggregator so that we build up the same state. If the connection
+/// is lost, the replicated shards are disconnected and we keep trying to reconnect.
+pub async fn follow(uri: http::Uri, aggregator: AggregatorSet) -> anyhow::Result<()> {
+    loop {
+        let mut player = ShardEventPlayer::new(aggregator.clone());
+        match follow_connection(&uri, &mut player).await {
+            Ok(()) => log::warn!("Connection to replicate shard messages from {} closed", uri),
+            Err(e) => log::error!("Error replicating shard messages from {}: {}", uri, e),
+        }
+        player.disconnect_all().await?;
+        tokio::time::sleep(REPLICATION_RETRY_DELAY).await;
+    }
+}
+
+async fn follow_connection(uri: &http::Uri, player: &mut ShardEventPlayer) -> anyhow::Result<()> {
+    let (_tx, mut rx) = ws_client::connect(uri).await?.into_channels();
+    log::info!("Replicating shard messages from {}", uri);
+    while let Some(msg) = rx.next().await {
+        let bytes = match msg? {
+            ws_client::RecvMessage::Binary(bytes) => bytes,
+            ws_client::RecvMessage::Text(s) => s.into_bytes(),
+        };
+        let recorded: RecordedShardEvent = bincode::options().deserialize(&bytes)?;
+        player.play(&recorded).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aggregator::{AggregatorOpts, ChainConflictPolicy};
+    use crate::state::{IncompleteNodePolicy, NodeCountSource, QuotaCountSource};
+    use common::node_message::{Payload, SystemInterval};
+    use common::node_types::{Block, NetworkId, NodeDetails};
+
+    fn opts() -> AggregatorOpts {
+        AggregatorOpts {
+            denylist: vec![],
+            allowlist: vec![],
+            max_queue_len: 10_000,
+            max_third_party_nodes: 1000,
+            quota_count_source: QuotaCountSource::All,
+            quota_exempt_chains: vec![],
+            max_nodes_per_chain: None,
+            skip_private_ip_location: true,
+            chain_conflict_policy: ChainConflictPolicy::Reregister,
+            feed_lag_threshold: 1000,
+            max_feed_queue_len: None,
+            node_count_source: NodeCountSource::All,
+            node_count_granularity: 1,
+            quota_warmup: Duration::ZERO,
+            max_third_party_nodes_during_warmup: 1000,
+            node_group_pattern: None,
+            node_blocklist: vec![],
+            location_overrides: vec![],
+            location_cache: None,
+            stale_node_timeout: None,
+            stale_threshold: Duration::from_secs(120),
+            chain_stale_thresholds: vec![],
+            stale_block_margin: None,
+            block_import_drop_margin: None,
+            block_propagation_messages: false,
+            sticky_node_ids: false,
+            keep_raw_node_payloads: false,
+            chain_label_overrides: HashMap::new(),
+            recommended_versions: vec![],
+            required_node_fields: vec![],
+            incomplete_node_policy: IncompleteNodePolicy::Reject,
+            nodes_per_feed_message: 64,
+            max_feed_message_bytes: 32 * 1024,
+            serialization_pool: Arc::new(rayon::ThreadPoolBuilder::new().build().unwrap()),
+            processing_errors: None,
+            max_shard_message_size: None,
+            max_nodes_per_shard: 10_000,
+            max_node_messages_per_second: None,
+            binary_feeds: false,
+            min_location_change_km: None,
+            chain_flaps: None,
+            denylist_path: None,
+            chain_aliases: HashMap::new(),
+            node_count_thresholds: None,
+            events: None,
+        }
+    }
+
+    fn add_node(local_id: usize) -> FromShardAggregator {
+        FromShardAggregator::AddNode {
+            ip: "127.0.0.1".parse().unwrap(),
+            node: NodeDetails {
+                chain: "Chain One".into(),
+                name: format!("Node {}", local_id).into(),
+                implementation: "Bar".into(),
+                version: "0.1".into(),
+                validator: None,
+                network_id: NetworkId::new(),
+                startup_time: None,
+                target_os: None,
+                target_arch: None,
+                target_env: None,
+                sysinfo: None,
+            },
+            local_id: ShardNodeId::new(local_id),
+            genesis_hash: BlockHash::zero(),
+        }
+    }
+
+    fn import_block(local_id: usize, height: u64) -> FromShardAggregator {
+        FromShardAggregator::UpdateNode {
+            local_id: ShardNodeId::new(local_id),
+            payload: Payload::SystemInterval(SystemInterval {
+                peers: None,
+                txcount: None,
+                bandwidth_upload: None,
+                bandwidth_download: None,
+                finalized_height: Some(height - 1),
+                finalized_hash: Some(BlockHash::from_low_u64_be(height - 1)),
+                block: Some(Block {
+                    hash: BlockHash::from_low_u64_be(height),
+                    height,
+                }),
+                used_state_cache_size: None,
+            }),
+        }
+    }
+
+    async fn nodes(aggregator: &AggregatorSet) -> Vec<(Box<str>, u64, u64)> {
+        let mut nodes: Vec<_> = aggregator
+            .chain_nodes(BlockHash::zero(), false)
+            .await
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|n| (n.name, n.best_block, n.finalized_block))
+            .collect();
+        nodes.sort();
+        nodes
+    }
+
+    #[tokio::test]
+    async fn replicas_converge_on_the_same_state() {
+        let primary = AggregatorSet::spawn(1, opts()).await.unwrap();
+        let replica = AggregatorSet::spawn(1, opts()).await.unwrap();
+
+        let mut recorder = ShardRecorder::new();
+        let shard_replicas = recorder.replicate();
+
+        // A shard connects to the primary, which records what it's sent:
+        let shard_recorder = recorder.connected(None);
+        let mut tx_to_primary = primary.subscribe_shard();
+        let (channel, _rx_mutes) = flume::unbounded();
+        tx_to_primary
+            .send(FromShardWebsocket::Initialize {
+                channel,
+                allowed_chains: None,
+            })
+            .await
+            .unwrap();
+        let shard_sends = |msgs: Vec<FromShardAggregator>| {
+            msgs.into_iter()
+                .map(|msg| {
+                    shard_recorder.message(&msg);
+                    FromShardWebsocket::from(msg)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for msg in shard_sends(vec![add_node(1), add_node(2), import_block(1, 10)]) {
+            tx_to_primary.send(msg).await.unwrap();
+        }
+
+        // The replica subscribes part way through, and sees what happens next:
+        let rx_events = shard_replicas.subscribe();
+        for msg in shard_sends(vec![
+            add_node(3),
+            import_block(3, 11),
+            add_node(4),
+            import_block(1, 11),
+            import_block(2, 11),
+            import_block(4, 12),
+            FromShardAggregator::RemoveNode {
+                local_id: ShardNodeId::new(4),
+            },
+        ]) {
+            tx_to_primary.send(msg).await.unwrap();
+        }
+
+        let mut player = ShardEventPlayer::new(replica.clone());
+        for bytes in rx_events.try_iter() {
+            let recorded: RecordedShardEvent = bincode::options().deserialize(&bytes).unwrap();
+            player.play(&recorded).await.unwrap();
+        }
+
+        let expected = vec![
+            ("Node 1".into(), 11, 10),
+            ("Node 2".into(), 11, 10),
+            ("Node 3".into(), 11, 10),
+        ];
+        assert_eq!(nodes(&primary).await, expected);
+        assert_eq!(nodes(&replica).await, expected);
+
+        // If the replica loses track of the primary, the replicated nodes go away:
+        player.disconnect_all().await.unwrap();
+        assert_eq!(
+            replica.chain_nodes(BlockHash::zero(), false).await.unwrap(),
+            None
+        );
+    }
+}
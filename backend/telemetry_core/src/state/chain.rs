@@ -15,11 +15,11 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use common::node_message::Payload;
-use common::node_types::BlockHash;
-use common::node_types::{Block, Timestamp};
+use common::node_types::{Block, BlockNumber, Timestamp};
+use common::node_types::{BlockHash, NetworkId};
 use common::{id_type, time, DenseMap, MostSeen, NumStats};
 use once_cell::sync::Lazy;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::str::FromStr;
 use std::time::{Duration, Instant};
 
@@ -29,6 +29,8 @@ use crate::find_location;
 use super::chain_stats::ChainStatsCollator;
 use super::counter::CounterValue;
 use super::node::Node;
+use super::state::QuotaCountSource;
+use super::version_compliance::VersionStatus;
 
 id_type! {
     /// A Node ID that is unique to the chain it's in.
@@ -37,15 +39,30 @@ id_type! {
 
 pub type Label = Box<str>;
 
-const STALE_TIMEOUT: u64 = 2 * 60 * 1000; // 2 minutes
+/// Nodes which haven't imported a new best block for this long are stale, unless the
+/// chain has been given a different threshold.
+pub const DEFAULT_STALE_THRESHOLD: Duration = Duration::from_secs(2 * 60);
 const STATS_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+/// How many recently imported best blocks do we remember, in order to work
+/// out how long they take to be finalized?
+const RECENT_BLOCK_IMPORTS_WINDOW: usize = 256;
+/// How many recently imported blocks do we remember the first sighting of, in
+/// order to work out how long they take to reach each node?
+const RECENT_BLOCK_HASHES_WINDOW: usize = 256;
+/// Nodes whose best block is within this many blocks of the best block of
+/// their chain are considered to be synced; the rest are still syncing.
+const SYNCED_BLOCK_MARGIN: BlockNumber = 10;
 
 pub struct Chain {
     /// Labels that nodes use for this chain. We keep track of
     /// the most commonly used label as nodes are added/removed.
     labels: MostSeen<Label>,
+    /// If set, this label is always used in place of the ones that nodes report.
+    label_override: Option<Label>,
     /// Set of nodes that are in this chain
     nodes: DenseMap<ChainNodeId, Node>,
+    /// The most nodes that this chain has had at once.
+    highest_node_count: usize,
     /// Best block
     best: Block,
     /// Finalized block
@@ -66,6 +83,37 @@ pub struct Chain {
     stats: ChainStats,
     /// Timestamp of when the stats were last regenerated.
     stats_last_regenerated: Instant,
+    /// Keeps track of how long it takes for blocks to be finalized.
+    time_to_finality: TimeToFinality,
+    /// If set, keeps track of when blocks were first imported by any node, so that
+    /// feeds can be told how long they took to reach the others.
+    block_propagation: Option<BlockPropagation>,
+    /// Nodes which haven't imported a new best block for this long are marked as stale.
+    stale_threshold: Duration,
+    /// If set, nodes whose best block is more than this many blocks
+    /// behind the chain's best block are marked as stale.
+    stale_block_margin: Option<BlockNumber>,
+    /// If set, feeds aren't told about blocks imported by nodes that are more
+    /// than this many blocks behind the chain's best block.
+    block_import_drop_margin: Option<BlockNumber>,
+    /// Which nodes count towards `max_nodes`.
+    quota_count_source: QuotaCountSource,
+    /// The decentralization score and located fraction last sent to feeds.
+    decentralization: (f64, f64),
+    /// The version that nodes on this chain are recommended to run, if known.
+    recommended_version: Option<semver::Version>,
+    /// The number of up to date and outdated nodes last sent to feeds.
+    version_compliance: Option<(usize, usize)>,
+    /// The number of synced and syncing nodes last sent to feeds.
+    sync_breakdown: (usize, usize),
+    /// The ID of the newest GRANDPA authority set that any node has told us about.
+    authority_set_id: Option<u64>,
+    /// If true, nodes that reconnect are given back the ID they had before, if it's free.
+    sticky_node_ids: bool,
+    /// The IDs that nodes which have left had, by their network ID. Both maps are
+    /// kept in step so that each ID is remembered for at most one network ID.
+    departed_node_ids: HashMap<NetworkId, ChainNodeId>,
+    departed_network_ids: HashMap<ChainNodeId, NetworkId>,
 }
 
 pub enum AddNodeResult {
@@ -107,7 +155,9 @@ impl Chain {
     pub fn new(genesis_hash: BlockHash, max_nodes: usize) -> Self {
         Chain {
             labels: MostSeen::default(),
+            label_override: None,
             nodes: DenseMap::new(),
+            highest_node_count: 0,
             best: Block::zero(),
             finalized: Block::zero(),
             block_times: NumStats::new(50),
@@ -118,17 +168,115 @@ impl Chain {
             stats_collator: Default::default(),
             stats: Default::default(),
             stats_last_regenerated: Instant::now(),
+            time_to_finality: TimeToFinality::new(),
+            block_propagation: None,
+            stale_threshold: DEFAULT_STALE_THRESHOLD,
+            stale_block_margin: None,
+            block_import_drop_margin: None,
+            quota_count_source: QuotaCountSource::All,
+            decentralization: (0.0, 0.0),
+            recommended_version: None,
+            version_compliance: None,
+            sync_breakdown: (0, 0),
+            authority_set_id: None,
+            sticky_node_ids: false,
+            departed_node_ids: HashMap::new(),
+            departed_network_ids: HashMap::new(),
         }
     }
 
-    /// Is the chain the node belongs to overquota?
-    pub fn is_overquota(&self) -> bool {
-        self.nodes.len() >= self.max_nodes
+    /// Compare the versions of nodes on this chain against the given one, or stop doing
+    /// so if `None` is given.
+    pub fn set_recommended_version(&mut self, recommended_version: Option<semver::Version>) {
+        self.recommended_version = recommended_version;
+    }
+
+    /// Always call the chain this, whatever its nodes report, or go back to using
+    /// the label most of them report if `None` is given.
+    pub fn set_label_override(&mut self, label_override: Option<Label>) {
+        self.label_override = label_override;
+    }
+
+    /// Mark nodes as stale if they haven't imported a new best block for this long.
+    pub fn set_stale_threshold(&mut self, stale_threshold: Duration) {
+        self.stale_threshold = stale_threshold;
+    }
+
+    /// Mark nodes as stale if their best block falls more than this many
+    /// blocks behind the best block of the chain.
+    pub fn set_stale_block_margin(&mut self, stale_block_margin: Option<BlockNumber>) {
+        self.stale_block_margin = stale_block_margin;
+    }
+
+    /// Give nodes that reconnect the same ID that they had before, as long as nobody
+    /// else has taken it in the meantime. Nodes are recognised by their network ID.
+    pub fn set_sticky_node_ids(&mut self, sticky_node_ids: bool) {
+        self.sticky_node_ids = sticky_node_ids;
+        if !sticky_node_ids {
+            self.departed_node_ids.clear();
+            self.departed_network_ids.clear();
+        }
+    }
+
+    /// Stop telling feeds about the blocks imported by nodes which are more than this
+    /// many blocks behind the best block of the chain, until they catch up.
+    pub fn set_block_import_drop_margin(&mut self, block_import_drop_margin: Option<BlockNumber>) {
+        self.block_import_drop_margin = block_import_drop_margin;
+    }
+
+    /// Tell feeds how long each block took to reach the nodes that import it after the
+    /// first one. This costs a feed message for most block imports, so it's opt-in.
+    pub fn set_block_propagation_messages(&mut self, enabled: bool) {
+        self.block_propagation = enabled.then(BlockPropagation::new);
+    }
+
+    /// Change the number of nodes that are allowed to be on this chain. Nodes
+    /// already on the chain are unaffected, but new ones may not be allowed.
+    pub fn set_max_nodes(&mut self, max_nodes: usize) {
+        self.max_nodes = max_nodes;
+    }
+
+    /// Change which nodes count towards the number allowed on this chain.
+    pub fn set_quota_count_source(&mut self, quota_count_source: QuotaCountSource) {
+        self.quota_count_source = quota_count_source;
+    }
+
+    /// Would adding the given node take this chain over its quota?
+    pub fn is_overquota(&self, node: &Node) -> bool {
+        // No node is counted more than once, so there's room for anything if
+        // there are fewer nodes than we allow:
+        if self.nodes.len() < self.max_nodes {
+            return false;
+        }
+
+        let details = node.details();
+        let nodes = self.nodes.iter().map(|(_, node)| node.details());
+        match self.quota_count_source {
+            QuotaCountSource::All => true,
+            QuotaCountSource::DistinctNetworkIds => {
+                // Nodes that don't report a network ID can't be told apart, so they all count:
+                let mut unidentified = 0;
+                let mut network_ids = HashSet::new();
+                for other in nodes {
+                    if other.network_id.is_empty() {
+                        unidentified += 1;
+                    } else {
+                        network_ids.insert(other.network_id);
+                    }
+                }
+                let is_known = network_ids.contains(&details.network_id);
+                !is_known && unidentified + network_ids.len() >= self.max_nodes
+            }
+            QuotaCountSource::Validators => {
+                details.validator.is_some()
+                    && nodes.filter(|other| other.validator.is_some()).count() >= self.max_nodes
+            }
+        }
     }
 
     /// Assign a node to this chain.
     pub fn add_node(&mut self, node: Node) -> AddNodeResult {
-        if self.is_overquota() {
+        if self.is_overquota(&node) {
             return AddNodeResult::Overquota;
         }
 
@@ -138,11 +286,23 @@ impl Chain {
 
         let node_chain_label = &details.chain;
         let label_result = self.labels.insert(node_chain_label);
-        let node_id = self.nodes.add(node);
+        let network_id = details.network_id;
+        let node_id = match self.departed_node_ids.remove(&network_id) {
+            Some(prev_id) => match self.nodes.add_at(prev_id, node) {
+                Ok(()) => prev_id,
+                Err(node) => self.nodes.add(node),
+            },
+            None => self.nodes.add(node),
+        };
+        // Whichever ID we ended up with, it's no longer free to hand back to anybody else:
+        if let Some(network_id) = self.departed_network_ids.remove(&node_id) {
+            self.departed_node_ids.remove(&network_id);
+        }
+        self.highest_node_count = self.highest_node_count.max(self.nodes.len());
 
         AddNodeResult::Added {
             id: node_id,
-            chain_renamed: label_result.has_changed(),
+            chain_renamed: label_result.has_changed() && self.label_override.is_none(),
         }
     }
 
@@ -164,8 +324,18 @@ impl Chain {
         let node_chain_label = &node.details().chain;
         let label_result = self.labels.remove(node_chain_label);
 
+        // Remember the ID this node had, in case it comes back. Nodes without a
+        // network ID can't be recognised.
+        let network_id = details.network_id;
+        if self.sticky_node_ids && !network_id.is_empty() {
+            if let Some(old_id) = self.departed_node_ids.insert(network_id, node_id) {
+                self.departed_network_ids.remove(&old_id);
+            }
+            self.departed_network_ids.insert(node_id, network_id);
+        }
+
         RemoveNodeResult {
-            chain_renamed: label_result.has_changed(),
+            chain_renamed: label_result.has_changed() && self.label_override.is_none(),
         }
     }
 
@@ -180,8 +350,14 @@ impl Chain {
             self.handle_block(block, nid, feed);
         }
 
+        let old_finalized_height = self.finalized.height;
+
         if let Some(node) = self.nodes.get_mut(nid) {
+            node.note_message(time::now());
+            node.note_raw_payload(&payload);
             match payload {
+                // Idle nodes keep sending the same interval, which we needn't apply again:
+                Payload::SystemInterval(ref interval) if !node.note_interval(interval) => {}
                 Payload::SystemInterval(ref interval) => {
                     // Send a feed message if any of the relevant node details change:
                     if node.update_hardware(interval) {
@@ -200,6 +376,16 @@ impl Chain {
                     if node.set_validator_address(authority.authority_id.clone()) {
                         feed.push(feed_message::AddedNode(nid.into(), &node));
                     }
+                    // The authority set is the same for the whole chain, and when it changes,
+                    // most nodes tell us about the new one at about the same time. Set IDs
+                    // only ever go up, so we take note of (and tell feeds about) each new
+                    // set once, and ignore nodes which haven't caught up with it yet:
+                    if let Ok(set_id) = authority.authority_set_id.parse::<u64>() {
+                        if self.authority_set_id.is_none_or(|known| set_id > known) {
+                            self.authority_set_id = Some(set_id);
+                            feed.push(feed_message::AuthoritySet(set_id));
+                        }
+                    }
                     return;
                 }
                 Payload::HwBench(ref hwbench) => {
@@ -236,10 +422,23 @@ impl Chain {
                 }
             }
         }
+
+        if self.finalized.height > old_finalized_height
+            && self.time_to_finality.note_blocks_finalized(
+                old_finalized_height,
+                self.finalized.height,
+                time::now(),
+            )
+        {
+            feed.push(feed_message::AverageTimeToFinality(
+                self.time_to_finality.average(),
+            ));
+        }
     }
 
     fn handle_block(&mut self, block: &Block, nid: ChainNodeId, feed: &mut FeedMessageSerializer) {
         let mut propagation_time = None;
+        let mut is_new_best_block = false;
         let now = time::now();
         let nodes_len = self.nodes.len();
 
@@ -252,6 +451,16 @@ impl Chain {
         };
 
         if node.update_block(*block) {
+            // Nodes that are catching up import lots of old blocks, which feeds aren't
+            // interested in. We still keep track of them so we know when they catch up.
+            let is_far_behind = match self.block_import_drop_margin {
+                Some(margin) => block.height.saturating_add(margin) < self.best.height,
+                None => false,
+            };
+            if is_far_behind {
+                return;
+            }
+
             if block.height > self.best.height {
                 self.best = *block;
                 log::debug!(
@@ -266,12 +475,15 @@ impl Chain {
                     self.average_block_time = Some(self.block_times.average());
                 }
                 self.timestamp = Some(now);
+                self.time_to_finality.note_block_imported(block.height, now);
                 feed.push(feed_message::BestBlock(
                     self.best.height,
                     now,
                     self.average_block_time,
+                    self.best.hash,
                 ));
                 propagation_time = Some(0);
+                is_new_best_block = true;
             } else if block.height == self.best.height {
                 if let Some(timestamp) = self.timestamp {
                     propagation_time = Some(now.saturating_sub(timestamp));
@@ -281,13 +493,30 @@ impl Chain {
             if let Some(details) = node.update_details(now, propagation_time) {
                 feed.push(feed_message::ImportedBlock(nid.into(), details));
             }
+
+            // The first node to import a block has nothing to report about its propagation:
+            if let Some(block_propagation) = &mut self.block_propagation {
+                let since_first_seen = block_propagation.note_block_imported(block.hash, now);
+                if since_first_seen > 0 {
+                    feed.push(feed_message::BlockPropagation(
+                        nid.into(),
+                        block.height,
+                        since_first_seen,
+                    ));
+                }
+            }
+        }
+
+        // A new best block may leave some nodes too far behind:
+        if is_new_best_block {
+            self.update_lagging_nodes(feed);
         }
     }
 
     /// Check if the chain is stale (has not received a new best block in a while).
     /// If so, find a new best block, ignoring any stale nodes and marking them as such.
     fn update_stale_nodes(&mut self, now: u64, feed: &mut FeedMessageSerializer) {
-        let threshold = now - STALE_TIMEOUT;
+        let threshold = now.saturating_sub(self.stale_threshold.as_millis() as u64);
         let timestamp = match self.timestamp {
             Some(ts) => ts,
             None => return,
@@ -327,6 +556,7 @@ impl Chain {
                 self.best.height,
                 timestamp.unwrap_or(now),
                 None,
+                self.best.hash,
             ));
             feed.push(feed_message::BestFinalized(
                 finalized.height,
@@ -335,6 +565,23 @@ impl Chain {
         }
     }
 
+    /// Mark nodes which have fallen too far behind the best block of the chain as stale,
+    /// even if they're still reporting in. Does nothing if no margin has been set.
+    fn update_lagging_nodes(&mut self, feed: &mut FeedMessageSerializer) {
+        let stale_block_margin = match self.stale_block_margin {
+            Some(margin) => margin,
+            None => return,
+        };
+
+        let min_height = self.best.height.saturating_sub(stale_block_margin);
+        for (nid, node) in self.nodes.iter_mut() {
+            if !node.stale() && node.best().height < min_height {
+                node.set_stale();
+                feed.push(feed_message::StaleNode(nid.into()));
+            }
+        }
+    }
+
     fn regenerate_stats_if_necessary(&mut self, feed: &mut FeedMessageSerializer) {
         let now = Instant::now();
         let elapsed = now - self.stats_last_regenerated;
@@ -348,6 +595,37 @@ impl Chain {
             self.stats = new_stats;
             feed.push(feed_message::ChainStatsUpdate(&self.stats));
         }
+
+        let decentralization = (self.decentralization_score(), self.located_fraction());
+        if decentralization != self.decentralization {
+            self.decentralization = decentralization;
+            feed.push(feed_message::ChainDecentralization(
+                decentralization.0,
+                decentralization.1,
+            ));
+        }
+
+        let version_compliance = self.version_compliance();
+        if version_compliance != self.version_compliance {
+            self.version_compliance = version_compliance;
+            if let Some((up_to_date, outdated)) = version_compliance {
+                feed.push(feed_message::VersionCompliance(
+                    self.genesis_hash,
+                    up_to_date,
+                    outdated,
+                ));
+            }
+        }
+
+        let sync_breakdown = self.sync_breakdown();
+        if sync_breakdown != self.sync_breakdown {
+            self.sync_breakdown = sync_breakdown;
+            feed.push(feed_message::SyncBreakdown(
+                self.genesis_hash,
+                sync_breakdown.0,
+                sync_breakdown.1,
+            ));
+        }
     }
 
     pub fn update_node_location(
@@ -363,18 +641,37 @@ impl Chain {
         }
     }
 
+    pub fn set_node_location_private(&mut self, node_id: ChainNodeId) -> bool {
+        if let Some(node) = self.nodes.get_mut(node_id) {
+            node.set_location_private();
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn get_node(&self, id: ChainNodeId) -> Option<&Node> {
         self.nodes.get(id)
     }
     pub fn nodes_slice(&self) -> &[Option<Node>] {
         self.nodes.as_slice()
     }
+    pub fn iter_nodes(&self) -> impl Iterator<Item = (ChainNodeId, &Node)> + '_ {
+        self.nodes.iter()
+    }
     pub fn label(&self) -> &str {
-        &self.labels.best()
+        match &self.label_override {
+            Some(label) => label,
+            None => self.labels.best(),
+        }
     }
     pub fn node_count(&self) -> usize {
         self.nodes.len()
     }
+    /// The most nodes that this chain has had at once.
+    pub fn highest_node_count(&self) -> usize {
+        self.highest_node_count
+    }
     pub fn best_block(&self) -> &Block {
         &self.best
     }
@@ -387,6 +684,79 @@ impl Chain {
     pub fn finalized_block(&self) -> &Block {
         &self.finalized
     }
+    pub fn average_time_to_finality(&self) -> Option<u64> {
+        self.time_to_finality.average()
+    }
+    pub fn authority_set_id(&self) -> Option<u64> {
+        self.authority_set_id
+    }
+    /// How many nodes on this chain are running at least the recommended version, and
+    /// how many are running an older one, or `None` if there's no recommended version.
+    /// Nodes whose versions can't be understood count towards neither.
+    pub fn version_compliance(&self) -> Option<(usize, usize)> {
+        let recommended = self.recommended_version.as_ref()?;
+        let mut up_to_date = 0;
+        let mut outdated = 0;
+        for (_, node) in self.nodes.iter() {
+            match VersionStatus::of(&node.details().version, recommended) {
+                VersionStatus::UpToDate => up_to_date += 1,
+                VersionStatus::Outdated => outdated += 1,
+                VersionStatus::Unknown => {}
+            }
+        }
+        Some((up_to_date, outdated))
+    }
+    /// How many nodes on this chain are synced, and how many are still syncing. Nodes
+    /// are synced if their best block is close enough to the best block of the chain.
+    pub fn sync_breakdown(&self) -> (usize, usize) {
+        let min_height = self.best.height.saturating_sub(SYNCED_BLOCK_MARGIN);
+        let synced = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.best().height >= min_height)
+            .count();
+        (synced, self.nodes.len() - synced)
+    }
+    /// How geographically spread out the located nodes on this chain are, from 0 (all in
+    /// one city) to 1 (every node in a different city). This is the entropy of the nodes'
+    /// distribution across cities, relative to the most that this many nodes could have.
+    /// Nodes that haven't been located are left out; see [`Chain::located_fraction`].
+    pub fn decentralization_score(&self) -> f64 {
+        let mut nodes_per_city: HashMap<&str, usize> = HashMap::new();
+        for (_, node) in self.nodes.iter() {
+            if let Some(location) = node.location() {
+                *nodes_per_city.entry(&location.city).or_default() += 1;
+            }
+        }
+
+        let located: usize = nodes_per_city.values().sum();
+        if located < 2 {
+            return 0.0;
+        }
+        let located = located as f64;
+        let entropy: f64 = nodes_per_city
+            .values()
+            .map(|&count| {
+                let p = count as f64 / located;
+                -p * p.ln()
+            })
+            .sum();
+        entropy / located.ln()
+    }
+
+    /// The fraction of nodes on this chain that have a known location.
+    pub fn located_fraction(&self) -> f64 {
+        if self.nodes.is_empty() {
+            return 0.0;
+        }
+        let located = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.location().is_some())
+            .count();
+        located as f64 / self.nodes.len() as f64
+    }
+
     pub fn genesis_hash(&self) -> BlockHash {
         self.genesis_hash
     }
@@ -394,3 +764,553 @@ impl Chain {
         &self.stats
     }
 }
+
+/// Match up the times that best blocks are first imported with the times that
+/// they are finalized, to work out how long blocks take to be finalized.
+struct TimeToFinality {
+    /// Heights of recent best blocks, and when we first saw them imported.
+    recent_block_imports: VecDeque<(BlockNumber, Timestamp)>,
+    /// How long recent blocks took to be finalized after first being imported.
+    finality_times: NumStats<u64>,
+    /// Calculated average time to finality
+    average: Option<u64>,
+}
+
+impl TimeToFinality {
+    fn new() -> Self {
+        TimeToFinality {
+            recent_block_imports: VecDeque::new(),
+            finality_times: NumStats::new(50),
+            average: None,
+        }
+    }
+
+    /// Make a note of when a new best block was first seen, so that we can
+    /// later work out how long it took to be finalized.
+    fn note_block_imported(&mut self, height: BlockNumber, now: Timestamp) {
+        if self.recent_block_imports.len() >= RECENT_BLOCK_IMPORTS_WINDOW {
+            self.recent_block_imports.pop_front();
+        }
+        self.recent_block_imports.push_back((height, now));
+    }
+
+    /// The chain has finalized blocks in the range `(old_height, new_height]`. Record how long
+    /// each of these blocks that we saw imported took to be finalized, returning `true` if the
+    /// average time to finality was updated.
+    fn note_blocks_finalized(
+        &mut self,
+        old_height: BlockNumber,
+        new_height: BlockNumber,
+        now: Timestamp,
+    ) -> bool {
+        let mut updated = false;
+        while let Some(&(height, imported_at)) = self.recent_block_imports.front() {
+            if height > new_height {
+                break;
+            }
+            self.recent_block_imports.pop_front();
+            if height > old_height {
+                self.finality_times.push(now.saturating_sub(imported_at));
+                updated = true;
+            }
+        }
+
+        if updated {
+            self.average = Some(self.finality_times.average());
+        }
+        updated
+    }
+
+    fn average(&self) -> Option<u64> {
+        self.average
+    }
+}
+
+/// Remember when recently imported blocks were first imported by any node on the
+/// chain, to work out how long it takes for them to reach every other node.
+struct BlockPropagation {
+    /// Hashes of recently imported blocks, oldest first.
+    recent_block_hashes: VecDeque<BlockHash>,
+    /// When each of the blocks in `recent_block_hashes` was first imported.
+    first_seen: HashMap<BlockHash, Timestamp>,
+}
+
+impl BlockPropagation {
+    fn new() -> Self {
+        BlockPropagation {
+            recent_block_hashes: VecDeque::new(),
+            first_seen: HashMap::new(),
+        }
+    }
+
+    /// Make a note of a node importing a block, returning how many milliseconds
+    /// it's been since any node first imported it. This is 0 for the first node.
+    fn note_block_imported(&mut self, hash: BlockHash, now: Timestamp) -> u64 {
+        if let Some(first_seen) = self.first_seen.get(&hash) {
+            return now.saturating_sub(*first_seen);
+        }
+
+        if self.recent_block_hashes.len() >= RECENT_BLOCK_HASHES_WINDOW {
+            if let Some(oldest) = self.recent_block_hashes.pop_front() {
+                self.first_seen.remove(&oldest);
+            }
+        }
+        self.recent_block_hashes.push_back(hash);
+        self.first_seen.insert(hash, now);
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed_message::FeedEncoding;
+    use common::node_message::AfgAuthoritySet;
+
+    #[test]
+    fn time_to_finality_is_averaged_over_finalized_blocks() {
+        let mut ttf = TimeToFinality::new();
+
+        // Blocks 1..=4 are imported 1s apart:
+        for height in 1..=4 {
+            ttf.note_block_imported(height, height * 1000);
+        }
+
+        // Blocks 1 and 2 are finalized at 5s; they took 4s and 3s respectively:
+        assert!(ttf.note_blocks_finalized(0, 2, 5000));
+        assert_eq!(ttf.average(), Some(3500));
+
+        // Nothing new finalized; no change:
+        assert!(!ttf.note_blocks_finalized(2, 2, 6000));
+
+        // Blocks 3 and 4 are finalized at 8s; they took 5s and 4s:
+        assert!(ttf.note_blocks_finalized(2, 4, 8000));
+        assert_eq!(ttf.average(), Some(4000));
+    }
+
+    #[test]
+    fn time_to_finality_ignores_blocks_we_did_not_see_imported() {
+        let mut ttf = TimeToFinality::new();
+
+        ttf.note_block_imported(10, 1000);
+
+        // Blocks below 10 were never seen imported, so only block 10 counts:
+        assert!(ttf.note_blocks_finalized(0, 10, 3000));
+        assert_eq!(ttf.average(), Some(2000));
+
+        // Finalizing blocks we never saw imported tells us nothing:
+        assert!(!ttf.note_blocks_finalized(10, 20, 4000));
+    }
+
+    /// A chain with a node in each of the given cities (`None` meaning unlocated).
+    fn chain_with_nodes_in(cities: &[Option<&str>]) -> Chain {
+        use common::node_types::{NetworkId, NodeDetails, NodeLocation};
+        use std::sync::Arc;
+
+        let mut chain = Chain::new(BlockHash::zero(), 100);
+        for (idx, city) in cities.iter().enumerate() {
+            let mut node = Node::new(NodeDetails {
+                chain: "Chain".into(),
+                name: format!("Node {}", idx).into(),
+                implementation: "Bar".into(),
+                version: "0.1".into(),
+                validator: None,
+                network_id: NetworkId::new(),
+                startup_time: None,
+                target_os: None,
+                target_arch: None,
+                target_env: None,
+                sysinfo: None,
+            });
+            node.update_location(city.map(|city| {
+                Arc::new(NodeLocation {
+                    latitude: 0.0,
+                    longitude: 0.0,
+                    city: city.into(),
+                })
+            }));
+            chain.add_node(node);
+        }
+        chain
+    }
+
+    #[test]
+    fn decentralization_score_reflects_how_spread_out_nodes_are() {
+        // Everything in one place:
+        let chain = chain_with_nodes_in(&[Some("Berlin"); 4]);
+        assert_eq!(chain.decentralization_score(), 0.0);
+        assert_eq!(chain.located_fraction(), 1.0);
+
+        // Every node somewhere different:
+        let chain = chain_with_nodes_in(&[
+            Some("Berlin"),
+            Some("London"),
+            Some("Tokyo"),
+            Some("Sydney"),
+        ]);
+        assert!((chain.decentralization_score() - 1.0).abs() < 1e-9);
+
+        // Spread evenly over two places; half of the most spread out 4 nodes could be:
+        let chain =
+            chain_with_nodes_in(&[Some("Berlin"), Some("Berlin"), Some("Tokyo"), Some("Tokyo")]);
+        assert!((chain.decentralization_score() - 0.5).abs() < 1e-9);
+
+        // Unlocated nodes don't count towards the score:
+        let chain = chain_with_nodes_in(&[Some("Berlin"), Some("Tokyo"), None, None]);
+        assert!((chain.decentralization_score() - 1.0).abs() < 1e-9);
+        assert_eq!(chain.located_fraction(), 0.5);
+
+        // Nothing located; nothing to score:
+        let chain = chain_with_nodes_in(&[None, None]);
+        assert_eq!(chain.decentralization_score(), 0.0);
+        assert_eq!(chain.located_fraction(), 0.0);
+    }
+
+    fn node_with(network_id: &str, validator: bool) -> Node {
+        use common::node_types::{NetworkId, NodeDetails};
+        Node::new(NodeDetails {
+            chain: "Chain".into(),
+            name: "Node".into(),
+            implementation: "Bar".into(),
+            version: "0.1".into(),
+            validator: validator.then(|| "0x1234".into()),
+            network_id: NetworkId::from(network_id).unwrap(),
+            startup_time: None,
+            target_os: None,
+            target_arch: None,
+            target_env: None,
+            sysinfo: None,
+        })
+    }
+
+    fn is_added(result: AddNodeResult) -> bool {
+        matches!(result, AddNodeResult::Added { .. })
+    }
+
+    #[test]
+    fn quota_counts_every_node_by_default() {
+        let mut chain = Chain::new(BlockHash::zero(), 2);
+        assert!(is_added(chain.add_node(node_with("A", true))));
+        assert!(is_added(chain.add_node(node_with("A", true))));
+
+        // A node connecting twice uses up the quota, as do observers:
+        assert!(!is_added(chain.add_node(node_with("A", true))));
+        assert!(!is_added(chain.add_node(node_with("B", false))));
+    }
+
+    #[test]
+    fn quota_can_count_distinct_network_ids() {
+        let mut chain = Chain::new(BlockHash::zero(), 2);
+        chain.set_quota_count_source(QuotaCountSource::DistinctNetworkIds);
+        assert!(is_added(chain.add_node(node_with("A", true))));
+        assert!(is_added(chain.add_node(node_with("A", true))));
+        assert!(is_added(chain.add_node(node_with("B", false))));
+
+        // Nodes that have already connected can connect again, but new ones can't:
+        assert!(is_added(chain.add_node(node_with("B", false))));
+        assert!(!is_added(chain.add_node(node_with("C", true))));
+
+        // Nodes without a network ID can't be told apart, so each one counts:
+        let mut chain = Chain::new(BlockHash::zero(), 2);
+        chain.set_quota_count_source(QuotaCountSource::DistinctNetworkIds);
+        assert!(is_added(chain.add_node(node_with("", true))));
+        assert!(is_added(chain.add_node(node_with("", true))));
+        assert!(!is_added(chain.add_node(node_with("", true))));
+    }
+
+    #[test]
+    fn quota_can_count_only_validators() {
+        let mut chain = Chain::new(BlockHash::zero(), 2);
+        chain.set_quota_count_source(QuotaCountSource::Validators);
+        assert!(is_added(chain.add_node(node_with("A", true))));
+        assert!(is_added(chain.add_node(node_with("B", false))));
+        assert!(is_added(chain.add_node(node_with("B", false))));
+
+        // Observers don't count towards the quota, but a node connecting twice does:
+        assert!(is_added(chain.add_node(node_with("A", true))));
+        assert!(!is_added(chain.add_node(node_with("C", true))));
+        assert!(is_added(chain.add_node(node_with("D", false))));
+    }
+
+    fn node_on_version(version: &str) -> Node {
+        use common::node_types::{NetworkId, NodeDetails};
+        Node::new(NodeDetails {
+            chain: "Chain".into(),
+            name: "Node".into(),
+            implementation: "Bar".into(),
+            version: version.into(),
+            validator: None,
+            network_id: NetworkId::new(),
+            startup_time: None,
+            target_os: None,
+            target_arch: None,
+            target_env: None,
+            sysinfo: None,
+        })
+    }
+
+    #[test]
+    fn version_compliance_counts_up_to_date_and_outdated_nodes() {
+        let mut chain = Chain::new(BlockHash::zero(), 100);
+        for version in [
+            "0.9.12-a1b2c3d-x86_64-linux-gnu",
+            "0.9.13",
+            "0.9.11-a1b2c3d-x86_64-linux-gnu",
+            "0.8.30",
+            "0.7.0",
+            "not-a-version",
+        ] {
+            chain.add_node(node_on_version(version));
+        }
+
+        // Nothing to compare against yet:
+        assert_eq!(chain.version_compliance(), None);
+
+        // Unparseable versions are left out of both counts:
+        chain.set_recommended_version(Some(semver::Version::new(0, 9, 12)));
+        assert_eq!(chain.version_compliance(), Some((2, 3)));
+
+        chain.set_recommended_version(Some(semver::Version::new(0, 8, 0)));
+        assert_eq!(chain.version_compliance(), Some((4, 1)));
+    }
+
+    #[test]
+    fn sync_breakdown_counts_nodes_near_the_best_block_as_synced() {
+        let mut chain = Chain::new(BlockHash::zero(), 100);
+        let mut feed = FeedMessageSerializer::new();
+        for height in [1000, 995, 990, 989, 500, 0] {
+            let nid = match chain.add_node(node_on_version("0.1")) {
+                AddNodeResult::Added { id, .. } => id,
+                AddNodeResult::Overquota => panic!("node should be added"),
+            };
+            let block = Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            };
+            chain.update_node(nid, Payload::BlockImport(block), &mut feed);
+        }
+
+        // Nodes within 10 blocks of the best block are synced:
+        assert_eq!(chain.sync_breakdown(), (3, 3));
+    }
+
+    #[test]
+    fn nodes_are_stale_after_the_configured_threshold() {
+        let is_stale_after = |stale_threshold: Duration, quiet_for: Duration| {
+            let mut chain = Chain::new(BlockHash::zero(), 100);
+            chain.set_stale_threshold(stale_threshold);
+            let nid = match chain.add_node(node_on_version("0.1")) {
+                AddNodeResult::Added { id, .. } => id,
+                AddNodeResult::Overquota => panic!("node should be added"),
+            };
+            let block = Block {
+                hash: BlockHash::from_low_u64_be(1),
+                height: 1,
+            };
+            let mut feed = FeedMessageSerializer::new();
+            chain.update_node(nid, Payload::BlockImport(block), &mut feed);
+
+            let now = time::now() + quiet_for.as_millis() as u64;
+            chain.update_stale_nodes(now, &mut feed);
+            chain.nodes.get(nid).unwrap().stale()
+        };
+
+        // A chain with 6 second blocks wants to know about quiet nodes quickly:
+        assert!(is_stale_after(
+            Duration::from_secs(30),
+            Duration::from_secs(60)
+        ));
+        // But a minute is nothing for a chain with 60 second blocks:
+        assert!(!is_stale_after(
+            Duration::from_secs(600),
+            Duration::from_secs(60)
+        ));
+        // And the default sits between the two:
+        assert!(!is_stale_after(
+            DEFAULT_STALE_THRESHOLD,
+            Duration::from_secs(60)
+        ));
+        assert!(is_stale_after(
+            DEFAULT_STALE_THRESHOLD,
+            Duration::from_secs(600)
+        ));
+    }
+
+    #[test]
+    fn block_propagation_is_measured_from_the_first_import() {
+        let mut propagation = BlockPropagation::new();
+        let hash = BlockHash::from_low_u64_be(1);
+        assert_eq!(propagation.note_block_imported(hash, 1000), 0);
+        assert_eq!(propagation.note_block_imported(hash, 1250), 250);
+        assert_eq!(propagation.note_block_imported(hash, 1400), 400);
+
+        // Only a limited number of blocks are remembered:
+        for n in 0..RECENT_BLOCK_HASHES_WINDOW as u64 {
+            propagation.note_block_imported(BlockHash::from_low_u64_be(n + 2), 2000);
+        }
+        assert_eq!(propagation.note_block_imported(hash, 3000), 0);
+        assert_eq!(propagation.first_seen.len(), RECENT_BLOCK_HASHES_WINDOW);
+    }
+
+    #[test]
+    fn nodes_importing_a_block_later_report_how_long_it_took_to_reach_them() {
+        let mut chain = Chain::new(BlockHash::zero(), 100);
+        chain.set_block_propagation_messages(true);
+        let mut add_node = || match chain.add_node(node_on_version("0.1")) {
+            AddNodeResult::Added { id, .. } => id,
+            AddNodeResult::Overquota => panic!("node should be added"),
+        };
+        let (first, second) = (add_node(), add_node());
+        let block = Block {
+            hash: BlockHash::from_low_u64_be(1),
+            height: 1,
+        };
+
+        let mut feed = FeedMessageSerializer::new();
+        chain.update_node(first, Payload::BlockImport(block), &mut feed);
+        std::thread::sleep(Duration::from_millis(20));
+        chain.update_node(second, Payload::BlockImport(block), &mut feed);
+
+        let bytes = feed
+            .into_finalized_encodings()
+            .unwrap()
+            .encoded_as(FeedEncoding::Json);
+        let msgs: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+        let propagation: Vec<_> = msgs
+            .chunks(2)
+            .filter(|msg| msg[0] == 28)
+            .map(|msg| {
+                let payload = msg[1].as_array().unwrap();
+                (payload[0].as_u64().unwrap(), payload[2].as_u64().unwrap())
+            })
+            .collect();
+
+        // Only the node which imported the block second is reported on:
+        assert_eq!(propagation.len(), 1);
+        assert_eq!(propagation[0].0, usize::from(second) as u64);
+        assert!(propagation[0].1 >= 20);
+    }
+
+    #[test]
+    fn block_imports_from_nodes_far_behind_can_be_dropped() {
+        let mut chain = Chain::new(BlockHash::zero(), 100);
+        chain.set_block_import_drop_margin(Some(100));
+        let mut feed = FeedMessageSerializer::new();
+        let mut import = |height| {
+            let nid = match chain.add_node(node_on_version("0.1")) {
+                AddNodeResult::Added { id, .. } => id,
+                AddNodeResult::Overquota => panic!("node should be added"),
+            };
+            let block = Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            };
+            chain.update_node(nid, Payload::BlockImport(block), &mut feed);
+            usize::from(nid) as u64
+        };
+        let at_tip = import(1000);
+        let far_behind = import(10);
+        let near_tip = import(950);
+
+        let bytes = feed
+            .into_finalized_encodings()
+            .unwrap()
+            .encoded_as(FeedEncoding::Json);
+        let msgs: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+        let imported_by: Vec<_> = msgs
+            .chunks(2)
+            .filter(|msg| msg[0] == 6)
+            .map(|msg| msg[1][0].as_u64().unwrap())
+            .collect();
+        assert_eq!(imported_by, vec![at_tip, near_tip]);
+        assert!(!imported_by.contains(&far_behind));
+
+        // The node's progress is still tracked:
+        let far_behind = chain
+            .get_node(ChainNodeId::from(far_behind as usize))
+            .unwrap();
+        assert_eq!(far_behind.best().height, 10);
+    }
+
+    #[test]
+    fn new_authority_sets_are_announced_once_per_chain() {
+        let mut chain = Chain::new(BlockHash::zero(), 100);
+        let nids: Vec<_> = (0..10)
+            .map(|_| match chain.add_node(node_on_version("0.1")) {
+                AddNodeResult::Added { id, .. } => id,
+                AddNodeResult::Overquota => panic!("node should be added"),
+            })
+            .collect();
+        let mut feed = FeedMessageSerializer::new();
+        let mut report = |nid, set_id: u64| {
+            let authority_set = AfgAuthoritySet {
+                authority_id: format!("authority-{}", usize::from(nid)).into(),
+                authorities: "[]".into(),
+                authority_set_id: set_id.to_string().into(),
+            };
+            chain.update_node(nid, Payload::AfgAuthoritySet(authority_set), &mut feed);
+        };
+
+        // Every node reports the same set, and then the same new set, but one node is
+        // slow to notice the change:
+        for &nid in &nids {
+            report(nid, 4);
+        }
+        for &nid in &nids[1..] {
+            report(nid, 5);
+        }
+        report(nids[0], 4);
+        report(nids[0], 5);
+
+        let bytes = feed
+            .into_finalized_encodings()
+            .unwrap()
+            .encoded_as(FeedEncoding::Json);
+        let msgs: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+        let announced: Vec<_> = msgs
+            .chunks(2)
+            .filter(|msg| msg[0] == 29)
+            .map(|msg| msg[1].as_u64().unwrap())
+            .collect();
+        assert_eq!(announced, vec![4, 5]);
+        assert_eq!(chain.authority_set_id(), Some(5));
+    }
+
+    #[test]
+    fn repeated_intervals_are_only_applied_once() {
+        let mut chain = Chain::new(BlockHash::zero(), 100);
+        let nid = match chain.add_node(node_on_version("0.1")) {
+            AddNodeResult::Added { id, .. } => id,
+            AddNodeResult::Overquota => panic!("node should be added"),
+        };
+        let interval = |peers| {
+            Payload::SystemInterval(common::node_message::SystemInterval {
+                peers: Some(peers),
+                txcount: Some(0),
+                bandwidth_upload: Some(1.0),
+                bandwidth_download: Some(1.0),
+                finalized_height: None,
+                finalized_hash: None,
+                block: None,
+                used_state_cache_size: None,
+            })
+        };
+        let hardware_updates = |chain: &mut Chain, payload| {
+            let mut feed = FeedMessageSerializer::new();
+            chain.update_node(nid, payload, &mut feed);
+            let count = feed
+                .counts()
+                .iter()
+                .find(|&(name, _)| name == "Hardware")
+                .unwrap()
+                .1;
+            count
+        };
+
+        assert_eq!(hardware_updates(&mut chain, interval(10)), 1);
+        assert_eq!(hardware_updates(&mut chain, interval(10)), 0);
+
+        // Something changing means it's applied again:
+        assert_eq!(hardware_updates(&mut chain, interval(11)), 1);
+    }
+}
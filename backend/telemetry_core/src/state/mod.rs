@@ -18,8 +18,10 @@ mod chain;
 mod chain_stats;
 mod counter;
 mod node;
+mod version_compliance;
 
 mod state;
 
 pub use node::Node;
 pub use state::*;
+pub use version_compliance::RecommendedVersion;
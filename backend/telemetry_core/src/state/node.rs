@@ -15,17 +15,31 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::find_location;
-use common::node_message::SystemInterval;
+use common::node_message::{AfgAuthoritySet, Finalized, Payload, SystemConnected, SystemInterval};
 use common::node_types::{
-    Block, BlockDetails, NodeDetails, NodeHardware, NodeHwBench, NodeIO, NodeLocation, NodeStats,
-    Timestamp,
+    Block, BlockDetails, BlockNumber, NodeDetails, NodeHardware, NodeHwBench, NodeIO, NodeLocation,
+    NodeStats, Timestamp,
 };
 use common::time;
+use std::collections::VecDeque;
 
 /// Minimum time between block below broadcasting updates to the browser gets throttled, in ms.
 const THROTTLE_THRESHOLD: u64 = 100;
 /// Minimum time of intervals for block updates sent to the browser when throttled, in ms.
 const THROTTLE_INTERVAL: u64 = 1000;
+/// How many recent best blocks are used to estimate the block time of a node.
+const BLOCK_TIME_WINDOW: usize = 10;
+
+/// The last payload of each kind that a node sent us, exactly as we received it.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RawNodePayloads {
+    pub system_connected: SystemConnected,
+    pub system_interval: Option<SystemInterval>,
+    pub block_import: Option<Block>,
+    pub notify_finalized: Option<Finalized>,
+    pub afg_authority_set: Option<AfgAuthoritySet>,
+    pub hwbench: Option<common::node_message::NodeHwBench>,
+}
 
 pub struct Node {
     /// Static details
@@ -44,12 +58,33 @@ pub struct Node {
     hardware: NodeHardware,
     /// Physical location details
     location: find_location::Location,
+    /// What we know about the node's location
+    location_status: find_location::LocationStatus,
     /// Flag marking if the node is stale (not syncing or producing blocks)
     stale: bool,
     /// Unix timestamp for when node started up (falls back to connection time)
     startup_time: Option<Timestamp>,
     /// Hardware benchmark results for the node
     hwbench: Option<NodeHwBench>,
+    /// The group that the node belongs to, derived from its name
+    group: Option<Box<str>>,
+    /// Heights and arrival times of recent best blocks, to estimate block time from
+    recent_blocks: VecDeque<(BlockNumber, Timestamp)>,
+    /// The payloads the node has sent us, if we're keeping hold of them
+    raw_payloads: Option<Box<RawNodePayloads>>,
+    /// The parts of the last interval we applied that we compare new ones against
+    last_interval: Option<IntervalSummary>,
+    /// Unix timestamp for when we last heard anything from the node
+    last_message: Timestamp,
+}
+
+/// The parts of a [`SystemInterval`] which decide whether it tells us anything new.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IntervalSummary {
+    best_height: Option<BlockNumber>,
+    finalized_height: Option<BlockNumber>,
+    peers: Option<u64>,
+    txcount: Option<u64>,
 }
 
 impl Node {
@@ -68,9 +103,15 @@ impl Node {
             throttle: 0,
             hardware: NodeHardware::default(),
             location: None,
+            location_status: find_location::LocationStatus::Unknown,
             stale: false,
             startup_time,
             hwbench: None,
+            group: None,
+            recent_blocks: VecDeque::with_capacity(BLOCK_TIME_WINDOW),
+            raw_payloads: None,
+            last_interval: None,
+            last_message: time::now(),
         }
     }
 
@@ -94,6 +135,15 @@ impl Node {
         self.best.block_timestamp
     }
 
+    /// When we last heard anything from the node, even if it told us nothing new.
+    pub fn last_message(&self) -> Timestamp {
+        self.last_message
+    }
+
+    pub fn note_message(&mut self, timestamp: Timestamp) {
+        self.last_message = timestamp;
+    }
+
     pub fn finalized(&self) -> &Block {
         &self.finalized
     }
@@ -102,14 +152,71 @@ impl Node {
         &self.hardware
     }
 
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn set_group(&mut self, group: Option<Box<str>>) {
+        self.group = group;
+    }
+
+    /// Keep hold of the payloads that this node sends us from now on, alongside the one it
+    /// connected with, so that they can be inspected as they were received.
+    pub fn keep_raw_payloads(&mut self, system_connected: SystemConnected) {
+        self.raw_payloads = Some(Box::new(RawNodePayloads {
+            system_connected,
+            system_interval: None,
+            block_import: None,
+            notify_finalized: None,
+            afg_authority_set: None,
+            hwbench: None,
+        }));
+    }
+
+    /// The last payload of each kind that this node sent us, if we're keeping hold of them.
+    pub fn raw_payloads(&self) -> Option<&RawNodePayloads> {
+        self.raw_payloads.as_deref()
+    }
+
+    /// Note a payload that this node sent us, if we're keeping hold of them.
+    pub fn note_raw_payload(&mut self, payload: &Payload) {
+        let raw = match &mut self.raw_payloads {
+            Some(raw) => raw,
+            None => return,
+        };
+        match payload {
+            Payload::SystemConnected(system_connected) => {
+                raw.system_connected = system_connected.clone()
+            }
+            Payload::SystemInterval(interval) => raw.system_interval = Some(interval.clone()),
+            Payload::BlockImport(block) => raw.block_import = Some(*block),
+            Payload::NotifyFinalized(finalized) => raw.notify_finalized = Some(finalized.clone()),
+            Payload::AfgAuthoritySet(authority) => raw.afg_authority_set = Some(authority.clone()),
+            Payload::HwBench(hwbench) => raw.hwbench = Some(hwbench.clone()),
+        }
+    }
+
     pub fn location(&self) -> Option<&NodeLocation> {
         self.location.as_deref()
     }
 
     pub fn update_location(&mut self, location: find_location::Location) {
+        self.location_status = match location {
+            Some(_) => find_location::LocationStatus::Located,
+            None => find_location::LocationStatus::Unknown,
+        };
         self.location = location;
     }
 
+    pub fn location_status(&self) -> find_location::LocationStatus {
+        self.location_status
+    }
+
+    pub fn set_location_private(&mut self) {
+        self.location = None;
+        self.location_status = find_location::LocationStatus::Private;
+    }
+
     pub fn block_details(&self) -> &BlockDetails {
         &self.best
     }
@@ -142,6 +249,12 @@ impl Node {
         self.best.block_timestamp = timestamp;
         self.best.propagation_time = propagation_time;
 
+        if self.recent_blocks.len() == BLOCK_TIME_WINDOW {
+            self.recent_blocks.pop_front();
+        }
+        self.recent_blocks
+            .push_back((self.best.block.height, timestamp));
+
         if self.throttle < timestamp {
             if self.best.block_time <= THROTTLE_THRESHOLD {
                 self.throttle = timestamp + THROTTLE_INTERVAL;
@@ -153,6 +266,32 @@ impl Node {
         }
     }
 
+    /// Estimate how long this node takes to import each block, in ms, from the
+    /// recent best blocks it has reported. Blocks that the node skipped over are
+    /// accounted for, so this is the time per block rather than per report.
+    pub fn estimated_block_time(&self) -> Option<u64> {
+        let &(first_height, first_time) = self.recent_blocks.front()?;
+        let &(last_height, last_time) = self.recent_blocks.back()?;
+        let blocks = last_height.saturating_sub(first_height);
+        if blocks == 0 {
+            return None;
+        }
+        Some(last_time.saturating_sub(first_time) / blocks)
+    }
+
+    /// Note an interval that this node sent us. Returns `false` if it has the same block
+    /// heights and peer and transaction counts as the last one, in which case there's no
+    /// need to apply it again.
+    pub fn note_interval(&mut self, interval: &SystemInterval) -> bool {
+        let summary = IntervalSummary {
+            best_height: interval.block.map(|block| block.height),
+            finalized_height: interval.finalized_height,
+            peers: interval.peers,
+            txcount: interval.txcount,
+        };
+        self.last_interval.replace(summary) != Some(summary)
+    }
+
     pub fn update_hardware(&mut self, interval: &SystemInterval) -> bool {
         let mut changed = false;
 
@@ -225,6 +364,11 @@ impl Node {
         self.stale
     }
 
+    /// Mark the node as stale. It stops being stale once it imports a new best block.
+    pub fn set_stale(&mut self) {
+        self.stale = true;
+    }
+
     pub fn set_validator_address(&mut self, addr: Box<str>) -> bool {
         if self.details.validator.as_ref() == Some(&addr) {
             false
@@ -238,3 +382,62 @@ impl Node {
         self.startup_time
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_types::{BlockHash, NetworkId};
+
+    fn import(node: &mut Node, height: BlockNumber, timestamp: Timestamp) {
+        let block = Block {
+            hash: BlockHash::from_low_u64_be(height),
+            height,
+        };
+        assert!(node.update_block(block));
+        node.update_details(timestamp, None);
+    }
+
+    #[test]
+    fn block_time_is_estimated_from_recent_blocks() {
+        let mut node = Node::new(NodeDetails {
+            chain: "Chain One".into(),
+            name: "A".into(),
+            implementation: "Bar".into(),
+            version: "0.1".into(),
+            validator: None,
+            network_id: NetworkId::new(),
+            startup_time: None,
+            target_os: None,
+            target_arch: None,
+            target_env: None,
+            sysinfo: None,
+        });
+        assert_eq!(node.estimated_block_time(), None);
+        let start = time::now();
+
+        // One block isn't enough to go on:
+        import(&mut node, 1, start + 6_000);
+        assert_eq!(node.estimated_block_time(), None);
+
+        // A block every 6 seconds:
+        for height in 2..=20 {
+            import(&mut node, height, start + height * 6_000);
+        }
+        assert_eq!(node.estimated_block_time(), Some(6_000));
+
+        // Skipping heights doesn't make blocks look slower:
+        import(&mut node, 25, start + 25 * 6_000);
+        import(&mut node, 28, start + 28 * 6_000);
+        assert_eq!(node.estimated_block_time(), Some(6_000));
+
+        // Once older blocks drop out of the window, the estimate follows a new cadence:
+        for height in 29..=40 {
+            import(
+                &mut node,
+                height,
+                start + 28 * 6_000 + (height - 28) * 12_000,
+            );
+        }
+        assert_eq!(node.estimated_block_time(), Some(12_000));
+    }
+}
@@ -17,13 +17,23 @@
 use super::node::Node;
 use crate::feed_message::{ChainStats, FeedMessageSerializer};
 use crate::find_location;
-use common::node_message::Payload;
-use common::node_types::{Block, BlockHash, NodeDetails, Timestamp};
-use common::{id_type, DenseMap};
+use common::node_message::{Payload, SystemConnected};
+use common::node_types::{Block, BlockHash, BlockNumber, NetworkId, NodeDetails, Timestamp};
+use common::{id_type, time, DenseMap};
+use regex::Regex;
 use std::collections::{HashMap, HashSet};
 use std::iter::IntoIterator;
+use std::time::Duration;
 
 use super::chain::{self, Chain, ChainNodeId};
+use super::version_compliance::RecommendedVersion;
+
+/// Nodes whose names don't match the node group pattern are put into this group.
+pub const DEFAULT_NODE_GROUP: &str = "default";
+
+/// Missing node details are replaced with this, if [`IncompleteNodePolicy::Placeholder`]
+/// is in effect. Network IDs are suffixed with a number so that they remain distinct.
+pub const PLACEHOLDER_NODE_DETAIL: &str = "unknown";
 
 id_type! {
     /// A globally unique Chain ID.
@@ -51,15 +61,95 @@ pub struct State {
     /// Chain labels that we do not want to allow connecting.
     denylist: HashSet<String>,
 
+    /// If provided, only chains whose label or genesis hash is on this list can connect.
+    allowlist: Option<ChainAllowlist>,
+
+    /// Network IDs of individual nodes that we do not want to allow connecting,
+    /// regardless of the chain they report.
+    node_blocklist: HashSet<String>,
+
     /// How many nodes from third party chains are allowed to connect
     /// before we prevent connections from them.
     max_third_party_nodes: usize,
+
+    /// Genesis hashes of third party chains which, like first party chains,
+    /// allow any number of nodes to connect.
+    quota_exempt_chains: HashSet<BlockHash>,
+
+    /// If provided, no chain can have more than this many nodes connected, whether
+    /// it's a third party chain or not.
+    max_nodes_per_chain: Option<usize>,
+
+    /// If provided, nodes are put into groups based on their names.
+    node_group_pattern: Option<Regex>,
+
+    /// Nodes which haven't imported a new best block for this long are stale, unless
+    /// their chain has a threshold of its own.
+    stale_threshold: Duration,
+    chain_stale_thresholds: HashMap<BlockHash, Duration>,
+
+    /// If provided, nodes this many blocks behind the best block of their chain are stale.
+    stale_block_margin: Option<BlockNumber>,
+
+    /// If provided, feeds aren't told about blocks imported this far behind the best block.
+    block_import_drop_margin: Option<BlockNumber>,
+
+    /// Should feeds be told how long blocks took to reach each node?
+    block_propagation_messages: bool,
+
+    /// Which nodes count towards the quota of third party chains.
+    quota_count_source: QuotaCountSource,
+
+    /// Should nodes that reconnect be given back the ID they had before?
+    sticky_node_ids: bool,
+
+    /// Should nodes keep hold of the payloads they send us, for debugging?
+    keep_raw_node_payloads: bool,
+
+    /// Details that every node must report, and what to do about nodes that don't.
+    required_node_fields: Vec<RequiredNodeField>,
+    incomplete_node_policy: IncompleteNodePolicy,
+
+    /// How many placeholder network IDs have been handed out, so that each is unique.
+    placeholder_network_ids: u64,
+
+    /// The versions that nodes on each chain are recommended to run.
+    recommended_versions: HashMap<BlockHash, semver::Version>,
+
+    /// Labels to use for chains in place of the ones that their nodes report.
+    chain_label_overrides: HashMap<BlockHash, Box<str>>,
+
+    /// Until this time (in unix ms), a more relaxed limit on the number of
+    /// third party nodes applies, so that we can absorb a surge of reconnecting nodes.
+    quota_warmup: Option<QuotaWarmup>,
+}
+
+struct ChainAllowlist {
+    labels: HashSet<String>,
+    genesis_hashes: HashSet<BlockHash>,
+}
+
+impl ChainAllowlist {
+    fn allows(&self, label: &str, genesis_hash: &BlockHash) -> bool {
+        self.labels.contains(label) || self.genesis_hashes.contains(genesis_hash)
+    }
+}
+
+struct QuotaWarmup {
+    ends_at: Timestamp,
+    max_third_party_nodes: usize,
 }
 
 /// Adding a node to a chain leads to this node_idult
 pub enum AddNodeResult<'a> {
     /// The chain is on the "deny list", so we can't add the node
     ChainOnDenyList,
+    /// There is an "allow list" and the chain isn't on it, so we can't add the node
+    ChainNotOnAllowList,
+    /// The node itself is on the blocklist, so we can't add it
+    NodeBlocked,
+    /// The node didn't report some required details, so we can't add it
+    NodeIncomplete,
     /// The chain is over quota (too many nodes connected), so can't add the node
     ChainOverQuota,
     /// The node was added to the chain
@@ -111,59 +201,406 @@ impl State {
             chains: DenseMap::new(),
             chains_by_genesis_hash: HashMap::new(),
             denylist: denylist.into_iter().collect(),
+            allowlist: None,
+            node_blocklist: HashSet::new(),
+            max_third_party_nodes,
+            quota_exempt_chains: HashSet::new(),
+            quota_warmup: None,
+            node_group_pattern: None,
+            stale_threshold: chain::DEFAULT_STALE_THRESHOLD,
+            chain_stale_thresholds: HashMap::new(),
+            stale_block_margin: None,
+            block_import_drop_margin: None,
+            block_propagation_messages: false,
+            quota_count_source: QuotaCountSource::All,
+            sticky_node_ids: false,
+            max_nodes_per_chain: None,
+            keep_raw_node_payloads: false,
+            required_node_fields: Vec::new(),
+            incomplete_node_policy: IncompleteNodePolicy::Reject,
+            placeholder_network_ids: 0,
+            recommended_versions: HashMap::new(),
+            chain_label_overrides: HashMap::new(),
+        }
+    }
+
+    /// Group nodes added from now on based on their names. The group is the part of the
+    /// name matching the first capture group in the pattern (or the whole match if there
+    /// are no capture groups). Nodes whose names don't match go into [`DEFAULT_NODE_GROUP`].
+    pub fn set_node_group_pattern(&mut self, pattern: Option<Regex>) {
+        self.node_group_pattern = pattern;
+    }
+
+    /// Which group does a node with the given name belong in?
+    fn node_group(&self, node_name: &str) -> Option<Box<str>> {
+        let pattern = self.node_group_pattern.as_ref()?;
+        let group = pattern
+            .captures(node_name)
+            .and_then(|caps| caps.get(1).or_else(|| caps.get(0)))
+            .map(|m| m.as_str())
+            .unwrap_or(DEFAULT_NODE_GROUP);
+        Some(group.into())
+    }
+
+    /// Until the given time (in unix ms), allow up to `max_third_party_nodes` nodes from
+    /// third party chains to connect rather than the usual limit.
+    pub fn set_quota_warmup(&mut self, ends_at: Timestamp, max_third_party_nodes: usize) {
+        self.quota_warmup = Some(QuotaWarmup {
+            ends_at,
             max_third_party_nodes,
+        });
+    }
+
+    /// Allow any number of nodes to connect to the chains with these genesis hashes, as
+    /// if they were first party chains. This applies the next time a node is added.
+    pub fn set_quota_exempt_chains<T: IntoIterator<Item = BlockHash>>(&mut self, chains: T) {
+        self.quota_exempt_chains = chains.into_iter().collect();
+    }
+
+    /// How many nodes from third party chains are allowed to connect right now?
+    fn current_max_third_party_nodes(&self) -> usize {
+        match &self.quota_warmup {
+            Some(warmup) if time::now() < warmup.ends_at => warmup.max_third_party_nodes,
+            _ => self.max_third_party_nodes,
         }
     }
 
+    /// Call the chains with the given genesis hashes by these labels, whatever their nodes
+    /// report. This applies to chains created from now on.
+    pub fn set_chain_label_overrides(&mut self, chain_label_overrides: HashMap<BlockHash, String>) {
+        self.chain_label_overrides = chain_label_overrides
+            .into_iter()
+            .map(|(genesis_hash, label)| (genesis_hash, label.into()))
+            .collect();
+    }
+
+    /// Mark nodes as stale once they haven't imported a new best block for the given
+    /// time. The chains given their own threshold use that instead.
+    pub fn set_stale_thresholds<T: IntoIterator<Item = ChainStaleThreshold>>(
+        &mut self,
+        stale_threshold: Duration,
+        chain_stale_thresholds: T,
+    ) {
+        self.stale_threshold = stale_threshold;
+        self.chain_stale_thresholds = chain_stale_thresholds
+            .into_iter()
+            .map(|cst| (cst.genesis_hash, cst.threshold))
+            .collect();
+        let genesis_hashes: Vec<_> = self
+            .chains
+            .iter()
+            .map(|(chain_id, chain)| (chain_id, chain.genesis_hash()))
+            .collect();
+        for (chain_id, genesis_hash) in genesis_hashes {
+            let stale_threshold = self.stale_threshold_for(&genesis_hash);
+            if let Some(chain) = self.chains.get_mut(chain_id) {
+                chain.set_stale_threshold(stale_threshold);
+            }
+        }
+    }
+
+    /// How long can nodes on the given chain go without a new best block before they're stale?
+    fn stale_threshold_for(&self, genesis_hash: &BlockHash) -> Duration {
+        self.chain_stale_thresholds
+            .get(genesis_hash)
+            .copied()
+            .unwrap_or(self.stale_threshold)
+    }
+
+    /// Mark nodes as stale once their best block is more than this many blocks behind
+    /// the best block of their chain. This applies to chains created from now on.
+    pub fn set_stale_block_margin(&mut self, stale_block_margin: Option<BlockNumber>) {
+        self.stale_block_margin = stale_block_margin;
+    }
+
+    /// Stop telling feeds about blocks imported by nodes which are more than this many blocks
+    /// behind the best block of their chain. This applies to chains created from now on.
+    pub fn set_block_import_drop_margin(&mut self, block_import_drop_margin: Option<BlockNumber>) {
+        self.block_import_drop_margin = block_import_drop_margin;
+    }
+
+    /// Tell feeds how long blocks took to reach each node after the first one to import
+    /// them. This applies to chains created from now on.
+    pub fn set_block_propagation_messages(&mut self, enabled: bool) {
+        self.block_propagation_messages = enabled;
+    }
+
+    /// Decide which nodes count towards the quota of third party chains. This applies
+    /// to chains created from now on.
+    pub fn set_quota_count_source(&mut self, quota_count_source: QuotaCountSource) {
+        self.quota_count_source = quota_count_source;
+    }
+
+    /// Don't allow more than this many nodes to connect to any one chain. Unlike the
+    /// third party quota, this counts every node and applies to every chain.
+    pub fn set_max_nodes_per_chain(&mut self, max_nodes_per_chain: Option<usize>) {
+        self.max_nodes_per_chain = max_nodes_per_chain;
+    }
+
+    /// Give nodes that reconnect the same ID they had before, as long as it's still free.
+    /// This applies to chains created from now on.
+    pub fn set_sticky_node_ids(&mut self, sticky_node_ids: bool) {
+        self.sticky_node_ids = sticky_node_ids;
+    }
+
+    /// Keep hold of the last payload of each kind that nodes send us, exactly as we
+    /// received them, so that they can be inspected. This applies to nodes added from
+    /// now on, and costs a fair bit of memory per node.
+    pub fn set_keep_raw_node_payloads(&mut self, keep_raw_node_payloads: bool) {
+        self.keep_raw_node_payloads = keep_raw_node_payloads;
+    }
+
+    /// The chain labels that are not allowed to connect, in order.
+    pub fn denylist(&self) -> Vec<String> {
+        let mut denylist: Vec<String> = self.denylist.iter().cloned().collect();
+        denylist.sort();
+        denylist
+    }
+
+    /// Replace the list of chain labels that are not allowed to connect.
+    pub fn set_denylist<T: IntoIterator<Item = String>>(&mut self, denylist: T) {
+        self.denylist = denylist.into_iter().collect();
+    }
+
+    /// Require nodes added from now on to report the given details, and decide whether
+    /// nodes which don't are rejected or given placeholder values instead.
+    pub fn set_required_node_fields(
+        &mut self,
+        required_node_fields: Vec<RequiredNodeField>,
+        incomplete_node_policy: IncompleteNodePolicy,
+    ) {
+        self.required_node_fields = required_node_fields;
+        self.incomplete_node_policy = incomplete_node_policy;
+    }
+
+    /// Check that the node reported everything we require of it, filling in any gaps
+    /// with placeholders if that's allowed. Returns false if the node should be rejected.
+    fn complete_node_details(&mut self, node_details: &mut NodeDetails) -> bool {
+        for field in &self.required_node_fields {
+            let is_missing = match field {
+                RequiredNodeField::Name => node_details.name.trim().is_empty(),
+                RequiredNodeField::Version => node_details.version.trim().is_empty(),
+                RequiredNodeField::NetworkId => node_details.network_id.trim().is_empty(),
+            };
+            if !is_missing {
+                continue;
+            }
+            if self.incomplete_node_policy == IncompleteNodePolicy::Reject {
+                return false;
+            }
+            match field {
+                RequiredNodeField::Name => node_details.name = PLACEHOLDER_NODE_DETAIL.into(),
+                RequiredNodeField::Version => node_details.version = PLACEHOLDER_NODE_DETAIL.into(),
+                RequiredNodeField::NetworkId => {
+                    self.placeholder_network_ids += 1;
+                    let network_id = format!(
+                        "{}-{}",
+                        PLACEHOLDER_NODE_DETAIL, self.placeholder_network_ids
+                    );
+                    node_details.network_id = NetworkId::from(&network_id).unwrap_or_default();
+                }
+            }
+        }
+        true
+    }
+
+    /// Replace the versions that nodes on each chain are recommended to run. This applies
+    /// to existing chains as well as new ones.
+    pub fn set_recommended_versions<T: IntoIterator<Item = RecommendedVersion>>(
+        &mut self,
+        recommended_versions: T,
+    ) {
+        self.recommended_versions = recommended_versions
+            .into_iter()
+            .map(|rv| (rv.genesis_hash, rv.version))
+            .collect();
+        for (_, chain) in self.chains.iter_mut() {
+            let recommended_version = self.recommended_versions.get(&chain.genesis_hash());
+            chain.set_recommended_version(recommended_version.cloned());
+        }
+    }
+
+    /// Only allow chains whose label or genesis hash is in the given list to connect.
+    /// Entries which parse as a hash are treated as genesis hashes, and everything else
+    /// as a label. An empty list allows every chain (that isn't on the denylist).
+    pub fn set_allowlist<T: IntoIterator<Item = String>>(&mut self, allowlist: T) {
+        let mut labels = HashSet::new();
+        let mut genesis_hashes = HashSet::new();
+        for entry in allowlist {
+            match entry.parse::<BlockHash>() {
+                Ok(genesis_hash) => genesis_hashes.insert(genesis_hash),
+                Err(_) => labels.insert(entry),
+            };
+        }
+        self.allowlist = if labels.is_empty() && genesis_hashes.is_empty() {
+            None
+        } else {
+            Some(ChainAllowlist {
+                labels,
+                genesis_hashes,
+            })
+        };
+    }
+
+    /// Return the IDs of all nodes which are connected but whose chain is on the denylist.
+    pub fn denied_node_ids(&self) -> Vec<NodeId> {
+        self.chains
+            .iter()
+            .flat_map(|(chain_id, chain)| {
+                chain
+                    .iter_nodes()
+                    .filter(|(_, node)| self.denylist.contains(&*node.details().chain))
+                    .map(move |(chain_node_id, _)| NodeId(chain_id, chain_node_id))
+            })
+            .collect()
+    }
+
+    /// Replace the list of network IDs of nodes that are not allowed to connect.
+    pub fn set_node_blocklist<T: IntoIterator<Item = String>>(&mut self, node_blocklist: T) {
+        self.node_blocklist = node_blocklist.into_iter().collect();
+    }
+
+    /// Return the IDs of all nodes which are connected but are on the node blocklist.
+    pub fn blocked_node_ids(&self) -> Vec<NodeId> {
+        self.chains
+            .iter()
+            .flat_map(|(chain_id, chain)| {
+                chain
+                    .iter_nodes()
+                    .filter(|(_, node)| self.is_node_blocked(node.details()))
+                    .map(move |(chain_node_id, _)| NodeId(chain_id, chain_node_id))
+            })
+            .collect()
+    }
+
+    /// The IDs of nodes which we haven't heard anything from since `threshold`.
+    pub fn stale_node_ids(&self, threshold: Timestamp) -> Vec<NodeId> {
+        self.chains
+            .iter()
+            .flat_map(|(chain_id, chain)| {
+                chain
+                    .iter_nodes()
+                    .filter(|(_, node)| node.last_message() < threshold)
+                    .map(move |(chain_node_id, _)| NodeId(chain_id, chain_node_id))
+            })
+            .collect()
+    }
+
+    fn is_node_blocked(&self, node_details: &NodeDetails) -> bool {
+        self.node_blocklist
+            .contains(node_details.network_id.as_str())
+    }
+
     pub fn iter_chains(&self) -> impl Iterator<Item = StateChain<'_>> {
         self.chains
             .iter()
-            .map(move |(_, chain)| StateChain { chain })
+            .map(move |(id, chain)| StateChain { id, chain })
     }
 
     pub fn get_chain_by_node_id(&self, node_id: NodeId) -> Option<StateChain<'_>> {
-        self.chains.get(node_id.0).map(|chain| StateChain { chain })
+        self.chains.get(node_id.0).map(|chain| StateChain {
+            id: node_id.0,
+            chain,
+        })
     }
 
     pub fn get_chain_by_genesis_hash(&self, genesis_hash: &BlockHash) -> Option<StateChain<'_>> {
-        self.chains_by_genesis_hash
-            .get(genesis_hash)
-            .and_then(|&chain_id| self.chains.get(chain_id))
-            .map(|chain| StateChain { chain })
+        let id = *self.chains_by_genesis_hash.get(genesis_hash)?;
+        self.chains.get(id).map(|chain| StateChain { id, chain })
+    }
+
+    /// The current label of every chain we know about, keyed by genesis hash.
+    pub fn chain_labels(&self) -> HashMap<BlockHash, Box<str>> {
+        self.chains
+            .iter()
+            .map(|(_, chain)| (chain.genesis_hash(), chain.label().into()))
+            .collect()
+    }
+
+    /// How many nodes are connected to each chain.
+    pub fn chain_node_counts(&self) -> HashMap<BlockHash, usize> {
+        self.chains
+            .iter()
+            .map(|(_, chain)| (chain.genesis_hash(), chain.node_count()))
+            .collect()
     }
 
     pub fn add_node(
         &mut self,
         genesis_hash: BlockHash,
-        node_details: NodeDetails,
+        mut node_details: NodeDetails,
     ) -> AddNodeResult<'_> {
         if self.denylist.contains(&*node_details.chain) {
             return AddNodeResult::ChainOnDenyList;
         }
+        if let Some(allowlist) = &self.allowlist {
+            if !allowlist.allows(&node_details.chain, &genesis_hash) {
+                return AddNodeResult::ChainNotOnAllowList;
+            }
+        }
+        if !self.complete_node_details(&mut node_details) {
+            return AddNodeResult::NodeIncomplete;
+        }
+        if self.is_node_blocked(&node_details) {
+            return AddNodeResult::NodeBlocked;
+        }
 
         // Get the chain ID, creating a new empty chain if one doesn't exist.
         // If we create a chain here, we are expecting that it will allow at
         // least this node to be added, because we don't currently try and clean it up
         // if the add fails.
+        let is_quota_exempt = chain::is_first_party_network(&genesis_hash)
+            || self.quota_exempt_chains.contains(&genesis_hash);
+        let max_nodes = match is_quota_exempt {
+            true => usize::MAX,
+            false => self.current_max_third_party_nodes(),
+        };
         let chain_id = match self.chains_by_genesis_hash.get(&genesis_hash) {
             Some(id) => *id,
             None => {
-                let max_nodes = match chain::is_first_party_network(&genesis_hash) {
-                    true => usize::MAX,
-                    false => self.max_third_party_nodes,
-                };
-                let chain_id = self.chains.add(Chain::new(genesis_hash, max_nodes));
+                let mut chain = Chain::new(genesis_hash, max_nodes);
+                chain.set_label_override(self.chain_label_overrides.get(&genesis_hash).cloned());
+                chain.set_stale_threshold(self.stale_threshold_for(&genesis_hash));
+                chain.set_stale_block_margin(self.stale_block_margin);
+                chain.set_block_import_drop_margin(self.block_import_drop_margin);
+                chain.set_block_propagation_messages(self.block_propagation_messages);
+                chain.set_quota_count_source(self.quota_count_source);
+                chain.set_sticky_node_ids(self.sticky_node_ids);
+                chain
+                    .set_recommended_version(self.recommended_versions.get(&genesis_hash).cloned());
+                let chain_id = self.chains.add(chain);
                 self.chains_by_genesis_hash.insert(genesis_hash, chain_id);
                 chain_id
             }
         };
 
+        let node_group = self.node_group(&node_details.name);
+
         // Get the chain.
         let chain = self.chains.get_mut(chain_id).expect(
             "should be known to exist after the above (unless chains_by_genesis_hash out of sync)",
         );
 
-        let node = Node::new(node_details);
+        // The limit can change over time (eg once any quota warm-up has ended):
+        chain.set_max_nodes(max_nodes);
+
+        // Every chain is also held to the per-chain limit, if there is one:
+        if self
+            .max_nodes_per_chain
+            .is_some_and(|max| chain.node_count() >= max)
+        {
+            return AddNodeResult::ChainOverQuota;
+        }
+
+        let system_connected = self.keep_raw_node_payloads.then(|| SystemConnected {
+            genesis_hash,
+            node: node_details.clone(),
+        });
+        let mut node = Node::new(node_details);
+        if let Some(system_connected) = system_connected {
+            node.keep_raw_payloads(system_connected);
+        }
+        node.set_group(node_group);
         let old_chain_label = chain.label().into();
 
         match chain.add_node(node) {
@@ -231,6 +668,10 @@ impl State {
     }
 
     /// Update the location for a node. Return `false` if the node was not found.
+    pub fn get_node(&self, NodeId(chain_id, chain_node_id): NodeId) -> Option<&Node> {
+        self.chains.get(chain_id)?.get_node(chain_node_id)
+    }
+
     pub fn update_node_location(
         &mut self,
         NodeId(chain_id, chain_node_id): NodeId,
@@ -242,6 +683,175 @@ impl State {
             false
         }
     }
+
+    /// Note that a node reported a private address and so won't be located.
+    /// Return `false` if the node was not found.
+    pub fn set_node_location_private(&mut self, NodeId(chain_id, chain_node_id): NodeId) -> bool {
+        if let Some(chain) = self.chains.get_mut(chain_id) {
+            chain.set_node_location_private(chain_node_id)
+        } else {
+            false
+        }
+    }
+}
+
+/// Which nodes are counted in the node count that we report for a chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeCountSource {
+    /// Every connected node.
+    All,
+    /// Only nodes which report that they are validators.
+    Validators,
+    /// Only nodes which we've found a location for.
+    Located,
+}
+
+impl std::str::FromStr for NodeCountSource {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(NodeCountSource::All),
+            "validators" => Ok(NodeCountSource::Validators),
+            "located" => Ok(NodeCountSource::Located),
+            _ => Err(anyhow::anyhow!(
+                "Expecting one of 'all', 'validators' or 'located', but got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+/// Which nodes count towards the quota of nodes allowed on a third party chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaCountSource {
+    /// Every connected node.
+    All,
+    /// Nodes reporting the same network ID are counted once, so that a node which
+    /// connects more than once doesn't use up more of the quota.
+    DistinctNetworkIds,
+    /// Only nodes which report that they are validators. Observers (non-validators)
+    /// don't use up any of the quota, and are always allowed to connect.
+    Validators,
+}
+
+impl std::str::FromStr for QuotaCountSource {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(QuotaCountSource::All),
+            "distinct-network-ids" => Ok(QuotaCountSource::DistinctNetworkIds),
+            "validators" => Ok(QuotaCountSource::Validators),
+            _ => Err(anyhow::anyhow!(
+                "Expecting one of 'all', 'distinct-network-ids' or 'validators', but got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+/// A label to use for a chain in place of the ones its nodes report. Parsed from strings
+/// of the form `GENESIS_HASH=LABEL`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainLabelOverride {
+    pub genesis_hash: BlockHash,
+    pub label: String,
+}
+
+impl std::str::FromStr for ChainLabelOverride {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (genesis_hash, label) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected GENESIS_HASH=LABEL"))?;
+        let genesis_hash = genesis_hash
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid genesis hash '{}'", genesis_hash))?;
+        let label = label.trim();
+        if label.is_empty() {
+            anyhow::bail!("Expected a label after the genesis hash");
+        }
+        Ok(ChainLabelOverride {
+            genesis_hash,
+            label: label.to_owned(),
+        })
+    }
+}
+
+/// How long nodes on a particular chain can go without importing a new best block before
+/// they're stale. Parsed from strings of the form `GENESIS_HASH=SECONDS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainStaleThreshold {
+    pub genesis_hash: BlockHash,
+    pub threshold: Duration,
+}
+
+impl std::str::FromStr for ChainStaleThreshold {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (genesis_hash, secs) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected GENESIS_HASH=SECONDS"))?;
+        let genesis_hash = genesis_hash
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid genesis hash '{}'", genesis_hash))?;
+        let secs = secs
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid number of seconds '{}'", secs))?;
+        Ok(ChainStaleThreshold {
+            genesis_hash,
+            threshold: Duration::from_secs(secs),
+        })
+    }
+}
+
+/// Details that nodes can be required to report when they connect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequiredNodeField {
+    Name,
+    Version,
+    NetworkId,
+}
+
+impl std::str::FromStr for RequiredNodeField {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(RequiredNodeField::Name),
+            "version" => Ok(RequiredNodeField::Version),
+            "network-id" => Ok(RequiredNodeField::NetworkId),
+            _ => Err(anyhow::anyhow!(
+                "Expecting one of 'name', 'version' or 'network-id', but got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with nodes that don't report every [`RequiredNodeField`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IncompleteNodePolicy {
+    /// Don't allow the node to connect.
+    Reject,
+    /// Allow the node to connect, filling in whatever is missing with
+    /// [`PLACEHOLDER_NODE_DETAIL`].
+    Placeholder,
+}
+
+impl std::str::FromStr for IncompleteNodePolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(IncompleteNodePolicy::Reject),
+            "placeholder" => Ok(IncompleteNodePolicy::Placeholder),
+            _ => Err(anyhow::anyhow!(
+                "Expecting one of 'reject' or 'placeholder', but got '{}'",
+                s
+            )),
+        }
+    }
 }
 
 /// When we ask for a chain, we get this struct back. This ensures that we have
@@ -249,6 +859,7 @@ impl State {
 /// aren't really intended for use outside of [`State`] methods. Any modification
 /// of a chain needs to go through [`State`].
 pub struct StateChain<'a> {
+    id: ChainId,
     chain: &'a Chain,
 }
 
@@ -262,6 +873,21 @@ impl<'a> StateChain<'a> {
     pub fn node_count(&self) -> usize {
         self.chain.node_count()
     }
+    pub fn highest_node_count(&self) -> usize {
+        self.chain.highest_node_count()
+    }
+    /// The number of nodes on this chain, counting only those given by `source`.
+    /// Quotas are worked out separately; see [`QuotaCountSource`].
+    pub fn node_count_from(&self, source: NodeCountSource) -> usize {
+        let nodes = self.chain.nodes_slice().iter().flatten();
+        match source {
+            NodeCountSource::All => self.node_count(),
+            NodeCountSource::Validators => nodes
+                .filter(|node| node.details().validator.is_some())
+                .count(),
+            NodeCountSource::Located => nodes.filter(|node| node.location().is_some()).count(),
+        }
+    }
     pub fn best_block(&self) -> &'a Block {
         self.chain.best_block()
     }
@@ -274,12 +900,48 @@ impl<'a> StateChain<'a> {
     pub fn finalized_block(&self) -> &'a Block {
         self.chain.finalized_block()
     }
+    pub fn average_time_to_finality(&self) -> Option<u64> {
+        self.chain.average_time_to_finality()
+    }
+    pub fn authority_set_id(&self) -> Option<u64> {
+        self.chain.authority_set_id()
+    }
     pub fn nodes_slice(&self) -> &[Option<Node>] {
         self.chain.nodes_slice()
     }
     pub fn stats(&self) -> &ChainStats {
         self.chain.stats()
     }
+    pub fn decentralization_score(&self) -> f64 {
+        self.chain.decentralization_score()
+    }
+    pub fn located_fraction(&self) -> f64 {
+        self.chain.located_fraction()
+    }
+    pub fn version_compliance(&self) -> Option<(usize, usize)> {
+        self.chain.version_compliance()
+    }
+    pub fn sync_breakdown(&self) -> (usize, usize) {
+        self.chain.sync_breakdown()
+    }
+    pub fn iter_nodes(&self) -> impl Iterator<Item = (NodeId, &'a Node)> + 'a {
+        let id = self.id;
+        self.chain
+            .iter_nodes()
+            .map(move |(chain_node_id, node)| (NodeId(id, chain_node_id), node))
+    }
+    /// The nodes on this chain, grouped by the group derived from their names.
+    pub fn subgroups(&self) -> HashMap<String, Vec<NodeId>> {
+        let mut subgroups: HashMap<String, Vec<NodeId>> = HashMap::new();
+        for (chain_node_id, node) in self.chain.iter_nodes() {
+            let group = node.group().unwrap_or(DEFAULT_NODE_GROUP);
+            subgroups
+                .entry(group.to_owned())
+                .or_default()
+                .push(NodeId(self.id, chain_node_id));
+        }
+        subgroups
+    }
 }
 
 #[cfg(test)]
@@ -313,7 +975,10 @@ mod test {
 
         let add_node_result = match add_result {
             AddNodeResult::ChainOnDenyList => panic!("Chain not on deny list"),
+            AddNodeResult::ChainNotOnAllowList => panic!("No allow list"),
             AddNodeResult::ChainOverQuota => panic!("Chain not Overquota"),
+            AddNodeResult::NodeBlocked => panic!("Node not blocked"),
+            AddNodeResult::NodeIncomplete => panic!("Node not incomplete"),
             AddNodeResult::NodeAddedToChain(details) => details,
         };
 
@@ -327,7 +992,10 @@ mod test {
 
         let add_node_result = match add_result {
             AddNodeResult::ChainOnDenyList => panic!("Chain not on deny list"),
+            AddNodeResult::ChainNotOnAllowList => panic!("No allow list"),
             AddNodeResult::ChainOverQuota => panic!("Chain not Overquota"),
+            AddNodeResult::NodeBlocked => panic!("Node not blocked"),
+            AddNodeResult::NodeIncomplete => panic!("Node not incomplete"),
             AddNodeResult::NodeAddedToChain(details) => details,
         };
 
@@ -398,6 +1066,125 @@ mod test {
         assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_some());
     }
 
+    #[test]
+    fn chain_labels_reflect_renamed_chains() {
+        let mut state = State::new(None, 1000);
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let chain2_genesis = BlockHash::from_low_u64_be(2);
+        state.add_node(chain1_genesis, node("A", "Chain One"));
+        state.add_node(chain2_genesis, node("B", "Chain Two"));
+
+        let labels = state.chain_labels();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(&*labels[&chain1_genesis], "Chain One");
+        assert_eq!(&*labels[&chain2_genesis], "Chain Two");
+
+        // Most nodes on the first chain now call it something else:
+        state.add_node(chain1_genesis, node("C", "Chain Uno"));
+        state.add_node(chain1_genesis, node("D", "Chain Uno"));
+
+        let labels = state.chain_labels();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(&*labels[&chain1_genesis], "Chain Uno");
+        assert_eq!(&*labels[&chain2_genesis], "Chain Two");
+    }
+
+    #[test]
+    fn replacing_denylist_finds_newly_denied_nodes() {
+        let mut state = State::new(vec!["Chain Three".to_string()], 1000);
+
+        let node_id0 = state
+            .add_node(BlockHash::from_low_u64_be(1), node("A", "Chain One"))
+            .unwrap_id();
+        let node_id1 = state
+            .add_node(BlockHash::from_low_u64_be(2), node("B", "Chain Two"))
+            .unwrap_id();
+
+        assert!(state.denied_node_ids().is_empty());
+
+        state.set_denylist(vec!["Chain Two".to_string()]);
+        assert_eq!(state.denied_node_ids(), vec![node_id1]);
+
+        // "Chain Three" is no longer denied:
+        assert!(matches!(
+            state.add_node(BlockHash::from_low_u64_be(3), node("C", "Chain Three")),
+            AddNodeResult::NodeAddedToChain(_)
+        ));
+
+        state.set_denylist(vec!["Chain One".to_string(), "Chain Two".to_string()]);
+        let mut denied = state.denied_node_ids();
+        denied.sort_by_key(|id| state.get_chain_by_node_id(*id).unwrap().label().to_owned());
+        assert_eq!(denied, vec![node_id0, node_id1]);
+    }
+
+    fn is_added(result: AddNodeResult) -> bool {
+        matches!(result, AddNodeResult::NodeAddedToChain(_))
+    }
+
+    #[test]
+    fn only_allowlisted_chains_can_connect() {
+        let chain2_genesis = BlockHash::from_low_u64_be(2);
+        let mut state = State::new(None, 1000);
+        state.set_allowlist(vec![
+            "Chain One".to_string(),
+            format!("{:?}", chain2_genesis),
+        ]);
+
+        // Chains are allowed by label or genesis hash:
+        assert!(is_added(state.add_node(
+            BlockHash::from_low_u64_be(1),
+            node("A", "Chain One")
+        )));
+        assert!(is_added(
+            state.add_node(chain2_genesis, node("B", "Renamed Chain Two"))
+        ));
+        assert!(matches!(
+            state.add_node(BlockHash::from_low_u64_be(3), node("C", "Chain Three")),
+            AddNodeResult::ChainNotOnAllowList
+        ));
+
+        // An empty allowlist lets everything in again:
+        state.set_allowlist(None);
+        assert!(is_added(state.add_node(
+            BlockHash::from_low_u64_be(3),
+            node("C", "Chain Three")
+        )));
+    }
+
+    #[test]
+    fn denylist_alone_does_not_restrict_other_chains() {
+        let mut state = State::new(vec!["Chain Two".to_string()], 1000);
+
+        assert!(is_added(state.add_node(
+            BlockHash::from_low_u64_be(1),
+            node("A", "Chain One")
+        )));
+        assert!(matches!(
+            state.add_node(BlockHash::from_low_u64_be(2), node("B", "Chain Two")),
+            AddNodeResult::ChainOnDenyList
+        ));
+    }
+
+    #[test]
+    fn denylist_takes_precedence_over_allowlist() {
+        let mut state = State::new(vec!["Chain Two".to_string()], 1000);
+        state.set_allowlist(vec!["Chain One".to_string(), "Chain Two".to_string()]);
+
+        assert!(is_added(state.add_node(
+            BlockHash::from_low_u64_be(1),
+            node("A", "Chain One")
+        )));
+        assert!(matches!(
+            state.add_node(BlockHash::from_low_u64_be(2), node("B", "Chain Two")),
+            AddNodeResult::ChainOnDenyList
+        ));
+        assert!(matches!(
+            state.add_node(BlockHash::from_low_u64_be(3), node("C", "Chain Three")),
+            AddNodeResult::ChainNotOnAllowList
+        ));
+    }
+
     #[test]
     fn chain_removed_when_last_node_is() {
         let mut state = State::new(None, 1000);
@@ -415,4 +1202,276 @@ mod test {
         assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_none());
         assert_eq!(state.iter_chains().count(), 0);
     }
+
+    #[test]
+    fn reconnecting_nodes_can_keep_their_ids() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let with_network_id = |name: &str| NodeDetails {
+            network_id: NetworkId::from(name).unwrap(),
+            ..node(name, "Chain One")
+        };
+
+        // Returns the ID that node A is given when it reconnects:
+        let reconnect = |sticky_node_ids: bool| {
+            let mut state = State::new(None, 1000);
+            state.set_sticky_node_ids(sticky_node_ids);
+            let a = state
+                .add_node(genesis_hash, with_network_id("A"))
+                .unwrap_id();
+            let b = state
+                .add_node(genesis_hash, with_network_id("B"))
+                .unwrap_id();
+            // Keep the chain around while A and B are gone:
+            state
+                .add_node(genesis_hash, with_network_id("C"))
+                .unwrap_id();
+
+            state.remove_node(a);
+            state.remove_node(b);
+            let reconnected = state
+                .add_node(genesis_hash, with_network_id("A"))
+                .unwrap_id();
+            (a, b, reconnected)
+        };
+
+        // Normally the most recently freed ID is handed out first:
+        let (_, b, reconnected) = reconnect(false);
+        assert_eq!(reconnected, b);
+
+        let (a, _, reconnected) = reconnect(true);
+        assert_eq!(reconnected, a);
+    }
+
+    #[test]
+    fn sticky_ids_are_not_taken_from_other_nodes() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let with_network_id = |name: &str| NodeDetails {
+            network_id: NetworkId::from(name).unwrap(),
+            ..node(name, "Chain One")
+        };
+        let mut state = State::new(None, 1000);
+        state.set_sticky_node_ids(true);
+
+        let a = state
+            .add_node(genesis_hash, with_network_id("A"))
+            .unwrap_id();
+        state
+            .add_node(genesis_hash, with_network_id("C"))
+            .unwrap_id();
+        state.remove_node(a);
+
+        // B is given A's old ID, so A gets a new one when it returns:
+        let b = state
+            .add_node(genesis_hash, with_network_id("B"))
+            .unwrap_id();
+        assert_eq!(b, a);
+        let reconnected = state
+            .add_node(genesis_hash, with_network_id("A"))
+            .unwrap_id();
+        assert_ne!(reconnected, a);
+    }
+
+    #[test]
+    fn quota_exempt_chains_admit_any_number_of_nodes() {
+        let mut state = State::new(None, 2);
+        let exempt = BlockHash::from_low_u64_be(1);
+        let not_exempt = BlockHash::from_low_u64_be(2);
+        state.set_quota_exempt_chains(vec![exempt]);
+
+        for n in 0..3 {
+            let name = format!("Node {}", n);
+            assert!(is_added(state.add_node(exempt, node(&name, "Exempt"))));
+        }
+        for n in 0..2 {
+            let name = format!("Node {}", n);
+            assert!(is_added(
+                state.add_node(not_exempt, node(&name, "Not Exempt"))
+            ));
+        }
+
+        // The quota is full, but that only matters for the chain which isn't exempt:
+        assert!(is_added(state.add_node(exempt, node("Node 3", "Exempt"))));
+        assert!(matches!(
+            state.add_node(not_exempt, node("Node 2", "Not Exempt")),
+            AddNodeResult::ChainOverQuota
+        ));
+    }
+
+    #[test]
+    fn chains_are_capped_at_the_per_chain_limit() {
+        let mut state = State::new(None, 10);
+        let third_party = BlockHash::from_low_u64_be(1);
+        let exempt = BlockHash::from_low_u64_be(2);
+        state.set_quota_exempt_chains(vec![exempt]);
+        state.set_max_nodes_per_chain(Some(3));
+
+        // The third party quota has room for more, but each chain is capped at 3 nodes:
+        for (genesis_hash, label) in [(third_party, "Third Party"), (exempt, "Exempt")] {
+            let ids: Vec<_> = (0..3)
+                .map(|n| {
+                    state
+                        .add_node(genesis_hash, node(&format!("Node {}", n), label))
+                        .unwrap_id()
+                })
+                .collect();
+            assert!(matches!(
+                state.add_node(genesis_hash, node("Node 3", label)),
+                AddNodeResult::ChainOverQuota
+            ));
+
+            // There's room again once a node leaves:
+            state.remove_node(ids[0]).expect("node exists");
+            state
+                .add_node(genesis_hash, node("Node 3", label))
+                .unwrap_id();
+        }
+
+        // The third party quota still applies if it's the lower of the two:
+        let mut state = State::new(None, 2);
+        state.set_max_nodes_per_chain(Some(3));
+        for n in 0..2 {
+            state
+                .add_node(third_party, node(&format!("Node {}", n), "Third Party"))
+                .unwrap_id();
+        }
+        assert!(matches!(
+            state.add_node(third_party, node("Node 2", "Third Party")),
+            AddNodeResult::ChainOverQuota
+        ));
+    }
+
+    #[test]
+    fn quota_is_relaxed_during_warmup() {
+        let mut state = State::new(None, 2);
+        let chain = BlockHash::from_low_u64_be(1);
+        state.set_quota_warmup(time::now() + 60_000, 4);
+
+        // During warm-up, more nodes than the usual limit are allowed:
+        let ids: Vec<_> = (0..4)
+            .map(|n| {
+                state
+                    .add_node(chain, node(&format!("Node {}", n), "Chain One"))
+                    .unwrap_id()
+            })
+            .collect();
+        assert!(matches!(
+            state.add_node(chain, node("Node 4", "Chain One")),
+            AddNodeResult::ChainOverQuota
+        ));
+
+        // Once warm-up has ended, the usual limit applies again:
+        state.set_quota_warmup(time::now() - 1, 4);
+        assert!(matches!(
+            state.add_node(chain, node("Node 4", "Chain One")),
+            AddNodeResult::ChainOverQuota
+        ));
+        for &id in &ids[..3] {
+            state.remove_node(id).expect("node exists");
+        }
+        state
+            .add_node(chain, node("Node 4", "Chain One"))
+            .unwrap_id();
+        assert!(matches!(
+            state.add_node(chain, node("Node 5", "Chain One")),
+            AddNodeResult::ChainOverQuota
+        ));
+    }
+
+    #[test]
+    fn nodes_are_grouped_by_name() {
+        let mut state = State::new(None, 1000);
+        state.set_node_group_pattern(Some(Regex::new("^([a-z]+)-").unwrap()));
+        let chain = BlockHash::from_low_u64_be(1);
+
+        let mut add = |name: &str| state.add_node(chain, node(name, "Chain One")).unwrap_id();
+        let eu = vec![add("eu-node-1"), add("eu-node-2")];
+        let us = vec![add("us-node-1")];
+        let other = vec![add("Bob's node")];
+
+        let mut expected = HashMap::new();
+        expected.insert("eu".to_owned(), eu);
+        expected.insert("us".to_owned(), us);
+        expected.insert(DEFAULT_NODE_GROUP.to_owned(), other);
+
+        let subgroups = state.get_chain_by_genesis_hash(&chain).unwrap().subgroups();
+        assert_eq!(subgroups, expected);
+    }
+
+    #[test]
+    fn chains_can_have_their_own_stale_threshold() {
+        let slow_chain = BlockHash::from_low_u64_be(1);
+        let normal_chain = BlockHash::from_low_u64_be(2);
+        let mut state = State::new(None, 1000);
+        state.set_stale_thresholds(
+            Duration::from_secs(60),
+            [format!("{:?}=600", slow_chain).parse().unwrap()],
+        );
+        assert_eq!(
+            state.stale_threshold_for(&slow_chain),
+            Duration::from_secs(600)
+        );
+        assert_eq!(
+            state.stale_threshold_for(&normal_chain),
+            Duration::from_secs(60)
+        );
+
+        assert!("0x01=600".parse::<ChainStaleThreshold>().is_err());
+        assert!(format!("{:?}=soon", slow_chain)
+            .parse::<ChainStaleThreshold>()
+            .is_err());
+    }
+
+    #[test]
+    fn nodes_far_behind_the_chain_are_stale() {
+        let mut state = State::new(None, 1000);
+        state.set_stale_block_margin(Some(5));
+        let chain = BlockHash::from_low_u64_be(1);
+        let leader = state.add_node(chain, node("A", "Chain One")).unwrap_id();
+        let stuck = state.add_node(chain, node("B", "Chain One")).unwrap_id();
+
+        let block = |height| Block {
+            hash: BlockHash::from_low_u64_be(height),
+            height,
+        };
+        let interval = |height| {
+            Payload::SystemInterval(common::node_message::SystemInterval {
+                peers: Some(10),
+                txcount: None,
+                bandwidth_upload: None,
+                bandwidth_download: None,
+                finalized_height: None,
+                finalized_hash: None,
+                block: Some(block(height)),
+                used_state_cache_size: None,
+            })
+        };
+        let is_stale = |state: &State, node_id: NodeId| {
+            let chain = state.get_chain_by_genesis_hash(&chain).unwrap();
+            let id: usize = node_id.get_chain_node_id().into();
+            chain.nodes_slice()[id].as_ref().unwrap().stale()
+        };
+
+        // The stuck node keeps reporting in, but never gets past block 1:
+        let mut stale_messages = 0;
+        for height in 1..=10 {
+            let mut feed = FeedMessageSerializer::new();
+            state.update_node(leader, Payload::BlockImport(block(height)), &mut feed);
+            state.update_node(stuck, interval(1), &mut feed);
+            stale_messages += feed
+                .counts()
+                .iter()
+                .find(|&(name, _)| name == "StaleNode")
+                .unwrap()
+                .1;
+
+            // It's only stale once it's more than 5 blocks behind:
+            assert_eq!(is_stale(&state, stuck), height > 6, "at height {}", height);
+            assert!(!is_stale(&state, leader));
+        }
+        assert_eq!(stale_messages, 1);
+
+        // Catching up means it's no longer stale:
+        state.update_node(stuck, interval(10), &mut FeedMessageSerializer::new());
+        assert!(!is_stale(&state, stuck));
+    }
 }
@@ -0,0 +1,123 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::Context;
+use common::node_types::BlockHash;
+use semver::Version;
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// The latest version that nodes on a chain are recommended to run. Parsed from
+/// strings of the form `GENESIS_HASH=VERSION`, for example `0x91b1...90c3=0.9.12`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RecommendedVersion {
+    pub genesis_hash: BlockHash,
+    pub version: Version,
+}
+
+impl FromStr for RecommendedVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (genesis_hash, version) = s
+            .split_once('=')
+            .with_context(|| "Expected GENESIS_HASH=VERSION")?;
+        let genesis_hash = genesis_hash
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid genesis hash '{}'", genesis_hash))?;
+        let version = version
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid version '{}'", version))?;
+        Ok(RecommendedVersion {
+            genesis_hash,
+            version,
+        })
+    }
+}
+
+impl TryFrom<String> for RecommendedVersion {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// How a node's version compares to the version recommended for its chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionStatus {
+    UpToDate,
+    Outdated,
+    /// We couldn't make sense of the version that the node reported.
+    Unknown,
+}
+
+impl VersionStatus {
+    /// Compare the version reported by a node against the recommended one. Nodes tend to
+    /// append a commit hash and their target to the version (eg `0.9.12-a1b2c3d-x86_64-linux-gnu`),
+    /// so only the leading `MAJOR.MINOR.PATCH` is compared.
+    pub fn of(node_version: &str, recommended: &Version) -> VersionStatus {
+        let release = node_version.split(['-', '+']).next().unwrap_or_default();
+        match Version::parse(release) {
+            Ok(version) if version >= *recommended => VersionStatus::UpToDate,
+            Ok(_) => VersionStatus::Outdated,
+            Err(_) => VersionStatus::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn node_versions_are_compared_by_release() {
+        let recommended = Version::new(0, 9, 12);
+        assert_eq!(
+            VersionStatus::of("0.9.12-a1b2c3d-x86_64-linux-gnu", &recommended),
+            VersionStatus::UpToDate
+        );
+        assert_eq!(
+            VersionStatus::of("1.0.0", &recommended),
+            VersionStatus::UpToDate
+        );
+        assert_eq!(
+            VersionStatus::of("0.9.9-a1b2c3d-x86_64-linux-gnu", &recommended),
+            VersionStatus::Outdated
+        );
+        assert_eq!(
+            VersionStatus::of("latest", &recommended),
+            VersionStatus::Unknown
+        );
+        assert_eq!(VersionStatus::of("", &recommended), VersionStatus::Unknown);
+    }
+
+    #[test]
+    fn recommended_versions_can_be_parsed() {
+        let rv: RecommendedVersion =
+            "0x0000000000000000000000000000000000000000000000000000000000000001=0.9.12"
+                .parse()
+                .unwrap();
+        assert_eq!(rv.genesis_hash, BlockHash::from_low_u64_be(1));
+        assert_eq!(rv.version, Version::new(0, 9, 12));
+
+        assert!("0x01".parse::<RecommendedVersion>().is_err());
+        assert!("nope=0.9.12".parse::<RecommendedVersion>().is_err());
+    }
+}
@@ -0,0 +1,352 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Generate made up chains and nodes, and send them to the aggregator as if they came
+//! from a shard. This lets the UI be developed against a running core without any
+//! real nodes or shards.
+
+use crate::aggregator::{AggregatorSet, FromShardWebsocket, ToShardWebsocket};
+use crate::find_location::LocationOverride;
+use common::internal_messages::ShardNodeId;
+use common::node_message::{Payload, SystemInterval};
+use common::node_types::{Block, BlockHash, BlockNumber, NetworkId, NodeDetails};
+use common::time;
+use futures::SinkExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+/// How often each synthetic chain produces a block.
+const BLOCK_TIME: Duration = Duration::from_secs(6);
+
+/// How many blocks behind the best block the synthetic nodes finalize.
+const FINALITY_LAG: BlockNumber = 2;
+
+/// Synthetic nodes are spread across these cities. Each gets its own range of
+/// (reserved for benchmarking) IP addresses, see [`location_overrides`].
+const CITIES: &[(f32, f32, &str)] = &[
+    (52.5167, 13.4, "Berlin"),
+    (51.5072, -0.1276, "London"),
+    (40.7128, -74.006, "New York"),
+    (37.7749, -122.4194, "San Francisco"),
+    (1.3521, 103.8198, "Singapore"),
+    (35.6762, 139.6503, "Tokyo"),
+    (-33.8688, 151.2093, "Sydney"),
+    (-23.5505, -46.6333, "São Paulo"),
+];
+
+/// The shape of the synthetic network to generate.
+#[derive(Debug, Clone, Copy)]
+pub struct SyntheticOpts {
+    /// How many chains to generate.
+    pub chains: usize,
+    /// How many nodes each chain has.
+    pub nodes_per_chain: usize,
+    /// How many nodes on each chain are replaced by new ones each minute.
+    pub churn_per_minute: f64,
+}
+
+/// Locations for the IP addresses handed out to synthetic nodes, so that they
+/// are placed on the map without being looked up.
+pub fn location_overrides() -> Vec<LocationOverride> {
+    CITIES
+        .iter()
+        .enumerate()
+        .map(|(idx, (latitude, longitude, city))| {
+            format!("198.18.{}.0/24={},{},{}", idx, latitude, longitude, city)
+                .parse()
+                .expect("synthetic location overrides are valid")
+        })
+        .collect()
+}
+
+/// Generate a synthetic network and feed it into the aggregator until the
+/// aggregator goes away.
+pub async fn run(aggregator: AggregatorSet, opts: SyntheticOpts) -> anyhow::Result<()> {
+    let mut tx_to_aggregator = aggregator.subscribe_shard();
+    let (channel, rx_from_aggregator) = flume::unbounded();
+    tx_to_aggregator
+        .send(FromShardWebsocket::Initialize {
+            channel,
+            allowed_chains: None,
+        })
+        .await?;
+
+    let mut network = SyntheticNetwork::new(opts, StdRng::from_entropy());
+    for msg in network.start() {
+        tx_to_aggregator.send(msg).await?;
+    }
+
+    let mut interval = tokio::time::interval(BLOCK_TIME);
+    loop {
+        interval.tick().await;
+        // Stop sending updates for any nodes that the aggregator doesn't want:
+        for msg in rx_from_aggregator.try_iter() {
+            let ToShardWebsocket::Mute { local_id, .. } = msg;
+            network.forget_node(local_id);
+        }
+        for msg in network.tick() {
+            tx_to_aggregator.send(msg).await?;
+        }
+    }
+}
+
+/// A made up set of chains and nodes which evolves a block at a time.
+struct SyntheticNetwork {
+    opts: SyntheticOpts,
+    rng: StdRng,
+    chains: Vec<SyntheticChain>,
+    next_local_id: usize,
+}
+
+struct SyntheticChain {
+    genesis_hash: BlockHash,
+    label: Box<str>,
+    best: BlockNumber,
+    nodes: Vec<SyntheticNode>,
+}
+
+struct SyntheticNode {
+    local_id: ShardNodeId,
+    best: BlockNumber,
+}
+
+impl SyntheticNetwork {
+    fn new(opts: SyntheticOpts, rng: StdRng) -> Self {
+        let chains = (0..opts.chains)
+            .map(|idx| SyntheticChain {
+                genesis_hash: block_hash(idx, 0),
+                label: format!("Synthetic Chain {}", idx + 1).into(),
+                best: 0,
+                nodes: Vec::new(),
+            })
+            .collect();
+
+        SyntheticNetwork {
+            opts,
+            rng,
+            chains,
+            next_local_id: 0,
+        }
+    }
+
+    /// Add the initial nodes on every chain.
+    fn start(&mut self) -> Vec<FromShardWebsocket> {
+        let mut msgs = Vec::new();
+        for chain_idx in 0..self.chains.len() {
+            for _ in 0..self.opts.nodes_per_chain {
+                self.add_node(chain_idx, &mut msgs);
+            }
+        }
+        msgs
+    }
+
+    /// Produce a new block on every chain, and replace some nodes if there's churn.
+    fn tick(&mut self) -> Vec<FromShardWebsocket> {
+        let mut msgs = Vec::new();
+        let churn_per_tick = self.opts.churn_per_minute * BLOCK_TIME.as_secs_f64() / 60.0;
+
+        for chain_idx in 0..self.chains.len() {
+            let chain = &mut self.chains[chain_idx];
+            chain.best += 1;
+
+            // Most nodes import the new block straight away; the rest catch up later:
+            for node in &mut chain.nodes {
+                if self.rng.gen_bool(0.9) {
+                    node.best = chain.best;
+                }
+                msgs.push(FromShardWebsocket::Update {
+                    local_id: node.local_id,
+                    payload: interval(&mut self.rng, chain_idx, node.best),
+                });
+            }
+
+            let mut churn = churn_per_tick.trunc() as usize;
+            if self.rng.gen_bool(churn_per_tick.fract()) {
+                churn += 1;
+            }
+            for _ in 0..churn.min(chain.nodes.len()) {
+                let nodes = &mut self.chains[chain_idx].nodes;
+                let node = nodes.swap_remove(self.rng.gen_range(0..nodes.len()));
+                msgs.push(FromShardWebsocket::Remove {
+                    local_id: node.local_id,
+                });
+                self.add_node(chain_idx, &mut msgs);
+            }
+        }
+
+        msgs
+    }
+
+    /// Stop generating messages for a node.
+    fn forget_node(&mut self, local_id: ShardNodeId) {
+        for chain in &mut self.chains {
+            chain.nodes.retain(|node| node.local_id != local_id);
+        }
+    }
+
+    fn add_node(&mut self, chain_idx: usize, msgs: &mut Vec<FromShardWebsocket>) {
+        let name = format!("synthetic-{}", self.next_local_id);
+        let local_id = ShardNodeId::new(self.next_local_id);
+        self.next_local_id += 1;
+
+        let city = self.rng.gen_range(0..CITIES.len());
+        let ip = Ipv4Addr::new(198, 18, city as u8, self.rng.gen_range(1..255));
+        let chain = &mut self.chains[chain_idx];
+
+        msgs.push(FromShardWebsocket::Add {
+            local_id,
+            ip: IpAddr::V4(ip),
+            node: NodeDetails {
+                chain: chain.label.clone(),
+                name: name.as_str().into(),
+                implementation: "Synthetic Node".into(),
+                version: "1.0.0".into(),
+                validator: None,
+                network_id: NetworkId::from(&name).unwrap_or_default(),
+                startup_time: Some(time::now().to_string().into()),
+                target_os: Some("linux".into()),
+                target_arch: Some("x86_64".into()),
+                target_env: Some("gnu".into()),
+                sysinfo: None,
+            },
+            genesis_hash: chain.genesis_hash,
+        });
+
+        // Nodes joining an existing chain start off in sync with it:
+        if chain.best > 0 {
+            msgs.push(FromShardWebsocket::Update {
+                local_id,
+                payload: interval(&mut self.rng, chain_idx, chain.best),
+            });
+        }
+        chain.nodes.push(SyntheticNode {
+            local_id,
+            best: chain.best,
+        });
+    }
+}
+
+/// A periodic update from a node which is at the given height.
+fn interval(rng: &mut StdRng, chain_idx: usize, best: BlockNumber) -> Payload {
+    let finalized = best.saturating_sub(FINALITY_LAG);
+    Payload::SystemInterval(SystemInterval {
+        peers: Some(rng.gen_range(5..50)),
+        txcount: Some(rng.gen_range(0..100)),
+        bandwidth_upload: Some(rng.gen_range(1000.0..100_000.0)),
+        bandwidth_download: Some(rng.gen_range(1000.0..100_000.0)),
+        finalized_height: Some(finalized),
+        finalized_hash: Some(block_hash(chain_idx, finalized)),
+        block: Some(Block {
+            hash: block_hash(chain_idx, best),
+            height: best,
+        }),
+        used_state_cache_size: Some(rng.gen_range(1_000_000.0..50_000_000.0)),
+    })
+}
+
+/// A made up, but unique, hash for a block on a synthetic chain.
+fn block_hash(chain_idx: usize, height: BlockNumber) -> BlockHash {
+    BlockHash::from_low_u64_be(((chain_idx as u64 + 1) << 40) | height)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed_message::FeedMessageSerializer;
+    use crate::state::{NodeId, State};
+    use std::collections::HashMap;
+
+    #[test]
+    fn generates_the_configured_shape_of_state() {
+        let mut network = SyntheticNetwork::new(
+            SyntheticOpts {
+                chains: 3,
+                nodes_per_chain: 4,
+                churn_per_minute: 5.0,
+            },
+            StdRng::seed_from_u64(0),
+        );
+        let mut state = State::new(None, 1000);
+        let mut node_ids: HashMap<ShardNodeId, NodeId> = HashMap::new();
+        let mut apply = |msgs: Vec<FromShardWebsocket>| {
+            for msg in msgs {
+                match msg {
+                    FromShardWebsocket::Add {
+                        local_id,
+                        node,
+                        genesis_hash,
+                        ..
+                    } => {
+                        let node_id = state.add_node(genesis_hash, node).unwrap_id();
+                        node_ids.insert(local_id, node_id);
+                    }
+                    FromShardWebsocket::Update { local_id, payload } => {
+                        let node_id = node_ids[&local_id];
+                        state.update_node(node_id, payload, &mut FeedMessageSerializer::new());
+                    }
+                    FromShardWebsocket::Remove { local_id } => {
+                        let node_id = node_ids.remove(&local_id).unwrap();
+                        state.remove_node(node_id).unwrap();
+                    }
+                    msg => panic!("Unexpected message {:?}", msg),
+                }
+            }
+        };
+
+        apply(network.start());
+        for _ in 0..20 {
+            apply(network.tick());
+        }
+
+        // Nodes have come and gone, but each chain has the configured number of them:
+        assert_eq!(node_ids.len(), 12);
+        let mut labels: Vec<_> = state.iter_chains().map(|c| c.label().to_owned()).collect();
+        labels.sort();
+        assert_eq!(
+            labels,
+            vec![
+                "Synthetic Chain 1",
+                "Synthetic Chain 2",
+                "Synthetic Chain 3"
+            ]
+        );
+        for chain in state.iter_chains() {
+            assert_eq!(chain.node_count(), 4);
+            assert_eq!(chain.best_block().height, 20);
+            assert_eq!(chain.finalized_block().height, 20 - FINALITY_LAG);
+        }
+    }
+
+    #[test]
+    fn synthetic_nodes_are_located() {
+        let overrides = crate::find_location::LocationOverrides::new(location_overrides());
+        let mut network = SyntheticNetwork::new(
+            SyntheticOpts {
+                chains: 1,
+                nodes_per_chain: 20,
+                churn_per_minute: 0.0,
+            },
+            StdRng::seed_from_u64(0),
+        );
+        for msg in network.start() {
+            if let FromShardWebsocket::Add { ip, .. } = msg {
+                assert!(overrides.find(ip).is_some(), "{} not located", ip);
+            }
+        }
+    }
+}
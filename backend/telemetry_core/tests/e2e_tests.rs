@@ -43,6 +43,7 @@ use std::{str::FromStr, time::Duration};
 use test_utils::{
     assert_contains_matches,
     feed_message_de::{FeedMessage, NodeDetails},
+    server::CoreProcess,
     workspace::{start_server, start_server_debug, CoreOpts, ServerOpts, ShardOpts},
 };
 
@@ -56,6 +57,24 @@ fn ghash(id: u64) -> BlockHash {
     BlockHash::from_low_u64_be(id)
 }
 
+/// Keep connecting new feeds until one is sent the message we're expecting, so that
+/// we don't rely on the core having caught up after some fixed amount of time.
+async fn wait_for_feed_message(core: &CoreProcess, expected: &FeedMessage) {
+    let wait = async {
+        loop {
+            let (_feed_tx, mut feed_rx) = core.connect_feed().await.unwrap();
+            let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+            if feed_messages.contains(expected) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(30), wait)
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for feed message {:?}", expected));
+}
+
 /// The simplest test we can run; the main benefit of this test (since we check similar)
 /// below) is just to give a feel for _how_ we can test basic feed related things.
 #[tokio::test]
@@ -330,7 +349,7 @@ async fn e2e_feeds_told_about_chain_rename_and_stay_subscribed() {
     assert_contains_matches!(
         feed_messages,
         FeedMessage::AddedNode { node: NodeDetails { name: node_name, .. }, ..} if node_name == "Node 2",
-        FeedMessage::AddedChain { name, genesis_hash, node_count: 2 } if name == "Initial chain name" && genesis_hash == ghash(1),
+        FeedMessage::ChainNodeCounts { genesis_hash, node_count: 2, highest_node_count: 2 } if genesis_hash == ghash(1),
     );
 
     // Subscribe a third node. The chain renames, so we're told about the new node but also
@@ -355,7 +374,7 @@ async fn e2e_feeds_told_about_chain_rename_and_stay_subscribed() {
     assert_contains_matches!(
         feed_messages,
         FeedMessage::AddedNode { node: NodeDetails { name: node_name, .. }, ..} if node_name == "Node 4",
-        FeedMessage::AddedChain { name, genesis_hash, node_count: 4 } if name == "New chain name" && genesis_hash == ghash(1),
+        FeedMessage::ChainNodeCounts { genesis_hash, node_count: 4, highest_node_count: 4 } if genesis_hash == ghash(1),
     );
 }
 
@@ -497,7 +516,7 @@ async fn e2e_feed_can_subscribe_and_unsubscribe_from_chain() {
         feed_messages,
         SubscribedTo { genesis_hash } if genesis_hash == ghash(1),
         TimeSync {..},
-        BestBlock { block_number: 0, timestamp: 0, avg_block_time: None },
+        BestBlock { block_number: 0, timestamp: 0, avg_block_time: None, .. },
         BestFinalized { block_number: 0, .. },
         AddedNode { node_id: 0, node: NodeDetails { name, .. }, .. } if name == "Alice 1",
         FinalizedBlock { node_id: 0, block_number: 0, .. }
@@ -860,3 +879,81 @@ async fn e2e_max_nodes_per_connection_is_enforced() {
     // Tidy up:
     server.shutdown().await;
 }
+
+/// Messages from shards can be recorded, and replaying them into a fresh
+/// core leads to the same state.
+#[tokio::test]
+async fn e2e_recorded_shard_messages_can_be_replayed() {
+    let recording =
+        std::env::temp_dir().join(format!("e2e-shard-recording-{}.bin", std::process::id()));
+    let replayed_recording = recording.with_extension("replay.bin");
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            record_shard_messages: Some(recording.clone()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+
+    for (id, name) in [(1, "Alice"), (2, "Bob")] {
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":id,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":"Local Testnet",
+                        "config":"",
+                        "genesis_hash": ghash(1),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":name,
+                        "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+    }
+
+    let expected_chain = FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 2,
+    };
+    wait_for_feed_message(server.get_core(), &expected_chain).await;
+
+    // Take a copy of the recording before shutting down, so that it doesn't
+    // contain the shard disconnecting:
+    std::fs::copy(&recording, &replayed_recording).unwrap();
+    server.shutdown().await;
+
+    // Replay into a new core; there's no need to connect any shards:
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            replay_shard_messages: Some(replayed_recording.clone()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    wait_for_feed_message(server.get_core(), &expected_chain).await;
+
+    // Tidy up:
+    server.shutdown().await;
+    let _ = std::fs::remove_file(recording);
+    let _ = std::fs::remove_file(replayed_recording);
+}
@@ -343,35 +343,39 @@ where
 
                 // Until the aggregator receives an `Add` message, which we can create once
                 // we see one of these SystemConnected ones, it will ignore messages with
-                // the corresponding message_id.
-                if let node_message::Payload::SystemConnected(info) = payload {
-                    // Too many nodes seen on this connection? Ignore this one.
-                    if allowed_message_ids.len() >= max_nodes_per_connection {
-                        log::info!("Ignoring new node from {:?} (we've hit the max of {} nodes per connection)", real_addr, max_nodes_per_connection);
-                        continue;
-                    }
-
-                    // Note of the message ID, allowing telemetry for it.
-                    allowed_message_ids.insert(message_id, Instant::now());
-
-                    // Tell the aggregator loop about the new node.
-                    log::info!("Adding node with message ID {} from {:?}", message_id, real_addr);
-                    let _ = tx_to_aggregator.send(FromWebsocket::Add {
-                        message_id,
-                        ip: real_addr,
-                        node: info.node,
-                        genesis_hash: info.genesis_hash,
-                    }).await;
-                }
-                // Anything that's not an "Add" is an Update. The aggregator will ignore
-                // updates against a message_id that hasn't first been Added, above.
-                else {
-                    if let Some(last_seen) = allowed_message_ids.get_mut(&message_id) {
-                        *last_seen = Instant::now();
-                        if let Err(e) = tx_to_aggregator.send(FromWebsocket::Update { message_id, payload } ).await {
-                            log::error!("Failed to send node message to aggregator: {}", e);
+                // the corresponding message_id. SystemConnected messages for an already added
+                // node are passed on as updates, so that the core can decide what to do if the
+                // node now claims to be on a different chain.
+                match payload {
+                    node_message::Payload::SystemConnected(info) if !allowed_message_ids.contains_key(&message_id) => {
+                        // Too many nodes seen on this connection? Ignore this one.
+                        if allowed_message_ids.len() >= max_nodes_per_connection {
+                            log::info!("Ignoring new node from {:?} (we've hit the max of {} nodes per connection)", real_addr, max_nodes_per_connection);
                             continue;
                         }
+
+                        // Note of the message ID, allowing telemetry for it.
+                        allowed_message_ids.insert(message_id, Instant::now());
+
+                        // Tell the aggregator loop about the new node.
+                        log::info!("Adding node with message ID {} from {:?}", message_id, real_addr);
+                        let _ = tx_to_aggregator.send(FromWebsocket::Add {
+                            message_id,
+                            ip: real_addr,
+                            node: info.node,
+                            genesis_hash: info.genesis_hash,
+                        }).await;
+                    }
+                    // Anything that's not an "Add" is an Update. The aggregator will ignore
+                    // updates against a message_id that hasn't first been Added, above.
+                    payload => {
+                        if let Some(last_seen) = allowed_message_ids.get_mut(&message_id) {
+                            *last_seen = Instant::now();
+                            if let Err(e) = tx_to_aggregator.send(FromWebsocket::Update { message_id, payload } ).await {
+                                log::error!("Failed to send node message to aggregator: {}", e);
+                                continue;
+                            }
+                        }
                     }
                 }
             }
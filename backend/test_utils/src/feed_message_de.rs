@@ -27,6 +27,7 @@ pub enum FeedMessage {
         block_number: BlockNumber,
         timestamp: Timestamp,
         avg_block_time: Option<u64>,
+        block_hash: BlockHash,
     },
     BestFinalized {
         block_number: BlockNumber,
@@ -79,6 +80,11 @@ pub enum FeedMessage {
     RemovedChain {
         genesis_hash: BlockHash,
     },
+    ChainNodeCounts {
+        genesis_hash: BlockHash,
+        node_count: usize,
+        highest_node_count: usize,
+    },
     SubscribedTo {
         genesis_hash: BlockHash,
     },
@@ -165,12 +171,13 @@ impl FeedMessage {
             }
             // BestBlock
             1 => {
-                let (block_number, timestamp, avg_block_time) =
+                let (block_number, timestamp, avg_block_time, block_hash) =
                     serde_json::from_str(raw_val.get())?;
                 FeedMessage::BestBlock {
                     block_number,
                     timestamp,
                     avg_block_time,
+                    block_hash,
                 }
             }
             // BestFinalized
@@ -273,6 +280,16 @@ impl FeedMessage {
                 let genesis_hash = serde_json::from_str(raw_val.get())?;
                 FeedMessage::RemovedChain { genesis_hash }
             }
+            // ChainNodeCounts
+            30 => {
+                let (genesis_hash, node_count, highest_node_count) =
+                    serde_json::from_str(raw_val.get())?;
+                FeedMessage::ChainNodeCounts {
+                    genesis_hash,
+                    node_count,
+                    highest_node_count,
+                }
+            }
             // SubscribedTo
             13 => {
                 let genesis_hash = serde_json::from_str(raw_val.get())?;
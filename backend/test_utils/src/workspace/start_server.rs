@@ -37,6 +37,8 @@ pub struct CoreOpts {
     pub feed_timeout: Option<u64>,
     pub worker_threads: Option<usize>,
     pub num_aggregators: Option<usize>,
+    pub record_shard_messages: Option<std::path::PathBuf>,
+    pub replay_shard_messages: Option<std::path::PathBuf>,
 }
 
 impl Default for CoreOpts {
@@ -45,6 +47,8 @@ impl Default for CoreOpts {
             feed_timeout: None,
             worker_threads: None,
             num_aggregators: None,
+            record_shard_messages: None,
+            replay_shard_messages: None,
         }
     }
 }
@@ -161,6 +165,12 @@ pub async fn start_server(
     if let Some(val) = core_opts.num_aggregators {
         core_command = core_command.arg("--num-aggregators").arg(val.to_string());
     }
+    if let Some(val) = core_opts.record_shard_messages {
+        core_command = core_command.arg("--record-shard-messages").arg(val);
+    }
+    if let Some(val) = core_opts.replay_shard_messages {
+        core_command = core_command.arg("--replay-shard-messages").arg(val);
+    }
 
     // Start the server
     Server::start(server::StartOpts::ShardAndCore {
@@ -29,6 +29,7 @@ import {
   UploadColumn,
   DownloadColumn,
   StateCacheColumn,
+  BlockPropagationColumn,
 } from './components/List';
 
 const CONNECTION_TIMEOUT_BASE = (1000 * 5) as Types.Milliseconds; // 5 seconds
@@ -255,6 +256,18 @@ export class Connection {
           break;
         }
 
+        case ACTIONS.BlockPropagation: {
+          const [id, height, propagationTime] = message.payload;
+
+          nodes.mutAndMaybeSort(
+            id,
+            (node) => node.updatePropagation(height, propagationTime),
+            sortByColumn === BlockPropagationColumn
+          );
+
+          break;
+        }
+
         case ACTIONS.FinalizedBlock: {
           const [id, height, hash] = message.payload;
 
@@ -327,6 +340,19 @@ export class Connection {
           break;
         }
 
+        case ACTIONS.ChainNodeCounts: {
+          const [genesisHash, nodeCount, highestNodeCount] = message.payload;
+          const chain = chains.get(genesisHash);
+
+          if (chain) {
+            chain.nodeCount = nodeCount;
+            chain.highestNodeCount = highestNodeCount;
+            this.appUpdate({ chains });
+          }
+
+          break;
+        }
+
         case ACTIONS.RemovedChain: {
           chains.delete(message.payload);
 
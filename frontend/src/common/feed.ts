@@ -36,6 +36,7 @@ import {
   Milliseconds,
   ChainLabel,
   GenesisHash,
+  AuthoritySetId,
   AuthoritySetInfo,
   ChainStats,
 } from './types';
@@ -64,6 +65,14 @@ export const ACTIONS = {
   StaleNode: 0x14 as 0x14,
   NodeIO: 0x15 as 0x15,
   ChainStatsUpdate: 0x16 as 0x16,
+  AverageTimeToFinality: 0x17 as 0x17,
+  NodeGroup: 0x18 as 0x18,
+  ChainDecentralization: 0x19 as 0x19,
+  VersionCompliance: 0x1a as 0x1a,
+  SyncBreakdown: 0x1b as 0x1b,
+  BlockPropagation: 0x1c as 0x1c,
+  AuthoritySet: 0x1d as 0x1d,
+  ChainNodeCounts: 0x1e as 0x1e,
 };
 
 export type Action = typeof ACTIONS[keyof typeof ACTIONS];
@@ -81,7 +90,7 @@ export namespace Variants {
 
   export interface BestBlockMessage extends MessageBase {
     action: typeof ACTIONS.BestBlock;
-    payload: [BlockNumber, Timestamp, Maybe<Milliseconds>];
+    payload: [BlockNumber, Timestamp, Maybe<Milliseconds>, BlockHash];
   }
 
   export interface BestFinalizedBlockMessage extends MessageBase {
@@ -197,6 +206,46 @@ export namespace Variants {
     action: typeof ACTIONS.ChainStatsUpdate;
     payload: ChainStats;
   }
+
+  export interface AverageTimeToFinalityMessage extends MessageBase {
+    action: typeof ACTIONS.AverageTimeToFinality;
+    payload: Maybe<Milliseconds>;
+  }
+
+  export interface NodeGroupMessage extends MessageBase {
+    action: typeof ACTIONS.NodeGroup;
+    payload: [NodeId, string];
+  }
+
+  export interface ChainDecentralizationMessage extends MessageBase {
+    action: typeof ACTIONS.ChainDecentralization;
+    payload: [number, number];
+  }
+
+  export interface VersionComplianceMessage extends MessageBase {
+    action: typeof ACTIONS.VersionCompliance;
+    payload: [GenesisHash, number, number];
+  }
+
+  export interface SyncBreakdownMessage extends MessageBase {
+    action: typeof ACTIONS.SyncBreakdown;
+    payload: [GenesisHash, number, number];
+  }
+
+  export interface BlockPropagationMessage extends MessageBase {
+    action: typeof ACTIONS.BlockPropagation;
+    payload: [NodeId, BlockNumber, Milliseconds];
+  }
+
+  export interface AuthoritySetMessage extends MessageBase {
+    action: typeof ACTIONS.AuthoritySet;
+    payload: AuthoritySetId;
+  }
+
+  export interface ChainNodeCountsMessage extends MessageBase {
+    action: typeof ACTIONS.ChainNodeCounts;
+    payload: [GenesisHash, NodeCount, NodeCount];
+  }
 }
 
 export type Message =
@@ -222,7 +271,15 @@ export type Message =
   | Variants.StaleNodeMessage
   | Variants.PongMessage
   | Variants.NodeIOMessage
-  | Variants.ChainStatsUpdate;
+  | Variants.ChainStatsUpdate
+  | Variants.AverageTimeToFinalityMessage
+  | Variants.NodeGroupMessage
+  | Variants.ChainDecentralizationMessage
+  | Variants.VersionComplianceMessage
+  | Variants.SyncBreakdownMessage
+  | Variants.BlockPropagationMessage
+  | Variants.AuthoritySetMessage
+  | Variants.ChainNodeCountsMessage;
 
 /**
  * Data type to be sent to the feed. Passing through strings means we can only serialize once,
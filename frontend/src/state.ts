@@ -171,6 +171,17 @@ export class Node {
     this.trigger();
   }
 
+  public updatePropagation(
+    height: Types.BlockNumber,
+    propagationTime: Types.Milliseconds
+  ) {
+    // Only the propagation of the block we're showing for the node is of interest:
+    if (height === this.height) {
+      this.propagationTime = propagationTime as Types.PropagationTime;
+      this.trigger();
+    }
+  }
+
   public updateFinalized(height: Types.BlockNumber, hash: Types.BlockHash) {
     this.finalized = height;
     this.finalizedHash = hash;
@@ -290,4 +301,5 @@ export interface ChainData {
   label: Types.ChainLabel;
   genesisHash: Types.GenesisHash;
   nodeCount: Types.NodeCount;
+  highestNodeCount?: Types.NodeCount;
 }